@@ -0,0 +1,172 @@
+//! Hermetic build environments
+//!
+//! A build sees none of the variables nexis itself was started with. Its
+//! environment is made from scratch:
+//!
+//! | variable            | value                                            |
+//! |---------------------|--------------------------------------------------|
+//! | `PATH`              | `bin/` of each build dependency in the store     |
+//! | `PKG_CONFIG_PATH`   | their `lib/pkgconfig` and `share/pkgconfig`      |
+//! | `CFLAGS`/`CXXFLAGS` | `-I` for their `include/`                        |
+//! | `LDFLAGS`           | `-L` for their `lib/`                            |
+//! | `SOURCE_DATE_EPOCH` | newest modification time in the source tree      |
+//! | `TZ`, `LC_ALL`      | `UTC` and `C`                                    |
+//! | `HOME`              | set by the builder to a private, empty directory |
+//!
+//! Directories a dependency does not have are left out. A build with no
+//! `bin/` directories gets `PATH=/var/empty` rather than an empty `PATH`,
+//! which `sh` would take to mean the current directory, and only finds what
+//! the shell provides.
+//! Git checkouts carry the commit time on every file, so for git sources
+//! `SOURCE_DATE_EPOCH` is the time of the locked commit.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+/// `PATH` of a build without `bin/` directories, conventionally empty or missing
+const EMPTY_PATH: &str = "/var/empty";
+
+/// Environment variables of one build
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BuildEnvironment {
+    vars: BTreeMap<String, String>,
+}
+
+impl BuildEnvironment {
+    /// Environment for a build depending on the store objects at `dependencies`
+    pub fn new(dependencies: &[PathBuf]) -> Self {
+        let dirs = |subdirs: &[&str]| -> Vec<PathBuf> {
+            dependencies
+                .iter()
+                .flat_map(|dep| subdirs.iter().map(move |sub| dep.join(sub)))
+                .filter(|dir| dir.is_dir())
+                .collect()
+        };
+        let search_path =
+            |dirs: Vec<PathBuf>| join(dirs.iter().map(|d| d.display().to_string()), ":");
+        let flags = |flag: &str, dirs: Vec<PathBuf>| {
+            join(dirs.iter().map(|d| format!("{}{}", flag, d.display())), " ")
+        };
+
+        let includes = flags("-I", dirs(&["include"]));
+        let mut env = Self::default();
+        let bins = dirs(&["bin"]);
+        if bins.is_empty() {
+            env.set("PATH", EMPTY_PATH);
+        } else {
+            env.set("PATH", search_path(bins));
+        }
+        env.set(
+            "PKG_CONFIG_PATH",
+            search_path(dirs(&["lib/pkgconfig", "share/pkgconfig"])),
+        );
+        env.set("CFLAGS", includes.clone());
+        env.set("CXXFLAGS", includes);
+        env.set("LDFLAGS", flags("-L", dirs(&["lib"])));
+        env.set("TZ", "UTC");
+        env.set("LC_ALL", "C");
+        env
+    }
+
+    /// Set `SOURCE_DATE_EPOCH` from the newest modification time under `src`
+    pub fn with_source_date_epoch(mut self, src: &Path) -> Result<Self> {
+        let mut newest = 0;
+        for entry in WalkDir::new(src) {
+            let entry = entry.with_context(|| format!("Failed to read {}", src.display()))?;
+            newest = newest.max(entry.metadata()?.mtime());
+        }
+        self.set("SOURCE_DATE_EPOCH", newest.to_string());
+        Ok(self)
+    }
+
+    /// Set `name` to `value`, replacing any earlier value
+    pub fn set(&mut self, name: impl Into<String>, value: impl Into<String>) {
+        self.vars.insert(name.into(), value.into());
+    }
+
+    /// Value of `name`, if set
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name).map(String::as_str)
+    }
+
+    /// Every variable, sorted by name
+    pub fn vars(&self) -> &BTreeMap<String, String> {
+        &self.vars
+    }
+}
+
+impl Extend<(String, String)> for BuildEnvironment {
+    fn extend<I: IntoIterator<Item = (String, String)>>(&mut self, vars: I) {
+        self.vars.extend(vars);
+    }
+}
+
+fn join(items: impl Iterator<Item = String>, separator: &str) -> String {
+    items.collect::<Vec<_>>().join(separator)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_dependency_dirs() {
+        let dir = tempfile::tempdir().unwrap();
+        let zlib = dir.path().join("zlib");
+        let make = dir.path().join("make");
+        for sub in ["include", "lib/pkgconfig"] {
+            fs::create_dir_all(zlib.join(sub)).unwrap();
+        }
+        fs::create_dir_all(make.join("bin")).unwrap();
+
+        let env = BuildEnvironment::new(&[zlib.clone(), make.clone()]);
+        let path = |p: &Path| p.display().to_string();
+        assert_eq!(env.get("PATH"), Some(path(&make.join("bin")).as_str()));
+        assert_eq!(
+            env.get("PKG_CONFIG_PATH"),
+            Some(path(&zlib.join("lib/pkgconfig")).as_str())
+        );
+        assert_eq!(
+            env.get("CFLAGS"),
+            Some(format!("-I{}", path(&zlib.join("include"))).as_str())
+        );
+        assert_eq!(
+            env.get("LDFLAGS"),
+            Some(format!("-L{}", path(&zlib.join("lib"))).as_str())
+        );
+        assert_eq!(env.get("TZ"), Some("UTC"));
+        assert_eq!(env.get("LC_ALL"), Some("C"));
+        assert_eq!(env.get("HOME"), None);
+
+        let empty = BuildEnvironment::new(&[]);
+        assert_eq!(empty.get("PATH"), Some(EMPTY_PATH));
+    }
+
+    #[test]
+    fn test_source_date_epoch() {
+        let src = tempfile::tempdir().unwrap();
+        fs::create_dir(src.path().join("lib")).unwrap();
+        let epoch = SystemTime::UNIX_EPOCH;
+        for (name, secs) in [("README", 1_000), ("lib/a.c", 1_700_000_000)] {
+            let file = fs::File::create(src.path().join(name)).unwrap();
+            file.set_modified(epoch + Duration::from_secs(secs))
+                .unwrap();
+        }
+        for dir in [src.path().join("lib"), src.path().to_path_buf()] {
+            fs::File::open(&dir)
+                .unwrap()
+                .set_modified(epoch + Duration::from_secs(5))
+                .unwrap();
+        }
+
+        let env = BuildEnvironment::new(&[])
+            .with_source_date_epoch(src.path())
+            .unwrap();
+        assert_eq!(env.get("SOURCE_DATE_EPOCH"), Some("1700000000"));
+    }
+}
@@ -0,0 +1,410 @@
+//! Build phase execution
+//!
+//! Runs the phases of a [`BuildPlan`] as child processes on tokio. Each phase
+//! is one `/bin/sh -e -x` script made of its commands, started in a process
+//! group of its own with nothing but its [`BuildEnvironment`]. Everything a
+//! build prints goes to a single log, `<log dir>/<hash>-<name>.log`, one
+//! timestamped line at a time:
+//!
+//! ```text
+//! 2026-01-05T10:42:17.031Z [nexis] ==> configure
+//! 2026-01-05T10:42:17.034Z [err] + ./configure --prefix=/usr
+//! 2026-01-05T10:42:17.120Z [out] checking for gcc... gcc
+//! 2026-01-05T10:42:19.877Z [nexis] <== configure: exit status: 0 (2.8s)
+//! ```
+//!
+//! A build that runs past its timeout has the process group of its current
+//! phase killed, taking along anything the phase started. Processes a phase
+//! leaves running in the background are killed once it exits.
+//!
+//! With the `sandbox` feature, phases can also run inside a
+//! [`Sandbox`](super::sandbox::Sandbox).
+
+use anyhow::{Context, Result};
+use chrono::{SecondsFormat, Utc};
+use rustix::process::{kill_process_group, Pid, Signal};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Stdio};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, BufWriter};
+use tokio::process::Command;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::{timeout_at, Instant};
+
+use super::environment::BuildEnvironment;
+use crate::config::BuildPhase;
+use crate::constants::{BUILD_TIMEOUT_SECS, NEXIS_BUILD_LOG_DIR};
+use crate::packages::BuildPlan;
+
+#[cfg(feature = "sandbox")]
+use super::sandbox::Sandbox;
+#[cfg(feature = "sandbox")]
+use std::sync::Arc;
+
+/// Shell running build phases
+///
+/// Named by absolute path, since a build's `PATH` only holds its dependencies.
+const BUILD_SHELL: &str = "/bin/sh";
+
+/// How one phase of a build ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseStatus {
+    /// Phase that ran
+    pub phase: BuildPhase,
+    /// Exit status of the phase's shell
+    pub status: ExitStatus,
+    /// Wall-clock time the phase took
+    pub duration: Duration,
+}
+
+/// What running a build plan did
+#[derive(Debug, Clone)]
+pub struct ExecutionReport {
+    /// Log the build's output was written to
+    pub log: PathBuf,
+    /// Phases that ran, in order; the build stops at the first failure
+    pub phases: Vec<PhaseStatus>,
+    /// Whether the last phase was killed for running past the timeout
+    pub timed_out: bool,
+}
+
+impl ExecutionReport {
+    /// The phase that failed, if any
+    pub fn failure(&self) -> Option<&PhaseStatus> {
+        self.phases.last().filter(|p| !p.status.success())
+    }
+
+    /// Whether every phase succeeded
+    pub fn success(&self) -> bool {
+        self.failure().is_none()
+    }
+}
+
+/// Runs build phases, logging their output
+#[derive(Debug, Clone)]
+pub struct Executor {
+    log_dir: PathBuf,
+    timeout: Option<Duration>,
+    #[cfg(feature = "sandbox")]
+    sandbox: Option<Arc<Sandbox>>,
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new(NEXIS_BUILD_LOG_DIR)
+    }
+}
+
+impl Executor {
+    /// Create an executor writing logs to `log_dir`
+    ///
+    /// Builds time out after [`BUILD_TIMEOUT_SECS`], unless that is zero.
+    pub fn new(log_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            log_dir: log_dir.into(),
+            timeout: (BUILD_TIMEOUT_SECS > 0).then(|| Duration::from_secs(BUILD_TIMEOUT_SECS)),
+            #[cfg(feature = "sandbox")]
+            sandbox: None,
+        }
+    }
+
+    /// Limit a whole build to `timeout`, or lift the limit with `None`
+    pub fn with_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Run phases inside `sandbox`, or directly on the host with `None`
+    #[cfg(feature = "sandbox")]
+    pub fn with_sandbox(mut self, sandbox: Option<Arc<Sandbox>>) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Path a phase sees for the host path `host`
+    ///
+    /// Only differs from `host` for the build directory of a sandboxed build.
+    pub fn guest_path(&self, host: &Path) -> PathBuf {
+        #[cfg(feature = "sandbox")]
+        if let Some(sandbox) = &self.sandbox {
+            return sandbox.guest_path(host);
+        }
+        host.to_path_buf()
+    }
+
+    /// Directory logs are written to
+    pub fn log_dir(&self) -> &Path {
+        &self.log_dir
+    }
+
+    /// Log of the build of `name` from the source with `hash`
+    pub fn log_path(&self, hash: &str, name: &str) -> PathBuf {
+        self.log_dir.join(format!("{}-{}.log", hash, name))
+    }
+
+    /// Most recently written build log of `name` in `log_dir`
+    pub fn latest_log(log_dir: &Path, name: &str) -> Result<Option<PathBuf>> {
+        let entries = match fs::read_dir(log_dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read {}", log_dir.display()))
+            }
+        };
+
+        let mut latest = None;
+        for entry in entries {
+            let entry = entry?;
+            let file_name = entry.file_name();
+            let Some(stem) = file_name.to_str().and_then(|f| f.strip_suffix(".log")) else {
+                continue;
+            };
+            let Some((hash, package)) = stem.split_once('-') else {
+                continue;
+            };
+            if package != name || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                continue;
+            }
+            let modified = entry.metadata()?.modified()?;
+            if latest.as_ref().is_none_or(|(time, _)| modified > *time) {
+                latest = Some((modified, entry.path()));
+            }
+        }
+        Ok(latest.map(|(_, path)| path))
+    }
+
+    /// Run the phases of `plan` in `dir` with only the variables in `env`
+    ///
+    /// The build stops at the first phase that fails or times out; the
+    /// report says which. An error means a phase could not be started or the
+    /// log could not be written.
+    pub async fn run(
+        &self,
+        name: &str,
+        hash: &str,
+        plan: &BuildPlan,
+        dir: &Path,
+        env: &BuildEnvironment,
+    ) -> Result<ExecutionReport> {
+        fs::create_dir_all(&self.log_dir)
+            .with_context(|| format!("Failed to create {}", self.log_dir.display()))?;
+        let log = self.log_path(hash, name);
+        let file = tokio::fs::File::create(&log)
+            .await
+            .with_context(|| format!("Failed to create {}", log.display()))?;
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<String>();
+        let writer = tokio::spawn(async move {
+            let mut file = BufWriter::new(file);
+            while let Some(line) = rx.recv().await {
+                file.write_all(line.as_bytes()).await?;
+            }
+            file.flush().await
+        });
+
+        let deadline = self.timeout.map(|t| Instant::now() + t);
+        let mut report = ExecutionReport {
+            log: log.clone(),
+            phases: Vec::new(),
+            timed_out: false,
+        };
+        for phase in &plan.phases {
+            let started = Instant::now();
+            let _ = tx.send(stamp("nexis", &format!("==> {}", phase.phase)));
+
+            let mut command = Command::new(BUILD_SHELL);
+            command
+                .arg("-e")
+                .arg("-x")
+                .arg("-c")
+                .arg(phase.commands.join("\n"))
+                .current_dir(dir)
+                .env_clear()
+                .envs(env.vars())
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .process_group(0)
+                .kill_on_drop(true);
+            #[cfg(feature = "sandbox")]
+            if let Some(sandbox) = &self.sandbox {
+                sandbox.confine(&mut command, dir)?;
+            }
+            let mut child = command
+                .spawn()
+                .with_context(|| format!("Failed to start the {} phase", phase.phase))?;
+            let group = child.id().and_then(|id| Pid::from_raw(id as i32));
+            let readers = [
+                child
+                    .stdout
+                    .take()
+                    .map(|out| tokio::spawn(forward(out, "out", tx.clone()))),
+                child
+                    .stderr
+                    .take()
+                    .map(|err| tokio::spawn(forward(err, "err", tx.clone()))),
+            ];
+
+            let waited = match deadline {
+                Some(deadline) => timeout_at(deadline, child.wait()).await.ok(),
+                None => Some(child.wait().await),
+            };
+            let status = match waited {
+                Some(status) => status?,
+                None => {
+                    report.timed_out = true;
+                    kill_group(group);
+                    child.wait().await?
+                }
+            };
+            // Close the output pipes held by anything left in the background
+            kill_group(group);
+            for reader in readers.into_iter().flatten() {
+                let _ = reader.await;
+            }
+
+            let duration = started.elapsed();
+            let outcome = if report.timed_out {
+                "timed out".to_string()
+            } else {
+                status.to_string()
+            };
+            let _ = tx.send(stamp(
+                "nexis",
+                &format!("<== {}: {} ({:.1?})", phase.phase, outcome, duration),
+            ));
+            report.phases.push(PhaseStatus {
+                phase: phase.phase,
+                status,
+                duration,
+            });
+            if !status.success() {
+                break;
+            }
+        }
+
+        drop(tx);
+        writer
+            .await?
+            .with_context(|| format!("Failed to write {}", log.display()))?;
+        Ok(report)
+    }
+}
+
+/// Send each line of `stream` to the log writer
+async fn forward(stream: impl AsyncRead + Unpin, tag: &'static str, tx: UnboundedSender<String>) {
+    let mut reader = BufReader::new(stream);
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        match reader.read_until(b'\n', &mut line).await {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {
+                let text = String::from_utf8_lossy(&line);
+                let _ = tx.send(stamp(tag, text.trim_end_matches(['\n', '\r'])));
+            }
+        }
+    }
+}
+
+fn stamp(tag: &str, text: &str) -> String {
+    format!(
+        "{} [{}] {}\n",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        tag,
+        text
+    )
+}
+
+fn kill_group(group: Option<Pid>) {
+    if let Some(group) = group {
+        // The group is gone once its last process has exited
+        let _ = kill_process_group(group, Signal::Kill);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::packages::recipe::PhaseCommands;
+
+    fn plan(phases: &[(BuildPhase, &str)]) -> BuildPlan {
+        BuildPlan {
+            phases: phases
+                .iter()
+                .map(|(phase, command)| PhaseCommands {
+                    phase: *phase,
+                    commands: vec![command.to_string()],
+                })
+                .collect(),
+            env: Default::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_phases_are_logged() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = Executor::new(dir.path().join("logs"));
+        let plan = plan(&[
+            (
+                BuildPhase::Build,
+                "echo building $name in ${HOME:-nowhere}; echo oops >&2",
+            ),
+            (BuildPhase::Check, "exit 3"),
+            (BuildPhase::Install, "echo never"),
+        ]);
+        let mut env = BuildEnvironment::default();
+        env.set("name", "hello");
+
+        let report = executor
+            .run("hello", "ab12", &plan, dir.path(), &env)
+            .await
+            .unwrap();
+        assert!(!report.success() && !report.timed_out);
+        assert_eq!(report.phases.len(), 2);
+        assert!(report.phases[0].status.success());
+        let failure = report.failure().unwrap();
+        assert_eq!(failure.phase, BuildPhase::Check);
+        assert_eq!(failure.status.code(), Some(3));
+
+        assert_eq!(report.log, dir.path().join("logs/ab12-hello.log"));
+        let log = fs::read_to_string(&report.log).unwrap();
+        assert!(log.contains("Z [nexis] ==> build\n"));
+        // Nothing is inherited from our own environment
+        assert!(log.contains("Z [out] building hello in nowhere\n"));
+        assert!(log.contains("Z [err] oops\n"));
+        assert!(log.contains("[nexis] <== check: exit status: 3"));
+        assert!(!log.contains("never"));
+
+        assert_eq!(
+            Executor::latest_log(&dir.path().join("logs"), "hello").unwrap(),
+            Some(report.log)
+        );
+        assert_eq!(
+            Executor::latest_log(&dir.path().join("logs"), "ello").unwrap(),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_timeout_kills_process_group() {
+        let dir = tempfile::tempdir().unwrap();
+        let executor = Executor::new(dir.path()).with_timeout(Some(Duration::from_millis(300)));
+        // The background sleep keeps the log pipes open unless it is killed too
+        let plan = plan(&[(BuildPhase::Build, "sleep 30 & sleep 30")]);
+        let mut env = BuildEnvironment::default();
+        env.set("PATH", std::env::var("PATH").unwrap());
+
+        let started = std::time::Instant::now();
+        let report = executor
+            .run("slow", "cd34", &plan, dir.path(), &env)
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(report.timed_out);
+        assert_eq!(report.failure().unwrap().phase, BuildPhase::Build);
+        let log = fs::read_to_string(&report.log).unwrap();
+        assert!(log.contains("<== build: timed out"));
+    }
+}
@@ -0,0 +1,20 @@
+//! Build execution
+//!
+//! Runs package builds once their sources are on disk:
+//! - Hermetic build environments
+//! - Phase execution with per-build logs and timeouts
+//! - Dependency-ordered parallel scheduling
+//! - Namespace sandboxing (`sandbox` feature)
+
+pub mod environment;
+pub mod executor;
+pub mod parallel;
+#[cfg(feature = "sandbox")]
+pub mod sandbox;
+
+// Re-export commonly used items
+pub use environment::BuildEnvironment;
+pub use executor::{ExecutionReport, Executor, PhaseStatus};
+pub use parallel::{Outcome, Scheduler, Task};
+#[cfg(feature = "sandbox")]
+pub use sandbox::Sandbox;
@@ -0,0 +1,347 @@
+//! Dependency-ordered parallel builds
+//!
+//! The [`Scheduler`] walks a dependency DAG: a task starts as soon as every
+//! task it depends on has finished, with at most `workers` running at once.
+//! A slow build only holds up the packages that need it.
+//!
+//! When a build fails, nothing new starts and the running builds are left to
+//! finish. With `keep_going`, only the failed task's dependents, direct and
+//! indirect, are given up on and everything else is still built.
+//!
+//! Running tasks are shown as spinners under an overall progress bar. The
+//! view is only drawn when stderr is a terminal.
+
+use anyhow::{anyhow, Result};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::time::Duration;
+
+/// One unit of work and the names of the tasks it waits for
+#[derive(Debug, Clone)]
+pub struct Task<T> {
+    /// Unique name, used by other tasks to depend on this one
+    pub name: String,
+    /// Names of tasks that must finish first; unknown names are ignored
+    pub dependencies: Vec<String>,
+    /// Work item handed to the build function
+    pub item: T,
+}
+
+/// What became of a task
+#[derive(Debug)]
+pub enum Outcome<R> {
+    /// Built successfully
+    Built(R),
+    /// The build ran and failed
+    Failed(anyhow::Error),
+    /// Not started because a task it depends on failed
+    DependencyFailed(String),
+    /// Not started because another build failed without `keep_going`
+    Cancelled,
+}
+
+impl<R> Outcome<R> {
+    /// The result of a successful build
+    pub fn built(&self) -> Option<&R> {
+        match self {
+            Outcome::Built(result) => Some(result),
+            _ => None,
+        }
+    }
+}
+
+impl<R> fmt::Display for Outcome<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::Built(_) => write!(f, "built"),
+            Outcome::Failed(err) => write!(f, "failed: {:#}", err),
+            Outcome::DependencyFailed(name) => write!(f, "skipped, `{}` failed", name),
+            Outcome::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+/// Runs tasks in dependency order on a bounded number of threads
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    workers: usize,
+    keep_going: bool,
+    progress: MultiProgress,
+}
+
+impl Scheduler {
+    /// Create a scheduler running up to `workers` tasks at once
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: workers.max(1),
+            keep_going: false,
+            progress: MultiProgress::new(),
+        }
+    }
+
+    /// Keep building everything that does not depend on a failed task
+    pub fn with_keep_going(mut self, keep_going: bool) -> Self {
+        self.keep_going = keep_going;
+        self
+    }
+
+    /// Draw progress through `progress` instead of on stderr
+    pub fn with_progress(mut self, progress: MultiProgress) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Run `build` on every task, returning one outcome per task in order
+    ///
+    /// `build` gets the task's item and the results of the dependencies it
+    /// declared, by name. Tasks must form a DAG; tasks on a cycle are never
+    /// started and end up [`Outcome::Cancelled`].
+    pub fn run<T, R, F>(&self, tasks: Vec<Task<T>>, build: F) -> Vec<(String, Outcome<R>)>
+    where
+        T: Send,
+        R: Clone + Send,
+        F: Fn(T, HashMap<String, R>) -> Result<R> + Sync,
+    {
+        let index: HashMap<&str, usize> = tasks
+            .iter()
+            .enumerate()
+            .map(|(i, task)| (task.name.as_str(), i))
+            .collect();
+        let dependencies: Vec<Vec<usize>> = tasks
+            .iter()
+            .map(|task| {
+                let mut found: Vec<usize> = task
+                    .dependencies
+                    .iter()
+                    .filter_map(|name| index.get(name.as_str()).copied())
+                    .collect();
+                found.sort_unstable();
+                found.dedup();
+                found
+            })
+            .collect();
+        drop(index);
+        let mut waiting: Vec<usize> = dependencies.iter().map(Vec::len).collect();
+        let mut dependents = vec![Vec::new(); tasks.len()];
+        for (i, found) in dependencies.iter().enumerate() {
+            for &d in found {
+                dependents[d].push(i);
+            }
+        }
+
+        let overall = self.progress.add(ProgressBar::new(tasks.len() as u64));
+        overall.set_style(
+            ProgressStyle::with_template("{prefix:>12} [{bar:30}] {pos}/{len} {wide_msg}")
+                .expect("valid template")
+                .progress_chars("=> "),
+        );
+        overall.set_prefix("Building");
+        let spinner_style = ProgressStyle::with_template("{prefix:>12} {spinner} {msg} {elapsed}")
+            .expect("valid template");
+
+        let (names, mut items): (Vec<_>, Vec<_>) = tasks
+            .into_iter()
+            .map(|task| (task.name, Some(task.item)))
+            .unzip();
+        let mut outcomes: Vec<Option<Outcome<R>>> = names.iter().map(|_| None).collect();
+        let mut spinners: Vec<Option<ProgressBar>> = names.iter().map(|_| None).collect();
+        let mut ready: VecDeque<usize> = (0..names.len()).filter(|&i| waiting[i] == 0).collect();
+
+        std::thread::scope(|scope| {
+            let (done, finished) = mpsc::channel();
+            let build = &build;
+            let mut running = 0;
+            let mut failed = false;
+            loop {
+                while running < self.workers && (self.keep_going || !failed) {
+                    let Some(i) = ready.pop_front() else {
+                        break;
+                    };
+                    let item = items[i].take().expect("each task starts once");
+                    let inputs: HashMap<String, R> = dependencies[i]
+                        .iter()
+                        .filter_map(|&d| {
+                            let built = outcomes[d].as_ref()?.built()?;
+                            Some((names[d].clone(), built.clone()))
+                        })
+                        .collect();
+
+                    let spinner = self
+                        .progress
+                        .insert_before(&overall, ProgressBar::new_spinner());
+                    spinner.set_style(spinner_style.clone());
+                    spinner.set_prefix("Building");
+                    spinner.set_message(names[i].clone());
+                    spinner.enable_steady_tick(Duration::from_millis(100));
+                    spinners[i] = Some(spinner);
+
+                    let done = done.clone();
+                    scope.spawn(move || {
+                        let result = panic::catch_unwind(AssertUnwindSafe(|| build(item, inputs)))
+                            .unwrap_or_else(|_| Err(anyhow!("build panicked")));
+                        // The scheduler outlives every worker
+                        let _ = done.send((i, result));
+                    });
+                    running += 1;
+                }
+                if running == 0 {
+                    break;
+                }
+
+                let (i, result) = finished.recv().expect("a worker is running");
+                running -= 1;
+                if let Some(spinner) = spinners[i].take() {
+                    spinner.finish_and_clear();
+                }
+                overall.inc(1);
+                match result {
+                    Ok(result) => {
+                        for &dependent in &dependents[i] {
+                            waiting[dependent] -= 1;
+                            if waiting[dependent] == 0 && outcomes[dependent].is_none() {
+                                ready.push_back(dependent);
+                            }
+                        }
+                        outcomes[i] = Some(Outcome::Built(result));
+                    }
+                    Err(err) => {
+                        overall.println(format!("{:>12} {}", "Failed", names[i]));
+                        failed = true;
+                        // Give up on everything downstream of the failure
+                        let mut stack = dependents[i].clone();
+                        while let Some(dependent) = stack.pop() {
+                            if outcomes[dependent].is_none() {
+                                outcomes[dependent] =
+                                    Some(Outcome::DependencyFailed(names[i].clone()));
+                                overall.inc(1);
+                                stack.extend(&dependents[dependent]);
+                            }
+                        }
+                        ready.retain(|&r| outcomes[r].is_none());
+                        outcomes[i] = Some(Outcome::Failed(err));
+                    }
+                }
+            }
+        });
+        overall.finish_and_clear();
+
+        names
+            .into_iter()
+            .zip(outcomes)
+            .map(|(name, outcome)| (name, outcome.unwrap_or(Outcome::Cancelled)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use anyhow::bail;
+    use indicatif::ProgressDrawTarget;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Mutex;
+
+    fn task(name: &str, dependencies: &[&str]) -> Task<String> {
+        Task {
+            name: name.to_string(),
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+            item: name.to_string(),
+        }
+    }
+
+    fn scheduler(workers: usize) -> Scheduler {
+        Scheduler::new(workers)
+            .with_progress(MultiProgress::with_draw_target(ProgressDrawTarget::hidden()))
+    }
+
+    #[test]
+    fn test_dependencies_finish_first() {
+        // A slow leaf must not hold up the unrelated chain
+        let tasks = vec![
+            task("slow", &[]),
+            task("zlib", &[]),
+            task("openssl", &["zlib"]),
+            task("curl", &["openssl", "zlib"]),
+        ];
+        let finished = Mutex::new(Vec::new());
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+
+        let outcomes = scheduler(2).run(tasks, |name, inputs| {
+            let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+            peak.fetch_max(now, Ordering::SeqCst);
+            if name == "slow" {
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            let mut inputs: Vec<_> = inputs.into_keys().collect();
+            inputs.sort();
+            finished.lock().unwrap().push(name.clone());
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok(format!("{}({})", name, inputs.join(",")))
+        });
+
+        let built: Vec<_> = outcomes
+            .iter()
+            .map(|(name, outcome)| (name.as_str(), outcome.built().unwrap().as_str()))
+            .collect();
+        assert_eq!(
+            built,
+            [
+                ("slow", "slow()"),
+                ("zlib", "zlib()"),
+                ("openssl", "openssl(zlib)"),
+                ("curl", "curl(openssl,zlib)")
+            ]
+        );
+        assert_eq!(
+            *finished.lock().unwrap(),
+            ["zlib", "openssl", "curl", "slow"]
+        );
+        assert_eq!(peak.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_failure_cancels_dependents() {
+        let tasks = || {
+            vec![
+                task("zlib", &[]),
+                task("openssl", &["zlib"]),
+                task("curl", &["openssl"]),
+                task("make", &[]),
+            ]
+        };
+        let build = |name: String, _| {
+            if name == "zlib" {
+                bail!("zlib does not build");
+            }
+            Ok(name)
+        };
+        let summary = |outcomes: Vec<(String, Outcome<String>)>| -> Vec<String> {
+            outcomes
+                .into_iter()
+                .map(|(name, outcome)| format!("{}: {}", name, outcome))
+                .collect()
+        };
+
+        let outcomes = scheduler(1).with_keep_going(true).run(tasks(), build);
+        assert_eq!(
+            summary(outcomes),
+            [
+                "zlib: failed: zlib does not build",
+                "openssl: skipped, `zlib` failed",
+                "curl: skipped, `zlib` failed",
+                "make: built"
+            ]
+        );
+
+        let outcomes = scheduler(1).run(tasks(), build);
+        assert_eq!(
+            summary(outcomes)[3],
+            "make: cancelled",
+            "nothing new starts after a failure"
+        );
+    }
+}
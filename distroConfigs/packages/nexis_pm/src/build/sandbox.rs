@@ -0,0 +1,615 @@
+//! Build sandbox
+//!
+//! With the `sandbox` feature, each build phase can run in fresh Linux
+//! namespaces. Only unprivileged user namespaces are needed, so this works
+//! without root:
+//!
+//! - **user**: the build runs as uid and gid 1000 (`nexisbld`), mapped to
+//!   whoever started nexis, and keeps no capabilities
+//! - **mount**: a private, read-only root holding only
+//!   - the build's inputs (dependency store objects and recipe patches) at
+//!     their own paths, read-only
+//!   - `/build`, the writable build directory, with `$src`, `$out` and
+//!     `HOME` beneath it
+//!   - `/bin/sh` from the first input providing `bin/sh`
+//!   - a fresh `/proc`, a private `/tmp`, the basic `/dev` nodes and a
+//!     minimal `/etc`
+//! - **PID**: the phase's shell is PID 1 and sees no other processes;
+//!   anything it leaves behind dies with it
+//! - **network**: a loopback interface and nothing else. Sources are
+//!   fetched before a build starts, so no phase needs the network
+//! - **UTS** and **IPC**: hostname `nexis-build` and private System V IPC
+//!
+//! A seccomp filter also denies mounting, namespace, tracing, kernel module
+//! and kexec syscalls, and refuses to create setuid or setgid files.
+//!
+//! Everything the sandboxed process does between `fork` and `exec` is
+//! prepared beforehand: it only makes system calls, never allocates.
+
+use anyhow::{bail, Context, Result};
+use rustix::fs::{statvfs, Mode, OFlags};
+use rustix::mount::{
+    mount, mount_bind, mount_change, mount_remount, unmount, MountFlags, MountPropagationFlags,
+    UnmountFlags,
+};
+use rustix::process::{chdir, getgid, getuid, pivot_root};
+use rustix::thread::{unshare, UnshareFlags};
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// Where the build directory appears inside the sandbox
+pub const GUEST_BUILD_DIR: &str = "/build";
+
+/// Hostname seen by builds
+const HOSTNAME: &[u8] = b"nexis-build";
+
+/// User and group builds run as
+const BUILD_UID: u32 = 1000;
+const BUILD_GID: u32 = 1000;
+
+/// Device nodes bound into `/dev`
+const DEVICES: &[&str] = &["null", "zero", "full", "random", "urandom"];
+
+const PASSWD: &str = "\
+root:x:0:0:root:/build:/bin/sh
+nexisbld:x:1000:1000:nexis build user:/build:/bin/sh
+nobody:x:65534:65534:nobody:/:/bin/sh
+";
+const GROUP: &str = "root:x:0:\nnexisbld:x:1000:\nnogroup:x:65534:\n";
+const HOSTS: &str = "127.0.0.1 localhost\n::1 localhost\n";
+
+/// Mount flags a bind mount inherits and may not clear in a user namespace
+const LOCKED_FLAGS: MountFlags = MountFlags::NOSUID
+    .union(MountFlags::NODEV)
+    .union(MountFlags::NOEXEC)
+    .union(MountFlags::NOATIME)
+    .union(MountFlags::NODIRATIME)
+    .union(MountFlags::RELATIME);
+
+/// An isolated root for the phases of one build
+#[derive(Debug)]
+pub struct Sandbox {
+    root: TempDir,
+    build_dir: PathBuf,
+    inputs: Vec<PathBuf>,
+    shell: PathBuf,
+}
+
+impl Sandbox {
+    /// Prepare a sandbox for the build in `build_dir`, exposing `inputs`
+    ///
+    /// Inputs are absolute paths to directories or files, bound read-only at
+    /// the same path inside the sandbox. One of them must provide `bin/sh`.
+    pub fn new(build_dir: &Path, inputs: Vec<PathBuf>) -> Result<Self> {
+        for input in &inputs {
+            if !input.is_absolute() || input == Path::new("/") {
+                bail!("Cannot expose {} to a sandboxed build", input.display());
+            }
+        }
+        let Some(shell) = inputs
+            .iter()
+            .map(|input| input.join("bin/sh"))
+            .find(|sh| sh.exists())
+        else {
+            bail!("Sandboxed builds need a build dependency providing `bin/sh`");
+        };
+
+        let parent = build_dir.parent().unwrap_or(build_dir);
+        let root = tempfile::Builder::new()
+            .prefix("sandbox-")
+            .tempdir_in(parent)
+            .context("Failed to create sandbox root")?;
+        let sandbox = Self {
+            root,
+            build_dir: build_dir.to_path_buf(),
+            inputs,
+            shell,
+        };
+        sandbox
+            .populate()
+            .with_context(|| format!("Failed to populate {}", sandbox.root.path().display()))?;
+        Ok(sandbox)
+    }
+
+    /// Path inside the sandbox of `host`, for paths in the build directory
+    pub fn guest_path(&self, host: &Path) -> PathBuf {
+        match host.strip_prefix(&self.build_dir) {
+            Ok(relative) => Path::new(GUEST_BUILD_DIR).join(relative),
+            Err(_) => host.to_path_buf(),
+        }
+    }
+
+    /// Make `command` run inside the sandbox, starting in `dir`
+    ///
+    /// `dir` is a host path in the build directory.
+    pub fn confine(&self, command: &mut tokio::process::Command, dir: &Path) -> Result<()> {
+        let setup = self.setup(dir)?;
+        // SAFETY: `Setup::enter` only makes system calls on data prepared
+        // here, which is what is allowed between `fork` and `exec`
+        unsafe {
+            command.pre_exec(move || setup.enter());
+        }
+        Ok(())
+    }
+
+    /// Create the mount points and files of the sandbox root
+    fn populate(&self) -> io::Result<()> {
+        let root = self.root.path();
+        for dir in ["bin", "build", "dev", "etc", "proc", "tmp"] {
+            fs::create_dir(root.join(dir))?;
+        }
+        fs::write(root.join("bin/sh"), "")?;
+        for device in DEVICES {
+            fs::write(root.join("dev").join(device), "")?;
+        }
+        symlink("/proc/self/fd", root.join("dev/fd"))?;
+        for (fd, name) in ["stdin", "stdout", "stderr"].iter().enumerate() {
+            symlink(format!("/proc/self/fd/{}", fd), root.join("dev").join(name))?;
+        }
+        fs::write(root.join("etc/passwd"), PASSWD)?;
+        fs::write(root.join("etc/group"), GROUP)?;
+        fs::write(root.join("etc/hosts"), HOSTS)?;
+
+        for input in &self.inputs {
+            let target = self.host_target(input);
+            if input.is_dir() {
+                fs::create_dir_all(&target)?;
+            } else {
+                fs::create_dir_all(target.parent().unwrap_or(root))?;
+                fs::write(&target, "")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Where `guest` lies in the sandbox root, seen from the host
+    fn host_target(&self, guest: &Path) -> PathBuf {
+        self.root
+            .path()
+            .join(guest.strip_prefix("/").unwrap_or(guest))
+    }
+
+    fn setup(&self, dir: &Path) -> Result<Setup> {
+        let mut binds = Vec::new();
+        for input in &self.inputs {
+            binds.push(Bind::new(input, &self.host_target(input), true)?);
+        }
+        binds.push(Bind::new(
+            &self.shell,
+            &self.host_target(Path::new("/bin/sh")),
+            true,
+        )?);
+        binds.push(Bind::new(
+            &self.build_dir,
+            &self.host_target(Path::new(GUEST_BUILD_DIR)),
+            false,
+        )?);
+        for device in DEVICES {
+            let device = Path::new("/dev").join(device);
+            binds.push(Bind::new(&device, &self.host_target(&device), false)?);
+        }
+
+        Ok(Setup {
+            root: c_path(self.root.path())?,
+            root_flags: locked_flags(self.root.path())?,
+            binds,
+            tmp: c_path(&self.host_target(Path::new("/tmp")))?,
+            proc: c_path(&self.host_target(Path::new("/proc")))?,
+            dir: c_path(&self.guest_path(dir))?,
+            uid_map: format!("{} {} 1\n", BUILD_UID, getuid().as_raw()).into_bytes(),
+            gid_map: format!("{} {} 1\n", BUILD_GID, getgid().as_raw()).into_bytes(),
+            filter: seccomp_filter(),
+        })
+    }
+}
+
+/// A bind mount into the sandbox root
+struct Bind {
+    source: CString,
+    target: CString,
+    /// Flags to remount read-only with, or `None` to stay writable
+    read_only: Option<MountFlags>,
+}
+
+impl Bind {
+    fn new(source: &Path, target: &Path, read_only: bool) -> Result<Self> {
+        Ok(Self {
+            source: c_path(source)?,
+            target: c_path(target)?,
+            read_only: if read_only {
+                Some(locked_flags(source)?)
+            } else {
+                None
+            },
+        })
+    }
+}
+
+/// Everything the sandboxed process needs, prepared before `fork`
+struct Setup {
+    root: CString,
+    root_flags: MountFlags,
+    binds: Vec<Bind>,
+    tmp: CString,
+    proc: CString,
+    dir: CString,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    filter: Vec<libc::sock_filter>,
+}
+
+impl Setup {
+    /// Move the calling process into the sandbox
+    ///
+    /// Runs in the child between `fork` and `exec`.
+    fn enter(&self) -> io::Result<()> {
+        unshare(
+            UnshareFlags::NEWUSER
+                | UnshareFlags::NEWNS
+                | UnshareFlags::NEWPID
+                | UnshareFlags::NEWNET
+                | UnshareFlags::NEWUTS
+                | UnshareFlags::NEWIPC,
+        )?;
+        write_file(c"/proc/self/setgroups", b"deny")?;
+        write_file(c"/proc/self/uid_map", &self.uid_map)?;
+        write_file(c"/proc/self/gid_map", &self.gid_map)?;
+        become_init()?;
+
+        mount_change(
+            c"/",
+            MountPropagationFlags::PRIVATE | MountPropagationFlags::REC,
+        )?;
+        mount_bind(self.root.as_c_str(), self.root.as_c_str())?;
+        for bind in &self.binds {
+            mount_bind(bind.source.as_c_str(), bind.target.as_c_str())?;
+            if let Some(flags) = bind.read_only {
+                remount_read_only(&bind.target, flags)?;
+            }
+        }
+        mount(
+            c"tmpfs",
+            self.tmp.as_c_str(),
+            c"tmpfs",
+            MountFlags::NOSUID | MountFlags::NODEV,
+            c"mode=1777",
+        )?;
+        // Needs the host's /proc still in view
+        mount(
+            c"proc",
+            self.proc.as_c_str(),
+            c"proc",
+            MountFlags::NOSUID | MountFlags::NODEV | MountFlags::NOEXEC,
+            c"",
+        )?;
+        rustix::system::sethostname(HOSTNAME)?;
+        loopback_up()?;
+
+        chdir(self.root.as_c_str())?;
+        pivot_root(c".", c".")?;
+        unmount(c".", UnmountFlags::DETACH)?;
+        remount_read_only(c"/", self.root_flags)?;
+        chdir(self.dir.as_c_str())?;
+
+        let program = libc::sock_fprog {
+            len: self.filter.len() as u16,
+            filter: self.filter.as_ptr() as *mut libc::sock_filter,
+        };
+        // SAFETY: `program` points at a filter that outlives the calls
+        unsafe {
+            if libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) != 0
+                || libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    libc::SECCOMP_MODE_FILTER,
+                    &program as *const libc::sock_fprog,
+                ) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fork, leaving the child as PID 1 of the new PID namespace
+///
+/// The parent waits for the child and exits with its status, so whoever
+/// spawned the process sees the sandboxed command's exit.
+fn become_init() -> io::Result<()> {
+    // SAFETY: the process is single-threaded between `fork` and `exec`
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()),
+        0 => Ok(()),
+        child => unsafe {
+            // Only the child goes on to exec; holding the spawner's error
+            // pipe open would make it wait for the whole phase
+            if libc::syscall(libc::SYS_close_range, 3, libc::c_uint::MAX, 0) != 0 {
+                for fd in 3..1024 {
+                    libc::close(fd);
+                }
+            }
+            let mut status = 0;
+            while libc::waitpid(child, &mut status, 0) == -1 {
+                if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    libc::_exit(127);
+                }
+            }
+            if libc::WIFEXITED(status) {
+                libc::_exit(libc::WEXITSTATUS(status))
+            }
+            libc::_exit(128 + libc::WTERMSIG(status))
+        },
+    }
+}
+
+fn write_file(path: &CStr, contents: &[u8]) -> io::Result<()> {
+    let fd = rustix::fs::open(path, OFlags::WRONLY | OFlags::CLOEXEC, Mode::empty())?;
+    rustix::io::write(&fd, contents)?;
+    Ok(())
+}
+
+fn remount_read_only(target: &CStr, locked: MountFlags) -> io::Result<()> {
+    mount_remount(target, MountFlags::BIND | MountFlags::RDONLY | locked, c"")?;
+    Ok(())
+}
+
+/// Bring up the loopback interface of the new network namespace
+fn loopback_up() -> io::Result<()> {
+    // SAFETY: plain socket and ioctl calls on a zeroed request
+    unsafe {
+        let socket = libc::socket(libc::AF_INET, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0);
+        if socket < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut request: libc::ifreq = std::mem::zeroed();
+        for (dst, src) in request.ifr_name.iter_mut().zip(b"lo") {
+            *dst = *src as libc::c_char;
+        }
+        request.ifr_ifru.ifru_flags = (libc::IFF_UP | libc::IFF_RUNNING) as libc::c_short;
+        let result = libc::ioctl(socket, libc::SIOCSIFFLAGS as _, &request);
+        libc::close(socket);
+        if result != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The flags of the mount holding `path` that a bind of it must keep
+fn locked_flags(path: &Path) -> Result<MountFlags> {
+    let stat = statvfs(path).with_context(|| format!("Failed to stat {}", path.display()))?;
+    // `statvfs` reports relatime as `ST_RELATIME`, which differs from
+    // `MS_RELATIME`; the other flags share their values
+    let bits = stat.f_flag.bits();
+    let mut flags = MountFlags::from_bits_truncate(bits as u32) & LOCKED_FLAGS;
+    if bits & libc::ST_RELATIME != 0 {
+        flags |= MountFlags::RELATIME;
+    }
+    if !flags.intersects(MountFlags::NOATIME | MountFlags::RELATIME) {
+        flags |= MountFlags::STRICTATIME;
+    }
+    Ok(flags)
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .with_context(|| format!("Invalid path {}", path.display()))
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+compile_error!("the build sandbox supports x86_64 and aarch64 only");
+
+/// Syscalls a build has no business making
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_fsopen,
+    libc::SYS_fsconfig,
+    libc::SYS_fsmount,
+    libc::SYS_fspick,
+    libc::SYS_move_mount,
+    libc::SYS_open_tree,
+    libc::SYS_mount_setattr,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_kexec_file_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_userfaultfd,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_open_by_handle_at,
+];
+
+/// Syscalls that set a file mode, with the index of the mode argument
+#[cfg(target_arch = "x86_64")]
+const MODE_SYSCALLS: &[(libc::c_long, u32)] = &[
+    (libc::SYS_chmod, 1),
+    (libc::SYS_fchmod, 1),
+    (libc::SYS_fchmodat, 2),
+    (libc::SYS_fchmodat2, 2),
+];
+#[cfg(target_arch = "aarch64")]
+const MODE_SYSCALLS: &[(libc::c_long, u32)] = &[
+    (libc::SYS_fchmod, 1),
+    (libc::SYS_fchmodat, 2),
+    (libc::SYS_fchmodat2, 2),
+];
+
+/// Where a BPF jump goes
+#[derive(Clone, Copy)]
+enum Target {
+    Next,
+    Skip(u8),
+    Allow,
+    Deny,
+    Kill,
+}
+
+/// Build the seccomp program
+///
+/// Denied calls fail with `EPERM` rather than killing the build, so tools
+/// probing for features keep working. Calls for another architecture kill
+/// the process, since their numbers mean something else.
+fn seccomp_filter() -> Vec<libc::sock_filter> {
+    // Offsets into `struct seccomp_data`
+    const NR: u32 = 0;
+    const ARCH: u32 = 4;
+    const ARGS: u32 = 16;
+
+    let load = |offset: u32| {
+        (
+            libc::BPF_LD | libc::BPF_W | libc::BPF_ABS,
+            offset,
+            Target::Next,
+            Target::Next,
+        )
+    };
+    let jump =
+        |op: u32, k: u32, jt: Target, jf: Target| (libc::BPF_JMP | op | libc::BPF_K, k, jt, jf);
+
+    let mut program = vec![
+        load(ARCH),
+        jump(libc::BPF_JEQ, AUDIT_ARCH, Target::Next, Target::Kill),
+        load(NR),
+    ];
+    // x32 syscalls share the x86_64 audit arch
+    #[cfg(target_arch = "x86_64")]
+    program.push(jump(libc::BPF_JGE, 0x4000_0000, Target::Deny, Target::Next));
+    for nr in DENIED_SYSCALLS {
+        program.push(jump(libc::BPF_JEQ, *nr as u32, Target::Deny, Target::Next));
+    }
+    for (nr, arg) in MODE_SYSCALLS {
+        program.push(jump(
+            libc::BPF_JEQ,
+            *nr as u32,
+            Target::Next,
+            Target::Skip(2),
+        ));
+        // The low half of the argument, on little-endian machines
+        program.push(load(ARGS + 8 * arg));
+        program.push(jump(
+            libc::BPF_JSET,
+            libc::S_ISUID | libc::S_ISGID,
+            Target::Deny,
+            Target::Allow,
+        ));
+    }
+
+    let allow = program.len();
+    let offset = |index: usize, target: Target| -> u8 {
+        let to = match target {
+            Target::Next => return 0,
+            Target::Skip(n) => return n,
+            Target::Allow => allow,
+            Target::Deny => allow + 1,
+            Target::Kill => allow + 2,
+        };
+        (to - index - 1) as u8
+    };
+    let mut filter: Vec<_> = program
+        .iter()
+        .enumerate()
+        .map(|(index, &(code, k, jt, jf))| libc::sock_filter {
+            code: code as u16,
+            jt: offset(index, jt),
+            jf: offset(index, jf),
+            k,
+        })
+        .collect();
+    for action in [
+        libc::SECCOMP_RET_ALLOW,
+        libc::SECCOMP_RET_ERRNO | libc::EPERM as u32,
+        libc::SECCOMP_RET_KILL_PROCESS,
+    ] {
+        filter.push(libc::sock_filter {
+            code: (libc::BPF_RET | libc::BPF_K) as u16,
+            jt: 0,
+            jf: 0,
+            k: action,
+        });
+    }
+    filter
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::{BuildEnvironment, Executor};
+    use crate::config::BuildPhase;
+    use crate::packages::recipe::{BuildPlan, PhaseCommands};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_sandboxed_build() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path().join("build-hello");
+        for sub in ["src", "out"] {
+            fs::create_dir_all(build_dir.join(sub)).unwrap();
+        }
+        // The host's own tools stand in for build dependencies
+        let inputs = ["/usr", "/lib", "/lib64"]
+            .into_iter()
+            .map(PathBuf::from)
+            .filter(|p| p.exists())
+            .collect();
+        let sandbox = Arc::new(Sandbox::new(&build_dir, inputs).unwrap());
+        assert_eq!(
+            sandbox.guest_path(&build_dir.join("out")),
+            Path::new("/build/out")
+        );
+
+        let mut env = BuildEnvironment::new(&[PathBuf::from("/usr")]);
+        env.set("out", "/build/out");
+        let plan = BuildPlan {
+            phases: vec![PhaseCommands {
+                phase: BuildPhase::Build,
+                commands: vec![
+                    "test \"$(pwd)\" = /build/src".into(),
+                    "test \"$(cat /proc/sys/kernel/hostname)\" = nexis-build".into(),
+                    "test $$ = 1 && test \"$(id -u)\" = 1000".into(),
+                    // Nothing of the host beyond the inputs, and no network
+                    "test ! -e /root && test ! -e /etc/hostname".into(),
+                    "test \"$(grep -c : /proc/net/dev)\" = 1".into(),
+                    "! touch /usr/nexis-sandbox-test 2>/dev/null".into(),
+                    "echo hello > $out/hello".into(),
+                    "! chmod 4755 $out/hello 2>/dev/null".into(),
+                ],
+            }],
+            env: Default::default(),
+        };
+
+        let executor = Executor::new(dir.path().join("logs")).with_sandbox(Some(sandbox));
+        let report = executor
+            .run("hello", "ab12", &plan, &build_dir.join("src"), &env)
+            .await
+            .unwrap();
+        let log = fs::read_to_string(&report.log).unwrap();
+        assert!(report.success(), "{}", log);
+        assert_eq!(
+            fs::read_to_string(build_dir.join("out/hello")).unwrap(),
+            "hello\n"
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! Command-line argument definitions
+
+use clap::{Args, Parser, Subcommand};
+use std::path::PathBuf;
+
+use crate::constants::{
+    NEXIS_BUILD_LOG_DIR, NEXIS_LOCK_FILE, NEXIS_STORE_ROOT, NEXIS_SYSTEM_CONFIG,
+};
+
+/// Declarative package manager for NexisOS
+#[derive(Debug, Parser)]
+#[command(name = "nexis", version, about)]
+pub struct Cli {
+    /// Command to run
+    #[command(subcommand)]
+    pub command: Commands,
+}
+
+/// Available subcommands
+#[derive(Debug, Subcommand)]
+pub enum Commands {
+    /// Build the system from its configuration
+    Build(BuildArgs),
+    /// Print the JSON Schema for configuration files
+    Schema(SchemaArgs),
+    /// Resolve package versions and update nexis.lock
+    ResolveVersions(ResolveVersionsArgs),
+    /// Show the composed configuration
+    Show(ShowArgs),
+    /// Inspect and maintain the package store
+    Store(StoreArgs),
+    /// Remove old generations and unreferenced store objects
+    Gc(GcArgs),
+    /// Query store objects and their references
+    Query(QueryArgs),
+    /// Print the last build log of a package
+    Log(LogArgs),
+    /// Manage signing keys
+    Key(KeyArgs),
+    /// Check store objects against their hashes and signatures
+    Verify(VerifyArgs),
+    /// Share built packages through binary caches
+    #[cfg(feature = "build-cache")]
+    Cache(CacheArgs),
+}
+
+/// Arguments for `nexis build`
+#[derive(Debug, Args)]
+pub struct BuildArgs {
+    /// System configuration to build
+    #[arg(short, long, default_value = NEXIS_SYSTEM_CONFIG)]
+    pub config: PathBuf,
+
+    /// Lockfile to read and update
+    #[arg(long, default_value = NEXIS_LOCK_FILE)]
+    pub lock: PathBuf,
+
+    /// Refuse to build anything that disagrees with the lockfile
+    #[arg(long)]
+    pub locked: bool,
+
+    /// Store to build into
+    #[arg(long, default_value = NEXIS_STORE_ROOT)]
+    pub store: PathBuf,
+
+    /// Build every package that has a source, ignoring prebuilt artifacts
+    #[arg(long, conflicts_with = "prebuilt_only")]
+    pub prefer_source: bool,
+
+    /// Install only prebuilt artifacts, never building from source
+    #[arg(long)]
+    pub prebuilt_only: bool,
+
+    /// Leave the build directory of a failed build in the store's `.tmp/`
+    #[arg(long)]
+    pub keep_failed: bool,
+
+    /// After a build fails, keep building packages that do not depend on it
+    #[arg(short, long)]
+    pub keep_going: bool,
+
+    /// Directory to write build logs to
+    #[arg(long, default_value = NEXIS_BUILD_LOG_DIR)]
+    pub log_dir: PathBuf,
+
+    /// Install declared files below this directory instead of `/`
+    #[arg(long, default_value = "/")]
+    pub root: PathBuf,
+
+    /// Run each source build in an isolated sandbox
+    #[cfg(feature = "sandbox")]
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// Substitute objects that no trusted key has signed
+    #[cfg(feature = "build-cache")]
+    #[arg(long)]
+    pub allow_unsigned: bool,
+}
+
+/// Arguments for `nexis schema`
+#[derive(Debug, Args)]
+pub struct SchemaArgs {
+    /// Describe profile and machine files, where every top-level key is optional
+    #[arg(long)]
+    pub fragment: bool,
+}
+
+/// Arguments for `nexis resolve-versions`
+#[derive(Debug, Args)]
+pub struct ResolveVersionsArgs {
+    /// System configuration to resolve
+    #[arg(short, long, default_value = NEXIS_SYSTEM_CONFIG)]
+    pub config: PathBuf,
+
+    /// Lockfile to update
+    #[arg(long, default_value = NEXIS_LOCK_FILE)]
+    pub lock: PathBuf,
+
+    /// Store holding the version cache
+    #[arg(long, default_value = NEXIS_STORE_ROOT)]
+    pub store: PathBuf,
+
+    /// List every remote again, even if its cached listing is fresh
+    #[arg(long, conflicts_with = "offline")]
+    pub refresh: bool,
+
+    /// Resolve from the version cache and the lockfile only
+    #[arg(long)]
+    pub offline: bool,
+
+    /// Only re-resolve these packages (default: all)
+    pub packages: Vec<String>,
+}
+
+/// Arguments for `nexis show`
+#[derive(Debug, Args)]
+pub struct ShowArgs {
+    /// Base system configuration
+    #[arg(short, long, default_value = NEXIS_SYSTEM_CONFIG)]
+    pub config: PathBuf,
+
+    /// Profile templates to apply, in order
+    #[arg(long = "profile")]
+    pub profiles: Vec<String>,
+
+    /// Machine file to apply last
+    #[arg(long)]
+    pub machine: Option<String>,
+
+    /// Explain which layers set a value (e.g. `packages[vim].version`)
+    #[arg(long, value_name = "PATH")]
+    pub explain: Option<String>,
+}
+
+/// Arguments for `nexis store`
+#[derive(Debug, Args)]
+pub struct StoreArgs {
+    /// Store subcommand to run
+    #[command(subcommand)]
+    pub command: StoreCommands,
+}
+
+/// `nexis store` subcommands
+#[derive(Debug, Subcommand)]
+pub enum StoreCommands {
+    /// Recompute refcounts from generation roots and report drift
+    VerifyDb(VerifyDbArgs),
+}
+
+/// Arguments for `nexis store verify-db`
+#[derive(Debug, Args)]
+pub struct VerifyDbArgs {
+    /// Store root
+    #[arg(long, default_value = NEXIS_STORE_ROOT)]
+    pub store: PathBuf,
+
+    /// Rewrite drifted refcounts
+    #[arg(long)]
+    pub repair: bool,
+}
+
+/// Arguments for `nexis query`
+#[derive(Debug, Args)]
+pub struct QueryArgs {
+    /// Query subcommand to run
+    #[command(subcommand)]
+    pub command: Option<QueryCommands>,
+
+    /// Store root
+    #[arg(long, default_value = NEXIS_STORE_ROOT, global = true)]
+    pub store: PathBuf,
+
+    /// Print JSON instead of a table
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    /// List the runtime closure of an object with sizes
+    #[arg(long, value_name = "OBJECT", group = "query")]
+    pub closure: Option<String>,
+
+    /// List the objects that reference an object
+    #[arg(long, value_name = "OBJECT", group = "query")]
+    pub referrers: Option<String>,
+
+    /// List the generations that keep an object alive
+    #[arg(long, value_name = "OBJECT", group = "query")]
+    pub roots: Option<String>,
+}
+
+/// `nexis query` subcommands
+#[derive(Debug, Subcommand)]
+pub enum QueryCommands {
+    /// Show the reference chain through which one object depends on another
+    WhyDepends(WhyDependsArgs),
+}
+
+/// Arguments for `nexis query why-depends`
+#[derive(Debug, Args)]
+pub struct WhyDependsArgs {
+    /// Dependent object (hash, hash prefix or package name)
+    pub from: String,
+
+    /// Dependency object (hash, hash prefix or package name)
+    pub to: String,
+}
+
+/// Arguments for `nexis gc`
+#[derive(Debug, Args)]
+pub struct GcArgs {
+    /// Store root
+    #[arg(long, default_value = NEXIS_STORE_ROOT)]
+    pub store: PathBuf,
+
+    /// Keep at most this many generations (0 = unlimited)
+    #[arg(long, value_name = "N")]
+    pub keep: Option<usize>,
+
+    /// Drop generations older than this (e.g. `30d`, `12h`, `2w`)
+    #[arg(long, value_name = "AGE", value_parser = parse_age)]
+    pub older_than: Option<chrono::Duration>,
+
+    /// Show what would be collected without deleting anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for `nexis log`
+#[derive(Debug, Args)]
+pub struct LogArgs {
+    /// Package whose log to print
+    pub package: String,
+
+    /// Directory build logs are written to
+    #[arg(long, default_value = NEXIS_BUILD_LOG_DIR)]
+    pub log_dir: PathBuf,
+}
+
+/// Arguments for `nexis cache`
+#[cfg(feature = "build-cache")]
+#[derive(Debug, Args)]
+pub struct CacheArgs {
+    /// Cache subcommand to run
+    #[command(subcommand)]
+    pub command: CacheCommands,
+}
+
+/// `nexis cache` subcommands
+#[cfg(feature = "build-cache")]
+#[derive(Debug, Subcommand)]
+pub enum CacheCommands {
+    /// Copy store objects and their references into a binary cache
+    Push(CachePushArgs),
+}
+
+/// Arguments for `nexis cache push`
+#[cfg(feature = "build-cache")]
+#[derive(Debug, Args)]
+pub struct CachePushArgs {
+    /// Objects to push (store path, hash, hash prefix or package name)
+    #[arg(required = true, value_name = "PATH")]
+    pub objects: Vec<String>,
+
+    /// Cache to push to (`file://` URL)
+    #[arg(long, value_name = "URL")]
+    pub to: String,
+
+    /// Secret key to sign the pushed objects with
+    #[arg(long, value_name = "FILE")]
+    pub sign_key: Option<PathBuf>,
+
+    /// Store root
+    #[arg(long, default_value = NEXIS_STORE_ROOT)]
+    pub store: PathBuf,
+}
+
+/// Arguments for `nexis key`
+#[derive(Debug, Args)]
+pub struct KeyArgs {
+    /// Key subcommand to run
+    #[command(subcommand)]
+    pub command: KeyCommands,
+}
+
+/// `nexis key` subcommands
+#[derive(Debug, Subcommand)]
+pub enum KeyCommands {
+    /// Generate an Ed25519 signing key pair
+    Generate(KeyGenerateArgs),
+}
+
+/// Arguments for `nexis key generate`
+#[derive(Debug, Args)]
+pub struct KeyGenerateArgs {
+    /// Key name, recorded in every signature (e.g. `cache.example.org-1`)
+    pub name: String,
+
+    /// File to write the secret key to; the public key goes to `<FILE>.pub`
+    #[arg(long, value_name = "FILE")]
+    pub out: PathBuf,
+}
+
+/// Arguments for `nexis verify`
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    /// Objects to verify (store path, hash, hash prefix or package name;
+    /// default: every package)
+    #[arg(value_name = "PATH")]
+    pub objects: Vec<String>,
+
+    /// System configuration listing the trusted public keys
+    #[arg(short, long, default_value = NEXIS_SYSTEM_CONFIG)]
+    pub config: PathBuf,
+
+    /// Store root
+    #[arg(long, default_value = NEXIS_STORE_ROOT)]
+    pub store: PathBuf,
+
+    /// Also fail on objects no trusted key has signed
+    #[arg(long)]
+    pub require_signatures: bool,
+}
+
+/// Parse an age such as `30d` into a duration
+fn parse_age(age: &str) -> Result<chrono::Duration, String> {
+    let split = age.find(|c: char| !c.is_ascii_digit()).unwrap_or(age.len());
+    let (amount, unit) = age.split_at(split);
+    let amount: i64 = amount
+        .parse()
+        .map_err(|_| format!("`{}` does not start with a number", age))?;
+    match unit {
+        "s" => Ok(chrono::Duration::seconds(amount)),
+        "m" => Ok(chrono::Duration::minutes(amount)),
+        "h" => Ok(chrono::Duration::hours(amount)),
+        "d" => Ok(chrono::Duration::days(amount)),
+        "w" => Ok(chrono::Duration::weeks(amount)),
+        _ => Err(format!("unknown unit in `{}` (use s, m, h, d or w)", age)),
+    }
+}
@@ -0,0 +1,299 @@
+//! `nexis build`
+
+use anyhow::{bail, Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::{error, info, warn};
+
+use crate::build::{Executor, Outcome};
+use crate::cli::args::BuildArgs;
+use crate::config::lockfile::{ensure_no_mismatches, Lockfile};
+use crate::config::{validator, BuildPolicy, ConfigLoader, FileDeclaration};
+use crate::constants::calculate_workers;
+use crate::files::FileInstaller;
+use crate::packages::resolver::DependencyResolver;
+use crate::packages::{
+    BuildJob, Fetcher, ParallelBuilder, Pipeline, ProfileInstaller, ProfilePackage,
+};
+#[cfg(feature = "build-cache")]
+use crate::security::TrustedKeys;
+use crate::store::{Store, StoreObject};
+#[cfg(feature = "build-cache")]
+use crate::{config::lockfile::LockedPackage, config::Package, packages::Substituters};
+
+/// Load, validate and build the system configuration
+///
+/// Every package is fetched from its prebuilt artifact or its source at the
+/// locked version, then built into the store in dependency order. Hashes
+/// seen for the first time are recorded in the lockfile unless `--locked` is
+/// given. Then nothing is built without hashes to verify it against: a
+/// package with no locked store hash, a source with no locked source hash
+/// and a prebuilt artifact with no hash locked for this architecture
+/// (unless the package falls back to its source) are refused, and so is a
+/// build that does not reproduce its locked store hash.
+///
+/// Up to `calculate_workers()` packages build at once, each as soon as its
+/// dependencies are built. The first failure stops new builds from
+/// starting; with `--keep-going`, only the packages depending on it are
+/// skipped.
+///
+/// With the `build-cache` feature, a package whose store hash is locked is
+/// taken from the store or the `[cache] substituters` when one has it, and
+/// is neither fetched nor built. Cached objects must be signed by one of the
+/// `[cache] trusted_public_keys` unless `--allow-unsigned` is given.
+///
+/// Once every package is in the store, they are linked into the profile of
+/// a new generation, with file collisions settled by `[collisions]` and
+/// package `priority`. The declared files of the system and its users are
+/// then installed below `--root`, and files the previous generation managed
+/// but the configuration dropped are removed. Existing files nexis did not
+/// create are backed up, adopted or refused according to `on_conflict`.
+///
+/// Source builds only see the store objects of their `build_depends`. Each
+/// logs to `--log-dir`; `nexis log <package>` prints the latest one. With
+/// the `sandbox` feature, `--sandbox` runs each in its own namespaces.
+pub async fn execute(args: BuildArgs) -> Result<()> {
+    let loaded = ConfigLoader::new().load(&args.config)?;
+
+    // Fail with a full report before anything touches the store
+    if let Err(report) = validator::validate(&loaded) {
+        eprintln!("{:?}", miette::Report::new(report));
+        bail!("Refusing to build an invalid configuration");
+    }
+
+    let mut lock = Lockfile::load(&args.lock)?;
+    if args.locked {
+        lock.ensure_matches(&loaded.config)?;
+    }
+
+    let order = DependencyResolver::new(&loaded.config.packages)?.build_order()?;
+
+    info!(
+        "Configuration {} is valid ({} packages, {} files, {} users)",
+        args.config.display(),
+        loaded.config.packages.len(),
+        loaded.config.files.len(),
+        loaded.config.users.len()
+    );
+    if !order.is_empty() {
+        info!("Build order: {}", order.join(", "));
+    }
+
+    let policy = if args.prefer_source {
+        Some(BuildPolicy::PreferSource)
+    } else if args.prebuilt_only {
+        Some(BuildPolicy::PrebuiltOnly)
+    } else {
+        None
+    };
+    let store = Arc::new(Store::open(&args.store)?);
+    // Nothing is rooted until the generation is installed
+    let _lock = tokio::task::block_in_place(|| store.lock_shared())?;
+    let pipeline = Pipeline::new(Fetcher::new(store.layout().downloads_dir())?)
+        .with_policy(policy)
+        .with_require_hashes(args.locked);
+
+    let mut pending = Vec::new();
+    for name in &order {
+        let package = loaded
+            .config
+            .packages
+            .iter()
+            .find(|p| &p.name == name)
+            .expect("build order only names declared packages");
+        let Some(locked) = lock.get(name) else {
+            bail!(
+                "`{}` is not in the lockfile; run `nexis resolve-versions`",
+                name
+            );
+        };
+        pending.push((package, locked.clone()));
+    }
+    if args.locked {
+        ensure_no_mismatches(&lock.check_hashed(order.iter().map(String::as_str)))?;
+    }
+
+    // Locked objects already in the store or a binary cache need no build
+    #[cfg(feature = "build-cache")]
+    let substituted = {
+        let substituters = Substituters::new(&loaded.config.cache.substituters)?
+            .with_trusted_keys(TrustedKeys::new(&loaded.config.cache.trusted_public_keys)?)
+            .with_allow_unsigned(args.allow_unsigned);
+        substitute(&store, &substituters, &pending).await?
+    };
+    #[cfg(not(feature = "build-cache"))]
+    let substituted: HashMap<String, StoreObject> = HashMap::new();
+    pending.retain(|(package, _)| !substituted.contains_key(&package.name));
+
+    let results = futures::future::join_all(
+        pending
+            .iter()
+            .map(|(package, locked)| pipeline.acquire(package, locked)),
+    )
+    .await;
+    let mut recorded = false;
+    let mut jobs = Vec::new();
+    for ((package, _), result) in pending.iter().zip(results) {
+        let acquired = result.with_context(|| format!("Failed to fetch `{}`", package.name))?;
+        if let Some(entry) = lock.get_mut(&package.name) {
+            recorded |= acquired.record(entry);
+        }
+        jobs.push(BuildJob {
+            package: (*package).clone(),
+            acquired,
+            dependencies: package
+                .build_depends
+                .keys()
+                .filter_map(|name| substituted.get(name))
+                .map(|object| object.path.clone())
+                .collect(),
+            references: package
+                .runtime_depends
+                .keys()
+                .filter_map(|name| substituted.get(name))
+                .map(|object| object.hash.clone())
+                .collect(),
+        });
+    }
+
+    // Patches are applied from inside the build directory
+    let config_path = args
+        .config
+        .canonicalize()
+        .with_context(|| format!("Failed to resolve {}", args.config.display()))?;
+    let config_dir = config_path.parent().unwrap_or(Path::new("/"));
+    let builder = ParallelBuilder::new(store.clone(), config_dir, calculate_workers())?
+        .with_executor(Executor::new(&args.log_dir))
+        .with_keep_failed(args.keep_failed)
+        .with_keep_going(args.keep_going);
+    #[cfg(feature = "sandbox")]
+    let builder = builder.with_sandbox(args.sandbox);
+    let outcomes = tokio::task::block_in_place(|| builder.build_packages(jobs));
+
+    let mut mismatches = Vec::new();
+    let mut failed = Vec::new();
+    for (name, outcome) in &outcomes {
+        let Some(built) = outcome.built() else {
+            error!("{}: {}", name, outcome);
+            if matches!(outcome, Outcome::Failed(_)) {
+                failed.push(name.as_str());
+            }
+            continue;
+        };
+        let hash = &built.object.hash;
+        if let Some(mismatch) = lock.check_store_hash(name, hash) {
+            warn!("{}", mismatch);
+            mismatches.push(mismatch);
+        }
+        if let Some(entry) = lock.get_mut(name) {
+            if entry.store_hash.as_ref() != Some(hash) {
+                entry.store_hash = Some(hash.clone());
+                recorded = true;
+            }
+        }
+    }
+    if !failed.is_empty() {
+        let built = outcomes.iter().filter(|(_, o)| o.built().is_some()).count();
+        bail!(
+            "Failed to build {} ({} of {} packages built)",
+            failed.join(", "),
+            built,
+            outcomes.len()
+        );
+    }
+
+    if args.locked {
+        ensure_no_mismatches(&mismatches)?;
+    } else if recorded {
+        lock.save(&args.lock)?;
+        info!("Recorded hashes in {}", args.lock.display());
+    }
+
+    let objects: HashMap<&str, &StoreObject> = outcomes
+        .iter()
+        .filter_map(|(name, outcome)| Some((name.as_str(), &outcome.built()?.object)))
+        .chain(
+            substituted
+                .iter()
+                .map(|(name, object)| (name.as_str(), object)),
+        )
+        .collect();
+    let packages: Vec<ProfilePackage> = loaded
+        .config
+        .packages
+        .iter()
+        .filter_map(|package| {
+            let object = objects.get(package.name.as_str())?;
+            Some(ProfilePackage::new(package, (*object).clone()))
+        })
+        .collect();
+    let installer =
+        ProfileInstaller::new(&store).with_resolutions(loaded.config.collisions.clone());
+    let installed = installer.install(installer.next_generation()?, &packages)?;
+    for collision in &installed.collisions {
+        warn!("{}", collision);
+    }
+    info!(
+        "Generation {}: {} ({} files linked)",
+        installed.generation,
+        installed.path.display(),
+        installed.links
+    );
+
+    let files: Vec<FileDeclaration> = loaded
+        .config
+        .files
+        .iter()
+        .chain(loaded.config.users.iter().flat_map(|user| &user.files))
+        .cloned()
+        .collect();
+    let file_installer = FileInstaller::new(&store, config_dir)
+        .with_root(&args.root)
+        .with_on_conflict(loaded.config.file_defaults.on_conflict);
+    let placed = file_installer.install(installed.generation, &files)?;
+    for path in &placed.kept {
+        warn!(
+            "{} is no longer declared but was changed since it was installed; leaving it in place",
+            path.display()
+        );
+    }
+    let backups = file_installer.backups();
+    for path in &placed.backed_up {
+        info!(
+            "Moved the original {} to {}",
+            path.display(),
+            backups.path(installed.generation, path).display()
+        );
+    }
+    for path in &placed.adopted {
+        info!("Adopted {}", path.display());
+    }
+    info!(
+        "Installed {} files, removed {}",
+        placed.manifest.files.len(),
+        placed.removed.len()
+    );
+    Ok(())
+}
+
+/// Find the locked store object of each pending package locally or through
+/// `substituters`, keyed by package name
+#[cfg(feature = "build-cache")]
+async fn substitute(
+    store: &Arc<Store>,
+    substituters: &Substituters,
+    pending: &[(&Package, LockedPackage)],
+) -> Result<HashMap<String, StoreObject>> {
+    let mut found = HashMap::new();
+    for (package, locked) in pending {
+        let Some(hash) = &locked.store_hash else {
+            continue;
+        };
+        if let Some(object) = substituters.substitute(store, hash).await? {
+            info!("{}: {}", package.name, object.path.display());
+            found.insert(package.name.clone(), object);
+        }
+    }
+    Ok(found)
+}
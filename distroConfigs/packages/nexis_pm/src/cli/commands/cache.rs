@@ -0,0 +1,40 @@
+//! `nexis cache`
+
+use anyhow::{Context, Result};
+
+use crate::cli::args::{CacheArgs, CacheCommands, CachePushArgs};
+use crate::packages::BinaryCache;
+use crate::security::SecretKey;
+use crate::store::query::StoreQuery;
+use crate::store::Store;
+
+/// Run a binary cache subcommand
+pub async fn execute(args: CacheArgs) -> Result<()> {
+    match args.command {
+        CacheCommands::Push(args) => push(args),
+    }
+}
+
+/// Copy store objects and everything they reference into a binary cache
+///
+/// With `--sign-key`, every object pushed is signed with that key.
+fn push(args: CachePushArgs) -> Result<()> {
+    let key = args.sign_key.as_deref().map(SecretKey::load).transpose()?;
+    let store = Store::open(&args.store)?;
+    let query = StoreQuery::new(&store);
+    let cache = BinaryCache::new(&args.to)?;
+
+    for spec in &args.objects {
+        let hash = query.resolve(spec)?;
+        let object = store
+            .get(&hash)?
+            .with_context(|| format!("{} is missing from the store", hash))?;
+        for info in cache.push(&store, &object, key.as_ref())? {
+            println!(
+                "{}-{} ({} → {} bytes)",
+                info.hash, info.name, info.nar_size, info.file_size
+            );
+        }
+    }
+    Ok(())
+}
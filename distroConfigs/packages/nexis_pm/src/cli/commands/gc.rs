@@ -0,0 +1,41 @@
+//! `nexis gc`
+
+use anyhow::Result;
+
+use crate::cli::args::GcArgs;
+use crate::store::gc::{GarbageCollector, GcOptions};
+use crate::store::Store;
+
+/// Drop old generations and delete store objects nothing references
+pub async fn execute(args: GcArgs) -> Result<()> {
+    let store = Store::open(&args.store)?;
+    let report = GarbageCollector::new(&store).collect(&GcOptions {
+        keep: args.keep,
+        older_than: args.older_than,
+        dry_run: args.dry_run,
+    })?;
+
+    let verb = if report.dry_run {
+        "Would remove"
+    } else {
+        "Removed"
+    };
+    for generation in &report.generations_removed {
+        println!("{} generation {}", verb, generation);
+    }
+    for path in &report.objects_removed {
+        println!("{} {}", verb, path.display());
+    }
+    println!(
+        "{} {} objects, {:.1} MiB {}",
+        verb,
+        report.objects_removed.len(),
+        report.bytes_freed as f64 / (1024.0 * 1024.0),
+        if report.dry_run {
+            "would be freed"
+        } else {
+            "freed"
+        }
+    );
+    Ok(())
+}
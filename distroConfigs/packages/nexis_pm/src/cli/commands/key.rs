@@ -0,0 +1,46 @@
+//! `nexis key`
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use tracing::info;
+
+use crate::cli::args::{KeyArgs, KeyCommands, KeyGenerateArgs};
+use crate::security::SecretKey;
+
+/// Run a key management subcommand
+pub async fn execute(args: KeyArgs) -> Result<()> {
+    match args.command {
+        KeyCommands::Generate(args) => generate(args),
+    }
+}
+
+/// Write a new key pair and print its public key
+///
+/// Existing key files are never overwritten.
+fn generate(args: KeyGenerateArgs) -> Result<()> {
+    let key = SecretKey::generate(&args.name)?;
+    let public = key.public_key();
+    let mut public_path = args.out.clone().into_os_string();
+    public_path.push(".pub");
+    let public_path = PathBuf::from(public_path);
+    if public_path.exists() {
+        bail!("{} already exists", public_path.display());
+    }
+
+    key.save(&args.out)?;
+    let mut public_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&public_path)
+        .with_context(|| format!("Failed to create {}", public_path.display()))?;
+    writeln!(public_file, "{}", public)?;
+
+    info!(
+        "Wrote the secret key to {}; add the public key to `[cache] trusted_public_keys`",
+        args.out.display()
+    );
+    println!("{}", public);
+    Ok(())
+}
@@ -0,0 +1,24 @@
+//! `nexis log`
+
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io;
+
+use crate::build::Executor;
+use crate::cli::args::LogArgs;
+
+/// Print the most recent build log of a package
+pub async fn execute(args: LogArgs) -> Result<()> {
+    let Some(path) = Executor::latest_log(&args.log_dir, &args.package)? else {
+        bail!(
+            "No build log for `{}` in {}",
+            args.package,
+            args.log_dir.display()
+        );
+    };
+    let mut log =
+        File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+    io::copy(&mut log, &mut io::stdout().lock())
+        .with_context(|| format!("Failed to print {}", path.display()))?;
+    Ok(())
+}
@@ -0,0 +1,14 @@
+//! Handlers for each `nexis` subcommand
+
+pub mod build;
+#[cfg(feature = "build-cache")]
+pub mod cache;
+pub mod gc;
+pub mod key;
+pub mod log;
+pub mod query;
+pub mod resolve_versions;
+pub mod schema;
+pub mod show;
+pub mod store;
+pub mod verify;
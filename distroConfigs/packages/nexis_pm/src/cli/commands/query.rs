@@ -0,0 +1,114 @@
+//! `nexis query`
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+
+use crate::cli::args::{QueryArgs, QueryCommands};
+use crate::store::query::{ObjectInfo, StoreQuery};
+use crate::store::Store;
+
+/// Answer a question about the store's reference graph
+pub async fn execute(args: QueryArgs) -> Result<()> {
+    let store = Store::open(&args.store)?;
+    let query = StoreQuery::new(&store);
+    let flag_given = args.closure.is_some() || args.referrers.is_some() || args.roots.is_some();
+
+    if let Some(QueryCommands::WhyDepends(why)) = &args.command {
+        if flag_given {
+            bail!("`why-depends` cannot be combined with --closure, --referrers or --roots");
+        }
+        let from = query.resolve(&why.from)?;
+        let to = query.resolve(&why.to)?;
+        let Some(chain) = query.why_depends(&from, &to)? else {
+            bail!("{} does not depend on {}", why.from, why.to);
+        };
+        if args.json {
+            return print_json(&chain);
+        }
+        for (depth, object) in chain.iter().enumerate() {
+            let arrow = if depth == 0 { "" } else { "└─ " };
+            println!(
+                "{:indent$}{}{}",
+                "",
+                arrow,
+                label(object),
+                indent = depth * 3
+            );
+        }
+        return Ok(());
+    }
+
+    if let Some(spec) = &args.closure {
+        let closure = query.closure(&query.resolve(spec)?)?;
+        if args.json {
+            return print_json(&closure);
+        }
+        print_table(&closure.objects);
+        println!(
+            "{} objects, {} total",
+            closure.objects.len(),
+            format_size(closure.total_size)
+        );
+    } else if let Some(spec) = &args.referrers {
+        let referrers = query.referrers(&query.resolve(spec)?)?;
+        if args.json {
+            return print_json(&referrers);
+        }
+        print_table(&referrers);
+    } else if let Some(spec) = &args.roots {
+        let roots = query.roots(&query.resolve(spec)?)?;
+        if args.json {
+            return print_json(&roots);
+        }
+        if roots.is_empty() {
+            println!("No generation keeps {} alive", spec);
+        }
+        for root in &roots {
+            let chain: Vec<String> = root.chain.iter().map(label).collect();
+            println!("generation {}: {}", root.generation, chain.join(" → "));
+        }
+    } else {
+        bail!("Nothing to query; pass --closure, --referrers, --roots or `why-depends`");
+    }
+    Ok(())
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(value)?);
+    Ok(())
+}
+
+fn print_table(objects: &[ObjectInfo]) {
+    println!("{:<12}  {:<24}  {:>10}", "HASH", "NAME", "SIZE");
+    for object in objects {
+        println!(
+            "{:<12}  {:<24}  {:>10}",
+            &object.hash[..object.hash.len().min(12)],
+            object.name.as_deref().unwrap_or("(file)"),
+            format_size(object.size)
+        );
+    }
+}
+
+fn label(object: &ObjectInfo) -> String {
+    match &object.name {
+        Some(name) => format!("{}-{}", &object.hash[..object.hash.len().min(12)], name),
+        None => object.hash.clone(),
+    }
+}
+
+/// Format a byte count with a binary unit (`1.5 GiB`)
+fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
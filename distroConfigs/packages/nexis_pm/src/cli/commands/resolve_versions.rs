@@ -0,0 +1,194 @@
+//! `nexis resolve-versions`
+
+use anyhow::{anyhow, bail, Result};
+use tracing::{info, warn};
+
+use crate::cli::args::ResolveVersionsArgs;
+use crate::config::lockfile::{LockedPackage, Lockfile};
+use crate::config::{Config, Package};
+use crate::packages::fetcher::TemplateVars;
+use crate::packages::resolver::DependencyResolver;
+use crate::packages::version::{
+    has_git_source, lock_by_hash, CachePolicy, ResolvedVersion, VersionResolver,
+};
+use crate::packages::{FetchRequest, Fetcher};
+use crate::store::StoreLayout;
+use crate::vcs::tags::parse_tag;
+use crate::vcs::RefCache;
+
+/// Resolve declared package versions and update the lockfile
+///
+/// Packages whose declaration is unchanged keep their lock entry unless they
+/// are named on the command line (or no names are given). An entry that
+/// resolves to the same revision as before keeps its hashes, and only takes
+/// the version spec and source it was now resolved from.
+/// Versions are picked to satisfy the requirements packages place on each
+/// other, and a locked version that no longer does is resolved again.
+///
+/// Remote listings come from the store's version cache when fresh enough.
+/// With `--offline` no remote is contacted: packages resolve from cached
+/// listings of any age, and those never listed keep their lock entry.
+///
+/// Tarball and local directory sources have no tags to resolve against and
+/// are locked by URL and source hash instead, fetching them unless
+/// `--offline` is given. Their entry changes whenever the hash does.
+///
+/// Otherwise, the `prebuilt` artifact of each package with no hash locked
+/// for this architecture is downloaded and its hash recorded, so builds can
+/// verify it.
+pub async fn execute(args: ResolveVersionsArgs) -> Result<()> {
+    let config = Config::load(&args.config)?;
+
+    for name in &args.packages {
+        if !config.packages.iter().any(|p| &p.name == name) {
+            bail!(
+                "Package `{}` is not declared in {}",
+                name,
+                args.config.display()
+            );
+        }
+    }
+
+    let mut lock = Lockfile::load(&args.lock)?;
+    lock.retain_declared(&config);
+
+    let policy = if args.offline {
+        CachePolicy::Offline
+    } else if args.refresh {
+        CachePolicy::Refresh
+    } else {
+        CachePolicy::Default
+    };
+    let dependencies = DependencyResolver::new(&config.packages)?;
+    let layout = StoreLayout::new(args.store.clone());
+    let cache = RefCache::load(layout.version_cache_path())?;
+    let fetcher = Fetcher::new(layout.downloads_dir())?;
+    let mut resolver = VersionResolver::new()
+        .with_cache(cache)
+        .with_policy(policy)
+        .with_dependencies(dependencies.clone());
+
+    for package in &config.packages {
+        let selected = args.packages.is_empty() || args.packages.contains(&package.name);
+        let current = lock.get(&package.name);
+        let up_to_date = current.is_some_and(|l| {
+            let version = parse_tag(&l.version, &package.name);
+            l.matches_declaration(package)
+                && dependencies
+                    .unsatisfied(&package.name, version.as_ref())
+                    .is_empty()
+        });
+        if up_to_date && !selected {
+            continue;
+        }
+
+        let resolved = if has_git_source(package) {
+            tokio::task::block_in_place(|| resolver.resolve(package))
+                .map(|resolved| locked_entry(package, resolved))
+        } else if args.offline {
+            Err(anyhow!("its source is not fetched with --offline"))
+        } else {
+            lock_by_hash(package, &fetcher).await
+        };
+        let entry = match resolved {
+            Ok(entry) => entry,
+            Err(e) if args.offline && up_to_date => {
+                warn!("Keeping locked version of `{}`: {:#}", package.name, e);
+                continue;
+            }
+            Err(e) => {
+                return Err(e.context(format!("Failed to resolve version of `{}`", package.name)))
+            }
+        };
+
+        let unchanged = current.is_some_and(|existing| {
+            existing.resolved == entry.resolved
+                && existing.rev == entry.rev
+                && (entry.source_hash.is_none() || existing.source_hash == entry.source_hash)
+        });
+        match current {
+            Some(existing) if unchanged => {
+                if !existing.matches_declaration(package) {
+                    let updated = LockedPackage {
+                        requested: entry.requested,
+                        source: entry.source,
+                        ..existing.clone()
+                    };
+                    lock.upsert(updated);
+                }
+            }
+            _ => {
+                info!("{} -> {}", package.name, entry.resolved);
+                lock.upsert(entry);
+            }
+        }
+    }
+
+    if !args.offline {
+        resolver.save_cache()?;
+        pin_prebuilts(&config, &mut lock, &fetcher).await?;
+    }
+    if lock.save(&args.lock)? {
+        info!("Updated {}", args.lock.display());
+    } else {
+        info!("{} is up to date", args.lock.display());
+    }
+    Ok(())
+}
+
+/// Record the hash of every prebuilt artifact not yet locked for this
+/// architecture
+///
+/// Artifacts that cannot be fetched are left unpinned.
+async fn pin_prebuilts(config: &Config, lock: &mut Lockfile, fetcher: &Fetcher) -> Result<()> {
+    for package in &config.packages {
+        let Some(entry) = lock.get(&package.name) else {
+            continue;
+        };
+        let arch = TemplateVars::for_locked(entry).arch;
+        if entry.prebuilt_hashes.contains_key(&arch) {
+            continue;
+        }
+        let Some(request) = FetchRequest::prebuilt(package, entry)? else {
+            continue;
+        };
+        match fetcher.fetch(&request).await {
+            Ok(fetched) => {
+                info!(
+                    "{}: pinned prebuilt {} for {}",
+                    package.name, fetched.url, arch
+                );
+                if let Some(entry) = lock.get_mut(&package.name) {
+                    entry.prebuilt_hashes.insert(arch, fetched.hash);
+                }
+            }
+            Err(e) => warn!(
+                "{}: could not pin the prebuilt artifact: {:#}",
+                package.name, e
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn locked_entry(package: &Package, resolved: ResolvedVersion) -> LockedPackage {
+    let source = package.source.clone().unwrap_or_default();
+    let rev = resolved.rev().to_string();
+    let (version, reference) = match resolved {
+        ResolvedVersion::Tag { name, .. } => (name.clone(), format!("tag={}", name)),
+        ResolvedVersion::Branch { name, .. } => (name.clone(), format!("branch={}", name)),
+        ResolvedVersion::Commit(commit) => (commit.clone(), format!("rev={}", commit)),
+    };
+
+    LockedPackage {
+        name: package.name.clone(),
+        requested: package.version.clone(),
+        version,
+        source: package.source.clone(),
+        resolved: format!("{}?{}", source, reference),
+        rev: Some(rev),
+        source_hash: None,
+        store_hash: None,
+        prebuilt_hashes: Default::default(),
+    }
+}
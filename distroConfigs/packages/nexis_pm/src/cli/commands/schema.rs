@@ -0,0 +1,17 @@
+//! `nexis schema`
+
+use anyhow::Result;
+
+use crate::cli::args::SchemaArgs;
+use crate::config::schema::{config_schema_json, SchemaKind};
+
+/// Print the JSON Schema for system or fragment configuration files
+pub async fn execute(args: SchemaArgs) -> Result<()> {
+    let kind = if args.fragment {
+        SchemaKind::Fragment
+    } else {
+        SchemaKind::System
+    };
+    println!("{}", config_schema_json(kind));
+    Ok(())
+}
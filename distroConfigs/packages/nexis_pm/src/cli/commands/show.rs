@@ -0,0 +1,31 @@
+//! `nexis show`
+
+use anyhow::{bail, Result};
+
+use crate::cli::args::ShowArgs;
+use crate::config::composer::Layer;
+use crate::fleet::FleetComposer;
+
+/// Print the composed configuration, or explain where one value came from
+pub async fn execute(args: ShowArgs) -> Result<()> {
+    let base = Layer::load("base", &args.config)?;
+    let composition = FleetComposer::compose(base, &args.profiles, args.machine.as_deref())?;
+
+    let Some(path) = args.explain else {
+        print!("{}", toml::to_string_pretty(&composition.table)?);
+        return Ok(());
+    };
+
+    let Some(contributions) = composition.provenance.explain(&path) else {
+        bail!("No layer sets `{}`", path);
+    };
+
+    match composition.value(&path) {
+        Some(value) => println!("{} = {}", path, value),
+        None => println!("{} is not set", path),
+    }
+    for contribution in contributions {
+        println!("  {:<11} by {}", contribution.action, contribution.layer);
+    }
+    Ok(())
+}
@@ -0,0 +1,41 @@
+//! `nexis store`
+
+use anyhow::{bail, Result};
+use tracing::info;
+
+use crate::cli::args::{StoreArgs, StoreCommands, VerifyDbArgs};
+use crate::store::Store;
+
+/// Run a store maintenance subcommand
+pub async fn execute(args: StoreArgs) -> Result<()> {
+    match args.command {
+        StoreCommands::VerifyDb(args) => verify_db(args),
+    }
+}
+
+/// Compare stored refcounts with the ones implied by the generation roots
+fn verify_db(args: VerifyDbArgs) -> Result<()> {
+    let store = Store::open(&args.store)?;
+    let drift = store.database().verify(args.repair)?;
+
+    if drift.is_empty() {
+        info!("All refcounts match the reference graph");
+        return Ok(());
+    }
+
+    for entry in &drift {
+        println!(
+            "{}: recorded {}, expected {}",
+            entry.hash, entry.recorded, entry.expected
+        );
+    }
+    if args.repair {
+        info!("Repaired {} refcounts", drift.len());
+        Ok(())
+    } else {
+        bail!(
+            "{} refcounts have drifted; run `nexis store verify-db --repair` to fix them",
+            drift.len()
+        )
+    }
+}
@@ -0,0 +1,84 @@
+//! `nexis verify`
+
+use anyhow::{bail, Result};
+use tracing::info;
+
+use crate::cli::args::VerifyArgs;
+use crate::config::ConfigLoader;
+use crate::security::{signing, TrustedKeys, Verdict};
+use crate::store::hash::object_hash;
+use crate::store::query::StoreQuery;
+use crate::store::Store;
+
+/// Re-hash package objects and re-check their recorded signatures
+///
+/// An object whose contents no longer match its hash, or that carries a
+/// signature from a trusted key that does not verify, is reported and makes
+/// the command fail. Objects without a trusted signature, such as local
+/// builds, only fail with `--require-signatures`.
+pub async fn execute(args: VerifyArgs) -> Result<()> {
+    let loaded = ConfigLoader::new().load(&args.config)?;
+    let trusted = TrustedKeys::new(&loaded.config.cache.trusted_public_keys)?;
+    let store = Store::open(&args.store)?;
+    let db = store.database();
+    let packages = db.packages()?;
+
+    let hashes = if args.objects.is_empty() {
+        packages.keys().cloned().collect()
+    } else {
+        let query = StoreQuery::new(&store);
+        args.objects
+            .iter()
+            .map(|spec| query.resolve(spec))
+            .collect::<Result<Vec<_>>>()?
+    };
+
+    let mut problems = 0;
+    let mut unsigned = 0;
+    for hash in &hashes {
+        let Some(metadata) = packages.get(hash) else {
+            bail!("{} is not a package object", hash);
+        };
+        let Some(object) = store.get(hash)? else {
+            println!("{}-{}: missing from the store", hash, metadata.name);
+            problems += 1;
+            continue;
+        };
+        let actual = tokio::task::block_in_place(|| object_hash(&metadata.name, &object.path))?;
+        if actual != *hash {
+            println!("{}-{}: contents hash to {}", hash, metadata.name, actual);
+            problems += 1;
+            continue;
+        }
+
+        let fingerprint = signing::fingerprint(hash, &metadata.name, &db.references(hash)?);
+        let mut signed = false;
+        for signature in db.signatures(hash)? {
+            match trusted.check(&fingerprint, &signature) {
+                Verdict::Trusted(_) => signed = true,
+                Verdict::Invalid(key) => {
+                    println!("{}-{}: invalid signature by {}", hash, metadata.name, key);
+                    problems += 1;
+                }
+                Verdict::Untrusted(_) => {}
+            }
+        }
+        if !signed {
+            unsigned += 1;
+            if args.require_signatures {
+                println!("{}-{}: not signed by a trusted key", hash, metadata.name);
+                problems += 1;
+            }
+        }
+    }
+
+    info!(
+        "Checked {} objects, {} without a trusted signature",
+        hashes.len(),
+        unsigned
+    );
+    if problems > 0 {
+        bail!("{} problems found", problems);
+    }
+    Ok(())
+}
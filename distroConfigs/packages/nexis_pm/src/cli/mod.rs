@@ -0,0 +1,11 @@
+//! Command-line interface for NexisPM
+//!
+//! Provides the `nexis` binary's argument parsing and command handlers:
+//! - Argument definitions (clap)
+//! - One handler module per subcommand
+
+pub mod args;
+pub mod commands;
+
+// Re-export commonly used items
+pub use args::{Cli, Commands};
@@ -1,27 +1,679 @@
-use merge::Merge;
-use anyhow::Result;
+//! Layered configuration composition
+//!
+//! A system configuration is composed from layers: the base `system.toml`,
+//! then each profile template in order, then the machine file. Layers are
+//! merged as raw TOML so partial entries are allowed, and the result is
+//! deserialized into a [`Config`] once at the end.
+//!
+//! Merge rules, applied layer by layer:
+//! - `[system]`, `[admin]` and other tables merge key by key; the later layer
+//!   wins for every value except `admin.users`, which is unioned
+//! - `[[packages]]` are keyed by `name`; a later entry overrides only the
+//!   fields it sets (typically `version`)
+//! - `[[files]]` are keyed by `path` and merge the same way
+//! - `[[users]]` are keyed by `name`; `groups` and `profiles` are unioned and
+//!   the user's `files` are merged keyed by `path`
+//! - an entry with `override = true` replaces the earlier entry entirely
+//! - an entry with `remove = true` drops the earlier entry
+//!
+//! Entries keep the position of their first appearance. Every value records
+//! which layers contributed to it in a [`Provenance`] map, queried with paths
+//! like `system.hostname`, `packages[vim].version` or
+//! `files[/etc/motd].mode`.
 
-pub struct ConfigComposer;
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use tracing::warn;
+
+use super::loader::ConfigLoader;
+use super::types::Config;
+
+/// One layer of configuration (base, profile or machine file)
+#[derive(Debug, Clone)]
+pub struct Layer {
+    /// Name shown in provenance reports (e.g. `profile:developer`)
+    pub name: String,
+    /// Raw TOML contents of the layer
+    pub table: toml::Table,
+}
+
+impl Layer {
+    /// Create a layer from a raw TOML table
+    pub fn new(name: impl Into<String>, table: toml::Table) -> Self {
+        Self {
+            name: name.into(),
+            table,
+        }
+    }
+
+    /// Load a layer from a file, following its includes
+    pub fn load(name: impl Into<String>, path: &Path) -> Result<Self> {
+        let (table, _) = ConfigLoader::new().load_table(path)?;
+        Ok(Self::new(name, table))
+    }
+}
+
+/// How a layer contributed to a value
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Set or overrode the value
+    Set,
+    /// Added elements to a unioned list
+    Union,
+    /// Replaced the whole entry (`override = true`)
+    Override,
+    /// Removed the entry (`remove = true`)
+    Remove,
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Action::Set => "set",
+            Action::Union => "extended",
+            Action::Override => "overridden",
+            Action::Remove => "removed",
+        })
+    }
+}
+
+/// A single layer's contribution to a value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contribution {
+    /// Name of the contributing layer
+    pub layer: String,
+    /// What the layer did
+    pub action: Action,
+}
+
+/// Which layers contributed to each value of a composed configuration
+#[derive(Debug, Clone, Default)]
+pub struct Provenance {
+    entries: BTreeMap<String, Vec<Contribution>>,
+}
+
+impl Provenance {
+    /// Contributions to the value at `path`, oldest first
+    ///
+    /// Falls back to the closest parent entry when the value itself was set
+    /// as part of a larger one.
+    pub fn explain(&self, path: &str) -> Option<&[Contribution]> {
+        let mut path = path;
+        loop {
+            if let Some(entries) = self.entries.get(path) {
+                return Some(entries);
+            }
+            path = parent_path(path)?;
+        }
+    }
+
+    /// All recorded paths
+    pub fn paths(&self) -> impl Iterator<Item = &str> {
+        self.entries.keys().map(String::as_str)
+    }
+
+    fn record(&mut self, path: &str, layer: &str, action: Action) {
+        self.entries
+            .entry(path.to_string())
+            .or_default()
+            .push(Contribution {
+                layer: layer.to_string(),
+                action,
+            });
+    }
+
+    /// Forget everything recorded below `path` once it is replaced
+    fn clear_children(&mut self, path: &str) {
+        let table_prefix = format!("{}.", path);
+        let list_prefix = format!("{}[", path);
+        self.entries
+            .retain(|k, _| !k.starts_with(&table_prefix) && !k.starts_with(&list_prefix));
+    }
+}
+
+/// A composed configuration and the provenance of its values
+#[derive(Debug, Clone)]
+pub struct Composition {
+    /// The composed configuration
+    pub config: Config,
+    /// The merged TOML the configuration was deserialized from
+    pub table: toml::Table,
+    /// Which layer contributed each value
+    pub provenance: Provenance,
+}
+
+impl Composition {
+    /// Look up a composed value by provenance path
+    pub fn value(&self, path: &str) -> Option<&toml::Value> {
+        let mut segments = parse_path(path)?.into_iter();
+        let first = segments.next()?;
+        let mut current = match first {
+            Segment::Key(key) => self.table.get(&key)?,
+            Segment::Id(_) => return None,
+        };
+        for segment in segments {
+            current = match segment {
+                Segment::Key(key) => current.as_table()?.get(&key)?,
+                Segment::Id(id) => current.as_array()?.iter().find(|entry| {
+                    ["name", "path"]
+                        .iter()
+                        .any(|field| entry.get(field).and_then(toml::Value::as_str) == Some(&id))
+                })?,
+            };
+        }
+        Some(current)
+    }
+}
+
+/// Keyed list sections and the field identifying their entries
+const KEYED_SECTIONS: &[(&str, &str)] =
+    &[("packages", "name"), ("files", "path"), ("users", "name")];
+
+/// Scalar lists that are unioned instead of replaced
+const UNION_LISTS: &[&str] = &["admin.users"];
+
+/// User fields that are unioned instead of replaced
+const USER_UNION_FIELDS: &[&str] = &["groups", "profiles"];
+
+/// Composes configuration layers with explicit, per-field merge rules
+#[derive(Debug, Default)]
+pub struct ConfigComposer {
+    table: toml::Table,
+    provenance: Provenance,
+}
 
 impl ConfigComposer {
     /// Compose configuration: base → profiles → machine → user overrides
     pub fn compose(
-        base: Config,
-        profiles: Vec<Config>,
-        machine: Option<Config>,
-    ) -> Result<Config> {
-        let mut composed = base;
-        
+        base: Layer,
+        profiles: Vec<Layer>,
+        machine: Option<Layer>,
+    ) -> Result<Composition> {
+        let mut composer = Self::new();
+
+        composer.apply(&base)?;
+
         // Merge profiles
-        for profile in profiles {
-            composed.merge(profile)?;
+        for profile in &profiles {
+            composer.apply(profile)?;
         }
-        
+
         // Merge machine-specific config
-        if let Some(machine_config) = machine {
-            composed.merge(machine_config)?;
+        if let Some(machine_config) = &machine {
+            composer.apply(machine_config)?;
+        }
+
+        composer.finish()
+    }
+
+    /// Create an empty composer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Merge a layer on top of everything applied so far
+    pub fn apply(&mut self, layer: &Layer) -> Result<()> {
+        for (key, value) in &layer.table {
+            let result = match KEYED_SECTIONS.iter().find(|(section, _)| section == key) {
+                Some((section, id_field)) => {
+                    let entries = list_entry(&mut self.table, key)?;
+                    merge_list(
+                        entries,
+                        value,
+                        section,
+                        id_field,
+                        &layer.name,
+                        &mut self.provenance,
+                    )
+                }
+                None => merge_value(
+                    &mut self.table,
+                    key,
+                    value,
+                    key,
+                    &layer.name,
+                    &mut self.provenance,
+                ),
+            };
+            result.with_context(|| format!("Failed to merge `{}` from {}", key, layer.name))?;
         }
-        
-        Ok(composed)
+        Ok(())
+    }
+
+    /// Deserialize the composed layers into a [`Config`]
+    pub fn finish(self) -> Result<Composition> {
+        let config: Config = toml::Value::Table(self.table.clone())
+            .try_into()
+            .context("Composed configuration is invalid")?;
+        Ok(Composition {
+            config,
+            table: self.table,
+            provenance: self.provenance,
+        })
+    }
+}
+
+/// Merge a plain (non-keyed) value into `dst[key]`
+fn merge_value(
+    dst: &mut toml::Table,
+    key: &str,
+    value: &toml::Value,
+    path: &str,
+    layer: &str,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    match (dst.get_mut(key), value) {
+        (Some(toml::Value::Table(existing)), toml::Value::Table(incoming)) => {
+            for (k, v) in incoming {
+                merge_value(
+                    existing,
+                    k,
+                    v,
+                    &format!("{}.{}", path, k),
+                    layer,
+                    provenance,
+                )?;
+            }
+        }
+        (Some(toml::Value::Array(existing)), toml::Value::Array(incoming))
+            if UNION_LISTS.contains(&path) =>
+        {
+            union(existing, incoming);
+            provenance.record(path, layer, Action::Union);
+        }
+        _ => {
+            provenance.clear_children(path);
+            dst.insert(key.to_string(), value.clone());
+            record_all(path, value, layer, Action::Set, provenance);
+        }
+    }
+    Ok(())
+}
+
+/// Merge a list of keyed entries into `entries`
+fn merge_list(
+    entries: &mut Vec<toml::Value>,
+    incoming: &toml::Value,
+    section: &str,
+    id_field: &str,
+    layer: &str,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    let incoming = incoming
+        .as_array()
+        .ok_or_else(|| anyhow!("`{}` must be an array of tables", section))?;
+
+    for entry in incoming {
+        let mut entry = entry
+            .as_table()
+            .ok_or_else(|| anyhow!("`{}` must be an array of tables", section))?
+            .clone();
+        let overrides = take_flag(&mut entry, "override")?;
+        let remove = take_flag(&mut entry, "remove")?;
+        let id = entry
+            .get(id_field)
+            .and_then(toml::Value::as_str)
+            .ok_or_else(|| anyhow!("every entry in `{}` needs a `{}`", section, id_field))?
+            .to_string();
+        let path = format!("{}[{}]", section, id);
+        let position = entries
+            .iter()
+            .position(|e| e.get(id_field).and_then(toml::Value::as_str) == Some(id.as_str()));
+
+        match position {
+            Some(pos) if remove => {
+                entries.remove(pos);
+                provenance.clear_children(&path);
+                provenance.record(&path, layer, Action::Remove);
+            }
+            None if remove => {
+                warn!("{} removes {} which no earlier layer declares", layer, path);
+            }
+            Some(pos) if overrides => {
+                provenance.clear_children(&path);
+                provenance.record(&path, layer, Action::Override);
+                record_fields(&path, &entry, layer, provenance);
+                entries[pos] = toml::Value::Table(entry);
+            }
+            None => {
+                provenance.clear_children(&path);
+                provenance.record(&path, layer, Action::Set);
+                record_fields(&path, &entry, layer, provenance);
+                entries.push(toml::Value::Table(entry));
+            }
+            Some(pos) => {
+                let existing = entries[pos]
+                    .as_table_mut()
+                    .expect("entries are always tables");
+                merge_entry(existing, &entry, section, &path, layer, provenance)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Merge the fields of a keyed entry into an existing one
+fn merge_entry(
+    existing: &mut toml::Table,
+    incoming: &toml::Table,
+    section: &str,
+    path: &str,
+    layer: &str,
+    provenance: &mut Provenance,
+) -> Result<()> {
+    for (field, value) in incoming {
+        let field_path = format!("{}.{}", path, field);
+        let is_user = section == "users";
+
+        if is_user && field == "files" {
+            let files = list_entry(existing, field)?;
+            merge_list(files, value, &field_path, "path", layer, provenance)?;
+            continue;
+        }
+
+        match (existing.get_mut(field), value) {
+            (Some(toml::Value::Array(current)), toml::Value::Array(added))
+                if is_user && USER_UNION_FIELDS.contains(&field.as_str()) =>
+            {
+                union(current, added);
+                provenance.record(&field_path, layer, Action::Union);
+            }
+            _ => {
+                provenance.clear_children(&field_path);
+                existing.insert(field.clone(), value.clone());
+                record_all(&field_path, value, layer, Action::Set, provenance);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get (or create) the array at `table[key]`
+fn list_entry<'a>(table: &'a mut toml::Table, key: &str) -> Result<&'a mut Vec<toml::Value>> {
+    table
+        .entry(key.to_string())
+        .or_insert_with(|| toml::Value::Array(Vec::new()))
+        .as_array_mut()
+        .ok_or_else(|| anyhow!("`{}` must be an array of tables", key))
+}
+
+/// Remove a boolean directive from an entry
+fn take_flag(entry: &mut toml::Table, flag: &str) -> Result<bool> {
+    match entry.remove(flag) {
+        None => Ok(false),
+        Some(toml::Value::Boolean(value)) => Ok(value),
+        Some(_) => bail!("`{}` must be a boolean", flag),
+    }
+}
+
+/// Append the elements of `added` that `current` does not contain yet
+fn union(current: &mut Vec<toml::Value>, added: &[toml::Value]) {
+    for value in added {
+        if !current.contains(value) {
+            current.push(value.clone());
+        }
+    }
+}
+
+fn record_fields(path: &str, entry: &toml::Table, layer: &str, provenance: &mut Provenance) {
+    for (field, value) in entry {
+        record_all(
+            &format!("{}.{}", path, field),
+            value,
+            layer,
+            Action::Set,
+            provenance,
+        );
+    }
+}
+
+/// Record `path` and, for tables, every value nested inside it
+fn record_all(
+    path: &str,
+    value: &toml::Value,
+    layer: &str,
+    action: Action,
+    provenance: &mut Provenance,
+) {
+    provenance.record(path, layer, action);
+    if let toml::Value::Table(table) = value {
+        for (key, nested) in table {
+            record_all(
+                &format!("{}.{}", path, key),
+                nested,
+                layer,
+                action,
+                provenance,
+            );
+        }
+    }
+}
+
+/// Path segments: `packages[vim].version` is `Key(packages)`, `Id(vim)`, `Key(version)`
+enum Segment {
+    Key(String),
+    Id(String),
+}
+
+fn parse_path(path: &str) -> Option<Vec<Segment>> {
+    let mut segments = Vec::new();
+    let mut rest = path;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']')?;
+            segments.push(Segment::Id(after[..end].to_string()));
+            rest = &after[end + 1..];
+        } else {
+            let rest_trimmed = rest.strip_prefix('.').unwrap_or(rest);
+            let end = rest_trimmed.find(['.', '[']).unwrap_or(rest_trimmed.len());
+            if end == 0 {
+                return None;
+            }
+            segments.push(Segment::Key(rest_trimmed[..end].to_string()));
+            rest = &rest_trimmed[end..];
+        }
+    }
+    Some(segments)
+}
+
+/// `packages[vim].version` → `packages[vim]` → `packages`
+fn parent_path(path: &str) -> Option<&str> {
+    let (mut depth, mut cut) = (0usize, None);
+    for (i, c) in path.char_indices() {
+        match c {
+            '[' => {
+                if depth == 0 {
+                    cut = Some(i);
+                }
+                depth += 1;
+            }
+            ']' => depth = depth.saturating_sub(1),
+            '.' if depth == 0 => cut = Some(i),
+            _ => {}
+        }
+    }
+    cut.map(|i| &path[..i])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(name: &str, toml: &str) -> Layer {
+        Layer::new(name, toml::from_str(toml).unwrap())
+    }
+
+    const BASE: &str = r#"
+[system]
+hostname = "base"
+timezone = "UTC"
+
+[[packages]]
+name = "vim"
+version = "9.0"
+source = "https://github.com/vim/vim.git"
+
+[[packages]]
+name = "git"
+version = "latest"
+
+[[users]]
+name = "alice"
+shell = "/bin/sh"
+groups = ["users"]
+
+[[users.files]]
+path = "/home/alice/.bashrc"
+content = "base"
+mode = "0644"
+owner = "alice"
+group = "users"
+"#;
+
+    const PROFILE: &str = r#"
+[[packages]]
+name = "vim"
+version = "9.1"
+
+[[packages]]
+name = "steam"
+version = "latest"
+
+[[users]]
+name = "alice"
+groups = ["wheel", "users"]
+
+[[users.files]]
+path = "/home/alice/.bashrc"
+content = "profile"
+"#;
+
+    const MACHINE: &str = r#"
+[system]
+hostname = "web01"
+
+[[packages]]
+name = "steam"
+remove = true
+
+[[packages]]
+name = "git"
+override = true
+version = "2.45.0"
+"#;
+
+    fn composed() -> Composition {
+        ConfigComposer::compose(
+            layer("base", BASE),
+            vec![layer("profile:developer", PROFILE)],
+            Some(layer("machine:web01", MACHINE)),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_packages_are_keyed_by_name() {
+        let composition = composed();
+        let packages: Vec<_> = composition
+            .config
+            .packages
+            .iter()
+            .map(|p| (p.name.as_str(), p.version.as_str(), p.source.as_deref()))
+            .collect();
+        assert_eq!(
+            packages,
+            [
+                // Version overridden, source kept from the base
+                ("vim", "9.1", Some("https://github.com/vim/vim.git")),
+                // Replaced entirely
+                ("git", "2.45.0", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_users_union_groups_and_merge_files() {
+        let composition = composed();
+        let alice = &composition.config.users[0];
+        assert_eq!(alice.groups, ["users", "wheel"]);
+        assert_eq!(alice.shell, "/bin/sh");
+        assert_eq!(alice.files.len(), 1);
+        assert_eq!(alice.files[0].content.as_deref(), Some("profile"));
+        assert_eq!(alice.files[0].mode, "0644");
+    }
+
+    #[test]
+    fn test_provenance() {
+        let composition = composed();
+        let provenance = &composition.provenance;
+
+        let layers = |path: &str| -> Vec<(String, Action)> {
+            provenance
+                .explain(path)
+                .unwrap()
+                .iter()
+                .map(|c| (c.layer.clone(), c.action))
+                .collect()
+        };
+
+        assert_eq!(
+            layers("system.hostname"),
+            [
+                ("base".to_string(), Action::Set),
+                ("machine:web01".to_string(), Action::Set)
+            ]
+        );
+        assert_eq!(
+            layers("packages[vim].version"),
+            [
+                ("base".to_string(), Action::Set),
+                ("profile:developer".to_string(), Action::Set)
+            ]
+        );
+        assert_eq!(
+            layers("packages[vim].source"),
+            [("base".to_string(), Action::Set)]
+        );
+        assert_eq!(
+            layers("packages[steam]"),
+            [
+                ("profile:developer".to_string(), Action::Set),
+                ("machine:web01".to_string(), Action::Remove)
+            ]
+        );
+        assert_eq!(
+            layers("users[alice].groups"),
+            [
+                ("base".to_string(), Action::Set),
+                ("profile:developer".to_string(), Action::Union)
+            ]
+        );
+        assert_eq!(
+            layers("users[alice].files[/home/alice/.bashrc].mode"),
+            [("base".to_string(), Action::Set)]
+        );
+    }
+
+    #[test]
+    fn test_value_lookup() {
+        let composition = composed();
+        assert_eq!(
+            composition.value("packages[vim].version"),
+            Some(&toml::Value::from("9.1"))
+        );
+        assert_eq!(
+            composition.value("users[alice].files[/home/alice/.bashrc].content"),
+            Some(&toml::Value::from("profile"))
+        );
+        assert!(composition.value("packages[steam]").is_none());
+    }
+
+    #[test]
+    fn test_parent_path() {
+        assert_eq!(parent_path("packages[vim].version"), Some("packages[vim]"));
+        assert_eq!(parent_path("files[/etc/a.conf]"), Some("files"));
+        assert_eq!(parent_path("system"), None);
     }
 }
@@ -0,0 +1,599 @@
+//! Configuration loading
+//!
+//! Reads a root TOML file, follows `[includes] paths = [...]` recursively and
+//! merges everything into a single [`Config`].
+//!
+//! Merge order is depth-first: the files listed in `[includes]` are merged in
+//! the order they are listed (each after its own includes), and the including
+//! file is merged last so its values win. Tables merge key by key, arrays of
+//! tables (`[[packages]]`, `[[files]]`, `[[users]]`) are appended, and any
+//! other value is replaced by the later file. A file reachable through several
+//! include chains is merged once, at its first occurrence.
+//!
+//! Every merged value keeps the file and byte span it came from in a
+//! [`SourceMap`], keyed by its path in the merged document
+//! (`system.hostname`, `packages[3].version`, ...), so later stages can point
+//! errors at the exact file that set a value.
+//!
+//! The `override` and `remove` directives only mean something when layers
+//! are composed (see [`ConfigComposer`](super::composer::ConfigComposer));
+//! includes simply append entries, so [`ConfigLoader::load`] rejects them
+//! instead of silently ignoring them.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml_edit::{ImDocument, Item, Table, Value};
+
+use super::types::Config;
+
+/// Identifier of a file loaded into a [`SourceMap`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SourceId(usize);
+
+/// A file that contributed to a loaded configuration
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+    /// Path the file was read from
+    pub path: PathBuf,
+    /// Raw file contents
+    pub contents: String,
+}
+
+/// Byte range of a value inside one of the loaded files
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    /// File the value was read from
+    pub source: SourceId,
+    /// Byte range within that file
+    pub range: Range<usize>,
+}
+
+/// Human-readable position of a [`Span`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// File the value was read from
+    pub path: PathBuf,
+    /// 1-based line number
+    pub line: usize,
+    /// 1-based column number
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.path.display(), self.line, self.column)
+    }
+}
+
+/// Source files and value spans of a loaded configuration
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+    files: Vec<SourceFile>,
+    spans: HashMap<String, Span>,
+}
+
+impl SourceMap {
+    /// All files that were loaded, in load order
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    /// Get a loaded file by id
+    pub fn file(&self, id: SourceId) -> &SourceFile {
+        &self.files[id.0]
+    }
+
+    /// Get the span of a value by its merged key path (e.g. `packages[0].name`)
+    pub fn span(&self, key: &str) -> Option<&Span> {
+        self.spans.get(key)
+    }
+
+    /// Get the file, line and column a value was set at
+    pub fn location(&self, key: &str) -> Option<Location> {
+        self.span(key).map(|span| self.locate(span))
+    }
+
+    /// Convert a span into a file, line and column
+    pub fn locate(&self, span: &Span) -> Location {
+        let file = self.file(span.source);
+        let (line, column) = line_col(&file.contents, span.range.start);
+        Location {
+            path: file.path.clone(),
+            line,
+            column,
+        }
+    }
+
+    fn add_file(&mut self, path: PathBuf, contents: String) -> SourceId {
+        self.files.push(SourceFile { path, contents });
+        SourceId(self.files.len() - 1)
+    }
+
+    fn record(&mut self, key: String, source: SourceId, range: Option<Range<usize>>) {
+        if let Some(range) = range {
+            self.spans.insert(key, Span { source, range });
+        }
+    }
+
+    /// Drop spans of everything below `key` once that value is replaced
+    fn forget_children(&mut self, key: &str) {
+        let table_prefix = format!("{}.", key);
+        let array_prefix = format!("{}[", key);
+        self.spans
+            .retain(|k, _| !k.starts_with(&table_prefix) && !k.starts_with(&array_prefix));
+    }
+}
+
+/// A configuration together with the files and spans it was built from
+#[derive(Debug, Clone)]
+pub struct LoadedConfig {
+    /// The merged configuration
+    pub config: Config,
+    /// Where each value of the configuration came from
+    pub sources: SourceMap,
+}
+
+/// Loads a configuration file and everything it includes
+#[derive(Debug, Default)]
+pub struct ConfigLoader {
+    sources: SourceMap,
+    merged: toml::Table,
+    /// Files currently being loaded, outermost first
+    stack: Vec<PathBuf>,
+    /// Files already merged
+    loaded: HashSet<PathBuf>,
+}
+
+impl ConfigLoader {
+    /// Create a new loader
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Load `path` and its includes into a single configuration
+    pub fn load(self, path: impl AsRef<Path>) -> Result<LoadedConfig> {
+        let path = path.as_ref();
+        let (merged, sources) = self.load_table(path)?;
+
+        let config: Config = toml::Value::Table(merged)
+            .try_into()
+            .with_context(|| format!("Invalid configuration in {}", path.display()))?;
+        reject_merge_directives(&config, &sources)?;
+
+        Ok(LoadedConfig { config, sources })
+    }
+
+    /// Load `path` and its includes into a merged TOML table without
+    /// deserializing it, for partial files such as profiles and machine files
+    pub fn load_table(mut self, path: impl AsRef<Path>) -> Result<(toml::Table, SourceMap)> {
+        self.load_file(path.as_ref(), None)?;
+        Ok((self.merged, self.sources))
+    }
+
+    fn load_file(&mut self, path: &Path, included_at: Option<&Span>) -> Result<()> {
+        let canonical = fs::canonicalize(path).map_err(|e| {
+            let err = anyhow!("Failed to read config file {}: {}", path.display(), e);
+            match included_at {
+                Some(span) => err.context(format!("included at {}", self.sources.locate(span))),
+                None => err,
+            }
+        })?;
+
+        if let Some(pos) = self.stack.iter().position(|p| p == &canonical) {
+            let chain: Vec<String> = self.stack[pos..]
+                .iter()
+                .chain(std::iter::once(&canonical))
+                .map(|p| p.display().to_string())
+                .collect();
+            let mut err = anyhow!("Include cycle detected: {}", chain.join(" -> "));
+            if let Some(span) = included_at {
+                err = err.context(format!("included at {}", self.sources.locate(span)));
+            }
+            return Err(err);
+        }
+        if !self.loaded.insert(canonical.clone()) {
+            return Ok(());
+        }
+
+        let contents = fs::read_to_string(&canonical)
+            .with_context(|| format!("Failed to read config file {}", canonical.display()))?;
+        let document = ImDocument::parse(contents.clone())
+            .with_context(|| format!("Failed to parse {}", canonical.display()))?;
+        let is_root = self.stack.is_empty();
+        let source = self.sources.add_file(canonical.clone(), contents);
+
+        self.stack.push(canonical.clone());
+
+        let base_dir = canonical.parent().unwrap_or(Path::new("/")).to_path_buf();
+        for (include, span) in include_paths(document.as_table(), source)? {
+            let include_path = base_dir.join(include);
+            self.load_file(&include_path, Some(&span))?;
+        }
+
+        // Only the root file's `[includes]` ends up in the merged config
+        let skip = if is_root { None } else { Some("includes") };
+        merge_table(
+            &mut self.merged,
+            document.as_table(),
+            "",
+            source,
+            &mut self.sources,
+            skip,
+        )?;
+
+        self.stack.pop();
+        Ok(())
+    }
+}
+
+impl Config {
+    /// Load a configuration file, following its includes
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        Ok(ConfigLoader::new().load(path)?.config)
+    }
+}
+
+/// Fail on the first entry carrying an `override` or `remove` directive
+fn reject_merge_directives(config: &Config, sources: &SourceMap) -> Result<()> {
+    let packages = config
+        .packages
+        .iter()
+        .enumerate()
+        .map(|(i, p)| (format!("packages[{}]", i), &p.merge));
+    let files = config
+        .files
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (format!("files[{}]", i), &f.merge));
+    let users = config.users.iter().enumerate().flat_map(|(i, user)| {
+        let files = user
+            .files
+            .iter()
+            .enumerate()
+            .map(move |(j, f)| (format!("users[{}].files[{}]", i, j), &f.merge));
+        std::iter::once((format!("users[{}]", i), &user.merge)).chain(files)
+    });
+
+    for (key, merge) in packages.chain(files).chain(users) {
+        let directive = if merge.overrides {
+            "override"
+        } else if merge.remove {
+            "remove"
+        } else {
+            continue;
+        };
+        let at = sources
+            .location(&format!("{}.{}", key, directive))
+            .map(|location| location.to_string())
+            .unwrap_or(key);
+        bail!(
+            "`{}` at {} only applies to profile and machine layers composed with \
+             `nexis show --profile/--machine`",
+            directive,
+            at
+        );
+    }
+    Ok(())
+}
+
+/// Extract `[includes] paths` along with the span of each entry
+fn include_paths(table: &Table, source: SourceId) -> Result<Vec<(String, Span)>> {
+    let Some(includes) = table.get("includes") else {
+        return Ok(Vec::new());
+    };
+    let Some(paths) = includes.get("paths") else {
+        return Ok(Vec::new());
+    };
+    let Some(array) = paths.as_array() else {
+        bail!("`includes.paths` must be an array of strings");
+    };
+
+    array
+        .iter()
+        .map(|value| {
+            let path = value
+                .as_str()
+                .ok_or_else(|| anyhow!("`includes.paths` must be an array of strings"))?;
+            let span = Span {
+                source,
+                range: value.span().unwrap_or(0..0),
+            };
+            Ok((path.to_string(), span))
+        })
+        .collect()
+}
+
+fn child_key(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", prefix, key)
+    }
+}
+
+fn merge_table(
+    dst: &mut toml::Table,
+    src: &Table,
+    prefix: &str,
+    source: SourceId,
+    sources: &mut SourceMap,
+    skip: Option<&str>,
+) -> Result<()> {
+    for (key, item) in src.iter() {
+        if Some(key) == skip {
+            continue;
+        }
+        let path = child_key(prefix, key);
+
+        match item {
+            Item::None => {}
+            Item::Table(table) => {
+                let entry = table_entry(dst, key, &path, sources);
+                sources.record(path.clone(), source, table.span());
+                merge_table(entry, table, &path, source, sources, None)?;
+            }
+            Item::Value(Value::InlineTable(inline)) => {
+                let entry = table_entry(dst, key, &path, sources);
+                sources.record(path.clone(), source, inline.span());
+                merge_table(
+                    entry,
+                    &inline.clone().into_table(),
+                    &path,
+                    source,
+                    sources,
+                    None,
+                )?;
+            }
+            Item::ArrayOfTables(array) => {
+                let tables = array.iter().map(|t| (t.clone(), t.span()));
+                append_tables(dst, key, &path, tables, source, sources)?;
+            }
+            Item::Value(Value::Array(array))
+                if array.iter().all(|v| v.is_inline_table()) && !array.is_empty() =>
+            {
+                let tables = array.iter().map(|v| {
+                    let inline = v.as_inline_table().expect("checked above");
+                    (inline.clone().into_table(), v.span())
+                });
+                append_tables(dst, key, &path, tables, source, sources)?;
+            }
+            Item::Value(value) => {
+                sources.forget_children(&path);
+                dst.insert(
+                    key.to_string(),
+                    convert_value(value, &path, source, sources),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Get (or create) the table at `key`, replacing any non-table value
+fn table_entry<'a>(
+    dst: &'a mut toml::Table,
+    key: &str,
+    path: &str,
+    sources: &mut SourceMap,
+) -> &'a mut toml::Table {
+    if !matches!(dst.get(key), Some(toml::Value::Table(_))) {
+        sources.forget_children(path);
+        dst.insert(key.to_string(), toml::Value::Table(toml::Table::new()));
+    }
+    match dst.get_mut(key) {
+        Some(toml::Value::Table(table)) => table,
+        _ => unreachable!("table inserted above"),
+    }
+}
+
+/// Append tables to the array at `key`, keeping spans indexed by merged position
+fn append_tables(
+    dst: &mut toml::Table,
+    key: &str,
+    path: &str,
+    tables: impl Iterator<Item = (Table, Option<Range<usize>>)>,
+    source: SourceId,
+    sources: &mut SourceMap,
+) -> Result<()> {
+    if !matches!(dst.get(key), Some(toml::Value::Array(_))) {
+        sources.forget_children(path);
+        dst.insert(key.to_string(), toml::Value::Array(Vec::new()));
+    }
+    let Some(toml::Value::Array(array)) = dst.get_mut(key) else {
+        unreachable!("array inserted above");
+    };
+
+    for (table, span) in tables {
+        let element_path = format!("{}[{}]", path, array.len());
+        sources.record(element_path.clone(), source, span);
+        let mut element = toml::Table::new();
+        merge_table(&mut element, &table, &element_path, source, sources, None)?;
+        array.push(toml::Value::Table(element));
+    }
+    Ok(())
+}
+
+/// Convert a `toml_edit` value, recording spans for it and everything inside it
+fn convert_value(
+    value: &Value,
+    path: &str,
+    source: SourceId,
+    sources: &mut SourceMap,
+) -> toml::Value {
+    sources.record(path.to_string(), source, value.span());
+
+    match value {
+        Value::String(s) => toml::Value::String(s.value().clone()),
+        Value::Integer(i) => toml::Value::Integer(*i.value()),
+        Value::Float(f) => toml::Value::Float(*f.value()),
+        Value::Boolean(b) => toml::Value::Boolean(*b.value()),
+        Value::Datetime(d) => toml::Value::Datetime(*d.value()),
+        Value::Array(array) => toml::Value::Array(
+            array
+                .iter()
+                .enumerate()
+                .map(|(i, v)| convert_value(v, &format!("{}[{}]", path, i), source, sources))
+                .collect(),
+        ),
+        Value::InlineTable(inline) => toml::Value::Table(
+            inline
+                .iter()
+                .map(|(k, v)| {
+                    let converted = convert_value(v, &child_key(path, k), source, sources);
+                    (k.to_string(), converted)
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Convert a byte offset into a 1-based line and column
+fn line_col(contents: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(contents.len());
+    let before = &contents[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before
+        .rfind('\n')
+        .map(|nl| before[nl + 1..].chars().count())
+        .unwrap_or_else(|| before.chars().count())
+        + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    const ROOT: &str = r#"
+[system]
+hostname = "myhost"
+timezone = "UTC"
+
+[includes]
+paths = ["packages/editors.toml", "packages/devtools.toml"]
+
+[[packages]]
+name = "vim"
+version = "latest"
+"#;
+
+    #[test]
+    fn test_includes_are_merged_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = write(dir.path(), "config.toml", ROOT);
+        write(
+            dir.path(),
+            "packages/editors.toml",
+            "[[packages]]\nname = \"helix\"\nversion = \"latest\"\n",
+        );
+        write(
+            dir.path(),
+            "packages/devtools.toml",
+            "[system]\ntimezone = \"Europe/Berlin\"\n\n[[packages]]\nname = \"git\"\nversion = \"^2\"\n",
+        );
+
+        let loaded = ConfigLoader::new().load(&root).unwrap();
+        let names: Vec<_> = loaded
+            .config
+            .packages
+            .iter()
+            .map(|p| p.name.as_str())
+            .collect();
+        assert_eq!(names, ["helix", "git", "vim"]);
+        // The including file is merged last and wins
+        assert_eq!(loaded.config.system.timezone, "UTC");
+        assert_eq!(loaded.sources.files().len(), 3);
+    }
+
+    #[test]
+    fn test_spans_point_at_defining_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = write(dir.path(), "config.toml", ROOT);
+        write(dir.path(), "packages/editors.toml", "");
+        write(
+            dir.path(),
+            "packages/devtools.toml",
+            "\n[[packages]]\nname = \"git\"\nversion = \"^2\"\n",
+        );
+
+        let loaded = ConfigLoader::new().load(&root).unwrap();
+        let location = loaded.sources.location("packages[0].version").unwrap();
+        assert!(location.path.ends_with("packages/devtools.toml"));
+        assert_eq!((location.line, location.column), (4, 11));
+
+        let location = loaded.sources.location("packages[1].name").unwrap();
+        assert!(location.path.ends_with("config.toml"));
+    }
+
+    #[test]
+    fn test_include_cycle_is_reported() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = write(
+            dir.path(),
+            "config.toml",
+            "[system]\nhostname = \"h\"\ntimezone = \"UTC\"\n[includes]\npaths = [\"a.toml\"]\n",
+        );
+        write(dir.path(), "a.toml", "[includes]\npaths = [\"b.toml\"]\n");
+        write(dir.path(), "b.toml", "[includes]\npaths = [\"a.toml\"]\n");
+
+        let err = format!("{:#}", ConfigLoader::new().load(&root).unwrap_err());
+        assert!(err.contains("Include cycle detected"), "{}", err);
+        assert!(err.contains("a.toml -> "), "{}", err);
+        assert!(err.contains("b.toml:2:"), "{}", err);
+    }
+
+    #[test]
+    fn test_shared_include_is_merged_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = write(
+            dir.path(),
+            "config.toml",
+            "[system]\nhostname = \"h\"\ntimezone = \"UTC\"\n[includes]\npaths = [\"a.toml\", \"common.toml\"]\n",
+        );
+        write(
+            dir.path(),
+            "a.toml",
+            "[includes]\npaths = [\"common.toml\"]\n",
+        );
+        write(
+            dir.path(),
+            "common.toml",
+            "[[packages]]\nname = \"git\"\nversion = \"latest\"\n",
+        );
+
+        let config = Config::load(&root).unwrap();
+        assert_eq!(config.packages.len(), 1);
+    }
+
+    #[test]
+    fn test_merge_directives_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = write(
+            dir.path(),
+            "config.toml",
+            "[system]\nhostname = \"h\"\ntimezone = \"UTC\"\n[includes]\npaths = [\"a.toml\"]\n",
+        );
+        write(
+            dir.path(),
+            "a.toml",
+            "[[packages]]\nname = \"git\"\nversion = \"latest\"\nremove = true\n",
+        );
+
+        let err = format!("{:#}", ConfigLoader::new().load(&root).unwrap_err());
+        assert!(err.contains("`remove` at "), "{}", err);
+        assert!(err.contains("a.toml:4:"), "{}", err);
+    }
+}
@@ -0,0 +1,421 @@
+//! `nexis.lock` handling
+//!
+//! The lockfile pins every declared package to an exact source revision and
+//! records the hashes observed when it was fetched and built:
+//!
+//! ```toml
+//! version = 1
+//!
+//! [[packages]]
+//! name = "firefox"
+//! requested = "latest"
+//! version = "120.0"
+//! source = "https://github.com/mozilla/firefox.git"
+//! resolved = "https://github.com/mozilla/firefox.git?tag=v120.0"
+//! rev = "3f1c..."
+//! source_hash = "9a0e..."
+//! store_hash = "c41b..."
+//!
+//! [packages.prebuilt_hashes]
+//! x86_64 = "51c2..."
+//! ```
+//!
+//! Entries are always written sorted by name and unchanged entries are
+//! serialized byte-for-byte identically, so updating one package produces a
+//! minimal diff.
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use super::types::{Config, Package};
+
+/// Current lockfile format version
+pub const LOCKFILE_VERSION: u32 = 1;
+
+/// Contents of `nexis.lock`
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Lockfile {
+    /// Lockfile format version
+    pub version: u32,
+    /// Locked packages, sorted by name
+    #[serde(default)]
+    pub packages: Vec<LockedPackage>,
+}
+
+/// A package pinned to an exact source revision
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LockedPackage {
+    /// Package name
+    pub name: String,
+    /// Version spec from the config this entry was resolved from
+    pub requested: String,
+    /// Resolved version (tag, branch or commit)
+    pub version: String,
+    /// Source location from the config
+    pub source: Option<String>,
+    /// Fully pinned source reference (`<url>?tag=...` or `<url>?rev=...`)
+    pub resolved: String,
+    /// Commit SHA the reference pointed at
+    pub rev: Option<String>,
+    /// BLAKE3 hash of the fetched source tree or archive
+    pub source_hash: Option<String>,
+    /// Hash of the store object the package produced
+    pub store_hash: Option<String>,
+    /// BLAKE3 hashes of the prebuilt artifact, keyed by architecture
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub prebuilt_hashes: BTreeMap<String, String>,
+}
+
+impl LockedPackage {
+    /// Whether this entry was resolved from the package's current declaration
+    pub fn matches_declaration(&self, package: &Package) -> bool {
+        self.name == package.name
+            && self.requested == package.version
+            && self.source == package.source
+    }
+}
+
+/// A disagreement between the config and the lockfile
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LockMismatch {
+    /// A declared package has no lock entry
+    Missing {
+        /// Package name
+        name: String,
+    },
+    /// A package's version spec or source changed since it was locked
+    DeclarationChanged {
+        /// Package name
+        name: String,
+        /// Version spec recorded in the lockfile
+        locked: String,
+        /// Version spec in the config
+        declared: String,
+    },
+    /// A package has no store hash to check its build against
+    Unhashed {
+        /// Package name
+        name: String,
+    },
+    /// A built package does not hash to the locked value
+    StoreHash {
+        /// Package name
+        name: String,
+        /// Hash recorded in the lockfile
+        locked: String,
+        /// Hash of the built store object
+        actual: String,
+    },
+}
+
+impl fmt::Display for LockMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LockMismatch::Missing { name } => {
+                write!(f, "`{}` is not in the lockfile", name)
+            }
+            LockMismatch::DeclarationChanged {
+                name,
+                locked,
+                declared,
+            } => write!(
+                f,
+                "`{}` was locked for `{}` but the config now declares `{}`",
+                name, locked, declared
+            ),
+            LockMismatch::Unhashed { name } => write!(
+                f,
+                "`{}` has no store hash in the lockfile; build it once without `--locked` to record one",
+                name
+            ),
+            LockMismatch::StoreHash {
+                name,
+                locked,
+                actual,
+            } => write!(
+                f,
+                "`{}` store hash is {} but the lockfile expects {}",
+                name, actual, locked
+            ),
+        }
+    }
+}
+
+impl Default for Lockfile {
+    fn default() -> Self {
+        Self {
+            version: LOCKFILE_VERSION,
+            packages: Vec::new(),
+        }
+    }
+}
+
+impl Lockfile {
+    /// Load a lockfile, returning an empty one if it does not exist
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+
+        let mut lockfile: Lockfile = toml::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        if lockfile.version != LOCKFILE_VERSION {
+            bail!(
+                "Unsupported lockfile version {} in {} (expected {})",
+                lockfile.version,
+                path.display(),
+                LOCKFILE_VERSION
+            );
+        }
+        lockfile.sort();
+        Ok(lockfile)
+    }
+
+    /// Write the lockfile atomically, returning whether its contents changed
+    pub fn save(&self, path: &Path) -> Result<bool> {
+        let content = self.to_toml_string()?;
+        if fs::read_to_string(path).ok().as_deref() == Some(content.as_str()) {
+            return Ok(false);
+        }
+
+        let dir = path.parent().unwrap_or(Path::new("."));
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("Failed to create temporary lockfile in {}", dir.display()))?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.as_file().sync_all()?;
+        tmp.persist(path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(true)
+    }
+
+    /// Serialize with packages in stable name order
+    pub fn to_toml_string(&self) -> Result<String> {
+        let mut sorted = self.clone();
+        sorted.sort();
+        Ok(toml::to_string_pretty(&sorted)?)
+    }
+
+    /// Get the lock entry for a package
+    pub fn get(&self, name: &str) -> Option<&LockedPackage> {
+        self.packages
+            .binary_search_by(|p| p.name.as_str().cmp(name))
+            .ok()
+            .map(|i| &self.packages[i])
+    }
+
+    /// Get a mutable lock entry for a package
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut LockedPackage> {
+        self.packages
+            .binary_search_by(|p| p.name.as_str().cmp(name))
+            .ok()
+            .map(|i| &mut self.packages[i])
+    }
+
+    /// Insert or replace a lock entry, keeping name order
+    pub fn upsert(&mut self, entry: LockedPackage) {
+        match self
+            .packages
+            .binary_search_by(|p| p.name.as_str().cmp(&entry.name))
+        {
+            Ok(i) => self.packages[i] = entry,
+            Err(i) => self.packages.insert(i, entry),
+        }
+    }
+
+    /// Drop entries for packages the config no longer declares
+    pub fn retain_declared(&mut self, config: &Config) {
+        self.packages
+            .retain(|locked| config.packages.iter().any(|p| p.name == locked.name));
+    }
+
+    /// Compare the config's declarations against the lockfile
+    pub fn check(&self, config: &Config) -> Vec<LockMismatch> {
+        config
+            .packages
+            .iter()
+            .filter_map(|package| match self.get(&package.name) {
+                None => Some(LockMismatch::Missing {
+                    name: package.name.clone(),
+                }),
+                Some(locked) if !locked.matches_declaration(package) => {
+                    Some(LockMismatch::DeclarationChanged {
+                        name: package.name.clone(),
+                        locked: locked.requested.clone(),
+                        declared: package.version.clone(),
+                    })
+                }
+                Some(_) => None,
+            })
+            .collect()
+    }
+
+    /// Fail if the config disagrees with the lockfile (`nexis build --locked`)
+    pub fn ensure_matches(&self, config: &Config) -> Result<()> {
+        ensure_no_mismatches(&self.check(config))
+    }
+
+    /// Entries of `names` with no store hash to verify a build against
+    ///
+    /// Source hashes are checked by the fetcher as sources are fetched.
+    pub fn check_hashed<'a>(&self, names: impl IntoIterator<Item = &'a str>) -> Vec<LockMismatch> {
+        names
+            .into_iter()
+            .filter(|name| self.get(name).is_some_and(|l| l.store_hash.is_none()))
+            .map(|name| LockMismatch::Unhashed {
+                name: name.to_string(),
+            })
+            .collect()
+    }
+
+    /// Check a built store hash against the lock entry
+    ///
+    /// Entries without a recorded hash accept any output.
+    pub fn check_store_hash(&self, name: &str, actual: &str) -> Option<LockMismatch> {
+        let locked = self.get(name)?.store_hash.as_ref()?;
+        (locked != actual).then(|| LockMismatch::StoreHash {
+            name: name.to_string(),
+            locked: locked.clone(),
+            actual: actual.to_string(),
+        })
+    }
+
+    fn sort(&mut self) {
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+}
+
+/// Turn a list of mismatches into a single error
+pub fn ensure_no_mismatches(mismatches: &[LockMismatch]) -> Result<()> {
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    let details: Vec<String> = mismatches.iter().map(|m| format!("  - {}", m)).collect();
+    bail!(
+        "Configuration does not match nexis.lock:\n{}\nRun `nexis resolve-versions` to update the lockfile",
+        details.join("\n")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn locked(name: &str, requested: &str) -> LockedPackage {
+        LockedPackage {
+            name: name.to_string(),
+            requested: requested.to_string(),
+            version: "1.0.0".to_string(),
+            source: Some(format!("https://example.com/{}.git", name)),
+            resolved: format!("https://example.com/{}.git?tag=v1.0.0", name),
+            rev: Some("0123456789abcdef0123456789abcdef01234567".to_string()),
+            source_hash: Some("aa".repeat(32)),
+            store_hash: None,
+            prebuilt_hashes: BTreeMap::new(),
+        }
+    }
+
+    fn config(packages: &[(&str, &str)]) -> Config {
+        let mut toml = String::from("[system]\nhostname = \"h\"\ntimezone = \"UTC\"\n");
+        for (name, version) in packages {
+            toml.push_str(&format!(
+                "[[packages]]\nname = \"{0}\"\nversion = \"{1}\"\nsource = \"https://example.com/{0}.git\"\n",
+                name, version
+            ));
+        }
+        toml::from_str(&toml).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip_is_sorted_and_stable() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nexis.lock");
+
+        let mut lock = Lockfile::default();
+        lock.upsert(locked("vim", "latest"));
+        lock.upsert(locked("git", "^2"));
+        assert!(lock.save(&path).unwrap());
+        // Saving identical contents leaves the file untouched
+        assert!(!lock.save(&path).unwrap());
+
+        let loaded = Lockfile::load(&path).unwrap();
+        assert_eq!(loaded, lock);
+        let names: Vec<_> = loaded.packages.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, ["git", "vim"]);
+    }
+
+    #[test]
+    fn test_updating_one_entry_only_changes_its_lines() {
+        let mut lock = Lockfile::default();
+        lock.upsert(locked("git", "^2"));
+        lock.upsert(locked("vim", "latest"));
+        let before = lock.to_toml_string().unwrap();
+
+        lock.get_mut("vim").unwrap().version = "9.1.0".to_string();
+        let after = lock.to_toml_string().unwrap();
+
+        let changed: Vec<_> = before
+            .lines()
+            .zip(after.lines())
+            .filter(|(a, b)| a != b)
+            .collect();
+        assert_eq!(changed, [("version = \"1.0.0\"", "version = \"9.1.0\"")]);
+    }
+
+    #[test]
+    fn test_check_reports_missing_and_changed() {
+        let mut lock = Lockfile::default();
+        lock.upsert(locked("git", "^2"));
+        lock.upsert(locked("vim", "latest"));
+
+        assert!(lock
+            .ensure_matches(&config(&[("git", "^2"), ("vim", "latest")]))
+            .is_ok());
+
+        let mismatches = lock.check(&config(&[("git", "^3"), ("helix", "latest")]));
+        assert_eq!(
+            mismatches,
+            [
+                LockMismatch::DeclarationChanged {
+                    name: "git".to_string(),
+                    locked: "^2".to_string(),
+                    declared: "^3".to_string(),
+                },
+                LockMismatch::Missing {
+                    name: "helix".to_string()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_checks() {
+        let mut lock = Lockfile::default();
+        lock.upsert(locked("git", "^2"));
+
+        // No store hash recorded yet
+        assert!(lock.check_store_hash("git", "anything").is_none());
+        assert_eq!(
+            lock.check_hashed(["git"]),
+            [LockMismatch::Unhashed { name: "git".into() }]
+        );
+
+        lock.get_mut("git").unwrap().store_hash = Some("cc".repeat(32));
+        assert!(lock.check_store_hash("git", &"cc".repeat(32)).is_none());
+        assert!(lock.check_store_hash("git", &"dd".repeat(32)).is_some());
+        assert!(lock.check_hashed(["git"]).is_empty());
+    }
+
+    #[test]
+    fn test_missing_lockfile_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = Lockfile::load(&dir.path().join("nexis.lock")).unwrap();
+        assert!(lock.packages.is_empty());
+    }
+}
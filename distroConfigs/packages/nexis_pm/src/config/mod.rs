@@ -0,0 +1,23 @@
+//! Configuration handling for NexisPM
+//!
+//! Provides everything needed to turn TOML files into a [`Config`]:
+//! - Config types
+//! - Loading with recursive includes
+//! - Profile/machine composition
+//! - Validation
+//! - Lockfile handling
+//! - Schema definitions
+
+pub mod composer;
+pub mod loader;
+pub mod lockfile;
+pub mod schema;
+pub mod types;
+pub mod validator;
+
+// Re-export commonly used items
+pub use loader::{ConfigLoader, LoadedConfig, SourceMap};
+pub use types::{
+    BuildPhase, BuildPolicy, CacheConfig, Config, FileDeclaration, FileDefaults, Includes,
+    OnConflict, Package, Recipe, RecipePreset, SystemConfig, User,
+};
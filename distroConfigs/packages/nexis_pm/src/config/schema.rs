@@ -0,0 +1,109 @@
+//! JSON Schema export for the TOML configuration types
+//!
+//! Generates a draft 2020-12 schema from [`Config`] so editors such as taplo
+//! or VS Code can offer completion and inline validation for `system.toml`,
+//! profile templates and machine files.
+
+use schemars::generate::SchemaSettings;
+use schemars::transform::RecursiveTransform;
+use schemars::Schema;
+use serde_json::Value;
+
+use super::types::Config;
+
+/// Which kind of configuration file the schema describes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaKind {
+    /// A complete `system.toml`
+    System,
+    /// A profile template or machine file, where every top-level key is optional
+    Fragment,
+}
+
+/// Generate the JSON Schema for a configuration file
+pub fn config_schema(kind: SchemaKind) -> Schema {
+    let mut schema = SchemaSettings::draft2020_12()
+        .with_transform(RecursiveTransform(drop_null))
+        .into_generator()
+        .into_root_schema_for::<Config>();
+
+    match kind {
+        SchemaKind::System => {
+            schema.insert("title".to_string(), "NexisOS system configuration".into());
+        }
+        SchemaKind::Fragment => {
+            schema.insert("title".to_string(), "NexisOS configuration fragment".into());
+            schema.remove("required");
+        }
+    }
+    schema
+}
+
+/// Render the schema as pretty-printed JSON
+pub fn config_schema_json(kind: SchemaKind) -> String {
+    serde_json::to_string_pretty(&config_schema(kind)).expect("schema is valid JSON")
+}
+
+/// TOML has no null, so strip the `null` alternatives generated for `Option` fields
+fn drop_null(schema: &mut Schema) {
+    let Some(object) = schema.as_object_mut() else {
+        return;
+    };
+
+    if let Some(Value::Array(types)) = object.get_mut("type") {
+        types.retain(|t| t != "null");
+        if types.len() == 1 {
+            let single = types.remove(0);
+            object.insert("type".to_string(), single);
+        }
+    }
+
+    for keyword in ["anyOf", "oneOf"] {
+        if let Some(Value::Array(variants)) = object.get_mut(keyword) {
+            variants.retain(|v| v.get("type").is_none_or(|t| t != "null"));
+            if variants.len() == 1 {
+                let Some(Value::Object(single)) = variants.pop() else {
+                    continue;
+                };
+                object.remove(keyword);
+                object.extend(single);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_system_schema() {
+        let schema = config_schema(SchemaKind::System);
+        assert_eq!(
+            schema.get("$schema").and_then(Value::as_str),
+            Some("https://json-schema.org/draft/2020-12/schema")
+        );
+        assert!(schema.get("required").is_some());
+
+        let package = schema.pointer("/$defs/Package/properties").unwrap();
+        for field in [
+            "prebuilt",
+            "fallback_to_source",
+            "provider",
+            "dinit_services",
+        ] {
+            assert!(package.get(field).is_some(), "missing {}", field);
+        }
+        assert_eq!(
+            package.pointer("/source/type"),
+            Some(&Value::from("string"))
+        );
+    }
+
+    #[test]
+    fn test_fragment_schema_has_no_required_keys() {
+        let schema = config_schema(SchemaKind::Fragment);
+        assert!(schema.get("required").is_none());
+        assert!(schema.pointer("/properties/packages").is_some());
+    }
+}
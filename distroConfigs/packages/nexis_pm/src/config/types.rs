@@ -1,45 +1,345 @@
+//! Configuration types
+//!
+//! Serde models for `system.toml`, profile templates and machine files.
+
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// A complete, composed system configuration
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Config {
+    /// Core system settings (`[system]`)
     pub system: SystemConfig,
+    /// Administrative settings (`[admin]`)
+    #[serde(default)]
     pub admin: AdminConfig,
+    /// Declared packages (`[[packages]]`)
+    #[serde(default)]
     pub packages: Vec<Package>,
+    /// Declared files (`[[files]]`)
+    #[serde(default)]
     pub files: Vec<FileDeclaration>,
+    /// Settings shared by every declared file (`[file_defaults]`)
+    #[serde(default)]
+    pub file_defaults: FileDefaults,
+    /// Declared users (`[[users]]`)
+    #[serde(default)]
     pub users: Vec<User>,
+    /// Binary cache settings (`[cache]`)
+    #[serde(default)]
+    pub cache: CacheConfig,
+    /// Package providing each profile path several packages ship
+    /// (`[collisions]`, e.g. `"bin/vi" = "neovim"`), overriding priorities
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub collisions: BTreeMap<String, String>,
+    /// Additional config files merged into this one (`[includes]`)
     pub includes: Option<Includes>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Core system settings
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct SystemConfig {
+    /// Machine hostname
     pub hostname: String,
+    /// Timezone name from the zoneinfo database (e.g. `Europe/Berlin`)
     pub timezone: String,
+    /// NexisOS release this config targets
     pub version: Option<String>,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// Administrative settings
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct AdminConfig {
+    /// Users granted administrative rights
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+/// Binary cache settings
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct CacheConfig {
+    /// Binary caches to substitute built packages from, tried in order
+    /// (`file://` or `http(s)://` URLs)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub substituters: Vec<String>,
+    /// Public keys (`<name>:<base64>`) whose signatures make a substituted
+    /// object trusted
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub trusted_public_keys: Vec<String>,
+}
+
+/// A declared package
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct Package {
+    /// Package name
     pub name: String,
+    /// Version spec: `latest`, a semver range, a tag or a commit
     pub version: String,
+    /// Let `latest` and semver ranges select pre-release tags
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prerelease: bool,
+    /// Source location (git repository, archive URL or local path)
     pub source: Option<String>,
+    /// Prebuilt artifact URL
+    ///
+    /// May contain `{name}`, `{version}`, `{tag}` and `{arch}` placeholders,
+    /// expanded once the version is resolved.
     pub prebuilt: Option<String>,
+    /// Build from `source` when the prebuilt artifact is missing, corrupt
+    /// or fails verification
+    #[serde(default)]
+    pub fallback_to_source: bool,
+    /// Whether to install the prebuilt artifact or build from source
+    pub build_policy: Option<BuildPolicy>,
+    /// How to build `source` (`[packages.recipe]`)
+    pub recipe: Option<Recipe>,
+    /// External version provider (e.g. `pypi`, `npm`, `cratesio`)
+    pub provider: Option<String>,
+    /// Packages needed to build this one, with Cargo-style version requirements
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub build_depends: BTreeMap<String, String>,
+    /// Packages needed at runtime, with Cargo-style version requirements
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub runtime_depends: BTreeMap<String, String>,
+    /// Profile priority when several packages ship the same file; the
+    /// lowest wins (default 5)
+    pub priority: Option<i32>,
+    /// Dinit services shipped by this package, keyed by service name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub dinit_services: BTreeMap<String, DinitService>,
+    /// Composition directives for profile and machine layers
+    #[serde(flatten)]
+    pub merge: MergeDirectives,
+}
+
+/// How a package with both a `prebuilt` artifact and a `source` is obtained
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum BuildPolicy {
+    /// Install the prebuilt artifact, building from source only as a fallback
+    #[default]
+    PreferPrebuilt,
+    /// Build from source whenever the package has one
+    PreferSource,
+    /// Install the prebuilt artifact or fail
+    PrebuiltOnly,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// How a package is built from source
+///
+/// See [`BuildPlan`](crate::packages::recipe::BuildPlan) for how phases and
+/// presets combine.
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Recipe {
+    /// Build system supplying the default phases; detected from the source
+    /// tree when unset
+    pub preset: Option<RecipePreset>,
+    /// Extra arguments for the preset's configure step (or `make`, for the
+    /// `make` preset)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub configure_flags: Vec<String>,
+    /// Patch files applied with `patch -p1`, relative to the system config
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patches: Vec<String>,
+    /// Phases not to run
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skip: Vec<BuildPhase>,
+    /// Extra environment variables for every phase
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+    /// Commands replacing the `unpack` phase
+    pub unpack: Option<Vec<String>>,
+    /// Commands replacing the `patch` phase
+    pub patch: Option<Vec<String>>,
+    /// Commands replacing the `configure` phase
+    pub configure: Option<Vec<String>>,
+    /// Commands replacing the `build` phase
+    pub build: Option<Vec<String>>,
+    /// Commands replacing the `check` phase
+    pub check: Option<Vec<String>>,
+    /// Commands replacing the `install` phase
+    pub install: Option<Vec<String>>,
+}
+
+/// Build systems with built-in phases
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum RecipePreset {
+    /// `./configure && make && make install`
+    Autotools,
+    /// CMake with its default generator
+    Cmake,
+    /// Meson and ninja
+    Meson,
+    /// A Rust crate's binaries
+    Cargo,
+    /// A plain Makefile honoring `PREFIX`
+    Make,
+}
+
+/// Phases of a source build, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum BuildPhase {
+    /// Prepare the copied source tree
+    Unpack,
+    /// Apply patches
+    Patch,
+    /// Configure the build
+    Configure,
+    /// Compile
+    Build,
+    /// Run the test suite
+    Check,
+    /// Install into `$out`
+    Install,
+}
+
+/// A dinit service declared by a package
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
+pub struct DinitService {
+    /// Service type
+    #[serde(rename = "type")]
+    pub service_type: DinitServiceType,
+    /// Command used to start the service
+    pub command: Option<String>,
+    /// Services that must be started first
+    #[serde(default)]
+    pub depends: Vec<String>,
+    /// User to run the service as
+    pub user: Option<String>,
+    /// Working directory of the service process
+    pub working_directory: Option<String>,
+    /// When to restart the service
+    pub restart: Option<RestartPolicy>,
+    /// File the service output is logged to
+    pub log_file: Option<String>,
+    /// Seconds to wait for the service to start
+    pub start_timeout: Option<u64>,
+    /// Start the service at boot
+    #[serde(default)]
+    pub enable: bool,
+}
+
+/// Dinit service types
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DinitServiceType {
+    /// Long-running foreground process
+    Process,
+    /// Process that daemonizes itself
+    Bgprocess,
+    /// Started and stopped by commands
+    Scripted,
+    /// No process; used to group dependencies
+    Internal,
+    /// Started by an external trigger
+    Triggered,
+}
+
+/// Service restart policies
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Always restart the service when it stops
+    Always,
+    /// Restart only when the service exits with an error
+    OnFailure,
+    /// Never restart the service
+    Never,
+}
+
+/// A declaratively managed file
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct FileDeclaration {
+    /// Target install path
     pub path: String,
+    /// Inline file content
     pub content: Option<String>,
+    /// Path of a file to import into the store
     pub source: Option<String>,
+    /// Octal permission mode (e.g. `"0644"`)
     pub mode: String,
+    /// Owning user
     pub owner: String,
+    /// Owning group
     pub group: String,
+    /// Symlink `path` to the store file instead of copying it; store files
+    /// are readable by everyone, so `mode` must be world-readable
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub link: bool,
+    /// What to do when `path` exists but nexis did not create it; defaults
+    /// to `[file_defaults] on_conflict`
+    pub on_conflict: Option<OnConflict>,
+    /// Composition directives for profile and machine layers
+    #[serde(flatten)]
+    pub merge: MergeDirectives,
+}
+
+/// Settings shared by every declared file
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct FileDefaults {
+    /// What to do when a declared path exists but nexis did not create it
+    #[serde(default)]
+    pub on_conflict: OnConflict,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+/// How a declared file treats an existing file nexis did not create
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum OnConflict {
+    /// Refuse to install, leaving the existing file alone
+    #[default]
+    Fail,
+    /// Move the existing file to the generation's backups, restored on
+    /// rollback
+    Backup,
+    /// Replace the existing file and manage it from now on
+    Adopt,
+}
+
+/// A declared user account
+#[derive(Debug, Clone, Deserialize, Serialize, JsonSchema)]
 pub struct User {
+    /// Login name
     pub name: String,
+    /// Login shell
     pub shell: String,
+    /// Supplementary groups
+    #[serde(default)]
     pub groups: Vec<String>,
+    /// Profile templates applied to this user
     pub profiles: Option<Vec<String>>,
+    /// Files managed for this user
+    #[serde(default)]
     pub files: Vec<FileDeclaration>,
+    /// Composition directives for profile and machine layers
+    #[serde(flatten)]
+    pub merge: MergeDirectives,
+}
+
+/// Config files merged into the including file
+#[derive(Debug, Clone, Default, Deserialize, Serialize, JsonSchema)]
+pub struct Includes {
+    /// Include paths, relative to the including file
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+/// How an entry in a profile or machine layer combines with earlier layers
+///
+/// See [`ConfigComposer`](super::composer::ConfigComposer) for the merge rules.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize, JsonSchema)]
+pub struct MergeDirectives {
+    /// Replace the earlier entry entirely instead of merging fields
+    #[serde(
+        default,
+        rename = "override",
+        skip_serializing_if = "std::ops::Not::not"
+    )]
+    pub overrides: bool,
+    /// Drop the entry added by an earlier layer
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub remove: bool,
 }
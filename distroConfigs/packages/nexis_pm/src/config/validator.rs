@@ -0,0 +1,692 @@
+//! Semantic validation of a loaded configuration
+//!
+//! Runs after [`ConfigLoader`](super::loader::ConfigLoader) and before anything
+//! touches the store. Every problem found becomes a [`ConfigDiagnostic`] that
+//! points at the file and span that set the offending value, so the whole
+//! [`ValidationReport`] can be rendered by miette in one go.
+
+use miette::{Diagnostic, LabeledSpan, NamedSource, SourceSpan};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use super::loader::{LoadedConfig, SourceMap};
+use super::types::FileDeclaration;
+use crate::constants::{HOME_ROOT, MANAGED_ROOTS, NEXIS_PROFILES_DIR};
+
+/// Kind of problem found by the validator
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueKind {
+    /// Two packages share a name
+    DuplicatePackage,
+    /// A file sets both `content` and `source`, or neither
+    FileContentSource,
+    /// A file `mode` is not a valid octal permission string
+    InvalidMode,
+    /// A package dependency's version requirement does not parse
+    InvalidRequirement,
+    /// A file linked into the store has a mode that is not world-readable
+    LinkedMode,
+    /// A package depends on a package that is not declared
+    UnknownDependency,
+    /// A user references a group that does not exist
+    UnknownGroup,
+    /// A user references a profile that does not exist
+    UnknownProfile,
+    /// The timezone is not in the zoneinfo database
+    UnknownTimezone,
+    /// A file path is outside the roots managed by nexis
+    UnmanagedPath,
+}
+
+impl IssueKind {
+    fn code(self) -> &'static str {
+        match self {
+            IssueKind::DuplicatePackage => "nexis::config::duplicate_package",
+            IssueKind::FileContentSource => "nexis::config::file_content_source",
+            IssueKind::InvalidMode => "nexis::config::invalid_mode",
+            IssueKind::InvalidRequirement => "nexis::config::invalid_requirement",
+            IssueKind::LinkedMode => "nexis::config::linked_mode",
+            IssueKind::UnknownDependency => "nexis::config::unknown_dependency",
+            IssueKind::UnknownGroup => "nexis::config::unknown_group",
+            IssueKind::UnknownProfile => "nexis::config::unknown_profile",
+            IssueKind::UnknownTimezone => "nexis::config::unknown_timezone",
+            IssueKind::UnmanagedPath => "nexis::config::unmanaged_path",
+        }
+    }
+}
+
+/// A single validation problem with its source location
+#[derive(Debug)]
+pub struct ConfigDiagnostic {
+    kind: IssueKind,
+    message: String,
+    help: String,
+    source_code: Option<NamedSource<String>>,
+    labels: Vec<LabeledSpan>,
+}
+
+impl ConfigDiagnostic {
+    /// Kind of problem
+    pub fn kind(&self) -> IssueKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for ConfigDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ConfigDiagnostic {}
+
+impl Diagnostic for ConfigDiagnostic {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new(self.kind.code()))
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new(&self.help))
+    }
+
+    fn source_code(&self) -> Option<&dyn miette::SourceCode> {
+        self.source_code
+            .as_ref()
+            .map(|s| s as &dyn miette::SourceCode)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = LabeledSpan> + '_>> {
+        if self.labels.is_empty() {
+            None
+        } else {
+            Some(Box::new(self.labels.iter().cloned()))
+        }
+    }
+}
+
+/// All problems found in a configuration
+#[derive(Debug)]
+pub struct ValidationReport {
+    issues: Vec<ConfigDiagnostic>,
+}
+
+impl ValidationReport {
+    /// The individual problems, in the order they were found
+    pub fn issues(&self) -> &[ConfigDiagnostic] {
+        &self.issues
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.issues.len() {
+            1 => write!(f, "Configuration is invalid: 1 problem found"),
+            n => write!(f, "Configuration is invalid: {} problems found", n),
+        }
+    }
+}
+
+impl std::error::Error for ValidationReport {}
+
+impl Diagnostic for ValidationReport {
+    fn code<'a>(&'a self) -> Option<Box<dyn fmt::Display + 'a>> {
+        Some(Box::new("nexis::config::invalid"))
+    }
+
+    fn related<'a>(&'a self) -> Option<Box<dyn Iterator<Item = &'a dyn Diagnostic> + 'a>> {
+        Some(Box::new(self.issues.iter().map(|i| i as &dyn Diagnostic)))
+    }
+}
+
+/// Host databases the validator checks references against
+#[derive(Debug, Clone)]
+pub struct ValidatorOptions {
+    /// Group database (`/etc/group` format)
+    pub group_file: PathBuf,
+    /// Zoneinfo database root
+    pub zoneinfo_dir: PathBuf,
+    /// Directory containing profile templates
+    pub profiles_dir: PathBuf,
+}
+
+impl Default for ValidatorOptions {
+    fn default() -> Self {
+        Self {
+            group_file: PathBuf::from("/etc/group"),
+            zoneinfo_dir: PathBuf::from("/usr/share/zoneinfo"),
+            profiles_dir: PathBuf::from(NEXIS_PROFILES_DIR),
+        }
+    }
+}
+
+/// Validate a configuration against the host's default databases
+pub fn validate(loaded: &LoadedConfig) -> Result<(), ValidationReport> {
+    Validator::new(ValidatorOptions::default()).validate(loaded)
+}
+
+/// Semantic validator for loaded configurations
+pub struct Validator {
+    options: ValidatorOptions,
+}
+
+impl Validator {
+    /// Create a validator using the given host databases
+    pub fn new(options: ValidatorOptions) -> Self {
+        Self { options }
+    }
+
+    /// Check `loaded`, returning every problem found
+    pub fn validate(&self, loaded: &LoadedConfig) -> Result<(), ValidationReport> {
+        let mut ctx = Context {
+            sources: &loaded.sources,
+            issues: Vec::new(),
+        };
+        let config = &loaded.config;
+
+        self.check_timezone(&mut ctx, &config.system.timezone);
+
+        let mut seen: HashMap<&str, usize> = HashMap::new();
+        for (i, package) in config.packages.iter().enumerate() {
+            let key = format!("packages[{}].name", i);
+            let first = *seen.entry(&package.name).or_insert(i);
+            if first != i {
+                let first_key = format!("packages[{}].name", first);
+                let first_at = ctx
+                    .sources
+                    .location(&first_key)
+                    .map(|l| format!("first declared at {}", l))
+                    .unwrap_or_else(|| "first declared earlier".to_string());
+                ctx.report(
+                    IssueKind::DuplicatePackage,
+                    format!("Package `{}` is declared more than once", package.name),
+                    &[(&key, "duplicate declaration")],
+                    format!(
+                        "{}; remove one of the declarations or give it a different name",
+                        first_at
+                    ),
+                );
+            }
+        }
+
+        for (i, package) in config.packages.iter().enumerate() {
+            let depends = [
+                ("build_depends", &package.build_depends),
+                ("runtime_depends", &package.runtime_depends),
+            ];
+            for (field, depends) in depends {
+                for (dependency, req) in depends {
+                    let key = format!("packages[{}].{}.{}", i, field, dependency);
+                    if !seen.contains_key(dependency.as_str()) {
+                        ctx.report(
+                            IssueKind::UnknownDependency,
+                            format!(
+                                "Package `{}` depends on undeclared package `{}`",
+                                package.name, dependency
+                            ),
+                            &[(&key, "not declared")],
+                            format!(
+                                "declare `{}` in [[packages]] or remove the dependency",
+                                dependency
+                            ),
+                        );
+                    }
+                    if let Err(e) = semver::VersionReq::parse(req) {
+                        ctx.report(
+                            IssueKind::InvalidRequirement,
+                            format!(
+                                "Package `{}` has an invalid version requirement on `{}`",
+                                package.name, dependency
+                            ),
+                            &[(&key, &e.to_string())],
+                            "use a Cargo-style requirement such as \"^1.2\", \">=1, <2\" or \"*\""
+                                .to_string(),
+                        );
+                    }
+                }
+            }
+        }
+
+        let user_names: HashSet<&str> = config.users.iter().map(|u| u.name.as_str()).collect();
+        for (i, file) in config.files.iter().enumerate() {
+            let key = format!("files[{}]", i);
+            self.check_file(&mut ctx, &key, file, None, &user_names);
+        }
+
+        let groups = self.host_groups();
+        for (i, user) in config.users.iter().enumerate() {
+            for (j, group) in user.groups.iter().enumerate() {
+                if !groups.contains(group.as_str()) && !user_names.contains(group.as_str()) {
+                    ctx.report(
+                        IssueKind::UnknownGroup,
+                        format!("User `{}` is in unknown group `{}`", user.name, group),
+                        &[(&format!("users[{}].groups[{}]", i, j), "group not found")],
+                        format!(
+                            "groups must exist in {} or be the name of a declared user",
+                            self.options.group_file.display()
+                        ),
+                    );
+                }
+            }
+
+            for (j, profile) in user.profiles.iter().flatten().enumerate() {
+                let path = self.options.profiles_dir.join(format!("{}.toml", profile));
+                if !is_plain_name(profile) || !path.is_file() {
+                    ctx.report(
+                        IssueKind::UnknownProfile,
+                        format!("User `{}` uses unknown profile `{}`", user.name, profile),
+                        &[(
+                            &format!("users[{}].profiles[{}]", i, j),
+                            "profile not found",
+                        )],
+                        format!("create {} or remove the profile", path.display()),
+                    );
+                }
+            }
+
+            for (j, file) in user.files.iter().enumerate() {
+                let key = format!("users[{}].files[{}]", i, j);
+                self.check_file(&mut ctx, &key, file, Some(&user.name), &user_names);
+            }
+        }
+
+        if ctx.issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationReport { issues: ctx.issues })
+        }
+    }
+
+    fn check_timezone(&self, ctx: &mut Context<'_>, timezone: &str) {
+        let known = Path::new(timezone)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+            && self.options.zoneinfo_dir.join(timezone).is_file();
+        if !known {
+            ctx.report(
+                IssueKind::UnknownTimezone,
+                format!("Unknown timezone `{}`", timezone),
+                &[("system.timezone", "not in the zoneinfo database")],
+                format!(
+                    "use a name from {}, e.g. \"UTC\" or \"Europe/Berlin\"",
+                    self.options.zoneinfo_dir.display()
+                ),
+            );
+        }
+    }
+
+    fn check_file(
+        &self,
+        ctx: &mut Context<'_>,
+        key: &str,
+        file: &FileDeclaration,
+        owner: Option<&str>,
+        user_names: &HashSet<&str>,
+    ) {
+        match (&file.content, &file.source) {
+            (Some(_), Some(_)) => ctx.report(
+                IssueKind::FileContentSource,
+                format!("File `{}` sets both `content` and `source`", file.path),
+                &[
+                    (&format!("{}.content", key), "inline content here"),
+                    (&format!("{}.source", key), "and a source file here"),
+                ],
+                "keep exactly one of `content` or `source`".to_string(),
+            ),
+            (None, None) => ctx.report(
+                IssueKind::FileContentSource,
+                format!("File `{}` sets neither `content` nor `source`", file.path),
+                &[(key, "no content declared")],
+                "add inline `content` or a `source` file to import".to_string(),
+            ),
+            _ => {}
+        }
+
+        match parse_mode(&file.mode) {
+            None => ctx.report(
+                IssueKind::InvalidMode,
+                format!("Invalid file mode `{}`", file.mode),
+                &[(&format!("{}.mode", key), "not an octal mode")],
+                "modes are 3 or 4 octal digits, e.g. \"0644\" or \"755\"".to_string(),
+            ),
+            // Store files are readable by everyone, whatever the mode says
+            Some(mode) if file.link && mode & 0o444 != 0o444 => ctx.report(
+                IssueKind::LinkedMode,
+                format!(
+                    "File `{}` is linked into the store but has mode `{}`",
+                    file.path, file.mode
+                ),
+                &[
+                    (&format!("{}.link", key), "linked into the store"),
+                    (&format!("{}.mode", key), "not world-readable"),
+                ],
+                "drop `link` to install a copy with this mode, or use a mode such as \"0444\""
+                    .to_string(),
+            ),
+            Some(_) => {}
+        }
+
+        if !self.is_managed_path(&file.path, owner, user_names) {
+            let help = match owner {
+                Some(user) => format!(
+                    "files of user `{}` must live under {}/{}",
+                    user, HOME_ROOT, user
+                ),
+                None => format!(
+                    "managed roots are {} and {}/<declared user>",
+                    MANAGED_ROOTS.join(", "),
+                    HOME_ROOT
+                ),
+            };
+            ctx.report(
+                IssueKind::UnmanagedPath,
+                format!("File path `{}` is outside the managed roots", file.path),
+                &[(&format!("{}.path", key), "unmanaged path")],
+                help,
+            );
+        }
+    }
+
+    fn is_managed_path(&self, path: &str, owner: Option<&str>, user_names: &HashSet<&str>) -> bool {
+        let path = Path::new(path);
+        if !path.is_absolute() || path.components().any(|c| c == Component::ParentDir) {
+            return false;
+        }
+
+        let home_of = |user: &str| Path::new(HOME_ROOT).join(user);
+        match owner {
+            Some(user) => path.starts_with(home_of(user)) && path != home_of(user),
+            None => {
+                MANAGED_ROOTS
+                    .iter()
+                    .any(|root| path.starts_with(root) && path != Path::new(root))
+                    || user_names
+                        .iter()
+                        .any(|user| path.starts_with(home_of(user)) && path != home_of(user))
+            }
+        }
+    }
+
+    fn host_groups(&self) -> HashSet<String> {
+        fs::read_to_string(&self.options.group_file)
+            .map(|contents| {
+                contents
+                    .lines()
+                    .filter(|line| !line.trim_start().starts_with('#'))
+                    .filter_map(|line| line.split(':').next())
+                    .filter(|name| !name.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Parse an octal permission string such as `"0644"`
+pub fn parse_mode(mode: &str) -> Option<u32> {
+    let valid = (3..=4).contains(&mode.len()) && mode.bytes().all(|b| (b'0'..=b'7').contains(&b));
+    if valid {
+        u32::from_str_radix(mode, 8).ok()
+    } else {
+        None
+    }
+}
+
+fn is_plain_name(name: &str) -> bool {
+    !name.is_empty() && !name.contains('/') && name != "." && name != ".."
+}
+
+struct Context<'a> {
+    sources: &'a SourceMap,
+    issues: Vec<ConfigDiagnostic>,
+}
+
+impl Context<'_> {
+    /// Record an issue, labelling every key that has a known span.
+    ///
+    /// Labels can only point into one file, so keys set in a different file
+    /// than the first labelled key are dropped.
+    fn report(&mut self, kind: IssueKind, message: String, labels: &[(&str, &str)], help: String) {
+        let mut source_code = None;
+        let mut source_id = None;
+        let mut spans = Vec::new();
+
+        for (key, text) in labels {
+            let Some(span) = self.sources.span(key) else {
+                continue;
+            };
+            if source_id.is_none() {
+                let file = self.sources.file(span.source);
+                source_code = Some(NamedSource::new(
+                    file.path.display().to_string(),
+                    file.contents.clone(),
+                ));
+                source_id = Some(span.source);
+            }
+            if source_id == Some(span.source) {
+                let range: SourceSpan = (span.range.start, span.range.len()).into();
+                spans.push(LabeledSpan::new_with_span(Some(text.to_string()), range));
+            }
+        }
+
+        self.issues.push(ConfigDiagnostic {
+            kind,
+            message,
+            help,
+            source_code,
+            labels: spans,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::loader::ConfigLoader;
+
+    struct Host {
+        dir: tempfile::TempDir,
+    }
+
+    impl Host {
+        fn new() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            fs::create_dir_all(dir.path().join("zoneinfo/Europe")).unwrap();
+            fs::write(dir.path().join("zoneinfo/UTC"), "").unwrap();
+            fs::write(dir.path().join("zoneinfo/Europe/Berlin"), "").unwrap();
+            fs::create_dir_all(dir.path().join("profiles")).unwrap();
+            fs::write(dir.path().join("profiles/developer.toml"), "").unwrap();
+            fs::write(
+                dir.path().join("group"),
+                "root:x:0:\nwheel:x:10:\nusers:x:100:\n",
+            )
+            .unwrap();
+            Self { dir }
+        }
+
+        fn validate(&self, config: &str) -> Result<(), ValidationReport> {
+            let path = self.dir.path().join("system.toml");
+            fs::write(&path, config).unwrap();
+            let loaded = ConfigLoader::new().load(&path).unwrap();
+            Validator::new(ValidatorOptions {
+                group_file: self.dir.path().join("group"),
+                zoneinfo_dir: self.dir.path().join("zoneinfo"),
+                profiles_dir: self.dir.path().join("profiles"),
+            })
+            .validate(&loaded)
+        }
+    }
+
+    fn kinds(report: ValidationReport) -> Vec<IssueKind> {
+        report.issues().iter().map(|i| i.kind()).collect()
+    }
+
+    #[test]
+    fn test_valid_config() {
+        let host = Host::new();
+        let config = r#"
+[system]
+hostname = "myhost"
+timezone = "Europe/Berlin"
+
+[[packages]]
+name = "vim"
+version = "latest"
+
+[[files]]
+path = "/etc/motd"
+content = "Welcome"
+mode = "0644"
+owner = "root"
+group = "root"
+
+[[users]]
+name = "alice"
+shell = "/bin/fish"
+groups = ["wheel", "users"]
+profiles = ["developer"]
+
+[[users.files]]
+path = "/home/alice/.config/fish/config.fish"
+source = "dotfiles/config.fish"
+mode = "644"
+owner = "alice"
+group = "users"
+"#;
+        host.validate(config).unwrap();
+    }
+
+    #[test]
+    fn test_reports_every_problem() {
+        let host = Host::new();
+        let config = r#"
+[system]
+hostname = "myhost"
+timezone = "Mars/Olympus"
+
+[[packages]]
+name = "vim"
+version = "latest"
+
+[[packages]]
+name = "vim"
+version = "9.1"
+
+[[files]]
+path = "/opt/thing.conf"
+content = "a"
+source = "b"
+mode = "0999"
+owner = "root"
+group = "root"
+
+[[users]]
+name = "bob"
+shell = "/bin/sh"
+groups = ["nosuchgroup"]
+profiles = ["gamer"]
+"#;
+        let report = host.validate(config).unwrap_err();
+        assert_eq!(
+            kinds(report),
+            [
+                IssueKind::UnknownTimezone,
+                IssueKind::DuplicatePackage,
+                IssueKind::FileContentSource,
+                IssueKind::InvalidMode,
+                IssueKind::UnmanagedPath,
+                IssueKind::UnknownGroup,
+                IssueKind::UnknownProfile,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_diagnostics_carry_spans() {
+        let host = Host::new();
+        let config = "[system]\nhostname = \"h\"\ntimezone = \"Nowhere\"\n";
+        let report = host.validate(config).unwrap_err();
+        let issue = &report.issues()[0];
+        let label = issue.labels().unwrap().next().unwrap();
+        assert_eq!(label.offset(), config.find("\"Nowhere\"").unwrap());
+        assert!(issue.source_code().is_some());
+        assert!(issue.help().is_some());
+    }
+
+    #[test]
+    fn test_user_files_stay_in_home() {
+        let host = Host::new();
+        let config = r#"
+[system]
+hostname = "h"
+timezone = "UTC"
+
+[[users]]
+name = "alice"
+shell = "/bin/sh"
+
+[[users.files]]
+path = "/etc/passwd"
+content = "x"
+mode = "0644"
+owner = "alice"
+group = "alice"
+"#;
+        let report = host.validate(config).unwrap_err();
+        assert_eq!(kinds(report), [IssueKind::UnmanagedPath]);
+    }
+
+    #[test]
+    fn test_package_dependencies() {
+        let host = Host::new();
+        let config = r#"
+[system]
+hostname = "h"
+timezone = "UTC"
+
+[[packages]]
+name = "curl"
+version = "latest"
+build_depends = { pkgconf = "*" }
+runtime_depends = { zlib = "one point two" }
+
+[[packages]]
+name = "zlib"
+version = "latest"
+"#;
+        let report = host.validate(config).unwrap_err();
+        assert_eq!(
+            kinds(report),
+            [IssueKind::UnknownDependency, IssueKind::InvalidRequirement]
+        );
+    }
+
+    #[test]
+    fn test_linked_files_are_world_readable() {
+        let host = Host::new();
+        let file = |path: &str, mode: &str| {
+            format!(
+                "[[files]]\npath = \"{}\"\ncontent = \"x\"\nmode = \"{}\"\nowner = \"root\"\ngroup = \"root\"\nlink = true\n",
+                path, mode
+            )
+        };
+        let config = format!(
+            "[system]\nhostname = \"h\"\ntimezone = \"UTC\"\n{}{}",
+            file("/etc/motd", "0644"),
+            file("/etc/secret", "0600")
+        );
+        let report = host.validate(&config).unwrap_err();
+        assert_eq!(kinds(report), [IssueKind::LinkedMode]);
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        assert_eq!(parse_mode("0644"), Some(0o644));
+        assert_eq!(parse_mode("755"), Some(0o755));
+        assert_eq!(parse_mode("4755"), Some(0o4755));
+        assert_eq!(parse_mode("0o644"), None);
+        assert_eq!(parse_mode("64"), None);
+        assert_eq!(parse_mode("rw-r--r--"), None);
+    }
+}
@@ -64,6 +64,15 @@ pub const SYSTEM_ETC_DIR: &str = "/etc";
 /// Boot directory
 pub const SYSTEM_BOOT_DIR: &str = "/boot";
 
+/// Root of user home directories
+pub const HOME_ROOT: &str = "/home";
+
+/// System roots that declared files may be installed under
+pub const MANAGED_ROOTS: &[&str] = &[SYSTEM_ETC_DIR, "/usr", SYSTEM_BOOT_DIR];
+
+/// Originals of files replaced by `on_conflict = "backup"`, by generation
+pub const NEXIS_BACKUPS_DIR: &str = "/var/lib/nexis/backups";
+
 // ============================================================================
 // Performance & Limits
 // ============================================================================
@@ -144,6 +153,16 @@ pub const DEFAULT_BRANCHES: &[&str] = &["main", "master", "develop"];
 /// Version cache duration in hours
 pub const VERSION_CACHE_DURATION_HOURS: u64 = 24;
 
+// ============================================================================
+// Generation Profiles
+// ============================================================================
+
+/// Package directories merged into a generation's profile
+pub const PROFILE_DIRS: &[&str] = &["bin", "lib", "share"];
+
+/// Priority of packages that set none; the lowest priority wins a collision
+pub const DEFAULT_PACKAGE_PRIORITY: i32 = 5;
+
 // ============================================================================
 // File Permissions
 // ============================================================================
@@ -0,0 +1,106 @@
+//! Backups of files replaced on conflict
+//!
+//! With `on_conflict = "backup"`, an existing file nexis did not create is
+//! moved into the backups of the generation replacing it:
+//!
+//! ```text
+//! /etc/motd -> /var/lib/nexis/backups/<generation>/etc/motd
+//! ```
+//!
+//! Each generation's `manifest.json` lists the paths it backed up, so
+//! rolling the generation back can put them back. Paths are recorded before
+//! they are moved, so a crash in between never loses track of a backup.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{lchown, symlink, MetadataExt};
+use std::path::{Path, PathBuf};
+
+use super::installer::{read_json, write_json};
+
+/// File name of the manifest in a generation's backups
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// The paths a generation backed up
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BackupManifest {
+    /// Declared paths whose originals were moved aside
+    pub files: BTreeSet<PathBuf>,
+}
+
+/// The backups directory, holding one directory per generation
+#[derive(Debug, Clone)]
+pub struct Backups {
+    dir: PathBuf,
+}
+
+impl Backups {
+    /// Keep backups under `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Where `generation` keeps the original of a declared path
+    pub fn path(&self, generation: u64, declared: &Path) -> PathBuf {
+        let relative = declared.strip_prefix("/").unwrap_or(declared);
+        self.dir.join(generation.to_string()).join(relative)
+    }
+
+    /// The paths `generation` backed up, empty if it backed up none
+    pub fn manifest(&self, generation: u64) -> Result<BackupManifest> {
+        Ok(read_json(&self.manifest_path(generation))?.unwrap_or_default())
+    }
+
+    /// Add `paths` to the manifest of `generation`
+    pub fn record(&self, generation: u64, paths: &[PathBuf]) -> Result<()> {
+        let mut manifest = self.manifest(generation)?;
+        manifest.files.extend(paths.iter().cloned());
+        write_json(&self.manifest_path(generation), &manifest)
+    }
+
+    fn manifest_path(&self, generation: u64) -> PathBuf {
+        self.dir.join(generation.to_string()).join(MANIFEST_FILE)
+    }
+}
+
+/// Move a file or symlink, keeping its owner and mode
+///
+/// Across filesystems, the copy is staged beside `to` and renamed over it
+/// before `from` is removed.
+pub fn move_file(from: &Path, to: &Path) -> Result<()> {
+    let dir = to
+        .parent()
+        .with_context(|| format!("{} has no parent directory", to.display()))?;
+    fs::create_dir_all(dir)?;
+    match fs::rename(from, to) {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::CrossesDevices => {}
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("Failed to move {} to {}", from.display(), to.display()))
+        }
+    }
+
+    let metadata = fs::symlink_metadata(from)?;
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".nexis-");
+    let staged = if metadata.is_symlink() {
+        let target = fs::read_link(from)?;
+        builder.make_in(dir, |path| symlink(&target, path))?
+    } else {
+        builder.make_in(dir, |path| fs::copy(from, path).map(drop))?
+    };
+    lchown(staged.path(), Some(metadata.uid()), Some(metadata.gid()))?;
+    if !metadata.is_symlink() {
+        // Changing the owner clears setuid and setgid bits
+        fs::set_permissions(staged.path(), metadata.permissions())?;
+    }
+    staged
+        .persist(to)
+        .with_context(|| format!("Failed to write {}", to.display()))?;
+    fs::remove_file(from).with_context(|| format!("Failed to remove {}", from.display()))?;
+    Ok(())
+}
@@ -1,16 +1,40 @@
-use blake3::Hasher;
-use std::fs;
-use anyhow::Result;
+//! Content addressing of declared files
+//!
+//! A declared file is stored once as a file object under
+//! `<store>/files/ab/cd/<hash>`, whichever of `content` or `source` it came
+//! from, so identical files share one object.
 
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+
+use crate::config::FileDeclaration;
+use crate::store::hash::{hash_bytes, hash_file as hash_file_streaming};
+use crate::store::{Store, StoreObject};
+
+/// Store hash of inline file content
 pub fn hash_content(content: &str) -> String {
-    let mut hasher = Hasher::new();
-    hasher.update(content.as_bytes());
-    hasher.finalize().to_hex().to_string()
+    hash_bytes(content.as_bytes())
 }
 
+/// Store hash of a file on disk
 pub fn hash_file(path: &Path) -> Result<String> {
-    let content = fs::read(path)?;
-    let mut hasher = Hasher::new();
-    hasher.update(&content);
-    Ok(hasher.finalize().to_hex().to_string())
+    // Streams the file so large sources are never loaded fully into memory
+    hash_file_streaming(path)
+}
+
+/// Add the contents of `file` to the store
+///
+/// A relative `source` is read from `config_dir`.
+pub fn store_file(store: &Store, file: &FileDeclaration, config_dir: &Path) -> Result<StoreObject> {
+    match (&file.content, &file.source) {
+        (Some(content), None) => store.add_bytes(content.as_bytes()),
+        (None, Some(source)) => {
+            let source = config_dir.join(source);
+            store
+                .add_file(&source)
+                .with_context(|| format!("Failed to import {}", source.display()))
+        }
+        (Some(_), Some(_)) => bail!("File `{}` sets both `content` and `source`", file.path),
+        (None, None) => bail!("File `{}` sets neither `content` nor `source`", file.path),
+    }
 }
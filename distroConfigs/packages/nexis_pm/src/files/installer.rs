@@ -0,0 +1,632 @@
+//! Declarative file installation
+//!
+//! Each `[[files]]` entry is added to the store as a file object, then put
+//! in place at its `path`, either as a copy with the declared mode and
+//! owner or, with `link = true`, as a symlink into the store:
+//!
+//! ```text
+//! /etc/motd -> <store>/files/3f/a0/3fa0...
+//! ```
+//!
+//! Every file is staged beside its target and renamed over it, so readers
+//! see the old file or the new one, never a partial write.
+//!
+//! The paths a generation manages are recorded in its `files.json`. An
+//! existing path that the previous generation did not manage, or that was
+//! changed since the previous generation put it there, is a conflict,
+//! settled by the file's `on_conflict`, or `[file_defaults] on_conflict`:
+//!
+//! - `fail` (the default): the install fails before touching anything,
+//!   listing every such path
+//! - `backup`: the original is moved to the generation's
+//!   [backups](super::backup), which [`FileInstaller::restore_backups`]
+//!   puts back on rollback
+//! - `adopt`: the original is replaced and managed from then on
+//!
+//! Existing directories are never replaced. Paths the previous generation
+//! managed but the new one drops are removed, unless they were changed
+//! since, in which case they are left in place.
+//!
+//! The manifest is written, and the file objects rooted in the generation,
+//! before anything is placed, so an install that fails halfway is still
+//! cleaned up by the next one.
+
+use anyhow::{bail, Context, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+use tracing::{debug, warn};
+
+use super::backup::{move_file, Backups};
+use super::content_address::{hash_file, store_file};
+use super::permissions::Permissions;
+use super::symlink::replace_with_symlink;
+use crate::config::{FileDeclaration, OnConflict};
+use crate::constants::NEXIS_BACKUPS_DIR;
+use crate::store::{Store, StoreObject};
+
+/// File name of the manifest in a generation directory
+const MANIFEST_FILE: &str = "files.json";
+
+/// A file put in place by a generation
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ManagedFile {
+    /// Store hash of its contents
+    pub hash: String,
+    /// Whether it is a symlink into the store rather than a copy
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub link: bool,
+}
+
+/// The files a generation manages, by declared path
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileManifest {
+    /// Managed files, by absolute path
+    pub files: BTreeMap<PathBuf, ManagedFile>,
+}
+
+impl FileManifest {
+    /// Read a manifest, or `None` if there is none at `path`
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        read_json(path)
+    }
+
+    /// Write the manifest, replacing any earlier one atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        write_json(path, self)
+    }
+}
+
+/// The outcome of installing a generation's files
+#[derive(Debug, Clone)]
+pub struct InstalledFiles {
+    /// Files the generation manages
+    pub manifest: FileManifest,
+    /// Dropped paths that were removed
+    pub removed: Vec<PathBuf>,
+    /// Dropped paths left in place because they changed since installed
+    pub kept: Vec<PathBuf>,
+    /// Conflicting paths whose originals were backed up
+    pub backed_up: Vec<PathBuf>,
+    /// Conflicting paths whose originals were replaced
+    pub adopted: Vec<PathBuf>,
+}
+
+/// Installs declared files from the store into place
+pub struct FileInstaller<'a> {
+    store: &'a Store,
+    config_dir: PathBuf,
+    root: PathBuf,
+    on_conflict: OnConflict,
+}
+
+impl<'a> FileInstaller<'a> {
+    /// Create an installer reading relative `source` files from `config_dir`
+    pub fn new(store: &'a Store, config_dir: &Path) -> Self {
+        Self {
+            store,
+            config_dir: config_dir.to_path_buf(),
+            root: PathBuf::from("/"),
+            on_conflict: OnConflict::default(),
+        }
+    }
+
+    /// Install below `root` instead of `/`, e.g. into a rootfs overlay
+    pub fn with_root(mut self, root: &Path) -> Self {
+        self.root = root.to_path_buf();
+        self
+    }
+
+    /// Settle conflicts of files that set no `on_conflict` this way
+    pub fn with_on_conflict(mut self, on_conflict: OnConflict) -> Self {
+        self.on_conflict = on_conflict;
+        self
+    }
+
+    /// The backups of replaced originals, kept below the root
+    pub fn backups(&self) -> Backups {
+        Backups::new(self.target(Path::new(NEXIS_BACKUPS_DIR)))
+    }
+
+    /// Where the manifest of `generation` is kept
+    pub fn manifest_path(&self, generation: u64) -> PathBuf {
+        self.store
+            .layout()
+            .generations_dir()
+            .join(generation.to_string())
+            .join(MANIFEST_FILE)
+    }
+
+    /// The manifest of the newest generation before `generation` that has one
+    pub fn previous_manifest(&self, generation: u64) -> Result<Option<FileManifest>> {
+        let mut earlier = Vec::new();
+        if let Ok(entries) = fs::read_dir(self.store.layout().generations_dir()) {
+            for entry in entries {
+                if let Ok(id) = entry?.file_name().to_string_lossy().parse::<u64>() {
+                    if id < generation {
+                        earlier.push(id);
+                    }
+                }
+            }
+        }
+        earlier.sort_unstable();
+        for id in earlier.into_iter().rev() {
+            if let Some(manifest) = FileManifest::load(&self.manifest_path(id))? {
+                return Ok(Some(manifest));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Install `files` as the managed files of `generation`
+    pub fn install(&self, generation: u64, files: &[FileDeclaration]) -> Result<InstalledFiles> {
+        // File objects are unreferenced until the generation roots them
+        let _lock = self.store.lock_shared()?;
+        let previous = self.previous_manifest(generation)?.unwrap_or_default();
+
+        let mut planned = Vec::with_capacity(files.len());
+        let mut manifest = FileManifest::default();
+        for file in files {
+            let path = declared_path(&file.path)?;
+            let object = store_file(self.store, file, &self.config_dir)?;
+            let permissions = Permissions::resolve(file)?;
+            let managed = ManagedFile {
+                hash: object.hash.clone(),
+                link: file.link,
+            };
+            if manifest.files.insert(path.clone(), managed).is_some() {
+                bail!("File `{}` is declared more than once", file.path);
+            }
+            let on_conflict = file.on_conflict.unwrap_or(self.on_conflict);
+            planned.push((path, object, permissions, file.link, on_conflict));
+        }
+
+        let mut conflicts = Vec::new();
+        let mut backed_up = Vec::new();
+        let mut adopted = Vec::new();
+        for (path, .., on_conflict) in &planned {
+            let target = self.target(path);
+            match fs::symlink_metadata(&target) {
+                Ok(metadata) if metadata.is_dir() => {
+                    conflicts.push(format!("{} (a directory)", path.display()))
+                }
+                Ok(_) => {
+                    // Still what the previous generation put there
+                    let changed = match previous.files.get(path) {
+                        Some(managed) if self.unchanged(&target, managed)? == Some(true) => {
+                            continue
+                        }
+                        Some(_) => true,
+                        None => false,
+                    };
+                    match on_conflict {
+                        OnConflict::Fail if changed => conflicts.push(format!(
+                            "{} (changed since it was installed)",
+                            path.display()
+                        )),
+                        OnConflict::Fail => conflicts.push(path.display().to_string()),
+                        OnConflict::Backup => backed_up.push(path.clone()),
+                        OnConflict::Adopt => adopted.push(path.clone()),
+                    }
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to inspect {}", target.display()))
+                }
+            }
+        }
+        if !conflicts.is_empty() {
+            bail!(
+                "Refusing to replace files nexis did not create or that were changed since: {}; move them away, drop them from `[[files]]` or set `on_conflict`",
+                conflicts.join(", ")
+            );
+        }
+
+        manifest.save(&self.manifest_path(generation))?;
+        let hashes: BTreeSet<&str> = planned
+            .iter()
+            .map(|(_, object, ..)| object.hash.as_str())
+            .collect();
+        self.store.database().transaction(|txn| {
+            for hash in &hashes {
+                txn.add_root(generation, hash)?;
+            }
+            Ok(())
+        })?;
+
+        if !backed_up.is_empty() {
+            let backups = self.backups();
+            backups.record(generation, &backed_up)?;
+            for path in &backed_up {
+                let backup = backups.path(generation, path);
+                move_file(&self.target(path), &backup)?;
+                debug!("Backed up {} to {}", path.display(), backup.display());
+            }
+        }
+
+        for (path, object, permissions, link, _) in &planned {
+            let target = self.target(path);
+            place(object, permissions, *link, &target)
+                .with_context(|| format!("Failed to install {}", path.display()))?;
+            debug!("Installed {}", target.display());
+        }
+
+        let mut removed = Vec::new();
+        let mut kept = Vec::new();
+        for (path, managed) in &previous.files {
+            if manifest.files.contains_key(path) {
+                continue;
+            }
+            let target = self.target(path);
+            match self.unchanged(&target, managed)? {
+                None => {}
+                Some(true) => {
+                    fs::remove_file(&target)
+                        .with_context(|| format!("Failed to remove {}", target.display()))?;
+                    removed.push(path.clone());
+                }
+                Some(false) => kept.push(path.clone()),
+            }
+        }
+
+        Ok(InstalledFiles {
+            manifest,
+            removed,
+            kept,
+            backed_up,
+            adopted,
+        })
+    }
+
+    /// Put back the originals `generation` backed up, returning their paths
+    ///
+    /// An original is only restored over the file `generation` installed or
+    /// into an empty path; paths changed since are left alone. Meant for
+    /// rolling back once the files of the generation rolled back to are in
+    /// place.
+    pub fn restore_backups(&self, generation: u64) -> Result<Vec<PathBuf>> {
+        let backups = self.backups();
+        let installed = FileManifest::load(&self.manifest_path(generation))?.unwrap_or_default();
+        let mut restored = Vec::new();
+        for path in backups.manifest(generation)?.files {
+            let backup = backups.path(generation, &path);
+            if fs::symlink_metadata(&backup).is_err() {
+                // Already restored
+                continue;
+            }
+            let target = self.target(&path);
+            let replaceable = match installed.files.get(&path) {
+                Some(managed) => self.unchanged(&target, managed)? != Some(false),
+                None => fs::symlink_metadata(&target).is_err(),
+            };
+            if !replaceable {
+                warn!(
+                    "{} changed since generation {} installed it; keeping the original in {}",
+                    path.display(),
+                    generation,
+                    backup.display()
+                );
+                continue;
+            }
+            move_file(&backup, &target)?;
+            restored.push(path);
+        }
+        Ok(restored)
+    }
+
+    /// Where a declared path lives below the root
+    fn target(&self, path: &Path) -> PathBuf {
+        self.root
+            .join(path.strip_prefix("/").expect("declared paths are absolute"))
+    }
+
+    /// Whether `target` is still what `managed` put there, or `None` if it
+    /// is gone
+    fn unchanged(&self, target: &Path, managed: &ManagedFile) -> Result<Option<bool>> {
+        let metadata = match fs::symlink_metadata(target) {
+            Ok(metadata) => metadata,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to inspect {}", target.display()))
+            }
+        };
+        let unchanged = if managed.link {
+            metadata.is_symlink()
+                && fs::read_link(target)? == self.store.layout().file_path(&managed.hash)?
+        } else {
+            metadata.is_file() && hash_file(target)? == managed.hash
+        };
+        Ok(Some(unchanged))
+    }
+}
+
+/// Read a JSON file, or `None` if there is none at `path`
+pub(super) fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Option<T>> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    let value =
+        serde_json::from_str(&text).with_context(|| format!("Invalid {}", path.display()))?;
+    Ok(Some(value))
+}
+
+/// Write a JSON file, replacing any earlier one atomically
+pub(super) fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let dir = path
+        .parent()
+        .with_context(|| format!("{} has no parent directory", path.display()))?;
+    fs::create_dir_all(dir)?;
+    let mut staged = tempfile::NamedTempFile::new_in(dir)?;
+    serde_json::to_writer_pretty(&mut staged, value)?;
+    staged.write_all(b"\n")?;
+    staged.as_file().sync_all()?;
+    staged
+        .persist(path)
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    Ok(())
+}
+
+/// Check that a declared path is absolute and free of `.` and `..`
+fn declared_path(path: &str) -> Result<PathBuf> {
+    let path = Path::new(path);
+    let mut components = path.components();
+    let plain = components.next() == Some(Component::RootDir)
+        && components.all(|component| matches!(component, Component::Normal(_)));
+    if !plain || path == Path::new("/") {
+        bail!(
+            "File path `{}` must be an absolute path to a file",
+            path.display()
+        );
+    }
+    Ok(path.to_path_buf())
+}
+
+/// Atomically put `object` at `target`
+fn place(object: &StoreObject, permissions: &Permissions, link: bool, target: &Path) -> Result<()> {
+    let dir = target.parent().expect("declared paths have a parent");
+    fs::create_dir_all(dir)?;
+    if link {
+        return replace_with_symlink(&object.path, target, |staged| {
+            permissions.apply_to_link(staged)
+        });
+    }
+
+    let mut staged = tempfile::Builder::new()
+        .prefix(".nexis-")
+        .tempfile_in(dir)?;
+    io::copy(&mut fs::File::open(&object.path)?, staged.as_file_mut())?;
+    permissions.apply(staged.as_file())?;
+    staged.as_file().sync_all()?;
+    staged.persist(target)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::types::MergeDirectives;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn declare(path: &str, content: Option<&str>, source: Option<&str>) -> FileDeclaration {
+        FileDeclaration {
+            path: path.to_string(),
+            content: content.map(str::to_string),
+            source: source.map(str::to_string),
+            mode: "0640".to_string(),
+            owner: uzers::get_current_username()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            group: uzers::get_current_groupname()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            link: false,
+            on_conflict: None,
+            merge: MergeDirectives::default(),
+        }
+    }
+
+    #[test]
+    fn test_install_and_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let config_dir = dir.path().join("config");
+        let root = dir.path().join("root");
+        fs::create_dir_all(&config_dir).unwrap();
+        fs::write(config_dir.join("app.conf"), "port = 80\n").unwrap();
+        let installer = FileInstaller::new(&store, &config_dir).with_root(&root);
+
+        let mut conf = declare("/etc/app/app.conf", None, Some("app.conf"));
+        conf.link = true;
+        let files = [
+            declare("/etc/motd", Some("hello\n"), None),
+            conf,
+            declare("/etc/issue", Some("nexis\n"), None),
+        ];
+        let installed = installer.install(1, &files).unwrap();
+        assert_eq!(installed.manifest.files.len(), 3);
+        assert_eq!(
+            FileManifest::load(&installer.manifest_path(1)).unwrap(),
+            Some(installed.manifest.clone())
+        );
+
+        let motd = root.join("etc/motd");
+        assert_eq!(fs::read_to_string(&motd).unwrap(), "hello\n");
+        let mode = fs::metadata(&motd).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o640);
+        let conf_hash = &installed.manifest.files[Path::new("/etc/app/app.conf")].hash;
+        assert_eq!(
+            fs::read_link(root.join("etc/app/app.conf")).unwrap(),
+            store.layout().file_path(conf_hash).unwrap()
+        );
+        assert_eq!(store.database().roots().unwrap()[&1].len(), 3);
+
+        // Installing the same files again replaces what generation 1 put there
+        installer.install(2, &files).unwrap();
+
+        fs::write(root.join("etc/issue"), "edited\n").unwrap();
+        let installed = installer
+            .install(3, &[declare("/etc/motd", Some("hi\n"), None)])
+            .unwrap();
+        assert_eq!(fs::read_to_string(&motd).unwrap(), "hi\n");
+        assert_eq!(installed.removed, [PathBuf::from("/etc/app/app.conf")]);
+        assert_eq!(installed.kept, [PathBuf::from("/etc/issue")]);
+        assert!(!root.join("etc/app/app.conf").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("etc/issue")).unwrap(),
+            "edited\n"
+        );
+    }
+
+    #[test]
+    fn test_unmanaged_files_are_not_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("etc/motd.d")).unwrap();
+        fs::write(root.join("etc/hostname"), "buildroot\n").unwrap();
+        let installer = FileInstaller::new(&store, dir.path()).with_root(&root);
+
+        let err = installer
+            .install(
+                1,
+                &[
+                    declare("/etc/issue", Some("nexis\n"), None),
+                    declare("/etc/hostname", Some("nexis\n"), None),
+                    declare("/etc/motd.d", Some("hello\n"), None),
+                ],
+            )
+            .unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("/etc/hostname"), "{}", message);
+        assert!(message.contains("/etc/motd.d (a directory)"), "{}", message);
+        assert!(!root.join("etc/issue").exists());
+        assert_eq!(
+            fs::read_to_string(root.join("etc/hostname")).unwrap(),
+            "buildroot\n"
+        );
+        assert!(!installer.manifest_path(1).exists());
+
+        assert!(installer
+            .install(1, &[declare("/etc/../shadow", Some(""), None)])
+            .is_err());
+        let twice = [
+            declare("/etc/issue", Some("a\n"), None),
+            declare("/etc/issue", Some("b\n"), None),
+        ];
+        assert!(installer.install(1, &twice).is_err());
+    }
+
+    #[test]
+    fn test_conflict_policies() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let root = dir.path().join("root");
+        fs::create_dir_all(root.join("etc")).unwrap();
+        for (name, contents) in [
+            ("motd", "welcome\n"),
+            ("hostname", "buildroot\n"),
+            ("issue", "buildroot\n"),
+        ] {
+            fs::write(root.join("etc").join(name), contents).unwrap();
+        }
+        let installer = FileInstaller::new(&store, dir.path())
+            .with_root(&root)
+            .with_on_conflict(OnConflict::Backup);
+
+        let mut hostname = declare("/etc/hostname", Some("nexis\n"), None);
+        hostname.on_conflict = Some(OnConflict::Adopt);
+        let mut issue = declare("/etc/issue", Some("nexis\n"), None);
+        issue.on_conflict = Some(OnConflict::Fail);
+        let motd = declare("/etc/motd", Some("hello\n"), None);
+
+        let files = [motd, hostname, issue];
+        let err = installer.install(1, &files).unwrap_err();
+        assert!(err.to_string().contains("/etc/issue"), "{}", err);
+        assert!(!err.to_string().contains("/etc/motd"), "{}", err);
+        assert_eq!(
+            fs::read_to_string(root.join("etc/motd")).unwrap(),
+            "welcome\n"
+        );
+
+        let installed = installer.install(1, &files[..2]).unwrap();
+        assert_eq!(installed.backed_up, [PathBuf::from("/etc/motd")]);
+        assert_eq!(installed.adopted, [PathBuf::from("/etc/hostname")]);
+        assert_eq!(
+            fs::read_to_string(root.join("etc/motd")).unwrap(),
+            "hello\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("etc/hostname")).unwrap(),
+            "nexis\n"
+        );
+        let backups = installer.backups();
+        let backup = backups.path(1, Path::new("/etc/motd"));
+        assert_eq!(backup, root.join("var/lib/nexis/backups/1/etc/motd"));
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "welcome\n");
+        assert_eq!(
+            backups.manifest(1).unwrap().files,
+            BTreeSet::from([PathBuf::from("/etc/motd")])
+        );
+
+        // Now managed, so installing again is not a conflict
+        installer.install(2, &files[..2]).unwrap();
+
+        assert_eq!(
+            installer.restore_backups(1).unwrap(),
+            [PathBuf::from("/etc/motd")]
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("etc/motd")).unwrap(),
+            "welcome\n"
+        );
+        assert!(!backup.exists());
+        assert!(installer.restore_backups(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_changed_managed_files_are_conflicts() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let root = dir.path().join("root");
+        let installer = FileInstaller::new(&store, dir.path()).with_root(&root);
+        let files = [
+            declare("/etc/motd", Some("hello\n"), None),
+            declare("/etc/issue", Some("nexis\n"), None),
+        ];
+        installer.install(1, &files).unwrap();
+
+        fs::write(root.join("etc/motd"), "edited\n").unwrap();
+        let err = installer.install(2, &files).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("/etc/motd (changed since it was installed)"),
+            "{}",
+            err
+        );
+        assert!(!err.to_string().contains("/etc/issue"), "{}", err);
+        assert_eq!(
+            fs::read_to_string(root.join("etc/motd")).unwrap(),
+            "edited\n"
+        );
+
+        let installer = installer.with_on_conflict(OnConflict::Backup);
+        let installed = installer.install(2, &files).unwrap();
+        assert_eq!(installed.backed_up, [PathBuf::from("/etc/motd")]);
+        assert_eq!(
+            fs::read_to_string(installer.backups().path(2, Path::new("/etc/motd"))).unwrap(),
+            "edited\n"
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("etc/motd")).unwrap(),
+            "hello\n"
+        );
+    }
+}
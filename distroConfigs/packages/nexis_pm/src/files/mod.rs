@@ -0,0 +1,19 @@
+//! Declarative file management
+//!
+//! Puts `[[files]]` entries in place from the store:
+//! - Content addressing of declared contents
+//! - Atomic installation and pruning of dropped files
+//! - Backups of files replaced on conflict
+//! - Ownership and mode
+//! - Atomic symlink replacement
+
+pub mod backup;
+pub mod content_address;
+pub mod installer;
+pub mod permissions;
+pub mod symlink;
+
+// Re-export commonly used items
+pub use backup::{BackupManifest, Backups};
+pub use installer::{FileInstaller, FileManifest, InstalledFiles, ManagedFile};
+pub use permissions::Permissions;
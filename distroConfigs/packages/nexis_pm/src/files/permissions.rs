@@ -0,0 +1,64 @@
+//! Ownership and mode of installed files
+
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::os::unix::fs::{fchown, lchown, PermissionsExt};
+use std::path::Path;
+
+use crate::config::validator::parse_mode;
+use crate::config::FileDeclaration;
+
+/// Mode and owner a declared file is installed with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    /// Permission bits
+    pub mode: u32,
+    /// Owning user id
+    pub uid: u32,
+    /// Owning group id
+    pub gid: u32,
+}
+
+impl Permissions {
+    /// Look up the `mode`, `owner` and `group` of `file`
+    ///
+    /// Owners and groups are looked up by name, falling back to numeric ids.
+    pub fn resolve(file: &FileDeclaration) -> Result<Self> {
+        let Some(mode) = parse_mode(&file.mode) else {
+            bail!("File `{}` has invalid mode `{}`", file.path, file.mode);
+        };
+        let uid = match uzers::get_user_by_name(&file.owner) {
+            Some(user) => user.uid(),
+            None => file.owner.parse().with_context(|| {
+                format!(
+                    "File `{}` is owned by unknown user `{}`",
+                    file.path, file.owner
+                )
+            })?,
+        };
+        let gid = match uzers::get_group_by_name(&file.group) {
+            Some(group) => group.gid(),
+            None => file.group.parse().with_context(|| {
+                format!("File `{}` has unknown group `{}`", file.path, file.group)
+            })?,
+        };
+        Ok(Self { mode, uid, gid })
+    }
+
+    /// Set the owner, then the mode, of an open file
+    ///
+    /// Changing the owner clears setuid and setgid bits, so the mode comes
+    /// second.
+    pub fn apply(&self, file: &fs::File) -> Result<()> {
+        fchown(file, Some(self.uid), Some(self.gid)).context("Failed to change owner")?;
+        file.set_permissions(fs::Permissions::from_mode(self.mode))
+            .context("Failed to change mode")?;
+        Ok(())
+    }
+
+    /// Set the owner of a symlink itself; symlinks have no mode of their own
+    pub fn apply_to_link(&self, path: &Path) -> Result<()> {
+        lchown(path, Some(self.uid), Some(self.gid))
+            .with_context(|| format!("Failed to change owner of {}", path.display()))
+    }
+}
@@ -0,0 +1,29 @@
+//! Atomic symlink replacement
+
+use anyhow::{Context, Result};
+use std::os::unix::fs::symlink;
+use std::path::Path;
+
+/// Point `link` at `target`, replacing whatever `link` was
+///
+/// The new symlink is created beside `link`, handed to `prepare` (e.g. to
+/// set its owner) and renamed over `link`, so `link` never goes missing. On
+/// error the staged symlink is removed.
+pub fn replace_with_symlink(
+    target: &Path,
+    link: &Path,
+    prepare: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    let dir = link
+        .parent()
+        .with_context(|| format!("{} has no parent directory", link.display()))?;
+    let staged = tempfile::Builder::new()
+        .prefix(".nexis-")
+        .make_in(dir, |path| symlink(target, path))
+        .with_context(|| format!("Failed to create a symlink in {}", dir.display()))?;
+    prepare(staged.path())?;
+    staged
+        .persist(link)
+        .with_context(|| format!("Failed to replace {}", link.display()))?;
+    Ok(())
+}
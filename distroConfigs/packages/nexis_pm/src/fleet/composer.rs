@@ -1,37 +1,54 @@
-use anyhow::Result;
+//! Fleet configuration composition
+//!
+//! Resolves profile and machine names to files under `/etc/nexis` and feeds
+//! them to [`ConfigComposer`] on top of a base configuration.
 
+use anyhow::{Context, Result};
+
+use crate::config::composer::{Composition, ConfigComposer, Layer};
+use crate::constants::{machines_dir, profiles_dir};
+
+/// Composes configurations for machines in a fleet
 pub struct FleetComposer;
 
 impl FleetComposer {
+    /// Compose the configuration of one machine: base → profiles → machine
     pub fn compose_for_machine(
-        base: Config,
+        base: Layer,
         profiles: &[String],
         machine_id: &str,
-    ) -> Result<Config> {
+    ) -> Result<Composition> {
+        Self::compose(base, profiles, Some(machine_id))
+    }
+
+    /// Compose a base with profiles and an optional machine file
+    pub fn compose(
+        base: Layer,
+        profiles: &[String],
+        machine_id: Option<&str>,
+    ) -> Result<Composition> {
         // Load profile templates
-        let profile_configs: Vec<Config> = profiles
+        let profile_configs: Vec<Layer> = profiles
             .iter()
             .map(|name| load_profile(name))
             .collect::<Result<_>>()?;
-        
+
         // Load machine-specific config
-        let machine_config = load_machine_config(machine_id)?;
-        
+        let machine_config = machine_id.map(load_machine_config).transpose()?;
+
         // Compose: base → profiles → machine
-        ConfigComposer::compose(base, profile_configs, Some(machine_config))
+        ConfigComposer::compose(base, profile_configs, machine_config)
     }
 }
 
-fn load_profile(name: &str) -> Result<Config> {
-    let path = PathBuf::from("/etc/nexis/profiles")
-        .join(format!("{}.toml", name));
-    let content = fs::read_to_string(path)?;
-    Ok(toml::from_str(&content)?)
+fn load_profile(name: &str) -> Result<Layer> {
+    let path = profiles_dir().join(format!("{}.toml", name));
+    Layer::load(format!("profile:{}", name), &path)
+        .with_context(|| format!("Failed to load profile `{}`", name))
 }
 
-fn load_machine_config(id: &str) -> Result<Config> {
-    let path = PathBuf::from("/etc/nexis/machines")
-        .join(format!("{}.toml", id));
-    let content = fs::read_to_string(path)?;
-    Ok(toml::from_str(&content)?)
+fn load_machine_config(id: &str) -> Result<Layer> {
+    let path = machines_dir().join(format!("{}.toml", id));
+    Layer::load(format!("machine:{}", id), &path)
+        .with_context(|| format!("Failed to load machine config `{}`", id))
 }
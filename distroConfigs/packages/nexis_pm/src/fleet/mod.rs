@@ -0,0 +1,9 @@
+//! Fleet management for NexisPM
+//!
+//! Composes per-machine configurations from shared profile templates:
+//! - Profile and machine file composition
+
+pub mod composer;
+
+// Re-export commonly used items
+pub use composer::FleetComposer;
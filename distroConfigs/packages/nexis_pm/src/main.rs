@@ -14,6 +14,18 @@ async fn main() -> Result<()> {
         Commands::Switch(args) => nexispm::cli::commands::switch::execute(args).await,
         Commands::Rollback(args) => nexispm::cli::commands::rollback::execute(args).await,
         Commands::Gc(args) => nexispm::cli::commands::gc::execute(args).await,
+        Commands::Query(args) => nexispm::cli::commands::query::execute(args).await,
+        Commands::Schema(args) => nexispm::cli::commands::schema::execute(args).await,
+        Commands::Show(args) => nexispm::cli::commands::show::execute(args).await,
+        Commands::Store(args) => nexispm::cli::commands::store::execute(args).await,
+        Commands::Log(args) => nexispm::cli::commands::log::execute(args).await,
+        Commands::Key(args) => nexispm::cli::commands::key::execute(args).await,
+        Commands::Verify(args) => nexispm::cli::commands::verify::execute(args).await,
+        #[cfg(feature = "build-cache")]
+        Commands::Cache(args) => nexispm::cli::commands::cache::execute(args).await,
+        Commands::ResolveVersions(args) => {
+            nexispm::cli::commands::resolve_versions::execute(args).await
+        }
         // ... other commands
     }
 }
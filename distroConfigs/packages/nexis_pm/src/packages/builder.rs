@@ -1,30 +1,394 @@
-use rayon::prelude::*;
-use anyhow::Result;
+//! Package builds
+//!
+//! Turns what the [`Pipeline`](super::pipeline::Pipeline) acquired into a
+//! store object. A prebuilt artifact is added as-is; a source tree is copied
+//! into a private build directory under the store's `.tmp/` and run through
+//! its [`BuildPlan`] by the [`Executor`], and whatever the recipe installed
+//! into `$out` becomes the object. Builds run in a [`BuildEnvironment`] made
+//! from their build dependencies only, with `HOME` pointing at an empty
+//! directory inside the build directory. With the `sandbox` feature, a
+//! builder can also confine each build to a
+//! [`Sandbox`](crate::build::sandbox::Sandbox) exposing its dependencies and
+//! patches.
+//!
+//! [`ParallelBuilder::build_packages`] builds several packages at once, each
+//! as soon as its dependencies are in the store, through a
+//! [`Scheduler`](crate::build::parallel::Scheduler). Each object is added
+//! with the store hashes of its `runtime_depends` as references, so garbage
+//! collection, closures and signatures follow them.
+//!
+//! A failed build's directory is removed unless the builder keeps failed
+//! builds, in which case it stays under `.tmp/` for inspection.
 
+use anyhow::{bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::runtime::Handle;
+use tracing::info;
+
+use super::fetcher::Fetched;
+use super::pipeline::Acquired;
+use super::recipe::BuildPlan;
+use crate::build::parallel::{Outcome, Scheduler, Task};
+use crate::build::{BuildEnvironment, Executor};
+use crate::config::Package;
+use crate::store::objects::copy_tree;
+use crate::store::{Store, StoreObject};
+
+/// A package ready to be built
+#[derive(Debug, Clone)]
+pub struct BuildJob {
+    /// Package declaration, including its recipe
+    pub package: Package,
+    /// Prebuilt artifact or source tree to build from
+    pub acquired: Acquired,
+    /// Store paths of the package's build dependencies
+    pub dependencies: Vec<PathBuf>,
+    /// Store hashes of the package's runtime dependencies
+    ///
+    /// They are recorded as references of the object the job builds.
+    pub references: Vec<String>,
+}
+
+/// Outcome of a successful build
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildResult {
+    /// Package name
+    pub name: String,
+    /// Store object the build produced
+    pub object: StoreObject,
+}
+
+/// Builds packages into the store, several at once
 pub struct ParallelBuilder {
-    pool: rayon::ThreadPool,
+    scheduler: Scheduler,
+    runtime: Handle,
+    store: Arc<Store>,
+    executor: Executor,
+    config_dir: PathBuf,
+    jobs: usize,
+    keep_failed: bool,
+    #[cfg(feature = "sandbox")]
+    sandbox: bool,
 }
 
 impl ParallelBuilder {
-    pub fn new(num_threads: usize) -> Self {
-        let pool = rayon::ThreadPoolBuilder::new()
-            .num_threads(num_threads)
-            .build()
-            .unwrap();
-        Self { pool }
-    }
-    
-    pub fn build_packages(&self, packages: Vec<Package>) -> Result<Vec<BuildResult>> {
-        self.pool.install(|| {
-            packages
-                .par_iter()
-                .map(|pkg| self.build_package(pkg))
-                .collect()
+    /// Create a builder running up to `num_threads` builds at once
+    ///
+    /// Recipe patch paths are resolved against `config_dir`. Build phases run
+    /// on the current tokio runtime, so this must be called from within one.
+    pub fn new(
+        store: Arc<Store>,
+        config_dir: impl Into<PathBuf>,
+        num_threads: usize,
+    ) -> Result<Self> {
+        let runtime = Handle::try_current().context("Builds need a tokio runtime")?;
+        Ok(Self {
+            scheduler: Scheduler::new(num_threads),
+            runtime,
+            store,
+            executor: Executor::default(),
+            config_dir: config_dir.into(),
+            jobs: num_threads.max(1),
+            keep_failed: false,
+            #[cfg(feature = "sandbox")]
+            sandbox: false,
+        })
+    }
+
+    /// Run build phases through `executor`
+    pub fn with_executor(mut self, executor: Executor) -> Self {
+        self.executor = executor;
+        self
+    }
+
+    /// Leave the build directory of a failed build in place
+    pub fn with_keep_failed(mut self, keep_failed: bool) -> Self {
+        self.keep_failed = keep_failed;
+        self
+    }
+
+    /// After a failed build, keep building packages that do not depend on it
+    pub fn with_keep_going(mut self, keep_going: bool) -> Self {
+        self.scheduler = self.scheduler.with_keep_going(keep_going);
+        self
+    }
+
+    /// Run every source build in its own sandbox
+    #[cfg(feature = "sandbox")]
+    pub fn with_sandbox(mut self, sandbox: bool) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Build every job once the jobs it depends on are built
+    ///
+    /// Returns one outcome per job, in order. Each job's `dependencies` gain
+    /// the store paths its `build_depends` were built to, and its
+    /// `references` the store hashes of its `runtime_depends`.
+    pub fn build_packages(&self, jobs: Vec<BuildJob>) -> Vec<(String, Outcome<BuildResult>)> {
+        let tasks = jobs
+            .into_iter()
+            .map(|job| Task {
+                name: job.package.name.clone(),
+                dependencies: job
+                    .package
+                    .build_depends
+                    .keys()
+                    .chain(job.package.runtime_depends.keys())
+                    .cloned()
+                    .collect(),
+                item: job,
+            })
+            .collect();
+        self.scheduler.run(tasks, |mut job, built| {
+            let outputs = job
+                .package
+                .build_depends
+                .keys()
+                .filter_map(|name| built.get(name))
+                .map(|result: &BuildResult| result.object.path.clone());
+            job.dependencies.extend(outputs);
+            let references = job
+                .package
+                .runtime_depends
+                .keys()
+                .filter_map(|name| built.get(name))
+                .map(|result: &BuildResult| result.object.hash.clone());
+            job.references.extend(references);
+            self.build_package(&job)
         })
     }
-    
-    fn build_package(&self, package: &Package) -> Result<BuildResult> {
-        // Build logic here
-        todo!()
+
+    /// Build a single job into the store
+    pub fn build_package(&self, job: &BuildJob) -> Result<BuildResult> {
+        let name = &job.package.name;
+        let references: Vec<&str> = job.references.iter().map(String::as_str).collect();
+        let object = match &job.acquired {
+            Acquired::Prebuilt(fetched) => {
+                self.store
+                    .add_tree_referencing(name, &fetched.path, &references)?
+            }
+            Acquired::Source(fetched) => self
+                .build_source(&job.package, fetched, &job.dependencies, &references)
+                .with_context(|| format!("Failed to build `{}`", name))?,
+        };
+        info!("{}: {}", name, object.path.display());
+        Ok(BuildResult {
+            name: name.clone(),
+            object,
+        })
+    }
+
+    fn build_source(
+        &self,
+        package: &Package,
+        fetched: &Fetched,
+        dependencies: &[PathBuf],
+        references: &[&str],
+    ) -> Result<StoreObject> {
+        let build_dir = tempfile::Builder::new()
+            .prefix(&format!("build-{}-", package.name))
+            .tempdir_in(self.store.layout().tmp_dir())
+            .context("Failed to create build directory")?;
+        let src = build_dir.path().join("src");
+        let out = build_dir.path().join("out");
+        let home = build_dir.path().join("home");
+        copy_tree(&fetched.path, &src)
+            .with_context(|| format!("Failed to copy {}", fetched.path.display()))?;
+        fs::create_dir(&out)?;
+        fs::create_dir(&home)?;
+
+        let plan = BuildPlan::new(package, &src, &self.config_dir)?;
+        #[allow(unused_mut)]
+        let mut executor = self.executor.clone();
+        #[cfg(feature = "sandbox")]
+        if self.sandbox {
+            let patches = package
+                .recipe
+                .iter()
+                .flat_map(|recipe| &recipe.patches)
+                .map(|patch| self.config_dir.join(patch));
+            let inputs = dependencies.iter().cloned().chain(patches).collect();
+            let sandbox = crate::build::Sandbox::new(build_dir.path(), inputs)?;
+            executor = executor.with_sandbox(Some(Arc::new(sandbox)));
+        }
+        let guest = |path: &Path| executor.guest_path(path).to_string_lossy().into_owned();
+
+        let mut env = BuildEnvironment::new(dependencies).with_source_date_epoch(&fetched.path)?;
+        env.set("HOME", guest(&home));
+        env.extend(plan.env.clone());
+        env.set("src", guest(&src));
+        env.set("out", guest(&out));
+        env.set("jobs", self.jobs.to_string());
+
+        info!(
+            "{}: building, log in {}",
+            package.name,
+            executor.log_path(&fetched.hash, &package.name).display()
+        );
+        let report =
+            self.runtime
+                .block_on(executor.run(&package.name, &fetched.hash, &plan, &src, &env))?;
+        if let Some(failure) = report.failure() {
+            let reason = if report.timed_out {
+                format!("{} phase timed out", failure.phase)
+            } else {
+                format!("{} phase failed with {}", failure.phase, failure.status)
+            };
+            if self.keep_failed {
+                let kept = build_dir.keep();
+                bail!(
+                    "{}; see {} (build directory kept at {})",
+                    reason,
+                    report.log.display(),
+                    kept.display()
+                );
+            }
+            bail!("{}; see {}", reason, report.log.display());
+        }
+
+        self.store
+            .add_tree_referencing(&package.name, &out, references)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::build::Executor;
+
+    /// A dependency providing `tools` from the host
+    fn host_tools(dir: &Path, tools: &[&str]) -> PathBuf {
+        let bin = dir.join("tools/bin");
+        fs::create_dir_all(&bin).unwrap();
+        let path = std::env::var_os("PATH").unwrap();
+        for tool in tools {
+            let host = std::env::split_paths(&path)
+                .map(|dir| dir.join(tool))
+                .find(|p| p.exists())
+                .unwrap();
+            std::os::unix::fs::symlink(host, bin.join(tool)).unwrap();
+        }
+        dir.join("tools")
+    }
+
+    fn job(dir: &Path, recipe: &str) -> BuildJob {
+        let src = dir.join("hello");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("hello.txt"), "hello\n").unwrap();
+        let package = toml::from_str(&format!(
+            "name = \"hello\"\nversion = \"1.0.0\"\nsource = \"{}\"\n[recipe]\n{}",
+            src.display(),
+            recipe
+        ))
+        .unwrap();
+        BuildJob {
+            package,
+            acquired: Acquired::Source(Fetched {
+                name: "hello".into(),
+                url: src.display().to_string(),
+                path: src,
+                hash: "ab12".into(),
+            }),
+            dependencies: Vec::new(),
+            references: Vec::new(),
+        }
+    }
+
+    fn builder(dir: &Path, num_threads: usize) -> ParallelBuilder {
+        let store = Arc::new(Store::open(dir.join("store")).unwrap());
+        ParallelBuilder::new(store, dir, num_threads)
+            .unwrap()
+            .with_executor(Executor::new(dir.join("logs")))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_from_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = builder(dir.path(), 2);
+
+        let mut job = job(
+            dir.path(),
+            r#"build = ["tr a-z A-Z < hello.txt > HELLO"]
+install = [
+    "mkdir -p $out/share/hello",
+    "cp HELLO $out/share/hello/",
+    "echo $jobs > $out/share/hello/jobs",
+    "echo $PATH:$HOME > $out/share/hello/env",
+]"#,
+        );
+        let tools = host_tools(dir.path(), &["tr", "mkdir", "cp"]);
+        job.dependencies.push(tools.clone());
+        let result = tokio::task::block_in_place(|| builder.build_package(&job)).unwrap();
+        let share = result.object.path.join("share/hello");
+        assert_eq!(fs::read_to_string(share.join("HELLO")).unwrap(), "HELLO\n");
+        assert_eq!(fs::read_to_string(share.join("jobs")).unwrap(), "2\n");
+        // Only the dependency's tools are on PATH, and HOME is private
+        let env = fs::read_to_string(share.join("env")).unwrap();
+        let (path, home) = env.trim_end().split_once(':').unwrap();
+        assert_eq!(Path::new(path), tools.join("bin"));
+        assert!(home.starts_with(&dir.path().join("store/.tmp").display().to_string()));
+        // The fetched tree is left untouched
+        assert!(!dir.path().join("hello/HELLO").exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_runtime_dependencies_become_references() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = builder(dir.path(), 2);
+        let prebuilt = |name: &str, extra: &str| {
+            let tree = dir.path().join(name);
+            fs::create_dir_all(&tree).unwrap();
+            fs::write(tree.join(name), name).unwrap();
+            BuildJob {
+                package: toml::from_str(&format!(
+                    "name = \"{}\"\nversion = \"1.0.0\"\n{}",
+                    name, extra
+                ))
+                .unwrap(),
+                acquired: Acquired::Prebuilt(Fetched {
+                    name: name.into(),
+                    url: tree.display().to_string(),
+                    path: tree,
+                    hash: "ab12".into(),
+                }),
+                dependencies: Vec::new(),
+                references: Vec::new(),
+            }
+        };
+
+        let jobs = vec![
+            prebuilt("app", "[runtime_depends]\nlib = \"*\""),
+            prebuilt("lib", ""),
+        ];
+        let outcomes = tokio::task::block_in_place(|| builder.build_packages(jobs));
+        let object = |i: usize| &outcomes[i].1.built().unwrap().object;
+        let db = builder.store.database();
+        assert_eq!(
+            db.references(&object(0).hash).unwrap(),
+            [object(1).hash.clone()]
+        );
+        assert!(db.references(&object(1).hash).unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_phase() {
+        let dir = tempfile::tempdir().unwrap();
+        let job = job(dir.path(), "build = [\"false\"]\ninstall = [\"true\"]");
+        let tmp = dir.path().join("store/.tmp");
+
+        let err =
+            tokio::task::block_in_place(|| builder(dir.path(), 1).build_package(&job)).unwrap_err();
+        let message = format!("{:#}", err);
+        assert!(message.contains("build phase failed with exit status: 1"));
+        assert!(message.contains(&dir.path().join("logs/ab12-hello.log").display().to_string()));
+        assert_eq!(fs::read_dir(&tmp).unwrap().count(), 0);
+
+        let builder = builder(dir.path(), 1).with_keep_failed(true);
+        let err = tokio::task::block_in_place(|| builder.build_package(&job)).unwrap_err();
+        assert!(format!("{:#}", err).contains("build directory kept at"));
+        assert_eq!(fs::read_dir(&tmp).unwrap().count(), 1);
     }
 }
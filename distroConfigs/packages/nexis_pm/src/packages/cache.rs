@@ -0,0 +1,733 @@
+//! Binary caches
+//!
+//! A binary cache (or substituter) is a directory of built package objects,
+//! read from the local filesystem (`file://`) or over HTTP(S):
+//!
+//! ```text
+//! <cache>/<store-hash>.narinfo
+//! <cache>/archives/<file-hash>.nar.zst
+//! ```
+//!
+//! Each `.narinfo` describes one store object in `Key: value` lines:
+//!
+//! ```text
+//! StorePath: c41b...-zlib
+//! URL: archives/9a0e....nar.zst
+//! Compression: zstd
+//! FileHash: 9a0e...
+//! FileSize: 48213
+//! NarHash: c41b...
+//! NarSize: 131072
+//! References: 51c2...
+//! Sig: cache.example.org-1:Rm9v...
+//! ```
+//!
+//! Archives are the object's canonical archive
+//! ([`store::hash`](crate::store::hash)) compressed with
+//! [`COMPRESSION_ALGORITHM`] at [`COMPRESSION_LEVEL`], so an object unpacks
+//! to exactly the store hash it is published under. Both hashes are checked
+//! before anything enters the store.
+//!
+//! `Sig` lines are Ed25519 signatures over the object's
+//! [`fingerprint`](crate::security::signing::fingerprint). An object is
+//! only substituted if one of them is from a key in
+//! `[cache] trusted_public_keys`, unless unsigned objects are explicitly
+//! allowed. The signatures are recorded in the store database so they can
+//! be checked again later.
+//!
+//! `nexis build` asks the `[cache] substituters` for every package whose
+//! store hash is locked before building it, and `nexis cache push` fills a
+//! local cache, signing what it pushes when given a key. The narinfo is
+//! written last, so a cache never advertises an object whose archive is
+//! incomplete.
+
+use anyhow::{bail, Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tracing::{debug, info, warn};
+
+use crate::constants::{COMPRESSION_ALGORITHM, COMPRESSION_LEVEL, DOWNLOAD_TIMEOUT_SECS};
+use crate::security::signing::{fingerprint, SecretKey, TrustedKeys};
+use crate::store::hash::{hash_file, is_store_hash, pack};
+use crate::store::{ObjectKind, Store, StoreObject};
+
+/// Metadata of one object in a binary cache
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NarInfo {
+    /// Store hash of the object
+    pub hash: String,
+    /// Package name
+    pub name: String,
+    /// Archive location, relative to the cache root
+    pub url: String,
+    /// Compression of the archive file
+    pub compression: String,
+    /// BLAKE3 hash of the compressed archive file
+    pub file_hash: String,
+    /// Size of the compressed archive file in bytes
+    pub file_size: u64,
+    /// Size of the uncompressed canonical archive in bytes
+    pub nar_size: u64,
+    /// Store hashes of the objects this one references at runtime
+    pub references: Vec<String>,
+    /// Signatures over the object's fingerprint (`<key name>:<base64>`)
+    pub signatures: Vec<String>,
+}
+
+impl NarInfo {
+    /// Parse the contents of a `.narinfo` file
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut fields = BTreeMap::new();
+        let mut signatures = Vec::new();
+        for line in text.lines().filter(|l| !l.trim().is_empty()) {
+            let Some((key, value)) = line.split_once(": ") else {
+                bail!("Malformed narinfo line `{}`", line);
+            };
+            // The only key that may repeat
+            if key == "Sig" {
+                signatures.push(value.trim().to_string());
+            } else {
+                fields.insert(key, value.trim());
+            }
+        }
+        let field = |key: &str| -> Result<&str> {
+            fields
+                .get(key)
+                .copied()
+                .with_context(|| format!("narinfo has no `{}`", key))
+        };
+        let size = |key: &str| -> Result<u64> {
+            field(key)?
+                .parse()
+                .with_context(|| format!("narinfo has an invalid `{}`", key))
+        };
+
+        let store_path = field("StorePath")?;
+        let Some((hash, name)) = store_path.split_once('-') else {
+            bail!("narinfo has an invalid `StorePath` `{}`", store_path);
+        };
+        if !is_store_hash(hash) {
+            bail!("narinfo has an invalid `StorePath` `{}`", store_path);
+        }
+        let nar_hash = field("NarHash")?;
+        if nar_hash != hash {
+            bail!(
+                "narinfo for {} describes a different object ({})",
+                hash,
+                nar_hash
+            );
+        }
+        let references: Vec<String> = fields
+            .get("References")
+            .map(|refs| refs.split_whitespace().map(String::from).collect())
+            .unwrap_or_default();
+        if let Some(bad) = references.iter().find(|r| !is_store_hash(r)) {
+            bail!("narinfo for {} references an invalid hash `{}`", hash, bad);
+        }
+        Ok(Self {
+            hash: hash.to_string(),
+            name: name.to_string(),
+            url: field("URL")?.to_string(),
+            compression: field("Compression")?.to_string(),
+            file_hash: field("FileHash")?.to_string(),
+            file_size: size("FileSize")?,
+            nar_size: size("NarSize")?,
+            references,
+            signatures,
+        })
+    }
+
+    /// What the object's signatures are made over
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.hash, &self.name, &self.references)
+    }
+}
+
+impl fmt::Display for NarInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "StorePath: {}-{}", self.hash, self.name)?;
+        writeln!(f, "URL: {}", self.url)?;
+        writeln!(f, "Compression: {}", self.compression)?;
+        writeln!(f, "FileHash: {}", self.file_hash)?;
+        writeln!(f, "FileSize: {}", self.file_size)?;
+        writeln!(f, "NarHash: {}", self.hash)?;
+        writeln!(f, "NarSize: {}", self.nar_size)?;
+        writeln!(f, "References: {}", self.references.join(" "))?;
+        for signature in &self.signatures {
+            writeln!(f, "Sig: {}", signature)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where a cache lives
+#[derive(Debug, Clone)]
+enum Location {
+    Local(PathBuf),
+    Remote(String),
+}
+
+/// A single binary cache
+#[derive(Debug, Clone)]
+pub struct BinaryCache {
+    url: String,
+    location: Location,
+    client: reqwest::Client,
+}
+
+impl BinaryCache {
+    /// Open the cache at a `file://`, `http://` or `https://` URL
+    pub fn new(url: &str) -> Result<Self> {
+        let location = if let Some(path) = url.strip_prefix("file://") {
+            Location::Local(PathBuf::from(path))
+        } else if url.starts_with("http://") || url.starts_with("https://") {
+            Location::Remote(url.trim_end_matches('/').to_string())
+        } else {
+            bail!("Unsupported binary cache URL `{}`", url);
+        };
+        // Archive files are hashed as served
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("nexis/", env!("CARGO_PKG_VERSION")))
+            .no_gzip()
+            .no_brotli()
+            .timeout(Duration::from_secs(DOWNLOAD_TIMEOUT_SECS))
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            url: url.to_string(),
+            location,
+            client,
+        })
+    }
+
+    /// URL the cache was opened from
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Look up the object with store hash `hash`
+    pub async fn query(&self, hash: &str) -> Result<Option<NarInfo>> {
+        if !is_store_hash(hash) {
+            bail!("`{}` is not a store hash", hash);
+        }
+        let name = format!("{}.narinfo", hash);
+        let text = match &self.location {
+            Location::Local(root) => match fs::read_to_string(root.join(&name)) {
+                Ok(text) => text,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e).context(format!("Failed to read {}", name)),
+            },
+            Location::Remote(base) => {
+                let url = format!("{}/{}", base, name);
+                let response = self
+                    .client
+                    .get(&url)
+                    .send()
+                    .await
+                    .with_context(|| format!("Failed to query {}", url))?;
+                match response.status() {
+                    reqwest::StatusCode::NOT_FOUND => return Ok(None),
+                    status if !status.is_success() => {
+                        bail!("Failed to query {}: HTTP {}", url, status)
+                    }
+                    _ => response.text().await?,
+                }
+            }
+        };
+        let info = NarInfo::parse(&text).with_context(|| format!("Invalid {}", name))?;
+        if info.hash != hash {
+            bail!("{} describes {}", name, info.hash);
+        }
+        Ok(Some(info))
+    }
+
+    /// Download the archive `info` describes and add it to `store`
+    pub async fn fetch(&self, info: &NarInfo, store: Arc<Store>) -> Result<StoreObject> {
+        if info.compression != COMPRESSION_ALGORITHM {
+            bail!("Unsupported archive compression `{}`", info.compression);
+        }
+        let staging = tempfile::Builder::new()
+            .prefix("substitute-")
+            .tempdir_in(store.layout().tmp_dir())
+            .context("Failed to create staging directory")?;
+        let file = match &self.location {
+            Location::Local(root) => root.join(&info.url),
+            Location::Remote(base) => {
+                let url = format!("{}/{}", base, info.url);
+                let path = staging.path().join("archive");
+                self.download(&url, &path).await?;
+                path
+            }
+        };
+
+        let info = info.clone();
+        tokio::task::spawn_blocking(move || {
+            let file_hash = hash_file(&file)?;
+            if file_hash != info.file_hash {
+                bail!(
+                    "Hash mismatch for {}: expected {}, got {}",
+                    info.url,
+                    info.file_hash,
+                    file_hash
+                );
+            }
+            let archive = fs::File::open(&file)
+                .with_context(|| format!("Failed to open {}", file.display()))?;
+            let mut decoder = zstd::Decoder::new(BufReader::new(archive))?;
+            let object = store.add_archive(&info.name, &mut decoder, &info.hash)?;
+            drop(staging);
+            Ok(object)
+        })
+        .await?
+    }
+
+    /// Add `object` and everything it references to this cache
+    ///
+    /// Only `file://` caches can be written to. Objects the cache already
+    /// has are skipped. Each narinfo carries the signatures recorded for
+    /// its object, plus one by `key` if given, which is recorded as well.
+    /// Returns the narinfo of every object pushed.
+    pub fn push(
+        &self,
+        store: &Store,
+        object: &StoreObject,
+        key: Option<&SecretKey>,
+    ) -> Result<Vec<NarInfo>> {
+        let Location::Local(root) = &self.location else {
+            bail!(
+                "Cannot push to {}; only file:// caches are writable",
+                self.url
+            );
+        };
+        fs::create_dir_all(root.join("archives"))
+            .with_context(|| format!("Failed to create {}", root.display()))?;
+
+        // References go first, so every narinfo's references are already there
+        let mut order = Vec::new();
+        let mut pending = vec![object.hash.clone()];
+        let mut seen = BTreeSet::new();
+        while let Some(hash) = pending.pop() {
+            if seen.insert(hash.clone()) {
+                pending.extend(store.database().references(&hash)?);
+                order.push(hash);
+            }
+        }
+
+        let mut pushed = Vec::new();
+        for hash in order.into_iter().rev() {
+            if root.join(format!("{}.narinfo", hash)).exists() {
+                debug!("{} already has {}", self.url, hash);
+                continue;
+            }
+            let Some(object) = store.get(&hash)? else {
+                bail!("{} is not in the store", hash);
+            };
+            let references = store.database().references(&hash)?;
+            pushed.push(push_object(store, root, &object, references, key)?);
+        }
+        Ok(pushed)
+    }
+
+    /// Download `url` into `dest`
+    async fn download(&self, url: &str, dest: &Path) -> Result<()> {
+        let mut response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .with_context(|| format!("Failed to download {}", url))?;
+        if !response.status().is_success() {
+            bail!("Failed to download {}: HTTP {}", url, response.status());
+        }
+        let mut file = tokio::fs::File::create(dest).await?;
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("Failed to download {}", url))?
+        {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+/// Write the archive and narinfo of `object` into the cache at `root`
+fn push_object(
+    store: &Store,
+    root: &Path,
+    object: &StoreObject,
+    references: Vec<String>,
+    key: Option<&SecretKey>,
+) -> Result<NarInfo> {
+    let (ObjectKind::Package, Some(name)) = (object.kind, &object.name) else {
+        bail!("{} is not a package object", object.hash);
+    };
+    let archives = root.join("archives");
+    let mut staged = tempfile::NamedTempFile::new_in(&archives)
+        .with_context(|| format!("Failed to create a file in {}", archives.display()))?;
+    let mut counter = CountingWriter {
+        inner: zstd::Encoder::new(staged.as_file_mut(), COMPRESSION_LEVEL)?,
+        count: 0,
+    };
+    pack(&object.path, &mut counter)?;
+    let nar_size = counter.count;
+    counter.inner.finish()?.flush()?;
+
+    let file_hash = hash_file(staged.path())?;
+    let url = format!("archives/{}.nar.zst", file_hash);
+    let file_size = staged.as_file().metadata()?.len();
+    staged
+        .persist(root.join(&url))
+        .with_context(|| format!("Failed to write {}", url))?;
+
+    let mut info = NarInfo {
+        hash: object.hash.clone(),
+        name: name.clone(),
+        url,
+        compression: COMPRESSION_ALGORITHM.to_string(),
+        file_hash,
+        file_size,
+        nar_size,
+        references,
+        signatures: store.database().signatures(&object.hash)?,
+    };
+    if let Some(key) = key {
+        let signature = key.sign(&info.fingerprint());
+        store
+            .database()
+            .transaction(|txn| txn.add_signatures(&info.hash, &[&signature]))?;
+        if !info.signatures.contains(&signature) {
+            info.signatures.push(signature);
+        }
+    }
+    let mut narinfo = tempfile::NamedTempFile::new_in(root)?;
+    narinfo.write_all(info.to_string().as_bytes())?;
+    narinfo
+        .persist(root.join(format!("{}.narinfo", info.hash)))
+        .with_context(|| format!("Failed to write the narinfo of {}", info.hash))?;
+    Ok(info)
+}
+
+/// Counts the bytes written through it
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The configured binary caches, tried in order
+#[derive(Debug, Clone, Default)]
+pub struct Substituters {
+    caches: Vec<BinaryCache>,
+    trusted: TrustedKeys,
+    allow_unsigned: bool,
+}
+
+impl Substituters {
+    /// Open a cache for each of `urls`
+    ///
+    /// No key is trusted until [`with_trusted_keys`](Self::with_trusted_keys)
+    /// is called, so nothing is substituted from a cache by default.
+    pub fn new(urls: &[String]) -> Result<Self> {
+        let caches = urls
+            .iter()
+            .map(|url| BinaryCache::new(url))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            caches,
+            trusted: TrustedKeys::default(),
+            allow_unsigned: false,
+        })
+    }
+
+    /// Accept objects signed by any of `trusted`
+    pub fn with_trusted_keys(mut self, trusted: TrustedKeys) -> Self {
+        self.trusted = trusted;
+        self
+    }
+
+    /// Also substitute objects no trusted key has signed
+    pub fn with_allow_unsigned(mut self, allow_unsigned: bool) -> Self {
+        self.allow_unsigned = allow_unsigned;
+        self
+    }
+
+    /// Make the object with store hash `hash` available in `store`
+    ///
+    /// An object already in the store is used as-is. Otherwise the first
+    /// cache that has it provides it, after everything it references. A
+    /// cache that cannot be reached, serves a bad archive or has no trusted
+    /// signature for the object is skipped with a warning. Returns `None` if
+    /// no cache has the object, and fails if its references lead back to it.
+    pub async fn substitute(&self, store: &Arc<Store>, hash: &str) -> Result<Option<StoreObject>> {
+        self.substitute_within(store, hash, &mut Vec::new()).await
+    }
+
+    /// [`Self::substitute`], with `pending` holding the objects whose
+    /// references are being substituted, outermost first
+    fn substitute_within<'a>(
+        &'a self,
+        store: &'a Arc<Store>,
+        hash: &'a str,
+        pending: &'a mut Vec<String>,
+    ) -> BoxFuture<'a, Result<Option<StoreObject>>> {
+        async move {
+            if let Some(object) = store.get(hash)? {
+                return Ok(Some(object));
+            }
+            if let Some(pos) = pending.iter().position(|p| p == hash) {
+                let mut chain = pending[pos..].to_vec();
+                chain.push(hash.to_string());
+                return Err(ReferenceCycle(chain).into());
+            }
+
+            pending.push(hash.to_string());
+            let mut found = None;
+            for cache in &self.caches {
+                match self.substitute_from(cache, store, hash, pending).await {
+                    Ok(Some(object)) => {
+                        found = Some(object);
+                        break;
+                    }
+                    Ok(None) => {}
+                    Err(e) if e.is::<ReferenceCycle>() => return Err(e),
+                    Err(e) => warn!("Skipping {} for {}: {:#}", cache.url(), hash, e),
+                }
+            }
+            pending.pop();
+            Ok(found)
+        }
+        .boxed()
+    }
+
+    async fn substitute_from(
+        &self,
+        cache: &BinaryCache,
+        store: &Arc<Store>,
+        hash: &str,
+        pending: &mut Vec<String>,
+    ) -> Result<Option<StoreObject>> {
+        let Some(info) = cache.query(hash).await? else {
+            return Ok(None);
+        };
+        match self
+            .trusted
+            .signed_by(&info.fingerprint(), &info.signatures)
+        {
+            Some(key) => debug!("{} is signed by {}", hash, key),
+            None if self.allow_unsigned => {
+                warn!("{} is not signed by a trusted key; allowed", hash)
+            }
+            None => bail!("{} is not signed by a trusted key", hash),
+        }
+        for reference in info.references.iter().filter(|r| *r != hash) {
+            if self
+                .substitute_within(store, reference, pending)
+                .await?
+                .is_none()
+            {
+                bail!("No cache has {}, which {} references", reference, hash);
+            }
+        }
+
+        info!("Substituting {}-{} from {}", hash, info.name, cache.url());
+        let object = cache.fetch(&info, store.clone()).await?;
+        let references: Vec<&str> = info.references.iter().map(String::as_str).collect();
+        let signatures: Vec<&str> = info.signatures.iter().map(String::as_str).collect();
+        store.database().transaction(|txn| {
+            txn.add_references(&object.hash, &references)?;
+            txn.add_signatures(&object.hash, &signatures)
+        })?;
+        Ok(Some(object))
+    }
+}
+
+/// Objects whose references lead back to themselves, first object repeated
+/// last
+#[derive(Debug)]
+struct ReferenceCycle(Vec<String>);
+
+impl fmt::Display for ReferenceCycle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Reference cycle between cached objects: {}",
+            self.0.join(" -> ")
+        )
+    }
+}
+
+impl std::error::Error for ReferenceCycle {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(store: &Store, dir: &Path, name: &str, contents: &str) -> StoreObject {
+        let src = dir.join(name);
+        fs::create_dir_all(src.join("bin")).unwrap();
+        fs::write(src.join("bin").join(name), contents).unwrap();
+        store.add_tree(name, &src).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_push_and_substitute() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = Store::open(dir.path().join("builder")).unwrap();
+        let zlib = package(&builder, dir.path(), "zlib", "zlib");
+        let curl = package(&builder, dir.path(), "curl", "curl");
+        builder
+            .database()
+            .transaction(|txn| txn.add_references(&curl.hash, &[&zlib.hash]))
+            .unwrap();
+
+        let url = format!("file://{}", dir.path().join("cache").display());
+        let cache = BinaryCache::new(&url).unwrap();
+        let key = SecretKey::generate("cache-1").unwrap();
+        let pushed = cache.push(&builder, &curl, Some(&key)).unwrap();
+        let hashes: Vec<_> = pushed.iter().map(|info| info.hash.as_str()).collect();
+        assert_eq!(hashes, [zlib.hash.as_str(), curl.hash.as_str()]);
+        assert!(cache.push(&builder, &curl, Some(&key)).unwrap().is_empty());
+        assert_eq!(
+            builder.database().signatures(&curl.hash).unwrap(),
+            pushed[1].signatures
+        );
+
+        let info = cache.query(&curl.hash).await.unwrap().unwrap();
+        assert_eq!(info, pushed[1]);
+        assert_eq!(info.references, [zlib.hash.as_str()]);
+        assert_eq!(NarInfo::parse(&info.to_string()).unwrap(), info);
+        let traversal = info.to_string().replace(&zlib.hash, "../../etc");
+        assert!(NarInfo::parse(&traversal).is_err());
+        assert!(cache.query(&"ab".repeat(32)).await.unwrap().is_none());
+        assert!(cache.query("../zlib").await.is_err());
+
+        let machine = Arc::new(Store::open(dir.path().join("machine")).unwrap());
+        let trusted = TrustedKeys::new(&[key.public_key().to_string()]).unwrap();
+        let substituters = Substituters::new(&[url])
+            .unwrap()
+            .with_trusted_keys(trusted);
+        let object = substituters
+            .substitute(&machine, &curl.hash)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(object.hash, curl.hash);
+        assert_eq!(
+            fs::read_to_string(object.path.join("bin/curl")).unwrap(),
+            "curl"
+        );
+        assert!(machine.exists(&zlib.hash).unwrap());
+        assert_eq!(
+            machine.database().references(&curl.hash).unwrap(),
+            [zlib.hash.as_str()]
+        );
+        assert_eq!(
+            machine.database().signatures(&curl.hash).unwrap(),
+            pushed[1].signatures
+        );
+    }
+
+    #[tokio::test]
+    async fn test_untrusted_objects_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = Store::open(dir.path().join("builder")).unwrap();
+        let zlib = package(&builder, dir.path(), "zlib", "zlib");
+        let url = format!("file://{}", dir.path().join("cache").display());
+        let stranger = SecretKey::generate("stranger").unwrap();
+        BinaryCache::new(&url)
+            .unwrap()
+            .push(&builder, &zlib, Some(&stranger))
+            .unwrap();
+
+        let machine = Arc::new(Store::open(dir.path().join("machine")).unwrap());
+        let trusted = SecretKey::generate("cache-1").unwrap().public_key();
+        let substituters = Substituters::new(&[url])
+            .unwrap()
+            .with_trusted_keys(TrustedKeys::new(&[trusted.to_string()]).unwrap());
+        let object = substituters.substitute(&machine, &zlib.hash).await.unwrap();
+        assert_eq!(object, None);
+        assert!(!machine.exists(&zlib.hash).unwrap());
+
+        let substituters = substituters.with_allow_unsigned(true);
+        let object = substituters.substitute(&machine, &zlib.hash).await.unwrap();
+        assert_eq!(object.unwrap().hash, zlib.hash);
+    }
+
+    #[tokio::test]
+    async fn test_corrupt_archives_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = Store::open(dir.path().join("builder")).unwrap();
+        let zlib = package(&builder, dir.path(), "zlib", "zlib");
+        let root = dir.path().join("cache");
+        let url = format!("file://{}", root.display());
+        let info = BinaryCache::new(&url)
+            .unwrap()
+            .push(&builder, &zlib, None)
+            .unwrap();
+
+        fs::write(root.join(&info[0].url), "corrupt").unwrap();
+        let machine = Arc::new(Store::open(dir.path().join("machine")).unwrap());
+        let substituters = Substituters::new(&[url]).unwrap().with_allow_unsigned(true);
+        let object = substituters.substitute(&machine, &zlib.hash).await.unwrap();
+        assert_eq!(object, None);
+        assert!(!machine.exists(&zlib.hash).unwrap());
+        assert_eq!(fs::read_dir(machine.layout().tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_reference_cycles_are_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let builder = Store::open(dir.path().join("builder")).unwrap();
+        let zlib = package(&builder, dir.path(), "zlib", "zlib");
+        let curl = package(&builder, dir.path(), "curl", "curl");
+        builder
+            .database()
+            .transaction(|txn| txn.add_references(&curl.hash, &[&zlib.hash]))
+            .unwrap();
+        let root = dir.path().join("cache");
+        let url = format!("file://{}", root.display());
+        BinaryCache::new(&url)
+            .unwrap()
+            .push(&builder, &curl, None)
+            .unwrap();
+
+        // Make zlib reference curl back
+        let narinfo = root.join(format!("{}.narinfo", zlib.hash));
+        let mut info = NarInfo::parse(&fs::read_to_string(&narinfo).unwrap()).unwrap();
+        info.references.push(curl.hash.clone());
+        fs::write(&narinfo, info.to_string()).unwrap();
+
+        let machine = Arc::new(Store::open(dir.path().join("machine")).unwrap());
+        let substituters = Substituters::new(&[url]).unwrap().with_allow_unsigned(true);
+        let err = substituters
+            .substitute(&machine, &curl.hash)
+            .await
+            .unwrap_err()
+            .to_string();
+        let cycle = format!("{} -> {} -> {}", curl.hash, zlib.hash, curl.hash);
+        assert!(err.contains(&cycle), "{}", err);
+        assert!(!machine.exists(&zlib.hash).unwrap());
+        assert!(!machine.exists(&curl.hash).unwrap());
+    }
+}
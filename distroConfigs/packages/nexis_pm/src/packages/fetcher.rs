@@ -0,0 +1,858 @@
+//! Source and artifact fetching
+//!
+//! Fetches what a package is built or installed from:
+//! - git repositories, checked out at the locked commit
+//! - `.tar.gz`, `.tar.xz` and `.tar.zst` archives over HTTP(S) or `file://`
+//! - local directories, used in place
+//!
+//! Everything fetched lands in a content-addressed cache
+//! ([`StoreLayout::downloads_dir`](crate::store::StoreLayout::downloads_dir)):
+//!
+//! ```text
+//! <downloads>/archives/<blake3>          verified archive
+//! <downloads>/unpacked/<blake3>/         its unpacked tree
+//! <downloads>/partial/<url-blake3>.part  interrupted download
+//! <downloads>/git/<url-blake3>/          bare mirror of a git source
+//! <downloads>/checkouts/<commit>/        tree of a locked commit
+//! ```
+//!
+//! An archive whose expected hash is already cached is not downloaded again,
+//! and an interrupted HTTP download resumes with a `Range` request. Fetches
+//! of the same URL take turns, so they never write to one partial file or
+//! git mirror at once. At most [`MAX_PARALLEL_DOWNLOADS`] fetches run at
+//! once, each bounded by [`DOWNLOAD_TIMEOUT_SECS`]; a git fetch that runs
+//! out of time is interrupted, and keeps its slot until it has stopped.
+
+use anyhow::{bail, Context, Result};
+use rustix::fs::{renameat_with, RenameFlags, CWD};
+use rustix::io::Errno;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tracing::{debug, info};
+
+use crate::config::lockfile::LockedPackage;
+use crate::config::Package;
+use crate::constants::{DOWNLOAD_TIMEOUT_SECS, MAX_PARALLEL_DOWNLOADS};
+use crate::store::hash::{hash_bytes, hash_file, hash_path};
+use crate::vcs::git;
+use crate::vcs::tags::parse_tag;
+
+/// Values substituted into `source` and `prebuilt` URL templates
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TemplateVars {
+    /// `{name}`: package name
+    pub name: String,
+    /// `{version}`: resolved version without tag decoration (`9.1.0`)
+    pub version: String,
+    /// `{tag}`: resolved tag, branch or commit (`v9.1.0`)
+    pub tag: String,
+    /// `{arch}`: machine architecture (`x86_64`, `aarch64`)
+    pub arch: String,
+}
+
+impl TemplateVars {
+    /// Values for a locked package on this machine
+    pub fn for_locked(locked: &LockedPackage) -> Self {
+        let version = parse_tag(&locked.version, &locked.name)
+            .map(|v| v.to_string())
+            .unwrap_or_else(|| locked.version.clone());
+        Self {
+            name: locked.name.clone(),
+            version,
+            tag: locked.version.clone(),
+            arch: std::env::consts::ARCH.to_string(),
+        }
+    }
+}
+
+/// Expand `{name}`, `{version}`, `{tag}` and `{arch}` in `template`
+///
+/// Any other placeholder is an error rather than being left in the URL.
+pub fn expand_template(template: &str, vars: &TemplateVars) -> Result<String> {
+    let mut expanded = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let Some(len) = rest[start..].find('}') else {
+            bail!("Unclosed placeholder in `{}`", template);
+        };
+        let value = match &rest[start + 1..start + len] {
+            "name" => &vars.name,
+            "version" => &vars.version,
+            "tag" => &vars.tag,
+            "arch" => &vars.arch,
+            other => bail!("Unknown placeholder `{{{}}}` in `{}`", other, template),
+        };
+        expanded.push_str(value);
+        rest = &rest[start + len + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+/// Compression of a tarball
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compression {
+    /// `.tar.gz`, `.tgz`
+    Gzip,
+    /// `.tar.xz`, `.txz`
+    Xz,
+    /// `.tar.zst`, `.tzst`
+    Zstd,
+}
+
+impl Compression {
+    /// Detect the compression from an archive URL's extension
+    pub fn from_url(url: &str) -> Option<Self> {
+        let path = url.split(['?', '#']).next().unwrap_or(url);
+        let path = path.to_ascii_lowercase();
+        if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+            Some(Self::Gzip)
+        } else if path.ends_with(".tar.xz") || path.ends_with(".txz") {
+            Some(Self::Xz)
+        } else if path.ends_with(".tar.zst") || path.ends_with(".tzst") {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+}
+
+/// How a URL is fetched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceKind {
+    /// A git repository
+    Git,
+    /// A compressed tarball
+    Archive(Compression),
+    /// A local directory
+    Directory,
+}
+
+impl SourceKind {
+    /// Classify a URL
+    ///
+    /// Tarball extensions win; otherwise remote URLs and paths ending in
+    /// `.git` are repositories, and anything else is a local directory.
+    pub fn detect(url: &str) -> Self {
+        if let Some(compression) = Compression::from_url(url) {
+            return Self::Archive(compression);
+        }
+        let remote = ["http://", "https://", "git://", "ssh://", "git@"]
+            .iter()
+            .any(|scheme| url.starts_with(scheme));
+        if remote || url.trim_end_matches('/').ends_with(".git") {
+            Self::Git
+        } else {
+            Self::Directory
+        }
+    }
+}
+
+/// Something to fetch
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FetchRequest {
+    /// Package the fetch is for
+    pub name: String,
+    /// Expanded URL or path
+    pub url: String,
+    /// Commit to check out, for git sources
+    pub rev: Option<String>,
+    /// BLAKE3 hash the archive or tree must have
+    pub expected_hash: Option<String>,
+}
+
+impl FetchRequest {
+    /// Fetch a package's `source` as pinned by its lock entry
+    pub fn source(package: &Package, locked: &LockedPackage) -> Result<Self> {
+        let Some(source) = &package.source else {
+            bail!("Package `{}` has no source", package.name);
+        };
+        Ok(Self {
+            name: package.name.clone(),
+            url: expand_template(source, &TemplateVars::for_locked(locked))?,
+            rev: locked.rev.clone(),
+            expected_hash: locked.source_hash.clone(),
+        })
+    }
+
+    /// Fetch a package's `prebuilt` artifact for the locked version, if it has one
+    ///
+    /// The artifact must be a tarball. Its expected hash is the one locked
+    /// for this machine's architecture.
+    pub fn prebuilt(package: &Package, locked: &LockedPackage) -> Result<Option<Self>> {
+        let Some(prebuilt) = &package.prebuilt else {
+            return Ok(None);
+        };
+        let vars = TemplateVars::for_locked(locked);
+        let url = expand_template(prebuilt, &vars)?;
+        if Compression::from_url(&url).is_none() {
+            bail!(
+                "Prebuilt artifact {} of `{}` is not a .tar.gz, .tar.xz or .tar.zst archive",
+                url,
+                package.name
+            );
+        }
+        Ok(Some(Self {
+            name: package.name.clone(),
+            url,
+            rev: None,
+            expected_hash: locked.prebuilt_hashes.get(&vars.arch).cloned(),
+        }))
+    }
+}
+
+/// A fetched source tree
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fetched {
+    /// Package the fetch was for
+    pub name: String,
+    /// URL it was fetched from
+    pub url: String,
+    /// Root of the fetched tree
+    ///
+    /// For archives with a single top-level directory, that directory.
+    pub path: PathBuf,
+    /// BLAKE3 hash of the archive, or of the tree for git and local sources
+    pub hash: String,
+}
+
+/// Fetches sources into the download cache
+#[derive(Debug, Clone)]
+pub struct Fetcher {
+    root: PathBuf,
+    client: reqwest::Client,
+    permits: Arc<Semaphore>,
+    /// One lock per URL, held while its partial file or git mirror is in use
+    downloads: Arc<Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>>,
+    timeout: Duration,
+}
+
+impl Fetcher {
+    /// Create a fetcher caching into `root`
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        // Archives are hashed as served, so transparent decompression must stay off
+        let client = reqwest::Client::builder()
+            .user_agent(concat!("nexis/", env!("CARGO_PKG_VERSION")))
+            .no_gzip()
+            .no_brotli()
+            .build()
+            .context("Failed to create HTTP client")?;
+        Ok(Self {
+            root: root.into(),
+            client,
+            permits: Arc::new(Semaphore::new(MAX_PARALLEL_DOWNLOADS)),
+            downloads: Arc::default(),
+            timeout: Duration::from_secs(DOWNLOAD_TIMEOUT_SECS),
+        })
+    }
+
+    /// Limit each download to `timeout` instead of [`DOWNLOAD_TIMEOUT_SECS`]
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Download cache root
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Fetch `request`, verifying its expected hash
+    pub async fn fetch(&self, request: &FetchRequest) -> Result<Fetched> {
+        let _permit = self.permits.acquire().await?;
+        let (path, hash) = match SourceKind::detect(&request.url) {
+            SourceKind::Archive(compression) => self.fetch_archive(request, compression).await?,
+            SourceKind::Git => self.fetch_git(request).await?,
+            SourceKind::Directory => self.fetch_directory(request).await?,
+        };
+        Ok(Fetched {
+            name: request.name.clone(),
+            url: request.url.clone(),
+            path,
+            hash,
+        })
+    }
+
+    /// Fetch every request concurrently, at most [`MAX_PARALLEL_DOWNLOADS`] at a time
+    pub async fn fetch_all(&self, requests: &[FetchRequest]) -> Vec<Result<Fetched>> {
+        futures::future::join_all(requests.iter().map(|request| async move {
+            self.fetch(request)
+                .await
+                .with_context(|| format!("Failed to fetch `{}` from {}", request.name, request.url))
+        }))
+        .await
+    }
+
+    async fn fetch_archive(
+        &self,
+        request: &FetchRequest,
+        compression: Compression,
+    ) -> Result<(PathBuf, String)> {
+        let lock = self.url_lock(&request.url);
+        let _download = lock.lock().await;
+
+        let cached = request
+            .expected_hash
+            .as_ref()
+            .map(|hash| self.archive_path(hash))
+            .filter(|path| path.exists());
+        let hash = match cached {
+            Some(_) => {
+                debug!("Using cached archive of {}", request.url);
+                request.expected_hash.clone().unwrap_or_default()
+            }
+            None => {
+                let partial = tokio::time::timeout(self.timeout, self.download(&request.url))
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Timed out after {}s downloading {}",
+                            self.timeout.as_secs(),
+                            request.url
+                        )
+                    })??;
+                let (partial, hash) = blocking(move || {
+                    let hash = hash_file(&partial)?;
+                    Ok((partial, hash))
+                })
+                .await?;
+                if let Err(e) = verify(request, &hash) {
+                    fs::remove_file(&partial)?;
+                    return Err(e);
+                }
+                let archive = self.archive_path(&hash);
+                fs::create_dir_all(self.root.join("archives"))?;
+                fs::rename(&partial, &archive).with_context(|| {
+                    format!("Failed to move download into {}", archive.display())
+                })?;
+                hash
+            }
+        };
+
+        let archive = self.archive_path(&hash);
+        let dest = self.root.join("unpacked").join(&hash);
+        let staging = self.staging_dir()?;
+        let tree = blocking(move || {
+            if !dest.exists() {
+                let staged = staging.path().join("tree");
+                unpack_archive(&archive, compression, &staged)?;
+                install(&staged, &dest)?;
+            }
+            source_root(&dest)
+        })
+        .await?;
+        Ok((tree, hash))
+    }
+
+    async fn fetch_git(&self, request: &FetchRequest) -> Result<(PathBuf, String)> {
+        let Some(rev) = request.rev.clone() else {
+            bail!(
+                "`{}` has no locked revision; run `nexis resolve-versions`",
+                request.name
+            );
+        };
+        let lock = self.url_lock(&request.url);
+        let _fetch = lock.lock().await;
+
+        let url = request.url.clone();
+        let mirror = self.root.join("git").join(hash_bytes(url.as_bytes()));
+        let dest = self.root.join("checkouts").join(&rev);
+        let staging = self.staging_dir()?;
+
+        let checkout = dest.clone();
+        let interrupt = Arc::new(AtomicBool::new(false));
+        let interrupted = interrupt.clone();
+        let mut fetch = std::pin::pin!(blocking(move || {
+            if !dest.exists() {
+                info!("Checking out {} at {}", url, rev);
+                let staged = staging.path().join("tree");
+                git::checkout(&url, &rev, &mirror, &staged, &interrupted)?;
+                install(&staged, &dest)?;
+            }
+            hash_path(&dest)
+        }));
+        let hash = match tokio::time::timeout(self.timeout, &mut fetch).await {
+            Ok(hash) => hash?,
+            Err(_) => {
+                // Dropping the task would leave it running; wait for it to stop
+                interrupt.store(true, Ordering::Relaxed);
+                let _ = fetch.await;
+                bail!(
+                    "Timed out after {}s fetching {}",
+                    self.timeout.as_secs(),
+                    request.url
+                );
+            }
+        };
+        verify(request, &hash)?;
+        Ok((checkout, hash))
+    }
+
+    async fn fetch_directory(&self, request: &FetchRequest) -> Result<(PathBuf, String)> {
+        let path = local_path(&request.url);
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("Source directory {} not found", path.display()))?;
+        let hashed = path.clone();
+        let hash = blocking(move || hash_path(&hashed)).await?;
+        verify(request, &hash)?;
+        Ok((path, hash))
+    }
+
+    /// Download `url` into its partial file, resuming where an earlier attempt stopped
+    async fn download(&self, url: &str) -> Result<PathBuf> {
+        let partial_dir = self.root.join("partial");
+        tokio::fs::create_dir_all(&partial_dir).await?;
+        let partial = partial_dir.join(format!("{}.part", hash_bytes(url.as_bytes())));
+
+        if !url.starts_with("http://") && !url.starts_with("https://") {
+            let path = local_path(url);
+            tokio::fs::copy(path, &partial)
+                .await
+                .with_context(|| format!("Failed to copy {}", path.display()))?;
+            return Ok(partial);
+        }
+
+        let offset = match tokio::fs::metadata(&partial).await {
+            Ok(metadata) => metadata.len(),
+            Err(_) => 0,
+        };
+        let mut get = self.client.get(url);
+        if offset > 0 {
+            debug!("Resuming {} at byte {}", url, offset);
+            get = get.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+        }
+        let mut response = get
+            .send()
+            .await
+            .with_context(|| format!("Failed to download {}", url))?;
+
+        let mut file = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => {
+                tokio::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&partial)
+                    .await?
+            }
+            // The partial file already holds everything; the hash check decides
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => return Ok(partial),
+            status if status.is_success() => tokio::fs::File::create(&partial).await?,
+            status => bail!("Failed to download {}: HTTP {}", url, status),
+        };
+        info!("Downloading {}", url);
+        while let Some(chunk) = response
+            .chunk()
+            .await
+            .with_context(|| format!("Failed to download {}", url))?
+        {
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(partial)
+    }
+
+    /// The lock fetches of `url` take turns on
+    fn url_lock(&self, url: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.downloads
+            .lock()
+            .expect("download locks poisoned")
+            .entry(url.to_string())
+            .or_default()
+            .clone()
+    }
+
+    fn archive_path(&self, hash: &str) -> PathBuf {
+        self.root.join("archives").join(hash)
+    }
+
+    fn staging_dir(&self) -> Result<tempfile::TempDir> {
+        fs::create_dir_all(&self.root)
+            .with_context(|| format!("Failed to create {}", self.root.display()))?;
+        tempfile::Builder::new()
+            .prefix(".fetch-")
+            .tempdir_in(&self.root)
+            .context("Failed to create staging directory")
+    }
+}
+
+/// Unpack a compressed tarball into `dest`
+///
+/// Entries escaping `dest` through `..` or absolute paths are rejected by
+/// the tar reader.
+pub fn unpack_archive(archive: &Path, compression: Compression, dest: &Path) -> Result<()> {
+    let file =
+        fs::File::open(archive).with_context(|| format!("Failed to open {}", archive.display()))?;
+    let reader: Box<dyn Read> = match compression {
+        Compression::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+        Compression::Xz => Box::new(liblzma::read::XzDecoder::new(file)),
+        Compression::Zstd => Box::new(zstd::Decoder::new(file)?),
+    };
+    fs::create_dir_all(dest)?;
+    let mut tar = tar::Archive::new(reader);
+    tar.set_preserve_permissions(true);
+    tar.unpack(dest)
+        .with_context(|| format!("Failed to unpack {}", archive.display()))
+}
+
+/// Fail if `actual` is not the hash `request` expects
+fn verify(request: &FetchRequest, actual: &str) -> Result<()> {
+    match &request.expected_hash {
+        Some(expected) if expected != actual => bail!(
+            "Hash mismatch for `{}` from {}: expected {}, got {}",
+            request.name,
+            request.url,
+            expected,
+            actual
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Rename a staged tree into place; losing a race to another fetch is fine
+fn install(staged: &Path, dest: &Path) -> Result<()> {
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    match renameat_with(CWD, staged, CWD, dest, RenameFlags::NOREPLACE) {
+        Ok(()) | Err(Errno::EXIST) => Ok(()),
+        Err(e) => Err(std::io::Error::from(e))
+            .with_context(|| format!("Failed to move fetched tree into {}", dest.display())),
+    }
+}
+
+/// The directory a tarball's contents actually live in
+///
+/// Release tarballs usually wrap everything in a `name-version/` directory.
+/// A lone `bin/`, `usr/` and the like is part of a prebuilt's layout, not a
+/// wrapper.
+fn source_root(dir: &Path) -> Result<PathBuf> {
+    const LAYOUT_DIRS: &[&str] = &[
+        "bin", "etc", "include", "lib", "lib64", "sbin", "share", "usr",
+    ];
+
+    let entries = fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    match entries.as_slice() {
+        [entry]
+            if entry.file_type()?.is_dir()
+                && !LAYOUT_DIRS.iter().any(|d| entry.file_name() == *d) =>
+        {
+            Ok(entry.path())
+        }
+        _ => Ok(dir.to_path_buf()),
+    }
+}
+
+fn local_path(url: &str) -> &Path {
+    Path::new(url.strip_prefix("file://").unwrap_or(url))
+}
+
+/// Run blocking filesystem or git work off the async runtime
+async fn blocking<T: Send + 'static>(f: impl FnOnce() -> Result<T> + Send + 'static) -> Result<T> {
+    tokio::task::spawn_blocking(f).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::Command;
+
+    fn vars() -> TemplateVars {
+        TemplateVars {
+            name: "vim".into(),
+            version: "9.1.0".into(),
+            tag: "v9.1.0".into(),
+            arch: "x86_64".into(),
+        }
+    }
+
+    /// A tarball of `vim-9.1.0/{README,bin/vim}`
+    fn tarball(dir: &Path, compression: Compression) -> PathBuf {
+        let mut tar = tar::Builder::new(Vec::new());
+        for (path, contents, mode) in [
+            ("vim-9.1.0/README", &b"readme"[..], 0o644),
+            ("vim-9.1.0/bin/vim", &b"#!/bin/sh\n"[..], 0o755),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(mode);
+            header.set_cksum();
+            tar.append_data(&mut header, path, contents).unwrap();
+        }
+        let tar = tar.into_inner().unwrap();
+
+        let (name, bytes) = match compression {
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&tar).unwrap();
+                ("vim.tar.gz", encoder.finish().unwrap())
+            }
+            Compression::Xz => {
+                let mut encoder = liblzma::write::XzEncoder::new(Vec::new(), 6);
+                encoder.write_all(&tar).unwrap();
+                ("vim.tar.xz", encoder.finish().unwrap())
+            }
+            Compression::Zstd => ("vim.tar.zst", zstd::encode_all(&tar[..], 3).unwrap()),
+        };
+        let path = dir.join(name);
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn request(url: String, expected_hash: Option<String>) -> FetchRequest {
+        FetchRequest {
+            name: "vim".into(),
+            url,
+            rev: None,
+            expected_hash,
+        }
+    }
+
+    /// Serve `body` to a single request, honoring `Range`, and return the request head
+    fn serve(body: Vec<u8>) -> (String, std::thread::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/vim.tar.gz", listener.local_addr().unwrap());
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = String::new();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                head.push_str(&line);
+            }
+
+            let offset = head
+                .lines()
+                .find_map(|l| {
+                    l.to_ascii_lowercase()
+                        .strip_prefix("range: bytes=")
+                        .map(String::from)
+                })
+                .map(|r| r.trim_end_matches('-').parse::<usize>().unwrap());
+            let (status, part) = match offset {
+                Some(offset) => ("206 Partial Content", &body[offset..]),
+                None => ("200 OK", &body[..]),
+            };
+            write!(
+                stream,
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                status,
+                part.len()
+            )
+            .unwrap();
+            stream.write_all(part).unwrap();
+            head
+        });
+        (url, handle)
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let url =
+            "https://github.com/vim/vim/releases/download/{tag}/vim-{tag}-linux-{arch}.tar.gz";
+        assert_eq!(
+            expand_template(url, &vars()).unwrap(),
+            "https://github.com/vim/vim/releases/download/v9.1.0/vim-v9.1.0-linux-x86_64.tar.gz"
+        );
+        assert_eq!(
+            expand_template("{name}-{version}.tar.xz", &vars()).unwrap(),
+            "vim-9.1.0.tar.xz"
+        );
+        assert!(expand_template("{os}.tar.gz", &vars()).is_err());
+        assert!(expand_template("{tag.tar.gz", &vars()).is_err());
+    }
+
+    #[test]
+    fn test_detect_kind() {
+        assert_eq!(
+            SourceKind::detect("https://example.org/a-1.0.tar.zst?download=1"),
+            SourceKind::Archive(Compression::Zstd)
+        );
+        assert_eq!(
+            SourceKind::detect("file:///srv/a.tgz"),
+            SourceKind::Archive(Compression::Gzip)
+        );
+        assert_eq!(
+            SourceKind::detect("https://github.com/vim/vim.git"),
+            SourceKind::Git
+        );
+        assert_eq!(SourceKind::detect("/srv/mirror/vim.git"), SourceKind::Git);
+        assert_eq!(
+            SourceKind::detect("/home/me/src/tool"),
+            SourceKind::Directory
+        );
+    }
+
+    #[tokio::test]
+    async fn test_fetch_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(dir.path().join("downloads")).unwrap();
+
+        for compression in [Compression::Gzip, Compression::Xz, Compression::Zstd] {
+            let archive = tarball(dir.path(), compression);
+            let url = format!("file://{}", archive.display());
+            let fetched = fetcher.fetch(&request(url, None)).await.unwrap();
+
+            assert_eq!(fetched.hash, hash_file(&archive).unwrap());
+            assert!(fetched.path.ends_with("vim-9.1.0"));
+            assert_eq!(fs::read(fetched.path.join("README")).unwrap(), b"readme");
+            let mode = fs::metadata(fetched.path.join("bin/vim"))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o111, 0o111);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(dir.path().join("downloads")).unwrap();
+        let archive = tarball(dir.path(), Compression::Gzip);
+        let url = format!("file://{}", archive.display());
+
+        let err = fetcher
+            .fetch(&request(url, Some("0".repeat(64))))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Hash mismatch"));
+        assert!(!dir.path().join("downloads/archives").exists());
+    }
+
+    #[tokio::test]
+    async fn test_cached_archives_are_not_downloaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(dir.path().join("downloads")).unwrap();
+        let archive = tarball(dir.path(), Compression::Gzip);
+        let hash = hash_file(&archive).unwrap();
+
+        let url = format!("file://{}", archive.display());
+        fetcher
+            .fetch(&request(url.clone(), Some(hash.clone())))
+            .await
+            .unwrap();
+        fs::remove_file(&archive).unwrap();
+        let fetched = fetcher
+            .fetch(&request(url, Some(hash.clone())))
+            .await
+            .unwrap();
+        assert_eq!(fetched.hash, hash);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrent_fetches_of_one_url() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(dir.path().join("downloads")).unwrap();
+        let archive = tarball(dir.path(), Compression::Gzip);
+        let url = format!("file://{}", archive.display());
+
+        let requests = vec![request(url, None); 4];
+        for fetched in fetcher.fetch_all(&requests).await {
+            assert_eq!(fetched.unwrap().hash, hash_file(&archive).unwrap());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resume_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(dir.path().join("downloads")).unwrap();
+        let body = fs::read(tarball(dir.path(), Compression::Gzip)).unwrap();
+        let (url, server) = serve(body.clone());
+
+        // An earlier attempt got half the file
+        let partial = dir.path().join("downloads/partial");
+        fs::create_dir_all(&partial).unwrap();
+        let half = body.len() / 2;
+        fs::write(
+            partial.join(format!("{}.part", hash_bytes(url.as_bytes()))),
+            &body[..half],
+        )
+        .unwrap();
+
+        let fetched = fetcher
+            .fetch(&request(url, Some(hash_bytes(&body))))
+            .await
+            .unwrap();
+        let head = server.join().unwrap().to_ascii_lowercase();
+        assert!(head.contains(&format!("range: bytes={}-", half)));
+        assert_eq!(fs::read(fetched.path.join("README")).unwrap(), b"readme");
+        assert!(fs::read_dir(partial).unwrap().next().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_download_timeout() {
+        let dir = tempfile::tempdir().unwrap();
+        let fetcher = Fetcher::new(dir.path().join("downloads"))
+            .unwrap()
+            .with_timeout(Duration::from_millis(200));
+        // Accepts connections but never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/vim.tar.gz", listener.local_addr().unwrap());
+
+        let err = fetcher.fetch(&request(url, None)).await.unwrap_err();
+        assert!(err.to_string().contains("Timed out"));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_git_and_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let work = dir.path().join("work");
+        fs::create_dir_all(work.join("src")).unwrap();
+        fs::write(work.join("src/main.c"), "int main() {}\n").unwrap();
+        let git = |args: &[&str]| {
+            let output = Command::new("git")
+                .args(["-c", "user.name=t", "-c", "user.email=t@t", "-C"])
+                .arg(&work)
+                .args(args)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap().trim().to_string()
+        };
+        git(&["init", "-q"]);
+        git(&["add", "."]);
+        git(&["commit", "-q", "-m", "init"]);
+        let rev = git(&["rev-parse", "HEAD"]);
+        let bare = dir.path().join("tool.git");
+        let output = Command::new("git")
+            .args(["clone", "-q", "--bare"])
+            .arg(&work)
+            .arg(&bare)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+
+        let fetcher = Fetcher::new(dir.path().join("downloads")).unwrap();
+        let mut from_git = request(bare.display().to_string(), None);
+        from_git.rev = Some(rev);
+        // A fresh cache, shared by concurrent fetches of one repository
+        let fetched = fetcher.fetch_all(&vec![from_git; 3]).await;
+        let checkout = fetched.into_iter().map(Result::unwrap).next().unwrap();
+        assert_eq!(
+            fs::read_to_string(checkout.path.join("src/main.c")).unwrap(),
+            "int main() {}\n"
+        );
+
+        // The same tree used in place hashes the same
+        fs::remove_dir_all(work.join(".git")).unwrap();
+        let local = fetcher
+            .fetch(&request(work.display().to_string(), Some(checkout.hash)))
+            .await
+            .unwrap();
+        assert_eq!(local.path, fs::canonicalize(&work).unwrap());
+    }
+}
@@ -0,0 +1,516 @@
+//! Generation profiles
+//!
+//! A generation's profile is the `/usr`-like view of every package it
+//! installs: the [`PROFILE_DIRS`] of each package's store object merged into
+//! one tree of real directories whose files are symlinks into the store.
+//!
+//! ```text
+//! <store>/generations/<id>/profile/bin/curl
+//!     -> <store>/packages/c4/1b/c41b...-curl/bin/curl
+//! ```
+//!
+//! When several packages ship the same path, the package named for it in
+//! `[collisions]` wins, then the one with the lowest `priority`. Paths whose
+//! contents are identical in every package are not collisions. Anything
+//! left undecided fails the install with every such path listed.
+//!
+//! The profile is assembled under the store's `.tmp/` and renamed into
+//! place, then the generation is registered with its packages as roots, so
+//! they are kept until the generation is collected. The store lock keeps
+//! GC out in between; a crash in between leaves an unregistered directory,
+//! which the next GC removes.
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+use walkdir::WalkDir;
+
+use crate::config::Package;
+use crate::constants::{DEFAULT_PACKAGE_PRIORITY, PROFILE_DIRS};
+use crate::store::hash::hash_file;
+use crate::store::{Store, StoreObject};
+
+/// A built package to link into a profile
+#[derive(Debug, Clone)]
+pub struct ProfilePackage {
+    /// Package name
+    pub name: String,
+    /// Store object the package was built to
+    pub object: StoreObject,
+    /// Collision priority; the lowest wins
+    pub priority: i32,
+}
+
+impl ProfilePackage {
+    /// The package `package` was built to `object`
+    pub fn new(package: &Package, object: StoreObject) -> Self {
+        Self {
+            name: package.name.clone(),
+            object,
+            priority: package.priority.unwrap_or(DEFAULT_PACKAGE_PRIORITY),
+        }
+    }
+}
+
+/// A path several packages ship, and the package that provides it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collision {
+    /// Path relative to the profile root
+    pub path: PathBuf,
+    /// Every package shipping the path
+    pub packages: Vec<String>,
+    /// The package the profile takes it from
+    pub winner: String,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} is shipped by {}; using `{}`",
+            self.path.display(),
+            self.packages.join(", "),
+            self.winner
+        )
+    }
+}
+
+/// A generation whose profile was installed
+#[derive(Debug, Clone)]
+pub struct InstalledProfile {
+    /// Generation id
+    pub generation: u64,
+    /// Profile root
+    pub path: PathBuf,
+    /// Number of files linked
+    pub links: usize,
+    /// Collisions settled by `[collisions]` or priority
+    pub collisions: Vec<Collision>,
+}
+
+/// One package's entry at a profile path
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    package: usize,
+    is_dir: bool,
+}
+
+/// What a profile path became
+enum Placed {
+    /// A real directory holding the entries of these packages
+    Dir(BTreeSet<usize>),
+    /// A symlink; nothing below it is placed
+    Link,
+}
+
+/// Links package outputs into generation profiles
+pub struct ProfileInstaller<'a> {
+    store: &'a Store,
+    resolutions: BTreeMap<String, String>,
+}
+
+impl<'a> ProfileInstaller<'a> {
+    /// Create an installer for generations of `store`
+    pub fn new(store: &'a Store) -> Self {
+        Self {
+            store,
+            resolutions: BTreeMap::new(),
+        }
+    }
+
+    /// Settle collisions on these paths in favour of the named packages
+    pub fn with_resolutions(mut self, resolutions: BTreeMap<String, String>) -> Self {
+        self.resolutions = resolutions;
+        self
+    }
+
+    /// The id after every generation registered or on disk
+    pub fn next_generation(&self) -> Result<u64> {
+        let mut last = self
+            .store
+            .database()
+            .generations()?
+            .into_keys()
+            .max()
+            .unwrap_or(0);
+        if let Ok(entries) = fs::read_dir(self.store.layout().generations_dir()) {
+            for entry in entries {
+                if let Ok(id) = entry?.file_name().to_string_lossy().parse::<u64>() {
+                    last = last.max(id);
+                }
+            }
+        }
+        Ok(last + 1)
+    }
+
+    /// Install `packages` as the profile of a new `generation`
+    pub fn install(
+        &self,
+        generation: u64,
+        packages: &[ProfilePackage],
+    ) -> Result<InstalledProfile> {
+        // Until the generation is registered, its directory looks orphaned
+        let _lock = self.store.lock_shared()?;
+        let layout = self.store.layout();
+        let dest = layout.generations_dir().join(generation.to_string());
+        let registered = self.store.database().generations()?;
+        if dest.exists() || registered.contains_key(&generation) {
+            bail!("Generation {} already exists", generation);
+        }
+        for package in packages {
+            if !self.store.exists(&package.object.hash)? {
+                bail!(
+                    "`{}` ({}) is not in the store",
+                    package.name,
+                    package.object.hash
+                );
+            }
+        }
+        let names: BTreeSet<&str> = packages.iter().map(|p| p.name.as_str()).collect();
+        for (path, name) in &self.resolutions {
+            if !names.contains(name.as_str()) {
+                bail!(
+                    "`[collisions]` gives {} to `{}`, which is not installed",
+                    path,
+                    name
+                );
+            }
+        }
+
+        fs::create_dir_all(layout.tmp_dir())?;
+        let staging = tempfile::Builder::new()
+            .prefix("generation-")
+            .tempdir_in(layout.tmp_dir())
+            .context("Failed to create staging directory")?;
+        let profile = staging.path().join("profile");
+        fs::create_dir(&profile)?;
+        let (links, collisions) = self.link_forest(&profile, packages)?;
+
+        fs::create_dir_all(layout.generations_dir())?;
+        fs::rename(staging.path(), &dest)
+            .with_context(|| format!("Failed to install {}", dest.display()))?;
+        let _ = staging.keep();
+        self.store.database().transaction(|txn| {
+            txn.register_generation(generation, Utc::now())?;
+            for package in packages {
+                txn.add_root(generation, &package.object.hash)?;
+            }
+            Ok(())
+        })?;
+        debug!("Installed generation {} at {}", generation, dest.display());
+
+        Ok(InstalledProfile {
+            generation,
+            path: dest.join("profile"),
+            links,
+            collisions,
+        })
+    }
+
+    /// Fill `profile` with directories and symlinks for `packages`
+    fn link_forest(
+        &self,
+        profile: &Path,
+        packages: &[ProfilePackage],
+    ) -> Result<(usize, Vec<Collision>)> {
+        // Parents sort before their children
+        let mut entries: BTreeMap<PathBuf, Vec<Entry>> = BTreeMap::new();
+        for (i, package) in packages.iter().enumerate() {
+            for dir in PROFILE_DIRS {
+                let root = package.object.path.join(dir);
+                if fs::symlink_metadata(&root).is_err() {
+                    continue;
+                }
+                for entry in WalkDir::new(&root).sort_by_file_name() {
+                    let entry = entry?;
+                    let relative = entry
+                        .path()
+                        .strip_prefix(&package.object.path)
+                        .expect("walked below the object")
+                        .to_path_buf();
+                    entries.entry(relative).or_default().push(Entry {
+                        package: i,
+                        is_dir: entry.file_type().is_dir(),
+                    });
+                }
+            }
+        }
+
+        let mut placed: BTreeMap<PathBuf, Placed> = BTreeMap::new();
+        let mut collisions = Vec::new();
+        let mut undecided = Vec::new();
+        let mut links = 0;
+        for (relative, mut candidates) in entries {
+            // Only packages whose parent directory was merged reach this far
+            if let Some(parent) = relative.parent().filter(|p| !p.as_os_str().is_empty()) {
+                match placed.get(parent) {
+                    Some(Placed::Dir(members)) => {
+                        candidates.retain(|entry| members.contains(&entry.package))
+                    }
+                    _ => continue,
+                }
+            }
+            if candidates.is_empty() {
+                continue;
+            }
+
+            let target = profile.join(&relative);
+            if candidates.iter().all(|entry| entry.is_dir) {
+                fs::create_dir(&target)
+                    .with_context(|| format!("Failed to create {}", target.display()))?;
+                let members = candidates.iter().map(|entry| entry.package).collect();
+                placed.insert(relative, Placed::Dir(members));
+                continue;
+            }
+
+            let names = || -> Vec<String> {
+                candidates
+                    .iter()
+                    .map(|entry| packages[entry.package].name.clone())
+                    .collect()
+            };
+            let Some((winner, collided)) = self.pick(packages, &relative, &candidates)? else {
+                undecided.push(format!("{} ({})", relative.display(), names().join(", ")));
+                continue;
+            };
+            if collided {
+                collisions.push(Collision {
+                    path: relative.clone(),
+                    packages: names(),
+                    winner: packages[winner.package].name.clone(),
+                });
+            }
+
+            if winner.is_dir {
+                fs::create_dir(&target)
+                    .with_context(|| format!("Failed to create {}", target.display()))?;
+                placed.insert(relative, Placed::Dir(BTreeSet::from([winner.package])));
+            } else {
+                let source = packages[winner.package].object.path.join(&relative);
+                symlink(&source, &target)
+                    .with_context(|| format!("Failed to link {}", target.display()))?;
+                placed.insert(relative, Placed::Link);
+                links += 1;
+            }
+        }
+
+        if !undecided.is_empty() {
+            bail!(
+                "Packages collide on {}; set a lower `priority` on the package to keep, or name it in `[collisions]`",
+                undecided.join(", ")
+            );
+        }
+        Ok((links, collisions))
+    }
+
+    /// The candidate `relative` is taken from and whether it won a
+    /// collision, or `None` if nothing settles the collision
+    fn pick(
+        &self,
+        packages: &[ProfilePackage],
+        relative: &Path,
+        candidates: &[Entry],
+    ) -> Result<Option<(Entry, bool)>> {
+        if candidates.len() == 1 || self.identical(packages, relative, candidates)? {
+            return Ok(Some((best(packages, candidates)[0], false)));
+        }
+        let resolved = relative
+            .to_str()
+            .and_then(|path| self.resolutions.get(path))
+            .and_then(|name| {
+                candidates
+                    .iter()
+                    .find(|entry| &packages[entry.package].name == name)
+            });
+        if let Some(entry) = resolved {
+            return Ok(Some((*entry, true)));
+        }
+        match best(packages, candidates).as_slice() {
+            [entry] => Ok(Some((*entry, true))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Whether every candidate ships the same file or symlink at `relative`
+    fn identical(
+        &self,
+        packages: &[ProfilePackage],
+        relative: &Path,
+        candidates: &[Entry],
+    ) -> Result<bool> {
+        let mut first = None;
+        for entry in candidates {
+            if entry.is_dir {
+                return Ok(false);
+            }
+            let path = packages[entry.package].object.path.join(relative);
+            let metadata = fs::symlink_metadata(&path)?;
+            let contents = if metadata.file_type().is_symlink() {
+                format!("symlink:{}", fs::read_link(&path)?.display())
+            } else {
+                format!("file:{}:{}", metadata.len(), hash_file(&path)?)
+            };
+            match &first {
+                None => first = Some(contents),
+                Some(first) if *first != contents => return Ok(false),
+                Some(_) => {}
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// The candidates sharing the lowest priority, in package order
+fn best(packages: &[ProfilePackage], candidates: &[Entry]) -> Vec<Entry> {
+    let lowest = candidates
+        .iter()
+        .map(|entry| packages[entry.package].priority)
+        .min()
+        .unwrap_or(DEFAULT_PACKAGE_PRIORITY);
+    candidates
+        .iter()
+        .filter(|entry| packages[entry.package].priority == lowest)
+        .copied()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(store: &Store, dir: &Path, name: &str, files: &[(&str, &str)]) -> StoreObject {
+        let src = dir.join(name);
+        for (path, contents) in files {
+            let path = src.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        store.add_tree(name, &src).unwrap()
+    }
+
+    fn entry(name: &str, object: &StoreObject, priority: i32) -> ProfilePackage {
+        ProfilePackage {
+            name: name.to_string(),
+            object: object.clone(),
+            priority,
+        }
+    }
+
+    #[test]
+    fn test_profile_merges_packages() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let curl = package(
+            &store,
+            dir.path(),
+            "curl",
+            &[
+                ("bin/curl", "curl"),
+                ("share/man/man1/curl.1", "man"),
+                ("share/licenses/COPYING", "MIT"),
+                ("include/curl.h", "not merged"),
+            ],
+        );
+        let zlib = package(
+            &store,
+            dir.path(),
+            "zlib",
+            &[
+                ("lib/libz.so", "zlib"),
+                ("share/man/man3/zlib.3", "man"),
+                ("share/licenses/COPYING", "MIT"),
+            ],
+        );
+        let installer = ProfileInstaller::new(&store);
+        assert_eq!(installer.next_generation().unwrap(), 1);
+
+        let packages = [entry("curl", &curl, 5), entry("zlib", &zlib, 5)];
+        let installed = installer.install(1, &packages).unwrap();
+        assert_eq!(
+            installed.path,
+            store.layout().generations_dir().join("1/profile")
+        );
+        assert_eq!(installed.links, 5);
+        assert!(
+            installed.collisions.is_empty(),
+            "identical files do not collide"
+        );
+
+        let profile = &installed.path;
+        assert_eq!(
+            fs::read_link(profile.join("bin/curl")).unwrap(),
+            curl.path.join("bin/curl")
+        );
+        assert_eq!(
+            fs::read_link(profile.join("lib/libz.so")).unwrap(),
+            zlib.path.join("lib/libz.so")
+        );
+        assert!(profile.join("share/man/man1/curl.1").exists());
+        assert!(profile.join("share/man/man3/zlib.3").exists());
+        assert!(!profile.join("share/man").is_symlink());
+        assert!(!profile.join("include").exists());
+
+        let db = store.database();
+        assert!(db.generations().unwrap().contains_key(&1));
+        assert_eq!(db.roots().unwrap()[&1].len(), 2);
+        assert_eq!(db.refcount(&curl.hash).unwrap(), 1);
+        assert_eq!(installer.next_generation().unwrap(), 2);
+        assert!(installer.install(1, &packages).is_err());
+    }
+
+    #[test]
+    fn test_collisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let vim = package(
+            &store,
+            dir.path(),
+            "vim",
+            &[("bin/vi", "vim"), ("bin/vim", "vim")],
+        );
+        let nvim = package(
+            &store,
+            dir.path(),
+            "neovim",
+            &[("bin/vi", "nvim"), ("bin/nvim", "nvim")],
+        );
+
+        let tied = [entry("vim", &vim, 5), entry("neovim", &nvim, 5)];
+        let err = ProfileInstaller::new(&store).install(1, &tied).unwrap_err();
+        assert!(err.to_string().contains("bin/vi (vim, neovim)"), "{}", err);
+        assert!(!store.layout().generations_dir().join("1").exists());
+        assert!(store.database().generations().unwrap().is_empty());
+
+        let installed = ProfileInstaller::new(&store)
+            .install(1, &[entry("vim", &vim, 5), entry("neovim", &nvim, 1)])
+            .unwrap();
+        assert_eq!(
+            installed.collisions,
+            [Collision {
+                path: "bin/vi".into(),
+                packages: vec!["vim".into(), "neovim".into()],
+                winner: "neovim".into(),
+            }]
+        );
+        assert_eq!(
+            fs::read_link(installed.path.join("bin/vi")).unwrap(),
+            nvim.path.join("bin/vi")
+        );
+        assert!(installed.path.join("bin/vim").exists());
+
+        let installed = ProfileInstaller::new(&store)
+            .with_resolutions(BTreeMap::from([("bin/vi".into(), "vim".into())]))
+            .install(2, &tied)
+            .unwrap();
+        assert_eq!(installed.collisions[0].winner, "vim");
+        assert_eq!(
+            fs::read_link(installed.path.join("bin/vi")).unwrap(),
+            vim.path.join("bin/vi")
+        );
+    }
+}
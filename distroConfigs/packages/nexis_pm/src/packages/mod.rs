@@ -0,0 +1,31 @@
+//! Package management for NexisPM
+//!
+//! Turns declared packages into store objects:
+//! - Version resolution against git tags
+//! - Dependency graphs and build ordering
+//! - Fetching sources into the download cache
+//! - Choosing between prebuilt artifacts and sources
+//! - Build recipes and source builds
+//! - Binary caches (`build-cache` feature)
+//! - Generation profiles linking package outputs
+
+pub mod builder;
+#[cfg(feature = "build-cache")]
+pub mod cache;
+pub mod fetcher;
+pub mod installer;
+pub mod pipeline;
+pub mod recipe;
+pub mod resolver;
+pub mod version;
+
+// Re-export commonly used items
+pub use builder::{BuildJob, BuildResult, ParallelBuilder};
+#[cfg(feature = "build-cache")]
+pub use cache::{BinaryCache, NarInfo, Substituters};
+pub use fetcher::{FetchRequest, Fetched, Fetcher};
+pub use installer::{Collision, InstalledProfile, ProfileInstaller, ProfilePackage};
+pub use pipeline::{Acquired, Pipeline};
+pub use recipe::BuildPlan;
+pub use resolver::DependencyResolver;
+pub use version::{CachePolicy, ResolvedVersion, VersionResolver};
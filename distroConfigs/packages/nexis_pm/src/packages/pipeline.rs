@@ -0,0 +1,331 @@
+//! Prebuilt-or-source selection
+//!
+//! A package may declare a `prebuilt` artifact, a `source`, or both. The
+//! pipeline decides which one a build starts from, per the package's
+//! [`BuildPolicy`] or a global override from the command line:
+//!
+//! | policy            | tries first | falls back to source                 |
+//! |-------------------|-------------|--------------------------------------|
+//! | `prefer-prebuilt` | prebuilt    | if `fallback_to_source` is set       |
+//! | `prefer-source`   | source      | (uses the prebuilt without a source) |
+//! | `prebuilt-only`   | prebuilt    | never                                |
+//!
+//! A prebuilt artifact that cannot be downloaded, does not unpack or does
+//! not match its locked hash counts as unavailable. So does one with no hash
+//! locked for this architecture, under `prebuilt-only` or when hashes are
+//! required (`build --locked`), as nothing could verify it. A source with
+//! no locked hash is refused outright when hashes are required.
+
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use tracing::{info, warn};
+
+use super::fetcher::{FetchRequest, Fetched, Fetcher, TemplateVars};
+use crate::config::lockfile::LockedPackage;
+use crate::config::{BuildPolicy, Package};
+
+/// What a package build starts from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Acquired {
+    /// An unpacked prebuilt artifact, installed as-is
+    Prebuilt(Fetched),
+    /// A source tree to build
+    Source(Fetched),
+}
+
+impl Acquired {
+    /// The fetched tree
+    pub fn fetched(&self) -> &Fetched {
+        match self {
+            Acquired::Prebuilt(fetched) | Acquired::Source(fetched) => fetched,
+        }
+    }
+
+    /// Record the observed hash in `locked` unless one is already pinned
+    ///
+    /// Returns whether the entry changed.
+    pub fn record(&self, locked: &mut LockedPackage) -> bool {
+        let hash = &self.fetched().hash;
+        match self {
+            Acquired::Prebuilt(_) => {
+                let arch = TemplateVars::for_locked(locked).arch;
+                if locked.prebuilt_hashes.contains_key(&arch) {
+                    return false;
+                }
+                locked.prebuilt_hashes.insert(arch, hash.clone());
+            }
+            Acquired::Source(_) => {
+                if locked.source_hash.is_some() {
+                    return false;
+                }
+                locked.source_hash = Some(hash.clone());
+            }
+        }
+        true
+    }
+}
+
+impl fmt::Display for Acquired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Acquired::Prebuilt(fetched) => write!(f, "prebuilt {}", fetched.url),
+            Acquired::Source(fetched) => write!(f, "source {}", fetched.url),
+        }
+    }
+}
+
+/// Fetches what each package is built or installed from
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    fetcher: Fetcher,
+    policy: Option<BuildPolicy>,
+    require_hashes: bool,
+}
+
+impl Pipeline {
+    /// Create a pipeline fetching through `fetcher`
+    pub fn new(fetcher: Fetcher) -> Self {
+        Self {
+            fetcher,
+            policy: None,
+            require_hashes: false,
+        }
+    }
+
+    /// Apply `policy` to every package, overriding their own
+    pub fn with_policy(mut self, policy: Option<BuildPolicy>) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Refuse prebuilt artifacts and sources whose hash is not locked
+    pub fn with_require_hashes(mut self, require_hashes: bool) -> Self {
+        self.require_hashes = require_hashes;
+        self
+    }
+
+    /// The policy in effect for `package`
+    pub fn policy_for(&self, package: &Package) -> BuildPolicy {
+        self.policy.or(package.build_policy).unwrap_or_default()
+    }
+
+    /// Fetch the prebuilt artifact or source of a locked package
+    pub async fn acquire(&self, package: &Package, locked: &LockedPackage) -> Result<Acquired> {
+        let policy = self.policy_for(package);
+        let has_source = package.source.is_some();
+
+        if policy == BuildPolicy::PreferSource && has_source {
+            return self.source(package, locked, "source preferred").await;
+        }
+        let Some(request) = FetchRequest::prebuilt(package, locked)? else {
+            if policy == BuildPolicy::PrebuiltOnly {
+                bail!(
+                    "`{}` is prebuilt-only but declares no `prebuilt` artifact",
+                    package.name
+                );
+            }
+            return self.source(package, locked, "no prebuilt artifact").await;
+        };
+
+        let unverifiable = request.expected_hash.is_none()
+            && (self.require_hashes || policy == BuildPolicy::PrebuiltOnly);
+        let fetched = if unverifiable {
+            Err(anyhow!(
+                "no hash of {} is locked for {}; run `nexis resolve-versions` to pin one",
+                request.url,
+                TemplateVars::for_locked(locked).arch
+            ))
+        } else {
+            self.fetcher.fetch(&request).await
+        };
+        match fetched {
+            Ok(fetched) => {
+                info!("{}: using prebuilt {}", package.name, fetched.url);
+                Ok(Acquired::Prebuilt(fetched))
+            }
+            Err(e)
+                if policy != BuildPolicy::PrebuiltOnly
+                    && package.fallback_to_source
+                    && has_source =>
+            {
+                warn!("{}: prebuilt unavailable: {:#}", package.name, e);
+                self.source(package, locked, "prebuilt unavailable").await
+            }
+            Err(e) => Err(e.context(format!(
+                "Failed to fetch prebuilt artifact of `{}`",
+                package.name
+            ))),
+        }
+    }
+
+    async fn source(
+        &self,
+        package: &Package,
+        locked: &LockedPackage,
+        reason: &str,
+    ) -> Result<Acquired> {
+        let request = FetchRequest::source(package, locked)?;
+        if self.require_hashes && request.expected_hash.is_none() {
+            bail!(
+                "no source hash of `{}` is locked; build it once without `--locked` to record one",
+                package.name
+            );
+        }
+        let fetched = self.fetcher.fetch(&request).await?;
+        info!(
+            "{}: building from source {} ({})",
+            package.name, fetched.url, reason
+        );
+        Ok(Acquired::Source(fetched))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::path::Path;
+
+    /// A prebuilt tarball and a source directory for `tool`
+    fn artifacts(dir: &Path) -> (String, String) {
+        let src = dir.join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("main.c"), "int main() {}\n").unwrap();
+
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o755);
+        header.set_cksum();
+        tar.append_data(&mut header, "bin/tool", &b"elf"[..])
+            .unwrap();
+        let tarball = dir.join("tool-1.0.0-x.tar.zst");
+        fs::write(
+            &tarball,
+            zstd::encode_all(&tar.into_inner().unwrap()[..], 3).unwrap(),
+        )
+        .unwrap();
+
+        (
+            format!("file://{}", dir.join("tool-{version}-x.tar.zst").display()),
+            src.display().to_string(),
+        )
+    }
+
+    fn package(prebuilt: &str, source: &str, extra: &str) -> Package {
+        toml::from_str(&format!(
+            "name = \"tool\"\nversion = \"^1\"\nprebuilt = \"{}\"\nsource = \"{}\"\n{}",
+            prebuilt, source, extra
+        ))
+        .unwrap()
+    }
+
+    fn locked() -> LockedPackage {
+        LockedPackage {
+            name: "tool".into(),
+            requested: "^1".into(),
+            version: "v1.0.0".into(),
+            source: None,
+            resolved: String::new(),
+            rev: None,
+            source_hash: None,
+            store_hash: None,
+            prebuilt_hashes: BTreeMap::new(),
+        }
+    }
+
+    fn pipeline(dir: &Path) -> Pipeline {
+        Pipeline::new(Fetcher::new(dir.join("downloads")).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_prebuilt_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let (prebuilt, source) = artifacts(dir.path());
+        let pipeline = pipeline(dir.path());
+
+        let acquired = pipeline
+            .acquire(&package(&prebuilt, &source, ""), &locked())
+            .await
+            .unwrap();
+        assert!(matches!(acquired, Acquired::Prebuilt(_)));
+        assert!(acquired.fetched().path.join("bin/tool").exists());
+
+        let mut entry = locked();
+        assert!(acquired.record(&mut entry));
+        assert!(!acquired.record(&mut entry));
+        assert_eq!(entry.prebuilt_hashes.len(), 1);
+
+        let acquired = pipeline
+            .acquire(
+                &package(&prebuilt, &source, "build_policy = \"prefer-source\""),
+                &locked(),
+            )
+            .await
+            .unwrap();
+        assert!(matches!(acquired, Acquired::Source(_)));
+    }
+
+    #[tokio::test]
+    async fn test_fallback_to_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let (prebuilt, source) = artifacts(dir.path());
+        let pipeline = pipeline(dir.path());
+
+        // The locked hash does not match what is served
+        let mut tampered = locked();
+        tampered
+            .prebuilt_hashes
+            .insert(std::env::consts::ARCH.into(), "0".repeat(64));
+
+        let err = pipeline
+            .acquire(&package(&prebuilt, &source, ""), &tampered)
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("Hash mismatch"));
+
+        let fallback = package(&prebuilt, &source, "fallback_to_source = true");
+        let acquired = pipeline.acquire(&fallback, &tampered).await.unwrap();
+        assert!(matches!(acquired, Acquired::Source(_)));
+
+        // A global prebuilt-only policy never falls back
+        let strict = pipeline.with_policy(Some(BuildPolicy::PrebuiltOnly));
+        assert!(strict.acquire(&fallback, &tampered).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unpinned_prebuilts_are_not_trusted() {
+        let dir = tempfile::tempdir().unwrap();
+        let (prebuilt, source) = artifacts(dir.path());
+        let pipeline = pipeline(dir.path()).with_require_hashes(true);
+
+        let err = pipeline
+            .acquire(&package(&prebuilt, &source, ""), &locked())
+            .await
+            .unwrap_err();
+        assert!(format!("{:#}", err).contains("no hash"), "{:#}", err);
+        // Falling back only helps once the source is pinned
+        let fallback = package(&prebuilt, &source, "fallback_to_source = true");
+        let err = pipeline.acquire(&fallback, &locked()).await.unwrap_err();
+        assert!(format!("{:#}", err).contains("no source hash"), "{:#}", err);
+        let mut source_pinned = locked();
+        source_pinned.source_hash =
+            Some(crate::store::hash::hash_path(Path::new(&source)).unwrap());
+        let acquired = pipeline.acquire(&fallback, &source_pinned).await.unwrap();
+        assert!(matches!(acquired, Acquired::Source(_)));
+
+        let only = package(&prebuilt, &source, "build_policy = \"prebuilt-only\"");
+        let lenient = pipeline.with_require_hashes(false);
+        assert!(lenient.acquire(&only, &locked()).await.is_err());
+
+        let mut pinned = locked();
+        let tarball = dir.path().join("tool-1.0.0-x.tar.zst");
+        pinned.prebuilt_hashes.insert(
+            std::env::consts::ARCH.into(),
+            crate::store::hash::hash_file(&tarball).unwrap(),
+        );
+        let acquired = lenient.acquire(&only, &pinned).await.unwrap();
+        assert!(matches!(acquired, Acquired::Prebuilt(_)));
+    }
+}
@@ -0,0 +1,297 @@
+//! Build recipes
+//!
+//! A package built from source describes how in a `[packages.recipe]`
+//! table. Most only need a preset:
+//!
+//! ```toml
+//! [[packages]]
+//! name = "zlib"
+//! version = "^1.3"
+//! source = "https://github.com/madler/zlib.git"
+//!
+//! [packages.recipe]
+//! preset = "cmake"
+//! configure_flags = ["-DZLIB_BUILD_EXAMPLES=OFF"]
+//! patches = ["patches/zlib-static.patch"]
+//! skip = ["check"]
+//! ```
+//!
+//! A build runs the phases `unpack`, `patch`, `configure`, `build`, `check`
+//! and `install` in order. Each phase is a list of shell commands, run as
+//! one `sh -e` script inside the source tree. A phase set in the recipe
+//! replaces the preset's; `skip` drops phases entirely. Without a preset, one
+//! is detected from the files at the top of the source tree.
+//!
+//! Commands see these variables:
+//! - `$src`: a private, writable copy of the source tree
+//! - `$out`: the package's store object, laid out like `/usr` (`$out/bin`,
+//!   `$out/lib`, ...)
+//! - `$jobs`: number of parallel jobs to use
+//!
+//! Presets configure for `/usr` and install into `$out`, so installed paths
+//! are correct once a generation exposes the object as its `/usr`.
+
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use crate::config::{BuildPhase, Package, Recipe, RecipePreset};
+
+impl BuildPhase {
+    /// Every phase, in the order they run
+    pub const ALL: [BuildPhase; 6] = [
+        BuildPhase::Unpack,
+        BuildPhase::Patch,
+        BuildPhase::Configure,
+        BuildPhase::Build,
+        BuildPhase::Check,
+        BuildPhase::Install,
+    ];
+}
+
+impl fmt::Display for BuildPhase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            BuildPhase::Unpack => "unpack",
+            BuildPhase::Patch => "patch",
+            BuildPhase::Configure => "configure",
+            BuildPhase::Build => "build",
+            BuildPhase::Check => "check",
+            BuildPhase::Install => "install",
+        })
+    }
+}
+
+impl RecipePreset {
+    /// Guess the build system from the top level of a source tree
+    pub fn detect(src: &Path) -> Option<Self> {
+        let has = |name: &str| src.join(name).exists();
+        if has("Cargo.toml") {
+            Some(Self::Cargo)
+        } else if has("meson.build") {
+            Some(Self::Meson)
+        } else if has("CMakeLists.txt") {
+            Some(Self::Cmake)
+        } else if has("configure") || has("configure.ac") {
+            Some(Self::Autotools)
+        } else if has("Makefile") || has("makefile") || has("GNUmakefile") {
+            Some(Self::Make)
+        } else {
+            None
+        }
+    }
+
+    /// Default commands for `phase`, with `flags` added to the configure step
+    fn phase(self, phase: BuildPhase, flags: &str) -> Vec<String> {
+        let commands: &[&str] = match (self, phase) {
+            (_, BuildPhase::Unpack | BuildPhase::Patch) => &[],
+
+            (Self::Autotools, BuildPhase::Configure) => &[
+                "[ -x ./configure ] || autoreconf -fi",
+                "./configure --prefix=/usr --sysconfdir=/etc --localstatedir=/var {flags}",
+            ],
+            (Self::Autotools, BuildPhase::Build) => &["make -j\"$jobs\""],
+            (Self::Autotools, BuildPhase::Check) => &["make check"],
+            (Self::Autotools, BuildPhase::Install) => &["make install prefix=\"$out\""],
+
+            (Self::Cmake, BuildPhase::Configure) => &[
+                "cmake -S . -B build -DCMAKE_INSTALL_PREFIX=/usr -DCMAKE_BUILD_TYPE=Release {flags}",
+            ],
+            (Self::Cmake, BuildPhase::Build) => &["cmake --build build -j \"$jobs\""],
+            (Self::Cmake, BuildPhase::Check) => &["ctest --test-dir build"],
+            (Self::Cmake, BuildPhase::Install) => &["cmake --install build --prefix \"$out\""],
+
+            (Self::Meson, BuildPhase::Configure) => {
+                &["meson setup build --prefix=/usr --buildtype=release {flags}"]
+            }
+            (Self::Meson, BuildPhase::Build) => &["meson compile -C build -j \"$jobs\""],
+            (Self::Meson, BuildPhase::Check) => &["meson test -C build"],
+            // Meson cannot change the prefix at install time
+            (Self::Meson, BuildPhase::Install) => &[
+                "meson install -C build --destdir \"$src/.destdir\"",
+                "cp -a \"$src/.destdir/usr/.\" \"$out/\"",
+            ],
+
+            (Self::Cargo, BuildPhase::Configure) => &[],
+            (Self::Cargo, BuildPhase::Build) => &["cargo build --release --locked -j \"$jobs\" {flags}"],
+            (Self::Cargo, BuildPhase::Check) => &["cargo test --release --locked -j \"$jobs\""],
+            (Self::Cargo, BuildPhase::Install) => &[
+                "cargo install --path . --locked --no-track --root \"$out\" {flags}",
+            ],
+
+            (Self::Make, BuildPhase::Configure) => &[],
+            (Self::Make, BuildPhase::Build) => &["make -j\"$jobs\" PREFIX=/usr {flags}"],
+            (Self::Make, BuildPhase::Check) => &[],
+            (Self::Make, BuildPhase::Install) => &["make install PREFIX=\"$out\" {flags}"],
+        };
+        commands
+            .iter()
+            .map(|c| c.replace("{flags}", flags).trim_end().to_string())
+            .collect()
+    }
+}
+
+/// Commands of one build phase
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PhaseCommands {
+    /// Phase the commands belong to
+    pub phase: BuildPhase,
+    /// Shell commands, run in order
+    pub commands: Vec<String>,
+}
+
+/// A recipe expanded into the exact commands a build runs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuildPlan {
+    /// Phases to run, in order; skipped and empty phases are left out
+    pub phases: Vec<PhaseCommands>,
+    /// Extra environment variables from the recipe
+    pub env: BTreeMap<String, String>,
+}
+
+impl BuildPlan {
+    /// Expand the recipe of `package` for the source tree at `src`
+    ///
+    /// Relative patch paths are resolved against `config_dir`.
+    pub fn new(package: &Package, src: &Path, config_dir: &Path) -> Result<Self> {
+        let default = Recipe::default();
+        let recipe = package.recipe.as_ref().unwrap_or(&default);
+        let preset = recipe.preset.or_else(|| RecipePreset::detect(src));
+        let flags = recipe
+            .configure_flags
+            .iter()
+            .map(|f| shell_quote(f))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let mut phases = Vec::new();
+        for phase in BuildPhase::ALL {
+            if recipe.skip.contains(&phase) {
+                continue;
+            }
+            let commands = match (override_for(recipe, phase), preset) {
+                (Some(commands), _) => commands.clone(),
+                (None, _) if phase == BuildPhase::Patch => recipe
+                    .patches
+                    .iter()
+                    .map(|p| {
+                        format!(
+                            "patch -p1 -i {}",
+                            shell_quote(&config_dir.join(p).to_string_lossy())
+                        )
+                    })
+                    .collect(),
+                (None, Some(preset)) => preset.phase(phase, &flags),
+                (None, None) => Vec::new(),
+            };
+            if !commands.is_empty() {
+                phases.push(PhaseCommands { phase, commands });
+            }
+        }
+
+        if !phases.iter().any(|p| p.phase == BuildPhase::Install) {
+            bail!(
+                "Don't know how to build `{}`: set `recipe.preset` or the `recipe.install` commands",
+                package.name
+            );
+        }
+        Ok(Self {
+            phases,
+            env: recipe.env.clone(),
+        })
+    }
+}
+
+fn override_for(recipe: &Recipe, phase: BuildPhase) -> Option<&Vec<String>> {
+    match phase {
+        BuildPhase::Unpack => recipe.unpack.as_ref(),
+        BuildPhase::Patch => recipe.patch.as_ref(),
+        BuildPhase::Configure => recipe.configure.as_ref(),
+        BuildPhase::Build => recipe.build.as_ref(),
+        BuildPhase::Check => recipe.check.as_ref(),
+        BuildPhase::Install => recipe.install.as_ref(),
+    }
+}
+
+/// Quote `arg` for `sh` unless it is plainly safe
+fn shell_quote(arg: &str) -> String {
+    let safe = !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_=+./:,@%".contains(c));
+    if safe {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', "'\\''"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn package(recipe: &str) -> Package {
+        toml::from_str(&format!(
+            "name = \"zlib\"\nversion = \"^1\"\nsource = \"/src/zlib\"\n[recipe]\n{}",
+            recipe
+        ))
+        .unwrap()
+    }
+
+    fn commands(plan: &BuildPlan, phase: BuildPhase) -> Vec<&str> {
+        plan.phases
+            .iter()
+            .filter(|p| p.phase == phase)
+            .flat_map(|p| p.commands.iter().map(String::as_str))
+            .collect()
+    }
+
+    #[test]
+    fn test_preset_phases() {
+        let src = tempfile::tempdir().unwrap();
+        let package = package(
+            "preset = \"cmake\"\nconfigure_flags = [\"-DBUILD_SHARED_LIBS=ON\", \"-DNAME=a b\"]\n\
+             patches = [\"patches/fix.patch\"]\nskip = [\"check\"]\nbuild = [\"make -C build\"]",
+        );
+        let plan = BuildPlan::new(&package, src.path(), Path::new("/etc/nexis")).unwrap();
+
+        let phases: Vec<_> = plan.phases.iter().map(|p| p.phase).collect();
+        assert_eq!(
+            phases,
+            [
+                BuildPhase::Patch,
+                BuildPhase::Configure,
+                BuildPhase::Build,
+                BuildPhase::Install
+            ]
+        );
+        assert_eq!(
+            commands(&plan, BuildPhase::Patch),
+            ["patch -p1 -i /etc/nexis/patches/fix.patch"]
+        );
+        assert_eq!(
+            commands(&plan, BuildPhase::Configure),
+            [
+                "cmake -S . -B build -DCMAKE_INSTALL_PREFIX=/usr -DCMAKE_BUILD_TYPE=Release \
+              -DBUILD_SHARED_LIBS=ON '-DNAME=a b'"
+            ]
+        );
+        assert_eq!(commands(&plan, BuildPhase::Build), ["make -C build"]);
+    }
+
+    #[test]
+    fn test_detected_preset() {
+        let src = tempfile::tempdir().unwrap();
+        let err = BuildPlan::new(&package(""), src.path(), Path::new("/")).unwrap_err();
+        assert!(err.to_string().contains("Don't know how to build"));
+
+        fs::write(src.path().join("configure"), "").unwrap();
+        let plan = BuildPlan::new(&package(""), src.path(), Path::new("/")).unwrap();
+        assert_eq!(
+            commands(&plan, BuildPhase::Install),
+            ["make install prefix=\"$out\""]
+        );
+    }
+}
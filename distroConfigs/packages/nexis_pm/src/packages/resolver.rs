@@ -1,33 +1,475 @@
+//! Package dependency resolution
+//!
+//! Packages declare what they need to build and to run:
+//!
+//! ```toml
+//! [[packages]]
+//! name = "curl"
+//! version = "^8"
+//! build_depends = { pkgconf = "*" }
+//! runtime_depends = { openssl = "^3", zlib = ">=1.2.11" }
+//! ```
+//!
+//! Requirements use Cargo's syntax, so a bare `"3"` means `^3`. Every
+//! package is declared once, so resolution unifies all requirements placed
+//! on it, its own `version` included, and picks one version satisfying all
+//! of them. Both kinds of dependency have to be in the store before a
+//! package is built, so both order builds and a cycle through either is an
+//! error.
+
+use anyhow::{bail, Result};
+use petgraph::algo::tarjan_scc;
 use petgraph::graph::{DiGraph, NodeIndex};
-use petgraph::algo::toposort;
-use anyhow::Result;
+use petgraph::visit::EdgeRef;
+use petgraph::Direction;
+use semver::{Version, VersionReq};
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::fmt;
+
+use super::version::VersionSpec;
+use crate::config::Package;
+
+/// When a dependency is needed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DependencyKind {
+    /// While building (`build_depends`)
+    Build,
+    /// While running (`runtime_depends`)
+    Runtime,
+}
+
+impl fmt::Display for DependencyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DependencyKind::Build => write!(f, "build"),
+            DependencyKind::Runtime => write!(f, "runtime"),
+        }
+    }
+}
+
+/// Where a version requirement comes from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RequirementSource {
+    /// The package's own `version`
+    Declared,
+    /// A package depending on it
+    Dependent {
+        /// Name of the depending package
+        name: String,
+        /// Kind of dependency
+        kind: DependencyKind,
+    },
+}
+
+/// A version requirement placed on a package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Requirement {
+    /// Who placed it
+    pub source: RequirementSource,
+    /// Versions it allows
+    pub req: VersionReq,
+}
+
+impl Requirement {
+    /// Whether the requirement allows any version
+    pub fn is_any(&self) -> bool {
+        self.req == VersionReq::STAR
+    }
+}
+
+impl fmt::Display for Requirement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.source {
+            RequirementSource::Declared => write!(f, "its declared version is {}", self.req),
+            RequirementSource::Dependent { name, kind } => {
+                write!(f, "`{}` ({}) requires {}", name, kind, self.req)
+            }
+        }
+    }
+}
 
+/// Dependency graph of a configuration's packages
+#[derive(Debug, Clone)]
 pub struct DependencyResolver {
-    graph: DiGraph<String, ()>,
+    /// Edges run from a package to each of its dependencies
+    graph: DiGraph<String, DependencyKind>,
+    nodes: BTreeMap<String, NodeIndex>,
+    requirements: HashMap<String, Vec<Requirement>>,
 }
 
 impl DependencyResolver {
-    pub fn new() -> Self {
-        Self {
-            graph: DiGraph::new(),
+    /// Build the graph of `packages`
+    ///
+    /// Fails listing every dependency on an undeclared package and every
+    /// requirement that does not parse.
+    pub fn new(packages: &[Package]) -> Result<Self> {
+        let mut graph = DiGraph::new();
+        let mut nodes = BTreeMap::new();
+        let mut requirements: HashMap<String, Vec<Requirement>> = HashMap::new();
+        for package in packages {
+            nodes.insert(package.name.clone(), graph.add_node(package.name.clone()));
+            if let VersionSpec::Range(req) = VersionSpec::parse(&package.version) {
+                requirements
+                    .entry(package.name.clone())
+                    .or_default()
+                    .push(Requirement {
+                        source: RequirementSource::Declared,
+                        req,
+                    });
+            }
         }
+
+        let mut problems = Vec::new();
+        for package in packages {
+            let declared = [
+                (DependencyKind::Build, &package.build_depends),
+                (DependencyKind::Runtime, &package.runtime_depends),
+            ];
+            for (kind, depends) in declared {
+                for (dependency, req) in depends {
+                    let Some(&to) = nodes.get(dependency) else {
+                        problems.push(format!(
+                            "`{}` has a {} dependency on `{}`, which is not declared",
+                            package.name, kind, dependency
+                        ));
+                        continue;
+                    };
+                    let req = match VersionReq::parse(req) {
+                        Ok(req) => req,
+                        Err(e) => {
+                            problems.push(format!(
+                                "`{}` has an invalid requirement `{}` on `{}`: {}",
+                                package.name, req, dependency, e
+                            ));
+                            continue;
+                        }
+                    };
+                    graph.add_edge(nodes[&package.name], to, kind);
+                    requirements
+                        .entry(dependency.clone())
+                        .or_default()
+                        .push(Requirement {
+                            source: RequirementSource::Dependent {
+                                name: package.name.clone(),
+                                kind,
+                            },
+                            req,
+                        });
+                }
+            }
+        }
+        if !problems.is_empty() {
+            bail!("{}", problems.join("\n"));
+        }
+
+        Ok(Self {
+            graph,
+            nodes,
+            requirements,
+        })
     }
-    
-    pub fn add_package(&mut self, package: &Package) -> NodeIndex {
-        self.graph.add_node(package.name.clone())
+
+    /// Every requirement placed on `name`, its declared version first
+    pub fn requirements(&self, name: &str) -> &[Requirement] {
+        self.requirements.get(name).map_or(&[], Vec::as_slice)
+    }
+
+    /// Requirements on `name` that `version` fails
+    ///
+    /// A package pinned to a branch or commit has no version, so it only
+    /// satisfies requirements that allow any version.
+    pub fn unsatisfied(&self, name: &str, version: Option<&Version>) -> Vec<&Requirement> {
+        self.requirements(name)
+            .iter()
+            .filter(|r| match version {
+                Some(version) => !r.req.matches(version),
+                None => !r.is_any(),
+            })
+            .collect()
+    }
+
+    /// Direct dependencies of `name`, sorted by name
+    pub fn dependencies(&self, name: &str) -> Vec<(&str, DependencyKind)> {
+        let Some(&node) = self.nodes.get(name) else {
+            return Vec::new();
+        };
+        let mut dependencies: Vec<_> = self
+            .graph
+            .edges(node)
+            .map(|edge| (self.graph[edge.target()].as_str(), *edge.weight()))
+            .collect();
+        dependencies.sort();
+        dependencies.dedup();
+        dependencies
     }
-    
-    pub fn add_dependency(&mut self, from: NodeIndex, to: NodeIndex) {
-        self.graph.add_edge(from, to, ());
+
+    /// Explain why none of `available` satisfies every requirement on `name`
+    pub fn explain_conflict(&self, name: &str, available: &[Version]) -> String {
+        let requirements = self.requirements(name);
+        let mut message = format!("No version of `{}` satisfies every requirement:", name);
+        for requirement in requirements {
+            message.push_str(&format!("\n  - {}", requirement));
+        }
+
+        let allowed = |r: &Requirement| -> BTreeSet<&Version> {
+            available.iter().filter(|v| r.req.matches(v)).collect()
+        };
+        for (i, a) in requirements.iter().enumerate() {
+            let allowed_a = allowed(a);
+            if allowed_a.is_empty() {
+                message.push_str(&format!("\nNo available version satisfies: {}", a));
+                continue;
+            }
+            for b in &requirements[i + 1..] {
+                let allowed_b = allowed(b);
+                if !allowed_b.is_empty() && allowed_a.is_disjoint(&allowed_b) {
+                    message.push_str(&format!("\nIncompatible: {}, but {}", a, b));
+                }
+            }
+        }
+
+        if available.is_empty() {
+            message.push_str("\nNo versions are available");
+        } else {
+            let versions: Vec<String> = available.iter().map(Version::to_string).collect();
+            message.push_str(&format!("\nAvailable versions: {}", versions.join(", ")));
+        }
+        message
     }
-    
-    pub fn resolve(&self) -> Result<Vec<String>> {
-        let sorted = toposort(&self.graph, None)
-            .map_err(|_| anyhow!("Circular dependency detected"))?;
-        
-        Ok(sorted
+
+    /// Package names in build order, dependencies first
+    ///
+    /// Packages that become buildable at the same time are ordered by name.
+    /// Fails with the full path of a dependency cycle if there is one.
+    pub fn build_order(&self) -> Result<Vec<String>> {
+        let mut pending: HashMap<NodeIndex, usize> = self
+            .nodes
+            .values()
+            .map(|&node| (node, self.dependency_nodes(node).len()))
+            .collect();
+        let mut ready: BTreeSet<&str> = pending
+            .iter()
+            .filter(|(_, &count)| count == 0)
+            .map(|(&node, _)| self.graph[node].as_str())
+            .collect();
+
+        let mut order = Vec::with_capacity(self.nodes.len());
+        while let Some(name) = ready.pop_first() {
+            order.push(name.to_string());
+            let node = self.nodes[name];
+            let dependents: BTreeSet<NodeIndex> = self
+                .graph
+                .neighbors_directed(node, Direction::Incoming)
+                .collect();
+            for dependent in dependents {
+                let count = pending.get_mut(&dependent).expect("every node has a count");
+                *count -= 1;
+                if *count == 0 {
+                    ready.insert(self.graph[dependent].as_str());
+                }
+            }
+        }
+
+        if order.len() < self.nodes.len() {
+            bail!("Dependency cycle: {}", self.find_cycle().join(" → "));
+        }
+        Ok(order)
+    }
+
+    /// Distinct dependencies of `node`; parallel build and runtime edges count once
+    fn dependency_nodes(&self, node: NodeIndex) -> BTreeSet<NodeIndex> {
+        self.graph.neighbors(node).collect()
+    }
+
+    /// Shortest cycle through the alphabetically first package on a cycle
+    fn find_cycle(&self) -> Vec<String> {
+        let start = tarjan_scc(&self.graph)
             .into_iter()
-            .map(|idx| self.graph[idx].clone())
-            .collect())
+            .filter(|scc| scc.len() > 1 || self.graph.contains_edge(scc[0], scc[0]))
+            .flatten()
+            .min_by(|a, b| self.graph[*a].cmp(&self.graph[*b]))
+            .expect("a graph without a topological order has a cycle");
+
+        // Breadth-first from `start` back to itself, visiting neighbours by name
+        let mut parents: HashMap<NodeIndex, NodeIndex> = HashMap::new();
+        let mut queue = VecDeque::from([start]);
+        while let Some(node) = queue.pop_front() {
+            let mut next: Vec<NodeIndex> = self.dependency_nodes(node).into_iter().collect();
+            next.sort_by(|a, b| self.graph[*a].cmp(&self.graph[*b]));
+            for dependency in next {
+                if dependency == start {
+                    let mut path = Vec::new();
+                    let mut current = node;
+                    while current != start {
+                        path.push(self.graph[current].clone());
+                        current = parents[&current];
+                    }
+                    path.push(self.graph[start].clone());
+                    path.reverse();
+                    path.push(self.graph[start].clone());
+                    return path;
+                }
+                if let Entry::Vacant(entry) = parents.entry(dependency) {
+                    entry.insert(node);
+                    queue.push_back(dependency);
+                }
+            }
+        }
+        unreachable!("start lies on a cycle")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packages(toml: &str) -> Vec<Package> {
+        #[derive(serde::Deserialize)]
+        struct Packages {
+            packages: Vec<Package>,
+        }
+        toml::from_str::<Packages>(toml).unwrap().packages
+    }
+
+    const CONFIG: &str = r#"
+[[packages]]
+name = "curl"
+version = "latest"
+build_depends = { pkgconf = "*" }
+runtime_depends = { openssl = "^3", zlib = ">=1.2" }
+
+[[packages]]
+name = "openssl"
+version = ">=1.1"
+runtime_depends = { zlib = "*" }
+
+[[packages]]
+name = "zlib"
+version = "latest"
+
+[[packages]]
+name = "pkgconf"
+version = "latest"
+
+[[packages]]
+name = "nginx"
+version = "latest"
+runtime_depends = { openssl = ">=1.1, <3" }
+"#;
+
+    fn versions(versions: &[&str]) -> Vec<Version> {
+        versions
+            .iter()
+            .map(|v| Version::parse(v).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_build_order_puts_dependencies_first() {
+        let resolver = DependencyResolver::new(&packages(CONFIG)).unwrap();
+        assert_eq!(
+            resolver.build_order().unwrap(),
+            ["pkgconf", "zlib", "openssl", "curl", "nginx"]
+        );
+        assert_eq!(
+            resolver.dependencies("curl"),
+            [
+                ("openssl", DependencyKind::Runtime),
+                ("pkgconf", DependencyKind::Build),
+                ("zlib", DependencyKind::Runtime),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_requirements_are_unified() {
+        let resolver = DependencyResolver::new(&packages(CONFIG)).unwrap();
+        assert_eq!(resolver.requirements("openssl").len(), 3);
+        assert_eq!(
+            resolver.requirements("openssl")[0].source,
+            RequirementSource::Declared
+        );
+
+        let v3 = Version::new(3, 0, 2);
+        let unsatisfied = resolver.unsatisfied("openssl", Some(&v3));
+        assert_eq!(unsatisfied.len(), 1);
+        assert_eq!(
+            unsatisfied[0].to_string(),
+            "`nginx` (runtime) requires >=1.1, <3"
+        );
+
+        // Pinned to a branch: only `*` requirements hold
+        assert_eq!(resolver.unsatisfied("zlib", None).len(), 1);
+        assert!(resolver.unsatisfied("pkgconf", None).is_empty());
+    }
+
+    #[test]
+    fn test_conflicts_name_the_packages_involved() {
+        let resolver = DependencyResolver::new(&packages(CONFIG)).unwrap();
+        let message = resolver.explain_conflict("openssl", &versions(&["1.1.1", "3.0.2"]));
+        assert!(message.contains("No version of `openssl`"), "{}", message);
+        assert!(
+            message.contains("Incompatible: `curl` (runtime) requires ^3, but `nginx` (runtime) requires >=1.1, <3"),
+            "{}",
+            message
+        );
+        assert!(
+            message.ends_with("Available versions: 1.1.1, 3.0.2"),
+            "{}",
+            message
+        );
+    }
+
+    #[test]
+    fn test_cycles_report_the_full_path() {
+        let config = r#"
+[[packages]]
+name = "a"
+version = "latest"
+build_depends = { b = "*" }
+
+[[packages]]
+name = "b"
+version = "latest"
+runtime_depends = { c = "*" }
+
+[[packages]]
+name = "c"
+version = "latest"
+build_depends = { a = "*" }
+"#;
+        let resolver = DependencyResolver::new(&packages(config)).unwrap();
+        let err = resolver.build_order().unwrap_err().to_string();
+        assert_eq!(err, "Dependency cycle: a → b → c → a");
+
+        let config = r#"
+[[packages]]
+name = "d"
+version = "latest"
+runtime_depends = { d = "*" }
+"#;
+        let resolver = DependencyResolver::new(&packages(config)).unwrap();
+        let err = resolver.build_order().unwrap_err().to_string();
+        assert_eq!(err, "Dependency cycle: d → d");
+    }
+
+    #[test]
+    fn test_undeclared_and_invalid_dependencies() {
+        let config = r#"
+[[packages]]
+name = "curl"
+version = "latest"
+runtime_depends = { openssl = "^3", zlib = "not a version" }
+
+[[packages]]
+name = "zlib"
+version = "latest"
+"#;
+        let err = DependencyResolver::new(&packages(config))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`curl` has a runtime dependency on `openssl`, which is not declared"));
+        assert!(err.contains("`curl` has an invalid requirement `not a version` on `zlib`"));
     }
 }
@@ -1,30 +1,718 @@
-use gix::Repository;
+//! Version resolution
+//!
+//! Turns a package's `version` spec into an exact git revision using the
+//! tags and branches its source repository advertises:
+//!
+//! | Spec                       | Resolves to                                       |
+//! |----------------------------|---------------------------------------------------|
+//! | `latest`                   | highest version tag, else the default branch      |
+//! | `^1.2`, `~1.2`, `>=1, <2`  | highest version tag matching the range            |
+//! | `1.2.3`                    | the tag for exactly that version                  |
+//! | `v1.2.3-hotfix`, `stable`  | the tag or branch with that name                  |
+//! | `3f1c9a2`                  | that commit, if a branch or tag points at it      |
+//!
+//! Tags are matched against [`TAG_PATTERNS`](crate::constants::TAG_PATTERNS).
+//! Pre-release versions are only considered when the package sets
+//! `prerelease = true` or the range itself names a pre-release. Whatever the
+//! spec, the result is pinned to a full commit SHA. An abbreviated commit
+//! can only be expanded from the advertised refs, so any other commit must
+//! be given in full.
+//!
+//! Remote listings go through a [`RefCache`]; see [`CachePolicy`] for when
+//! remotes are contacted. Requirements other packages place on a package
+//! narrow the choice further; see [`DependencyResolver`].
+//!
+//! Tarball and local directory sources advertise no tags. [`lock_by_hash`]
+//! locks them by their URL, with the version spec substituted in as
+//! written, and the hash of what the URL served instead of a revision.
+
+use anyhow::{bail, Result};
 use semver::{Version, VersionReq};
-use anyhow::Result;
+use std::fmt;
+use tracing::debug;
+
+use super::fetcher::{FetchRequest, Fetcher, SourceKind};
+use super::resolver::{DependencyResolver, Requirement};
+use crate::config::lockfile::LockedPackage;
+use crate::config::Package;
+use crate::vcs::branches::default_branch;
+use crate::vcs::cache::RefCache;
+use crate::vcs::tags::{parse_tag, parse_version, version_tags, VersionTag};
+use crate::vcs::{list_remote, RemoteRefs};
+
+/// A version spec as written in the config
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VersionSpec {
+    /// Newest release
+    Latest,
+    /// Newest release matching a semver requirement
+    Range(VersionReq),
+    /// A commit SHA, possibly abbreviated
+    Commit(String),
+    /// A tag or branch name
+    Named(String),
+}
 
+impl VersionSpec {
+    /// Parse a `version` field
+    ///
+    /// A bare version (`1.2.3`) means exactly that version rather than
+    /// Cargo's implicit caret.
+    pub fn parse(spec: &str) -> Self {
+        let spec = spec.trim();
+        if spec == "latest" {
+            return Self::Latest;
+        }
+        if is_commit(spec) {
+            return Self::Commit(spec.to_ascii_lowercase());
+        }
+        if parse_version(spec).is_some() {
+            if let Ok(req) = VersionReq::parse(&format!("={}", spec)) {
+                return Self::Range(req);
+            }
+        }
+        let operator = spec.starts_with(['^', '~', '=', '<', '>', '*']);
+        match VersionReq::parse(spec) {
+            Ok(req) if operator => Self::Range(req),
+            _ => Self::Named(spec.to_string()),
+        }
+    }
+}
+
+/// The exact revision a version spec resolved to
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ResolvedVersion {
-    Tag(String),
-    Branch(String),
+    /// A tag, and the commit it points at
+    Tag {
+        /// Tag name
+        name: String,
+        /// Commit SHA
+        rev: String,
+    },
+    /// The current tip of a branch
+    Branch {
+        /// Branch name
+        name: String,
+        /// Commit SHA
+        rev: String,
+    },
+    /// A commit named directly
     Commit(String),
 }
 
-pub async fn resolve_version(package: &Package) -> Result<ResolvedVersion> {
-    match package.version.as_str() {
-        "latest" => resolve_latest(&package.source).await,
-        v if v.starts_with('^') || v.starts_with('~') => {
-            resolve_semver_range(&package.source, v).await
+impl ResolvedVersion {
+    /// Commit SHA the version is pinned to
+    pub fn rev(&self) -> &str {
+        match self {
+            Self::Tag { rev, .. } | Self::Branch { rev, .. } | Self::Commit(rev) => rev,
+        }
+    }
+}
+
+impl fmt::Display for ResolvedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tag { name, rev } => write!(f, "tag {} ({})", name, short(rev)),
+            Self::Branch { name, rev } => write!(f, "branch {} ({})", name, short(rev)),
+            Self::Commit(rev) => write!(f, "commit {}", short(rev)),
+        }
+    }
+}
+
+/// When the resolver contacts remotes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Use cached listings younger than the cache duration, list the rest
+    #[default]
+    Default,
+    /// List every remote, ignoring cached listings
+    Refresh,
+    /// Never contact remotes; use cached listings of any age
+    Offline,
+}
+
+/// Resolves version specs against a package's git source
+#[derive(Debug, Clone, Default)]
+pub struct VersionResolver {
+    prerelease: bool,
+    policy: CachePolicy,
+    cache: Option<RefCache>,
+    dependencies: Option<DependencyResolver>,
+}
+
+impl VersionResolver {
+    /// Create a resolver that ignores pre-releases unless a package opts in
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Consider pre-release tags for every package
+    pub fn include_prerelease(mut self, include: bool) -> Self {
+        self.prerelease = include;
+        self
+    }
+
+    /// Read and record remote listings in `cache`
+    pub fn with_cache(mut self, cache: RefCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Set when remotes are contacted
+    pub fn with_policy(mut self, policy: CachePolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Also satisfy the version requirements packages place on each other
+    pub fn with_dependencies(mut self, dependencies: DependencyResolver) -> Self {
+        self.dependencies = Some(dependencies);
+        self
+    }
+
+    /// Write listings taken so far back to the cache
+    pub fn save_cache(&self) -> Result<()> {
+        match &self.cache {
+            Some(cache) => cache.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Resolve the package's version spec, listing its remote if needed
+    ///
+    /// This may perform blocking network I/O. A full commit SHA resolves to
+    /// itself without any listing.
+    pub fn resolve(&mut self, package: &Package) -> Result<ResolvedVersion> {
+        if let Some(provider) = &package.provider {
+            bail!("Version provider `{}` is not supported yet", provider);
         }
-        v => Ok(ResolvedVersion::Explicit(v.to_string())),
+        let Some(source) = &package.source else {
+            bail!(
+                "Package `{}` has no source to resolve versions from",
+                package.name
+            );
+        };
+        if let VersionSpec::Commit(commit) = VersionSpec::parse(&package.version) {
+            if commit.len() == 40 {
+                return Ok(ResolvedVersion::Commit(commit));
+            }
+        }
+        let refs = self.remote_refs(source)?;
+        self.select(package, &refs)
+    }
+
+    /// The refs `source` advertises, from the cache or the remote
+    fn remote_refs(&mut self, source: &str) -> Result<RemoteRefs> {
+        let cache = self.cache.as_ref();
+        match self.policy {
+            CachePolicy::Offline => {
+                return match cache.and_then(|c| c.get(source)) {
+                    Some(cached) => Ok(cached.refs.clone()),
+                    None => bail!("{} has not been listed before and --offline is set", source),
+                };
+            }
+            CachePolicy::Default => {
+                if let Some(refs) = cache.and_then(|c| c.fresh(source)) {
+                    debug!("Using cached listing of {}", source);
+                    return Ok(refs.clone());
+                }
+            }
+            CachePolicy::Refresh => {}
+        }
+
+        let refs = list_remote(source)?;
+        debug!(
+            "{} advertises {} tags and {} branches",
+            source,
+            refs.tags.len(),
+            refs.branches.len()
+        );
+        if let Some(cache) = &mut self.cache {
+            cache.insert(source, refs.clone());
+        }
+        Ok(refs)
+    }
+
+    /// Resolve the package's version spec against already-listed refs
+    ///
+    /// With [`with_dependencies`](Self::with_dependencies), the newest
+    /// version also satisfying every dependent's requirement is picked.
+    pub fn select(&self, package: &Package, refs: &RemoteRefs) -> Result<ResolvedVersion> {
+        let prerelease = self.prerelease || package.prerelease;
+        let tags = version_tags(&refs.tags, &package.name);
+        let requirements = self.requirements(&package.name);
+        let allowed = |tag: &VersionTag| {
+            requirements
+                .iter()
+                .all(|r| matches(&r.req, &tag.version, prerelease))
+        };
+
+        match VersionSpec::parse(&package.version) {
+            VersionSpec::Latest => {
+                let eligible: Vec<&VersionTag> = tags
+                    .iter()
+                    .filter(|t| prerelease || t.version.pre.is_empty())
+                    .collect();
+                if let Some(tag) = eligible.iter().rev().find(|t| allowed(t)) {
+                    return Ok(tag_version(tag));
+                }
+                if !eligible.is_empty() {
+                    bail!("{}", self.explain_conflict(&package.name, versions(&tags)));
+                }
+                match default_branch(refs) {
+                    Some(branch) => self.unversioned(
+                        package,
+                        ResolvedVersion::Branch {
+                            name: branch.name.clone(),
+                            rev: branch.commit.clone(),
+                        },
+                    ),
+                    None => bail!("Repository has no version tags and no default branch"),
+                }
+            }
+            VersionSpec::Range(req) => {
+                let matching: Vec<&VersionTag> = tags
+                    .iter()
+                    .filter(|t| matches(&req, &t.version, prerelease))
+                    .collect();
+                if let Some(tag) = matching.iter().rev().find(|t| allowed(t)) {
+                    return Ok(tag_version(tag));
+                }
+                if !matching.is_empty() {
+                    bail!("{}", self.explain_conflict(&package.name, versions(&tags)));
+                }
+                if tags.is_empty() {
+                    bail!("Repository has no version tags");
+                }
+                let available: Vec<String> =
+                    versions(&tags).iter().map(Version::to_string).collect();
+                bail!(
+                    "No tag matches `{}` (available: {})",
+                    req,
+                    available.join(", ")
+                )
+            }
+            VersionSpec::Commit(commit) => {
+                let Some(full) = refs.expand_commit(&commit) else {
+                    bail!(
+                        "No branch or tag points at commit `{}`; pin it by its full 40-character SHA",
+                        commit
+                    );
+                };
+                self.unversioned(package, ResolvedVersion::Commit(full.to_string()))
+            }
+            VersionSpec::Named(name) => {
+                if let Some(tag) = refs.tag(&name) {
+                    let resolved = ResolvedVersion::Tag {
+                        name: name.clone(),
+                        rev: tag.commit.clone(),
+                    };
+                    let Some(version) = parse_tag(&name, &package.name) else {
+                        return self.unversioned(package, resolved);
+                    };
+                    if requirements
+                        .iter()
+                        .all(|r| matches(&r.req, &version, prerelease))
+                    {
+                        return Ok(resolved);
+                    }
+                    bail!("{}", self.explain_conflict(&package.name, vec![version]));
+                } else if let Some(branch) = refs.branch(&name) {
+                    self.unversioned(
+                        package,
+                        ResolvedVersion::Branch {
+                            name,
+                            rev: branch.commit.clone(),
+                        },
+                    )
+                } else {
+                    bail!("Repository has no tag or branch named `{}`", name)
+                }
+            }
+        }
+    }
+
+    /// Requirements other packages place on `name`
+    fn requirements(&self, name: &str) -> &[Requirement] {
+        self.dependencies
+            .as_ref()
+            .map_or(&[], |dependencies| dependencies.requirements(name))
+    }
+
+    fn explain_conflict(&self, name: &str, versions: Vec<Version>) -> String {
+        match &self.dependencies {
+            Some(dependencies) => dependencies.explain_conflict(name, &versions),
+            None => format!("No version of `{}` satisfies its requirements", name),
+        }
+    }
+
+    /// Accept a branch or commit only if nothing requires a specific version
+    fn unversioned(&self, package: &Package, resolved: ResolvedVersion) -> Result<ResolvedVersion> {
+        let unmet: Vec<String> = self
+            .requirements(&package.name)
+            .iter()
+            .filter(|r| !r.is_any())
+            .map(ToString::to_string)
+            .collect();
+        if unmet.is_empty() {
+            return Ok(resolved);
+        }
+        bail!(
+            "`{}` resolves to {}, which has no version, but {}",
+            package.name,
+            resolved,
+            unmet.join("; ")
+        )
+    }
+}
+
+/// Whether `package` has a git source whose tags versions resolve against
+pub fn has_git_source(package: &Package) -> bool {
+    package
+        .source
+        .as_deref()
+        .is_some_and(|source| SourceKind::detect(source) == SourceKind::Git)
+}
+
+/// Lock a package with a tarball or local directory source
+///
+/// The source is fetched to record its hash, so a build from the entry
+/// fails if the URL later serves something else.
+pub async fn lock_by_hash(package: &Package, fetcher: &Fetcher) -> Result<LockedPackage> {
+    let mut entry = LockedPackage {
+        name: package.name.clone(),
+        requested: package.version.clone(),
+        version: package.version.clone(),
+        source: package.source.clone(),
+        resolved: String::new(),
+        rev: None,
+        source_hash: None,
+        store_hash: None,
+        prebuilt_hashes: Default::default(),
+    };
+    let request = FetchRequest::source(package, &entry)?;
+    let fetched = fetcher.fetch(&request).await?;
+    entry.resolved = fetched.url;
+    entry.source_hash = Some(fetched.hash);
+    Ok(entry)
+}
+
+/// Whether `version` satisfies `req`
+///
+/// With `prerelease`, a pre-release counts when its release would match.
+fn matches(req: &VersionReq, version: &Version, prerelease: bool) -> bool {
+    req.matches(version)
+        || (prerelease
+            && !version.pre.is_empty()
+            && req.matches(&Version::new(version.major, version.minor, version.patch)))
+}
+
+fn versions(tags: &[VersionTag]) -> Vec<Version> {
+    tags.iter().map(|t| t.version.clone()).collect()
+}
+
+fn tag_version(tag: &VersionTag) -> ResolvedVersion {
+    ResolvedVersion::Tag {
+        name: tag.tag.clone(),
+        rev: tag.commit.clone(),
     }
 }
 
-async fn resolve_latest(repo_url: &str) -> Result<ResolvedVersion> {
-    // 1. Try to find latest semver tag
-    if let Some(tag) = find_latest_semver_tag(repo_url).await? {
-        return Ok(ResolvedVersion::Tag(tag));
+/// Whether `spec` looks like a (possibly abbreviated) commit SHA
+fn is_commit(spec: &str) -> bool {
+    (7..=40).contains(&spec.len())
+        && spec.chars().all(|c| c.is_ascii_hexdigit())
+        && (spec.len() == 40 || spec.chars().any(|c| c.is_ascii_alphabetic()))
+}
+
+fn short(rev: &str) -> &str {
+    &rev[..rev.len().min(12)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcs::RemoteRef;
+    use std::path::Path;
+    use std::process::Command;
+
+    fn package(version: &str, source: &str) -> Package {
+        toml::from_str(&format!(
+            "name = \"zlib\"\nversion = \"{}\"\nsource = \"{}\"",
+            version, source
+        ))
+        .unwrap()
+    }
+
+    fn remote(tags: &[&str], branches: &[&str]) -> RemoteRefs {
+        let refs = |names: &[&str], kind: &str| {
+            names
+                .iter()
+                .map(|name| RemoteRef {
+                    name: name.to_string(),
+                    commit: format!("{}-{}", kind, name),
+                })
+                .collect()
+        };
+        RemoteRefs {
+            tags: refs(tags, "tag"),
+            branches: refs(branches, "branch"),
+            head: None,
+        }
+    }
+
+    fn select(version: &str, refs: &RemoteRefs) -> Result<ResolvedVersion> {
+        VersionResolver::new().select(&package(version, "unused"), refs)
+    }
+
+    fn tag(name: &str) -> ResolvedVersion {
+        ResolvedVersion::Tag {
+            name: name.to_string(),
+            rev: format!("tag-{}", name),
+        }
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(VersionSpec::parse("latest"), VersionSpec::Latest);
+        assert_eq!(
+            VersionSpec::parse("3F1C9A2"),
+            VersionSpec::Commit("3f1c9a2".into())
+        );
+        assert_eq!(
+            VersionSpec::parse("1.2"),
+            VersionSpec::Range(VersionReq::parse("=1.2").unwrap())
+        );
+        assert_eq!(
+            VersionSpec::parse(">=1.0, <2"),
+            VersionSpec::Range(VersionReq::parse(">=1.0, <2").unwrap())
+        );
+        assert_eq!(
+            VersionSpec::parse("stable"),
+            VersionSpec::Named("stable".into())
+        );
+        assert_eq!(
+            VersionSpec::parse("v1.2.3"),
+            VersionSpec::Named("v1.2.3".into())
+        );
+    }
+
+    #[test]
+    fn test_ranges_pick_highest_stable_match() {
+        let refs = remote(
+            &[
+                "v1.0.0",
+                "v1.4.2",
+                "release-1.9",
+                "v2.0.0-rc.1",
+                "zlib-2.1.0",
+                "v3.0.0-beta",
+            ],
+            &["main"],
+        );
+        assert_eq!(select("^1.2", &refs).unwrap(), tag("release-1.9"));
+        assert_eq!(select("~1.4", &refs).unwrap(), tag("v1.4.2"));
+        assert_eq!(select("1.0.0", &refs).unwrap(), tag("v1.0.0"));
+        assert_eq!(select("latest", &refs).unwrap(), tag("zlib-2.1.0"));
+        assert_eq!(select("^2.0.0-rc.1", &refs).unwrap(), tag("zlib-2.1.0"));
+
+        let err = select("^4", &refs).unwrap_err().to_string();
+        assert!(err.contains("available: 1.0.0, 1.4.2"), "{}", err);
+    }
+
+    #[test]
+    fn test_prerelease_opt_in() {
+        let refs = remote(&["v1.0.0", "v2.0.0-rc.1"], &[]);
+        let mut pkg = package("latest", "unused");
+        pkg.prerelease = true;
+        let resolver = VersionResolver::new();
+        assert_eq!(resolver.select(&pkg, &refs).unwrap(), tag("v2.0.0-rc.1"));
+
+        pkg.version = "^2".into();
+        assert_eq!(resolver.select(&pkg, &refs).unwrap(), tag("v2.0.0-rc.1"));
+        assert!(select("^2", &refs).is_err());
+    }
+
+    #[test]
+    fn test_latest_falls_back_to_default_branch() {
+        let branch = |name: &str| ResolvedVersion::Branch {
+            name: name.to_string(),
+            rev: format!("branch-{}", name),
+        };
+
+        let refs = remote(&["nightly"], &["develop", "master", "feature"]);
+        assert_eq!(select("latest", &refs).unwrap(), branch("master"));
+
+        let mut refs = remote(&[], &["master", "trunk"]);
+        refs.head = Some("trunk".into());
+        assert_eq!(select("latest", &refs).unwrap(), branch("trunk"));
+
+        assert!(select("latest", &remote(&[], &["feature"])).is_err());
+    }
+
+    #[test]
+    fn test_named_refs_and_commits() {
+        let refs = remote(&["v1.0.0"], &["stable"]);
+        assert_eq!(select("v1.0.0", &refs).unwrap(), tag("v1.0.0"));
+        assert_eq!(
+            select("stable", &refs).unwrap(),
+            ResolvedVersion::Branch {
+                name: "stable".into(),
+                rev: "branch-stable".into()
+            }
+        );
+        assert!(
+            select("abcdef1", &refs).is_err(),
+            "abbreviated commits must expand to a full SHA"
+        );
+        assert!(select("missing", &refs).is_err());
+
+        let full = format!("abcdef1{}", "0".repeat(33));
+        let mut refs = remote(&[], &["topic"]);
+        refs.branches[0].commit = full.clone();
+        assert_eq!(
+            select("ABCDEF1", &refs).unwrap(),
+            ResolvedVersion::Commit(full)
+        );
+    }
+
+    #[test]
+    fn test_dependents_narrow_the_choice() {
+        let config = |openssl: &str, nginx: &str| -> Vec<Package> {
+            #[derive(serde::Deserialize)]
+            struct Packages {
+                packages: Vec<Package>,
+            }
+            let toml = format!(
+                r#"
+[[packages]]
+name = "openssl"
+version = "{}"
+source = "unused"
+
+[[packages]]
+name = "curl"
+version = "latest"
+runtime_depends = {{ openssl = "^3" }}
+
+[[packages]]
+name = "nginx"
+version = "latest"
+runtime_depends = {{ openssl = "{}" }}
+"#,
+                openssl, nginx
+            );
+            toml::from_str::<Packages>(&toml).unwrap().packages
+        };
+        let select = |openssl: &str, nginx: &str| {
+            let packages = config(openssl, nginx);
+            let dependencies = DependencyResolver::new(&packages).unwrap();
+            VersionResolver::new()
+                .with_dependencies(dependencies)
+                .select(
+                    &packages[0],
+                    &remote(&["v1.1.1", "v3.0.2", "v3.1.0"], &["main"]),
+                )
+        };
+
+        assert_eq!(select("latest", "<3.1").unwrap(), tag("v3.0.2"));
+        assert_eq!(select(">=1", "*").unwrap(), tag("v3.1.0"));
+
+        let err = select("latest", "<3").unwrap_err().to_string();
+        assert!(
+            err.contains("Incompatible: `curl` (runtime) requires ^3, but `nginx`"),
+            "{}",
+            err
+        );
+        let err = select("v1.1.1", "*").unwrap_err().to_string();
+        assert!(
+            err.contains("No available version satisfies: `curl`"),
+            "{}",
+            err
+        );
+        let err = select("main", "*").unwrap_err().to_string();
+        assert!(
+            err.contains("branch main (branch-main), which has no version"),
+            "{}",
+            err
+        );
+    }
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .args(args)
+            .current_dir(dir)
+            .env("GIT_AUTHOR_NAME", "nexis")
+            .env("GIT_AUTHOR_EMAIL", "nexis@localhost")
+            .env("GIT_COMMITTER_NAME", "nexis")
+            .env("GIT_COMMITTER_EMAIL", "nexis@localhost")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {:?} failed", args);
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    /// A bare repository with `v1.0.0` on the first commit, annotated
+    /// `v1.1.0` on the second and `main` at the third
+    fn bare_repository(dir: &Path) -> (String, [String; 3]) {
+        let work = dir.join("work");
+        std::fs::create_dir(&work).unwrap();
+
+        git(&work, &["init", "-q", "-b", "main"]);
+        git(&work, &["commit", "-q", "--allow-empty", "-m", "one"]);
+        git(&work, &["tag", "v1.0.0"]);
+        let one = git(&work, &["rev-parse", "HEAD"]);
+        git(&work, &["commit", "-q", "--allow-empty", "-m", "two"]);
+        git(&work, &["tag", "-a", "-m", "release", "v1.1.0"]);
+        let two = git(&work, &["rev-parse", "HEAD"]);
+        git(&work, &["commit", "-q", "--allow-empty", "-m", "three"]);
+        let three = git(&work, &["rev-parse", "HEAD"]);
+        git(
+            dir,
+            &["clone", "-q", "--bare", work.to_str().unwrap(), "zlib.git"],
+        );
+
+        let bare = dir.join("zlib.git").to_str().unwrap().to_string();
+        (bare, [one, two, three])
+    }
+
+    #[test]
+    fn test_resolve_against_bare_repository() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, [one, two, three]) = bare_repository(dir.path());
+        let mut resolver = VersionResolver::new();
+        let mut resolve = |version: &str| resolver.resolve(&package(version, &source)).unwrap();
+
+        // Annotated tags are peeled to the commit they point at
+        assert_eq!(resolve("latest").rev(), two);
+        assert_eq!(resolve("~1.0.0").rev(), one);
+        assert_eq!(resolve("main").rev(), three);
+        assert_eq!(resolve(&three).rev(), three);
+    }
+
+    #[test]
+    fn test_cached_listings() {
+        let dir = tempfile::tempdir().unwrap();
+        let (source, [_, two, _]) = bare_repository(dir.path());
+        let cache_path = dir.path().join("version-cache.json");
+        let package = package("latest", &source);
+        let resolver = |policy| {
+            VersionResolver::new()
+                .with_cache(RefCache::load(&cache_path).unwrap())
+                .with_policy(policy)
+        };
+
+        assert!(resolver(CachePolicy::Offline).resolve(&package).is_err());
+
+        let mut online = resolver(CachePolicy::Default);
+        assert_eq!(online.resolve(&package).unwrap().rev(), two);
+        online.save_cache().unwrap();
+
+        // With the remote gone, only the cached listing can answer
+        std::fs::remove_dir_all(&source).unwrap();
+        for policy in [CachePolicy::Default, CachePolicy::Offline] {
+            assert_eq!(resolver(policy).resolve(&package).unwrap().rev(), two);
+        }
+        assert!(resolver(CachePolicy::Refresh).resolve(&package).is_err());
     }
-    
-    // 2. Fallback to default branch
-    let branch = find_default_branch(repo_url).await?;
-    Ok(ResolvedVersion::Branch(branch))
 }
@@ -0,0 +1,9 @@
+//! Security
+//!
+//! Checks on where store objects come from:
+//! - Ed25519 signing and trusted-key verification
+
+pub mod signing;
+
+// Re-export commonly used items
+pub use signing::{PublicKey, SecretKey, TrustedKeys, Verdict};
@@ -0,0 +1,282 @@
+//! Ed25519 signatures over store objects
+//!
+//! Keys and signatures are named, so a signature says which key made it.
+//! All three are written as `<name>:<base64>`:
+//!
+//! ```text
+//! secret key   cache.example.org-1:<32-byte seed>
+//! public key   cache.example.org-1:<32-byte key>
+//! signature    cache.example.org-1:<64-byte signature>
+//! ```
+//!
+//! What gets signed is an object's [`fingerprint`]: its store hash, name and
+//! runtime references. The store hash is a hash of the object's contents, so
+//! a valid signature vouches for the exact tree and everything it pulls in.
+//!
+//! Public keys are trusted by listing them in `[cache] trusted_public_keys`
+//! of `system.toml`.
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
+use rustix::rand::{getrandom, GetRandomFlags};
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+/// The bytes a signature over a store object covers
+///
+/// References are sorted, so the order they were recorded in does not
+/// matter.
+pub fn fingerprint(hash: &str, name: &str, references: &[String]) -> String {
+    let mut references: Vec<&str> = references.iter().map(String::as_str).collect();
+    references.sort_unstable();
+    format!("nexis-1;{};{};{}", hash, name, references.join(","))
+}
+
+/// Split `<name>:<base64>` into the name and exactly `N` decoded bytes
+fn parse_named<const N: usize>(text: &str, what: &str) -> Result<(String, [u8; N])> {
+    let Some((name, encoded)) = text.trim().split_once(':') else {
+        bail!("Malformed {} `{}`; expected `<name>:<base64>`", what, text);
+    };
+    if name.is_empty() {
+        bail!("Malformed {} `{}`; the name is empty", what, text);
+    }
+    let bytes = STANDARD
+        .decode(encoded)
+        .with_context(|| format!("Malformed {} `{}`", what, name))?;
+    let bytes = bytes.try_into().map_err(|bytes: Vec<u8>| {
+        anyhow::anyhow!("{} `{}` is {} bytes", what, name, bytes.len())
+    })?;
+    Ok((name.to_string(), bytes))
+}
+
+/// A named Ed25519 signing key
+pub struct SecretKey {
+    name: String,
+    key: SigningKey,
+}
+
+impl SecretKey {
+    /// Generate a fresh key called `name`, seeded from the kernel's CSPRNG
+    pub fn generate(name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains(':') || name.contains(char::is_whitespace) {
+            bail!("Invalid key name `{}`", name);
+        }
+        let mut seed = [0u8; 32];
+        let mut filled = 0;
+        while filled < seed.len() {
+            filled += getrandom(&mut seed[filled..], GetRandomFlags::empty())
+                .context("Failed to read random bytes")?;
+        }
+        Ok(Self {
+            name: name.to_string(),
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// Parse a secret key in `<name>:<base64>` form
+    pub fn parse(text: &str) -> Result<Self> {
+        let (name, seed) = parse_named::<32>(text, "secret key")?;
+        Ok(Self {
+            name,
+            key: SigningKey::from_bytes(&seed),
+        })
+    }
+
+    /// Read a secret key file
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid key in {}", path.display()))
+    }
+
+    /// Write the key to a new file readable by its owner only
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        writeln!(
+            file,
+            "{}:{}",
+            self.name,
+            STANDARD.encode(self.key.to_bytes())
+        )?;
+        Ok(())
+    }
+
+    /// Key name, shared with its public key and signatures
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The matching public key
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey {
+            name: self.name.clone(),
+            key: self.key.verifying_key(),
+        }
+    }
+
+    /// Sign `fingerprint`, returning the signature in `<name>:<base64>` form
+    pub fn sign(&self, fingerprint: &str) -> String {
+        let signature = self.key.sign(fingerprint.as_bytes());
+        format!("{}:{}", self.name, STANDARD.encode(signature.to_bytes()))
+    }
+}
+
+impl fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SecretKey")
+            .field("name", &self.name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A named Ed25519 public key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublicKey {
+    name: String,
+    key: VerifyingKey,
+}
+
+impl PublicKey {
+    /// Parse a public key in `<name>:<base64>` form
+    pub fn parse(text: &str) -> Result<Self> {
+        let (name, bytes) = parse_named::<32>(text, "public key")?;
+        let key = VerifyingKey::from_bytes(&bytes)
+            .with_context(|| format!("Public key `{}` is not a valid Ed25519 key", name))?;
+        Ok(Self { name, key })
+    }
+
+    /// Key name
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether `signature` is this key's signature over `fingerprint`
+    pub fn verify(&self, fingerprint: &str, signature: &str) -> bool {
+        let Ok((name, bytes)) = parse_named::<64>(signature, "signature") else {
+            return false;
+        };
+        let signature = ed25519_dalek::Signature::from_bytes(&bytes);
+        name == self.name
+            && self
+                .key
+                .verify_strict(fingerprint.as_bytes(), &signature)
+                .is_ok()
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.name, STANDARD.encode(self.key.to_bytes()))
+    }
+}
+
+/// How a single signature checks out against the trusted keys
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Verdict {
+    /// Made by the named trusted key
+    Trusted(String),
+    /// Claims to be from the named trusted key but does not verify
+    Invalid(String),
+    /// Made by a key that is not trusted, or not a signature at all
+    Untrusted(String),
+}
+
+/// The public keys whose signatures are accepted
+#[derive(Debug, Clone, Default)]
+pub struct TrustedKeys {
+    keys: Vec<PublicKey>,
+}
+
+impl TrustedKeys {
+    /// Parse each of `keys` (`trusted_public_keys` entries)
+    pub fn new(keys: &[String]) -> Result<Self> {
+        let keys = keys
+            .iter()
+            .map(|key| PublicKey::parse(key))
+            .collect::<Result<_>>()?;
+        Ok(Self { keys })
+    }
+
+    /// Whether no key is trusted
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Check one signature over `fingerprint`
+    pub fn check(&self, fingerprint: &str, signature: &str) -> Verdict {
+        let name = signature
+            .split_once(':')
+            .map_or(signature, |(name, _)| name);
+        match self.keys.iter().find(|key| key.name == name) {
+            Some(key) if key.verify(fingerprint, signature) => Verdict::Trusted(name.to_string()),
+            Some(_) => Verdict::Invalid(name.to_string()),
+            None => Verdict::Untrusted(name.to_string()),
+        }
+    }
+
+    /// Name of the first trusted key that signed `fingerprint`, if any
+    pub fn signed_by<'a>(&self, fingerprint: &str, signatures: &'a [String]) -> Option<&'a str> {
+        signatures
+            .iter()
+            .find(|signature| matches!(self.check(fingerprint, signature), Verdict::Trusted(_)))
+            .map(|signature| signature.split_once(':').map_or("", |(name, _)| name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_verify() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.sec");
+        let key = SecretKey::generate("cache-1").unwrap();
+        key.save(&path).unwrap();
+        assert!(key.save(&path).is_err(), "keys are never overwritten");
+        let loaded = SecretKey::load(&path).unwrap();
+        let public = loaded.public_key();
+        assert_eq!(public, key.public_key());
+        assert_eq!(PublicKey::parse(&public.to_string()).unwrap(), public);
+
+        let fp = fingerprint("c41b", "zlib", &["9a0e".into(), "51c2".into()]);
+        assert_eq!(
+            fp,
+            fingerprint("c41b", "zlib", &["51c2".into(), "9a0e".into()])
+        );
+        let signature = key.sign(&fp);
+        assert!(signature.starts_with("cache-1:"));
+
+        let trusted = TrustedKeys::new(&[public.to_string()]).unwrap();
+        assert_eq!(
+            trusted.check(&fp, &signature),
+            Verdict::Trusted("cache-1".into())
+        );
+        let other = fingerprint("c41b", "zlib", &[]);
+        assert_eq!(
+            trusted.check(&other, &signature),
+            Verdict::Invalid("cache-1".into())
+        );
+        let stranger = SecretKey::generate("stranger").unwrap().sign(&fp);
+        assert_eq!(
+            trusted.check(&fp, &stranger),
+            Verdict::Untrusted("stranger".into())
+        );
+        assert_eq!(
+            trusted.signed_by(&fp, &[stranger.clone(), signature]),
+            Some("cache-1")
+        );
+        assert_eq!(trusted.signed_by(&fp, &[stranger]), None);
+        assert!(TrustedKeys::new(&["cache-1:bm90IGEga2V5".into()]).is_err());
+    }
+}
@@ -1,39 +1,618 @@
-use redb::{Database, TableDefinition, ReadableTable};
-use anyhow::Result;
+//! Store metadata database
+//!
+//! A redb wrapper recording what lives in the store: package and file object
+//! metadata keyed by hash, the reference graph between objects, the objects
+//! each generation roots, reference counts, and the signatures objects were
+//! substituted or pushed with.
+//!
+//! An object's refcount is the number of generation roots pointing at it plus
+//! the number of *live* objects referencing it, so a non-zero refcount means
+//! "reachable from some generation". When an object becomes live its
+//! references gain a count; when its last count goes away its references
+//! lose one, cascading through the graph.
+//!
+//! Every mutation goes through a [`StoreTransaction`]. Callers batch a whole
+//! operation (one `nexis switch`, one GC run) into a single transaction, so a
+//! crash part-way through leaves the database exactly as it was before.
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+use redb::{
+    Database, MultimapTableDefinition, ReadableMultimapTable, ReadableTable, TableDefinition,
+    WriteTransaction,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
 
 const PACKAGES_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("packages");
 const FILES_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("files");
 const REFCOUNT_TABLE: TableDefinition<&str, u64> = TableDefinition::new("refcounts");
+/// Object → objects it references at runtime
+const REFERENCES_TABLE: MultimapTableDefinition<&str, &str> =
+    MultimapTableDefinition::new("references");
+/// Object → objects referencing it (reverse of `references`)
+const REFERRERS_TABLE: MultimapTableDefinition<&str, &str> =
+    MultimapTableDefinition::new("referrers");
+/// Object → signatures over its fingerprint (`<key name>:<base64>`)
+const SIGNATURES_TABLE: MultimapTableDefinition<&str, &str> =
+    MultimapTableDefinition::new("signatures");
+/// Generation → objects it keeps alive
+const ROOTS_TABLE: MultimapTableDefinition<u64, &str> = MultimapTableDefinition::new("roots");
+/// Generation → creation time (unix seconds)
+const GENERATIONS_TABLE: TableDefinition<u64, i64> = TableDefinition::new("generations");
+
+/// Metadata of a package object
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PackageMetadata {
+    /// Package name (the `-name` suffix of the object directory)
+    pub name: String,
+    /// Total size of the object's files in bytes
+    pub size: u64,
+    /// When the object was added to the store
+    pub added_at: DateTime<Utc>,
+}
+
+/// Metadata of a file object
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FileMetadata {
+    /// File size in bytes
+    pub size: u64,
+    /// When the object was added to the store
+    pub added_at: DateTime<Utc>,
+}
 
+/// A recorded refcount that disagrees with the reference graph
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RefcountDrift {
+    /// Object hash
+    pub hash: String,
+    /// Refcount stored in the database
+    pub recorded: u64,
+    /// Refcount implied by the roots and references
+    pub expected: u64,
+}
+
+/// Handle to the store's metadata database
 pub struct StoreDatabase {
     db: Database,
 }
 
 impl StoreDatabase {
+    /// Open the database, creating it and its tables if needed
     pub fn open(path: &Path) -> Result<Self> {
         let db = Database::create(path)?;
+        let write_txn = db.begin_write()?;
+        {
+            write_txn.open_table(PACKAGES_TABLE)?;
+            write_txn.open_table(FILES_TABLE)?;
+            write_txn.open_table(REFCOUNT_TABLE)?;
+            write_txn.open_multimap_table(REFERENCES_TABLE)?;
+            write_txn.open_multimap_table(REFERRERS_TABLE)?;
+            write_txn.open_multimap_table(SIGNATURES_TABLE)?;
+            write_txn.open_multimap_table(ROOTS_TABLE)?;
+            write_txn.open_table(GENERATIONS_TABLE)?;
+        }
+        write_txn.commit()?;
         Ok(Self { db })
     }
-    
+
+    /// Start a write transaction
+    ///
+    /// Nothing is written unless [`StoreTransaction::commit`] is called.
+    pub fn begin(&self) -> Result<StoreTransaction> {
+        Ok(StoreTransaction {
+            txn: self.db.begin_write()?,
+        })
+    }
+
+    /// Run `f` in a write transaction, committing only if it succeeds
+    pub fn transaction<T>(&self, f: impl FnOnce(&mut StoreTransaction) -> Result<T>) -> Result<T> {
+        let mut txn = self.begin()?;
+        let value = f(&mut txn)?;
+        txn.commit()?;
+        Ok(value)
+    }
+
+    /// Record a package object
     pub fn insert_package(&self, hash: &str, metadata: &PackageMetadata) -> Result<()> {
-        let write_txn = self.db.begin_write()?;
-        {
-            let mut table = write_txn.open_table(PACKAGES_TABLE)?;
-            let data = bincode::serialize(metadata)?;
-            table.insert(hash, data.as_slice())?;
+        self.transaction(|txn| txn.insert_package(hash, metadata))
+    }
+
+    /// Look up a package object
+    pub fn get_package(&self, hash: &str) -> Result<Option<PackageMetadata>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PACKAGES_TABLE)?;
+        match table.get(hash)? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
         }
-        write_txn.commit()?;
-        Ok(())
     }
-    
+
+    /// Every package object, keyed by hash
+    pub fn packages(&self) -> Result<BTreeMap<String, PackageMetadata>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(PACKAGES_TABLE)?;
+        let mut packages = BTreeMap::new();
+        for entry in table.iter()? {
+            let (hash, data) = entry?;
+            packages.insert(
+                hash.value().to_string(),
+                serde_json::from_slice(data.value())?,
+            );
+        }
+        Ok(packages)
+    }
+
+    /// Record a file object
+    pub fn insert_file(&self, hash: &str, metadata: &FileMetadata) -> Result<()> {
+        self.transaction(|txn| txn.insert_file(hash, metadata))
+    }
+
+    /// Look up a file object
+    pub fn get_file(&self, hash: &str) -> Result<Option<FileMetadata>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(FILES_TABLE)?;
+        match table.get(hash)? {
+            Some(data) => Ok(Some(serde_json::from_slice(data.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Increment the reference count of an object
     pub fn increment_refcount(&self, hash: &str) -> Result<()> {
-        let write_txn = self.db.begin_write()?;
-        {
-            let mut table = write_txn.open_table(REFCOUNT_TABLE)?;
-            let current = table.get(hash)?.map(|v| v.value()).unwrap_or(0);
-            table.insert(hash, current + 1)?;
+        self.transaction(|txn| txn.increment_refcount(hash))
+    }
+
+    /// Current refcount of an object
+    pub fn refcount(&self, hash: &str) -> Result<u64> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(REFCOUNT_TABLE)?;
+        Ok(table.get(hash)?.map(|v| v.value()).unwrap_or(0))
+    }
+
+    /// Objects `hash` references
+    pub fn references(&self, hash: &str) -> Result<Vec<String>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(REFERENCES_TABLE)?;
+        collect_values(table.get(hash)?)
+    }
+
+    /// Objects referencing `hash`
+    pub fn referrers(&self, hash: &str) -> Result<Vec<String>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(REFERRERS_TABLE)?;
+        collect_values(table.get(hash)?)
+    }
+
+    /// Signatures recorded for `hash`
+    pub fn signatures(&self, hash: &str) -> Result<Vec<String>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(SIGNATURES_TABLE)?;
+        collect_values(table.get(hash)?)
+    }
+
+    /// Objects rooted by each generation
+    pub fn roots(&self) -> Result<BTreeMap<u64, Vec<String>>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_multimap_table(ROOTS_TABLE)?;
+        let mut roots = BTreeMap::new();
+        for entry in table.iter()? {
+            let (generation, hashes) = entry?;
+            roots.insert(generation.value(), collect_values(hashes)?);
+        }
+        Ok(roots)
+    }
+
+    /// Creation time of each registered generation
+    pub fn generations(&self) -> Result<BTreeMap<u64, DateTime<Utc>>> {
+        let read_txn = self.db.begin_read()?;
+        let table = read_txn.open_table(GENERATIONS_TABLE)?;
+        let mut generations = BTreeMap::new();
+        for entry in table.iter()? {
+            let (generation, created) = entry?;
+            if let Some(created) = DateTime::from_timestamp(created.value(), 0) {
+                generations.insert(generation.value(), created);
+            }
+        }
+        Ok(generations)
+    }
+
+    /// Recompute every refcount from the roots and compare with the stored ones
+    ///
+    /// With `repair`, drifted counts are rewritten in the same transaction.
+    pub fn verify(&self, repair: bool) -> Result<Vec<RefcountDrift>> {
+        let mut txn = self.begin()?;
+        let drift = txn.verify_refcounts(repair)?;
+        if repair && !drift.is_empty() {
+            txn.commit()?;
+        }
+        Ok(drift)
+    }
+}
+
+/// A batch of store database changes applied atomically
+pub struct StoreTransaction {
+    txn: WriteTransaction,
+}
+
+impl StoreTransaction {
+    /// Apply every change made in this transaction
+    pub fn commit(self) -> Result<()> {
+        self.txn.commit()?;
+        Ok(())
+    }
+
+    /// Discard every change made in this transaction
+    pub fn abort(self) -> Result<()> {
+        self.txn.abort()?;
+        Ok(())
+    }
+
+    /// Record a package object
+    pub fn insert_package(&mut self, hash: &str, metadata: &PackageMetadata) -> Result<()> {
+        let mut table = self.txn.open_table(PACKAGES_TABLE)?;
+        let data = serde_json::to_vec(metadata)?;
+        table.insert(hash, data.as_slice())?;
+        Ok(())
+    }
+
+    /// Record a file object
+    pub fn insert_file(&mut self, hash: &str, metadata: &FileMetadata) -> Result<()> {
+        let mut table = self.txn.open_table(FILES_TABLE)?;
+        let data = serde_json::to_vec(metadata)?;
+        table.insert(hash, data.as_slice())?;
+        Ok(())
+    }
+
+    /// Record that `hash` references each of `references` at runtime
+    ///
+    /// If `hash` is already live, the new references gain a count.
+    pub fn add_references(&mut self, hash: &str, references: &[&str]) -> Result<()> {
+        let live = self.refcount(hash)? > 0;
+        for &reference in references {
+            // Self-references never keep an object alive
+            if reference == hash {
+                continue;
+            }
+            let existed = {
+                let mut table = self.txn.open_multimap_table(REFERENCES_TABLE)?;
+                table.insert(hash, reference)?
+            };
+            if existed {
+                continue;
+            }
+            self.txn
+                .open_multimap_table(REFERRERS_TABLE)?
+                .insert(reference, hash)?;
+            if live {
+                self.increment_refcount(reference)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record signatures over the fingerprint of `hash`
+    ///
+    /// Signatures already recorded are kept once.
+    pub fn add_signatures(&mut self, hash: &str, signatures: &[&str]) -> Result<()> {
+        let mut table = self.txn.open_multimap_table(SIGNATURES_TABLE)?;
+        for &signature in signatures {
+            table.insert(hash, signature)?;
+        }
+        Ok(())
+    }
+
+    /// Record when `generation` was created
+    pub fn register_generation(
+        &mut self,
+        generation: u64,
+        created_at: DateTime<Utc>,
+    ) -> Result<()> {
+        self.txn
+            .open_table(GENERATIONS_TABLE)?
+            .insert(generation, created_at.timestamp())?;
+        Ok(())
+    }
+
+    /// Make `generation` keep `hash` alive
+    pub fn add_root(&mut self, generation: u64, hash: &str) -> Result<()> {
+        let existed = self
+            .txn
+            .open_multimap_table(ROOTS_TABLE)?
+            .insert(generation, hash)?;
+        if !existed {
+            self.increment_refcount(hash)?;
+        }
+        Ok(())
+    }
+
+    /// Drop `generation` and its roots, returning the objects that became unreferenced
+    pub fn remove_generation(&mut self, generation: u64) -> Result<Vec<String>> {
+        self.txn.open_table(GENERATIONS_TABLE)?.remove(generation)?;
+        let hashes = {
+            let mut table = self.txn.open_multimap_table(ROOTS_TABLE)?;
+            let hashes = collect_values(table.remove_all(generation)?)?;
+            hashes
+        };
+        let mut dead = Vec::new();
+        for hash in hashes {
+            dead.extend(self.decrement_refcount(&hash)?);
+        }
+        Ok(dead)
+    }
+
+    /// Increment an object's refcount, cascading to its references when it
+    /// becomes live
+    pub fn increment_refcount(&mut self, hash: &str) -> Result<()> {
+        let mut pending = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            let current = self.refcount(&hash)?;
+            self.txn
+                .open_table(REFCOUNT_TABLE)?
+                .insert(hash.as_str(), current + 1)?;
+            if current == 0 {
+                pending.extend(self.references(&hash)?);
+            }
         }
-        write_txn.commit()?;
         Ok(())
     }
+
+    /// Decrement an object's refcount, cascading to its references when it
+    /// becomes unreferenced
+    ///
+    /// Returns every object whose refcount dropped to zero.
+    pub fn decrement_refcount(&mut self, hash: &str) -> Result<Vec<String>> {
+        let mut dead = Vec::new();
+        let mut pending = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            let current = self.refcount(&hash)?;
+            let mut table = self.txn.open_table(REFCOUNT_TABLE)?;
+            match current {
+                0 => bail!("Refcount of {} would drop below zero", hash),
+                1 => {
+                    table.remove(hash.as_str())?;
+                    drop(table);
+                    pending.extend(self.references(&hash)?);
+                    dead.push(hash);
+                }
+                n => {
+                    table.insert(hash.as_str(), n - 1)?;
+                }
+            }
+        }
+        Ok(dead)
+    }
+
+    /// Forget an unreferenced object and its outgoing references
+    pub fn remove_object(&mut self, hash: &str) -> Result<()> {
+        if self.refcount(hash)? > 0 {
+            bail!("Refusing to remove {} while it is still referenced", hash);
+        }
+        let references = {
+            let mut table = self.txn.open_multimap_table(REFERENCES_TABLE)?;
+            let references = collect_values(table.remove_all(hash)?)?;
+            references
+        };
+        let mut referrers = self.txn.open_multimap_table(REFERRERS_TABLE)?;
+        for reference in &references {
+            referrers.remove(reference.as_str(), hash)?;
+        }
+        drop(referrers);
+        self.txn
+            .open_multimap_table(SIGNATURES_TABLE)?
+            .remove_all(hash)?;
+        self.txn.open_table(PACKAGES_TABLE)?.remove(hash)?;
+        self.txn.open_table(FILES_TABLE)?.remove(hash)?;
+        Ok(())
+    }
+
+    /// Current refcount of an object, including changes made in this transaction
+    pub fn refcount(&self, hash: &str) -> Result<u64> {
+        let table = self.txn.open_table(REFCOUNT_TABLE)?;
+        let count = table.get(hash)?.map(|v| v.value()).unwrap_or(0);
+        Ok(count)
+    }
+
+    /// Objects `hash` references, including changes made in this transaction
+    pub fn references(&self, hash: &str) -> Result<Vec<String>> {
+        let table = self.txn.open_multimap_table(REFERENCES_TABLE)?;
+        let values = table.get(hash)?;
+        collect_values(values)
+    }
+
+    /// Recompute refcounts from the roots, optionally rewriting drifted ones
+    pub fn verify_refcounts(&mut self, repair: bool) -> Result<Vec<RefcountDrift>> {
+        let mut expected: BTreeMap<String, u64> = BTreeMap::new();
+        let mut pending = Vec::new();
+        {
+            let roots = self.txn.open_multimap_table(ROOTS_TABLE)?;
+            for entry in roots.iter()? {
+                let (_, hashes) = entry?;
+                for hash in collect_values(hashes)? {
+                    *expected.entry(hash.clone()).or_default() += 1;
+                    pending.push(hash);
+                }
+            }
+        }
+
+        // Every live object adds one count to each of its references
+        let mut live = BTreeSet::new();
+        while let Some(hash) = pending.pop() {
+            if !live.insert(hash.clone()) {
+                continue;
+            }
+            for reference in self.references(&hash)? {
+                *expected.entry(reference.clone()).or_default() += 1;
+                pending.push(reference);
+            }
+        }
+
+        let mut recorded = BTreeMap::new();
+        {
+            let table = self.txn.open_table(REFCOUNT_TABLE)?;
+            for entry in table.iter()? {
+                let (hash, count) = entry?;
+                recorded.insert(hash.value().to_string(), count.value());
+            }
+        }
+
+        let hashes: BTreeSet<&String> = expected.keys().chain(recorded.keys()).collect();
+        let drift: Vec<RefcountDrift> = hashes
+            .into_iter()
+            .filter_map(|hash| {
+                let recorded = recorded.get(hash).copied().unwrap_or(0);
+                let expected = expected.get(hash).copied().unwrap_or(0);
+                (recorded != expected).then(|| RefcountDrift {
+                    hash: hash.clone(),
+                    recorded,
+                    expected,
+                })
+            })
+            .collect();
+
+        if repair {
+            let mut table = self.txn.open_table(REFCOUNT_TABLE)?;
+            for entry in &drift {
+                if entry.expected == 0 {
+                    table.remove(entry.hash.as_str())?;
+                } else {
+                    table.insert(entry.hash.as_str(), entry.expected)?;
+                }
+            }
+        }
+        Ok(drift)
+    }
+}
+
+fn collect_values(values: redb::MultimapValue<&'static str>) -> Result<Vec<String>> {
+    values.map(|value| Ok(value?.value().to_string())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open() -> (tempfile::TempDir, StoreDatabase) {
+        let dir = tempfile::tempdir().unwrap();
+        let db = StoreDatabase::open(&dir.path().join("metadata.redb")).unwrap();
+        (dir, db)
+    }
+
+    /// app → lib → libc, tool → libc
+    fn graph(db: &StoreDatabase) {
+        db.transaction(|txn| {
+            txn.add_references("app", &["lib"])?;
+            txn.add_references("lib", &["libc"])?;
+            txn.add_references("tool", &["libc"])
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn test_roots_cascade_through_references() {
+        let (_dir, db) = open();
+        graph(&db);
+
+        db.transaction(|txn| {
+            txn.add_root(1, "app")?;
+            txn.add_root(2, "app")?;
+            txn.add_root(2, "tool")
+        })
+        .unwrap();
+        assert_eq!(db.refcount("app").unwrap(), 2);
+        assert_eq!(db.refcount("lib").unwrap(), 1);
+        assert_eq!(db.refcount("libc").unwrap(), 2);
+
+        let dead = db.transaction(|txn| txn.remove_generation(2)).unwrap();
+        assert_eq!(dead, ["tool"]);
+        assert_eq!(db.refcount("libc").unwrap(), 1);
+
+        let mut dead = db.transaction(|txn| txn.remove_generation(1)).unwrap();
+        dead.sort();
+        assert_eq!(dead, ["app", "lib", "libc"]);
+        assert!(db.verify(false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_references_added_to_live_objects_are_counted() {
+        let (_dir, db) = open();
+        db.transaction(|txn| txn.add_root(1, "app")).unwrap();
+        db.transaction(|txn| txn.add_references("app", &["lib", "app"]))
+            .unwrap();
+        assert_eq!(db.refcount("lib").unwrap(), 1);
+        assert_eq!(db.refcount("app").unwrap(), 1);
+        assert_eq!(db.referrers("lib").unwrap(), ["app"]);
+    }
+
+    #[test]
+    fn test_failed_transaction_changes_nothing() {
+        let (_dir, db) = open();
+        graph(&db);
+        db.transaction(|txn| txn.add_root(1, "app")).unwrap();
+
+        let result: Result<()> = db.transaction(|txn| {
+            txn.remove_generation(1)?;
+            bail!("crash mid-switch")
+        });
+        assert!(result.is_err());
+        assert_eq!(db.refcount("app").unwrap(), 1);
+        assert_eq!(db.refcount("libc").unwrap(), 1);
+        assert_eq!(db.roots().unwrap()[&1], ["app"]);
+    }
+
+    #[test]
+    fn test_verify_reports_and_repairs_drift() {
+        let (_dir, db) = open();
+        graph(&db);
+        db.transaction(|txn| txn.add_root(1, "app")).unwrap();
+        // Simulate drift left by an older version
+        db.increment_refcount("libc").unwrap();
+        db.increment_refcount("orphan").unwrap();
+
+        let drift = db.verify(false).unwrap();
+        assert_eq!(
+            drift,
+            [
+                RefcountDrift {
+                    hash: "libc".to_string(),
+                    recorded: 2,
+                    expected: 1
+                },
+                RefcountDrift {
+                    hash: "orphan".to_string(),
+                    recorded: 1,
+                    expected: 0
+                },
+            ]
+        );
+        // Reporting alone does not change anything
+        assert_eq!(db.verify(false).unwrap(), drift);
+
+        assert_eq!(db.verify(true).unwrap(), drift);
+        assert!(db.verify(false).unwrap().is_empty());
+        assert_eq!(db.refcount("orphan").unwrap(), 0);
+    }
+
+    #[test]
+    fn test_remove_object() {
+        let (_dir, db) = open();
+        graph(&db);
+        db.transaction(|txn| {
+            txn.add_root(1, "app")?;
+            txn.add_signatures("app", &["cache-1:c2ln", "cache-1:c2ln"])
+        })
+        .unwrap();
+        assert_eq!(db.signatures("app").unwrap(), ["cache-1:c2ln"]);
+        assert!(db.transaction(|txn| txn.remove_object("app")).is_err());
+
+        db.transaction(|txn| {
+            txn.remove_generation(1)?;
+            txn.remove_object("app")
+        })
+        .unwrap();
+        assert!(db.references("app").unwrap().is_empty());
+        assert!(db.referrers("lib").unwrap().is_empty());
+        assert!(db.signatures("app").unwrap().is_empty());
+    }
 }
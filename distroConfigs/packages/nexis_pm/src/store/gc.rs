@@ -0,0 +1,320 @@
+//! Mark-and-sweep garbage collection
+//!
+//! A collection run holds the store lock exclusively
+//! ([`store::lock`](super::lock)), then:
+//! 1. empties whatever an interrupted run left in `.trash/`
+//! 2. picks the generations to drop (`--keep N`, `--older-than`); the
+//!    current and the newest generation are always kept
+//! 3. marks every object reachable from the remaining roots
+//! 4. in one database transaction, drops the generations and forgets every
+//!    unmarked object
+//! 5. renames unmarked objects and dropped generation directories into
+//!    `.trash/`, which is atomic per object
+//! 6. deletes the trash on a parallel worker pool
+//!
+//! The sweep works from what is on disk rather than from the database, so
+//! objects orphaned by a crash between steps 4 and 6 are picked up by the
+//! next run.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use rayon::prelude::*;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use super::objects::Store;
+use crate::constants::MAX_GENERATIONS_KEEP;
+
+/// Which generations a collection keeps
+#[derive(Debug, Clone, Default)]
+pub struct GcOptions {
+    /// Keep at most this many generations (0 = unlimited)
+    ///
+    /// Defaults to [`MAX_GENERATIONS_KEEP`] when neither `keep` nor
+    /// `older_than` is set.
+    pub keep: Option<usize>,
+    /// Drop generations created longer ago than this
+    pub older_than: Option<Duration>,
+    /// Report what would be collected without changing anything
+    pub dry_run: bool,
+}
+
+/// Outcome of a collection run
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GcReport {
+    /// Generations dropped
+    pub generations_removed: Vec<u64>,
+    /// Store paths of the objects collected
+    pub objects_removed: Vec<PathBuf>,
+    /// Bytes freed (or that would be freed in a dry run)
+    pub bytes_freed: u64,
+    /// Whether this was a dry run
+    pub dry_run: bool,
+}
+
+/// Garbage collector for a [`Store`]
+pub struct GarbageCollector<'a> {
+    store: &'a Store,
+}
+
+impl<'a> GarbageCollector<'a> {
+    /// Create a collector for `store`
+    pub fn new(store: &'a Store) -> Self {
+        Self { store }
+    }
+
+    /// Run one collection, once no build is adding objects
+    pub fn collect(&self, options: &GcOptions) -> Result<GcReport> {
+        let _lock = self.store.lock_exclusive()?;
+        let layout = self.store.layout();
+        let db = self.store.database();
+        let mut report = GcReport {
+            dry_run: options.dry_run,
+            ..Default::default()
+        };
+
+        if !options.dry_run {
+            report.bytes_freed += self.empty_trash()?;
+        }
+
+        let roots = db.roots()?;
+        let created = db.generations()?;
+        let generations: BTreeSet<u64> = roots.keys().chain(created.keys()).copied().collect();
+        let dropped =
+            select_generations(&generations, &created, self.current_generation(), options);
+
+        // Mark
+        let mut live = BTreeSet::new();
+        let mut pending: Vec<String> = roots
+            .iter()
+            .filter(|(generation, _)| !dropped.contains(generation))
+            .flat_map(|(_, hashes)| hashes.iter().cloned())
+            .collect();
+        while let Some(hash) = pending.pop() {
+            if live.insert(hash.clone()) {
+                pending.extend(db.references(&hash)?);
+            }
+        }
+
+        // Sweep
+        let dead: Vec<(String, PathBuf)> = self
+            .objects_on_disk()?
+            .into_iter()
+            .filter(|(hash, _)| !live.contains(hash))
+            .collect();
+        let mut doomed_dirs: Vec<PathBuf> = dead.iter().map(|(_, path)| path.clone()).collect();
+        doomed_dirs.extend(self.generation_dirs(&generations, &dropped)?);
+
+        report.generations_removed = dropped.iter().copied().collect();
+        report.objects_removed = dead.iter().map(|(_, path)| path.clone()).collect();
+
+        if options.dry_run {
+            for path in &doomed_dirs {
+                report.bytes_freed += tree_size(path);
+            }
+            return Ok(report);
+        }
+
+        db.transaction(|txn| {
+            for &generation in &dropped {
+                txn.remove_generation(generation)?;
+            }
+            // Counts may have drifted from the graph; the mark above is authoritative
+            let drift = txn.verify_refcounts(true)?;
+            if !drift.is_empty() {
+                warn!("Repaired {} drifted refcounts during GC", drift.len());
+            }
+            for (hash, _) in &dead {
+                if txn.refcount(hash)? == 0 {
+                    txn.remove_object(hash)?;
+                }
+            }
+            Ok(())
+        })?;
+
+        let trash = layout.trash_dir();
+        fs::create_dir_all(&trash)?;
+        for path in &doomed_dirs {
+            let name = path.file_name().expect("store paths have a file name");
+            let mut target = trash.join(name);
+            if path.parent() == Some(&layout.generations_dir()) {
+                target = trash.join(format!("generation-{}", name.to_string_lossy()));
+            }
+            make_movable(path).with_context(|| format!("Failed to trash {}", path.display()))?;
+            match fs::rename(path, &target) {
+                Ok(()) => debug!("Moved {} to the trash", path.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to trash {}", path.display()))
+                }
+            }
+        }
+
+        report.bytes_freed += self.empty_trash()?;
+        info!(
+            "Collected {} objects and {} generations, freed {} bytes",
+            report.objects_removed.len(),
+            report.generations_removed.len(),
+            report.bytes_freed
+        );
+        Ok(report)
+    }
+
+    /// Delete everything in `.trash/` in parallel, returning the bytes freed
+    fn empty_trash(&self) -> Result<u64> {
+        let trash = self.store.layout().trash_dir();
+        let entries: Vec<PathBuf> = match fs::read_dir(&trash) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|e| e.path()))
+                .collect::<std::io::Result<_>>()?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+
+        entries
+            .par_iter()
+            .map(|path| {
+                let size = tree_size(path);
+                remove_tree(path)
+                    .with_context(|| format!("Failed to delete {}", path.display()))?;
+                Ok(size)
+            })
+            .sum()
+    }
+
+    /// Every package and file object on disk, keyed by hash
+    fn objects_on_disk(&self) -> Result<Vec<(String, PathBuf)>> {
+        let layout = self.store.layout();
+        let mut objects = Vec::new();
+        for dir in [layout.packages_dir(), layout.files_dir()] {
+            for entry in WalkDir::new(&dir).min_depth(3).max_depth(3) {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy();
+                let hash = name
+                    .split_once('-')
+                    .map_or(&*name, |(hash, _)| hash)
+                    .to_string();
+                objects.push((hash, entry.into_path()));
+            }
+        }
+        Ok(objects)
+    }
+
+    /// Directories of dropped generations, plus any left behind by earlier runs
+    fn generation_dirs(
+        &self,
+        generations: &BTreeSet<u64>,
+        dropped: &BTreeSet<u64>,
+    ) -> Result<Vec<PathBuf>> {
+        let dir = self.store.layout().generations_dir();
+        let entries = match fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let current = self.current_generation();
+        let mut dirs = Vec::new();
+        for entry in entries {
+            let entry = entry?;
+            let Ok(id) = entry.file_name().to_string_lossy().parse::<u64>() else {
+                continue;
+            };
+            let orphaned = !generations.contains(&id) && Some(id) != current;
+            if dropped.contains(&id) || orphaned {
+                dirs.push(entry.path());
+            }
+        }
+        Ok(dirs)
+    }
+
+    /// The generation the `current` symlink points at
+    fn current_generation(&self) -> Option<u64> {
+        let link = self.store.layout().generations_dir().join("current");
+        fs::read_link(link)
+            .ok()?
+            .file_name()?
+            .to_str()?
+            .parse()
+            .ok()
+    }
+}
+
+/// Pick the generations to drop; the newest and the current one are always kept
+fn select_generations(
+    generations: &BTreeSet<u64>,
+    created: &BTreeMap<u64, DateTime<Utc>>,
+    current: Option<u64>,
+    options: &GcOptions,
+) -> BTreeSet<u64> {
+    let keep = match (options.keep, options.older_than) {
+        (None, None) => Some(MAX_GENERATIONS_KEEP),
+        (keep, _) => keep,
+    };
+    let cutoff = options.older_than.map(|age| Utc::now() - age);
+
+    generations
+        .iter()
+        .rev()
+        .enumerate()
+        .filter(|&(index, &generation)| {
+            if index == 0 || Some(generation) == current {
+                return false;
+            }
+            let over_limit = keep.is_some_and(|keep| keep > 0 && index >= keep);
+            let too_old = cutoff.is_some_and(|cutoff| {
+                created
+                    .get(&generation)
+                    .is_some_and(|created| *created < cutoff)
+            });
+            over_limit || too_old
+        })
+        .map(|(_, &generation)| generation)
+        .collect()
+}
+
+/// Total size of the files under `path`
+fn tree_size(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| entry.metadata().ok())
+        .filter(|metadata| metadata.is_file())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Let the owner write to a directory, so it can be moved to another parent
+///
+/// Moving a directory rewrites its `..` entry, which needs write permission
+/// on the directory itself; store objects are read-only.
+fn make_movable(path: &Path) -> std::io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mode = metadata.permissions().mode();
+    if metadata.is_dir() && mode & 0o200 == 0 {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode | 0o200))?;
+    }
+    Ok(())
+}
+
+/// Remove a file or directory tree, even if store immutability made it read-only
+fn remove_tree(path: &Path) -> std::io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return fs::remove_file(path);
+    }
+    for entry in WalkDir::new(path).into_iter().filter_map(|e| e.ok()) {
+        if entry.file_type().is_dir() {
+            fs::set_permissions(entry.path(), fs::Permissions::from_mode(0o755))?;
+        }
+    }
+    fs::remove_dir_all(path)
+}
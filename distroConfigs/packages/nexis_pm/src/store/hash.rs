@@ -0,0 +1,441 @@
+//! BLAKE3 hashing and canonical archives of store objects
+//!
+//! A store object is hashed through a canonical, NAR-like serialization of
+//! its tree. The same bytes double as the archive format used to move
+//! objects between stores. Package objects also hash their name, so two
+//! packages with identical trees still get objects of their own, and an
+//! archive unpacked under its name always hashes to the hash it was
+//! published under.
+//!
+//! Every token is a little-endian `u64` length followed by the bytes, padded
+//! with zeroes to a multiple of 8:
+//!
+//! ```text
+//! object    = "nexis-object-1" name archive
+//! archive   = "nexis-archive-1" node
+//! node      = "regular" ("executable" | "plain") size contents
+//!           | "symlink" target
+//!           | "directory" { "entry" name node } "end"
+//! ```
+//!
+//! Directory entries are sorted by the raw bytes of their names. Only the
+//! file type, executable bit, symlink target and contents are recorded;
+//! timestamps, owners and other permission bits are not, so two builds of
+//! the same tree produce the same hash on any machine.
+
+use anyhow::{anyhow, bail, Context, Result};
+use blake3::Hasher;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::Path;
+
+/// Magic token opening every archive
+const ARCHIVE_MAGIC: &[u8] = b"nexis-archive-1";
+
+/// Magic token opening the hashed form of a package object
+const OBJECT_MAGIC: &[u8] = b"nexis-object-1";
+
+/// Contents are streamed in chunks of this size
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Writes at least this large are hashed on the rayon pool
+///
+/// Below this, spawning work costs more than it saves.
+const PARALLEL_THRESHOLD: usize = 128 * 1024;
+
+/// Longest name or symlink target accepted when unpacking
+const MAX_TOKEN_LEN: u64 = 4096;
+
+/// Hash a file, directory or symlink through its canonical archive
+pub fn hash_path(path: &Path) -> Result<String> {
+    let mut writer = HashWriter::new();
+    pack(path, &mut writer)?;
+    Ok(writer.finalize())
+}
+
+/// Store hash of the package object `name` whose tree is at `path`
+pub fn object_hash(name: &str, path: &Path) -> Result<String> {
+    let mut writer = HashWriter::new();
+    write_token(&mut writer, OBJECT_MAGIC)?;
+    write_token(&mut writer, name.as_bytes())?;
+    pack(path, &mut writer)?;
+    Ok(writer.finalize())
+}
+
+/// Hash the raw bytes of a single file without reading it fully into memory
+pub fn hash_file(path: &Path) -> Result<String> {
+    let mut writer = HashWriter::new();
+    let mut file =
+        fs::File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    copy_chunked(&mut file, &mut writer)?;
+    Ok(writer.finalize())
+}
+
+/// Hash in-memory bytes
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    let mut writer = HashWriter::new();
+    writer.update(bytes);
+    writer.finalize()
+}
+
+/// Whether `hash` has the shape of a store hash: 64 hex digits
+pub fn is_store_hash(hash: &str) -> bool {
+    hash.len() == blake3::OUT_LEN * 2 && hash.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Write the canonical archive of `path` to `writer`
+pub fn pack(path: &Path, writer: &mut impl Write) -> Result<()> {
+    write_token(writer, ARCHIVE_MAGIC)?;
+    pack_node(path, writer)
+}
+
+/// Unpack a canonical archive into `dest`, which must not exist yet
+///
+/// Archives that are not in canonical form (unsorted or duplicate entries,
+/// unsafe names, trailing data) are rejected.
+pub fn unpack(reader: &mut impl Read, dest: &Path) -> Result<()> {
+    let mut reader = ArchiveReader { inner: reader };
+    reader.expect(ARCHIVE_MAGIC)?;
+    reader.unpack_node(dest)?;
+
+    let mut trailing = [0u8; 1];
+    if reader.inner.read(&mut trailing)? != 0 {
+        bail!("Trailing data after archive");
+    }
+    Ok(())
+}
+
+fn pack_node(path: &Path, writer: &mut impl Write) -> Result<()> {
+    let metadata =
+        fs::symlink_metadata(path).with_context(|| format!("Failed to stat {}", path.display()))?;
+    let file_type = metadata.file_type();
+
+    if file_type.is_symlink() {
+        let target = fs::read_link(path)?;
+        write_token(writer, b"symlink")?;
+        write_token(writer, target.as_os_str().as_bytes())?;
+    } else if file_type.is_dir() {
+        let mut names: Vec<_> = fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect::<io::Result<_>>()?;
+        names.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+
+        write_token(writer, b"directory")?;
+        for name in names {
+            write_token(writer, b"entry")?;
+            write_token(writer, name.as_bytes())?;
+            pack_node(&path.join(&name), writer)?;
+        }
+        write_token(writer, b"end")?;
+    } else if file_type.is_file() {
+        let executable = metadata.permissions().mode() & 0o111 != 0;
+        let size = metadata.len();
+        write_token(writer, b"regular")?;
+        write_token(writer, if executable { b"executable" } else { b"plain" })?;
+        writer.write_all(&size.to_le_bytes())?;
+
+        let file = fs::File::open(path)?;
+        let copied = copy_chunked(&mut file.take(size), writer)?;
+        if copied != size {
+            bail!("{} changed size while it was being read", path.display());
+        }
+        write_padding(writer, size)?;
+    } else {
+        bail!(
+            "{} is not a regular file, directory or symlink",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+fn write_token(writer: &mut impl Write, token: &[u8]) -> io::Result<()> {
+    let len = token.len() as u64;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(token)?;
+    write_padding(writer, len)
+}
+
+fn write_padding(writer: &mut impl Write, len: u64) -> io::Result<()> {
+    let padding = padding(len);
+    writer.write_all(&[0u8; 8][..padding])
+}
+
+fn padding(len: u64) -> usize {
+    ((8 - len % 8) % 8) as usize
+}
+
+fn copy_chunked(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<u64> {
+    let mut buf = vec![0u8; CHUNK_SIZE];
+    let mut total = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        total += n as u64;
+    }
+}
+
+/// BLAKE3 sink that hashes large writes in parallel
+struct HashWriter {
+    hasher: Hasher,
+}
+
+impl HashWriter {
+    fn new() -> Self {
+        Self {
+            hasher: Hasher::new(),
+        }
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        if bytes.len() >= PARALLEL_THRESHOLD {
+            self.hasher.update_rayon(bytes);
+        } else {
+            self.hasher.update(bytes);
+        }
+    }
+
+    fn finalize(&self) -> String {
+        self.hasher.finalize().to_hex().to_string()
+    }
+}
+
+impl Write for HashWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct ArchiveReader<'a, R> {
+    inner: &'a mut R,
+}
+
+impl<R: Read> ArchiveReader<'_, R> {
+    fn read_u64(&mut self) -> Result<u64> {
+        let mut buf = [0u8; 8];
+        self.inner
+            .read_exact(&mut buf)
+            .context("Unexpected end of archive")?;
+        Ok(u64::from_le_bytes(buf))
+    }
+
+    fn read_token(&mut self) -> Result<Vec<u8>> {
+        let len = self.read_u64()?;
+        if len > MAX_TOKEN_LEN {
+            bail!("Archive token of {} bytes is too long", len);
+        }
+        let mut token = vec![0u8; len as usize];
+        self.inner
+            .read_exact(&mut token)
+            .context("Unexpected end of archive")?;
+        self.skip_padding(len)?;
+        Ok(token)
+    }
+
+    fn expect(&mut self, expected: &[u8]) -> Result<()> {
+        let token = self.read_token()?;
+        if token != expected {
+            bail!(
+                "Expected `{}` in archive, found `{}`",
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(&token)
+            );
+        }
+        Ok(())
+    }
+
+    fn skip_padding(&mut self, len: u64) -> Result<()> {
+        let mut buf = [0u8; 8];
+        let padding = &mut buf[..padding(len)];
+        self.inner
+            .read_exact(padding)
+            .context("Unexpected end of archive")?;
+        if padding.iter().any(|&b| b != 0) {
+            bail!("Non-zero padding in archive");
+        }
+        Ok(())
+    }
+
+    fn unpack_node(&mut self, dest: &Path) -> Result<()> {
+        match self.read_token()?.as_slice() {
+            b"regular" => {
+                let mode = match self.read_token()?.as_slice() {
+                    b"executable" => 0o755,
+                    b"plain" => 0o644,
+                    other => bail!(
+                        "Unknown file flag `{}` in archive",
+                        String::from_utf8_lossy(other)
+                    ),
+                };
+                let size = self.read_u64()?;
+                let mut file = fs::File::create(dest)
+                    .with_context(|| format!("Failed to create {}", dest.display()))?;
+                let copied = copy_chunked(&mut (&mut *self.inner).take(size), &mut file)?;
+                if copied != size {
+                    bail!("Unexpected end of archive");
+                }
+                self.skip_padding(size)?;
+                file.set_permissions(fs::Permissions::from_mode(mode))?;
+            }
+            b"symlink" => {
+                let target = self.read_token()?;
+                if target.is_empty() || target.contains(&0) {
+                    bail!("Invalid symlink target in archive");
+                }
+                symlink(OsStr::from_bytes(&target), dest)
+                    .with_context(|| format!("Failed to create {}", dest.display()))?;
+            }
+            b"directory" => {
+                fs::create_dir(dest)
+                    .with_context(|| format!("Failed to create {}", dest.display()))?;
+                let mut previous: Option<Vec<u8>> = None;
+                loop {
+                    match self.read_token()?.as_slice() {
+                        b"end" => break,
+                        b"entry" => {}
+                        other => bail!(
+                            "Expected `entry` or `end` in archive, found `{}`",
+                            String::from_utf8_lossy(other)
+                        ),
+                    }
+                    let name = self.read_token()?;
+                    validate_entry_name(&name)?;
+                    if previous.as_ref().is_some_and(|p| *p >= name) {
+                        bail!(
+                            "Directory entry `{}` is out of order or duplicated",
+                            String::from_utf8_lossy(&name)
+                        );
+                    }
+                    self.unpack_node(&dest.join(OsStr::from_bytes(&name)))?;
+                    previous = Some(name);
+                }
+                fs::set_permissions(dest, fs::Permissions::from_mode(0o755))?;
+            }
+            other => {
+                return Err(anyhow!(
+                    "Unknown node type `{}` in archive",
+                    String::from_utf8_lossy(other)
+                ))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn validate_entry_name(name: &[u8]) -> Result<()> {
+    if name.is_empty() || name == b"." || name == b".." || name.contains(&b'/') || name.contains(&0)
+    {
+        bail!(
+            "Unsafe directory entry `{}` in archive",
+            String::from_utf8_lossy(name)
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn tree(dir: &Path, name: &str) -> PathBuf {
+        let root = dir.join(name);
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::create_dir_all(root.join("share/empty")).unwrap();
+        fs::write(root.join("bin/tool"), "#!/bin/sh\n").unwrap();
+        fs::set_permissions(root.join("bin/tool"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(root.join("share/data"), "data").unwrap();
+        symlink("bin/tool", root.join("tool")).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_hash_is_deterministic() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tree(dir.path(), "a");
+        let b = tree(dir.path(), "b");
+        // Permission bits other than executable do not matter
+        fs::set_permissions(b.join("share/data"), fs::Permissions::from_mode(0o600)).unwrap();
+
+        assert_eq!(hash_path(&a).unwrap(), hash_path(&b).unwrap());
+    }
+
+    #[test]
+    fn test_hash_covers_metadata() {
+        let dir = tempfile::tempdir().unwrap();
+        let a = tree(dir.path(), "a");
+        let original = hash_path(&a).unwrap();
+
+        fs::set_permissions(a.join("share/data"), fs::Permissions::from_mode(0o755)).unwrap();
+        let executable = hash_path(&a).unwrap();
+        assert_ne!(original, executable);
+
+        fs::remove_file(a.join("tool")).unwrap();
+        symlink("share/data", a.join("tool")).unwrap();
+        assert_ne!(executable, hash_path(&a).unwrap());
+    }
+
+    #[test]
+    fn test_pack_unpack_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = tree(dir.path(), "src");
+        // Larger than one chunk and not a multiple of 8
+        let big: Vec<u8> = (0..CHUNK_SIZE + 13).map(|i| i as u8).collect();
+        fs::write(src.join("share/big"), &big).unwrap();
+
+        let mut archive = Vec::new();
+        pack(&src, &mut archive).unwrap();
+        assert_eq!(hash_bytes(&archive), hash_path(&src).unwrap());
+
+        let dest = dir.path().join("dest");
+        unpack(&mut archive.as_slice(), &dest).unwrap();
+        assert_eq!(hash_path(&dest).unwrap(), hash_path(&src).unwrap());
+        assert_eq!(fs::read(dest.join("share/big")).unwrap(), big);
+        assert!(dest.join("share/empty").is_dir());
+    }
+
+    #[test]
+    fn test_single_file_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        fs::write(&file, "hello").unwrap();
+
+        let mut archive = Vec::new();
+        pack(&file, &mut archive).unwrap();
+        let dest = dir.path().join("copy");
+        unpack(&mut archive.as_slice(), &dest).unwrap();
+        assert_eq!(fs::read_to_string(dest).unwrap(), "hello");
+        assert_eq!(hash_file(&file).unwrap(), hash_bytes(b"hello"));
+    }
+
+    #[test]
+    fn test_unpack_rejects_unsafe_archives() {
+        let dir = tempfile::tempdir().unwrap();
+
+        let mut escape = Vec::new();
+        write_token(&mut escape, ARCHIVE_MAGIC).unwrap();
+        write_token(&mut escape, b"directory").unwrap();
+        write_token(&mut escape, b"entry").unwrap();
+        write_token(&mut escape, b"..").unwrap();
+        assert!(unpack(&mut escape.as_slice(), &dir.path().join("escape")).is_err());
+
+        let src = tree(dir.path(), "src");
+        let mut trailing = Vec::new();
+        pack(&src, &mut trailing).unwrap();
+        trailing.push(0);
+        assert!(unpack(&mut trailing.as_slice(), &dir.path().join("trailing")).is_err());
+    }
+}
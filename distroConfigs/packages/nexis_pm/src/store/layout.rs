@@ -1,33 +1,149 @@
-use std::path::PathBuf;
+//! Store directory layout
+//!
+//! Every path inside the store is derived from its root, so a store can be
+//! opened anywhere (tests use a temporary directory):
+//!
+//! ```text
+//! <root>/packages/ab/cd/abcd1234...-name/
+//! <root>/files/ab/cd/abcd1234...
+//! <root>/generations/<id>/
+//! <root>/metadata.redb
+//! <root>/gc.lock
+//! <root>/version-cache.json
+//! <root>/downloads/
+//! <root>/.tmp/
+//! <root>/.trash/
+//! ```
+//!
+//! Objects are bucketed by the first hex digits of their hash, so object
+//! paths are only built for full store hashes; anything else is an error
+//! rather than a path outside the buckets.
 
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+
+use super::hash::is_store_hash;
+use crate::constants::HASH_PREFIX_LENGTH;
+
+/// Maps object hashes to paths inside a store root
+#[derive(Debug, Clone)]
 pub struct StoreLayout {
     root: PathBuf,
 }
 
 impl StoreLayout {
+    /// Create a layout rooted at `root`
     pub fn new(root: PathBuf) -> Self {
         Self { root }
     }
-    
+
+    /// Store root
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Package objects directory
+    pub fn packages_dir(&self) -> PathBuf {
+        self.root.join("packages")
+    }
+
+    /// File objects directory
+    pub fn files_dir(&self) -> PathBuf {
+        self.root.join("files")
+    }
+
+    /// Generations directory
+    pub fn generations_dir(&self) -> PathBuf {
+        self.root.join("generations")
+    }
+
+    /// Metadata database
+    pub fn database_path(&self) -> PathBuf {
+        self.root.join("metadata.redb")
+    }
+
+    /// Lock held shared while objects are added and exclusively by GC
+    pub fn lock_path(&self) -> PathBuf {
+        self.root.join("gc.lock")
+    }
+
+    /// Cached remote tag listings used by version resolution
+    pub fn version_cache_path(&self) -> PathBuf {
+        self.root.join("version-cache.json")
+    }
+
+    /// Content-addressed cache of fetched sources and artifacts
+    pub fn downloads_dir(&self) -> PathBuf {
+        self.root.join("downloads")
+    }
+
+    /// Staging directory for objects being added
+    ///
+    /// Lives on the same filesystem as the objects so staged trees can be
+    /// renamed into place atomically.
+    pub fn tmp_dir(&self) -> PathBuf {
+        self.root.join(".tmp")
+    }
+
+    /// Directory unreferenced objects are moved to before deletion
+    pub fn trash_dir(&self) -> PathBuf {
+        self.root.join(".trash")
+    }
+
+    /// Bucket directory holding every package object whose hash starts like `hash`
+    pub fn package_bucket(&self, hash: &str) -> Result<PathBuf> {
+        let (prefix1, prefix2) = prefixes(hash)?;
+        Ok(self.packages_dir().join(prefix1).join(prefix2))
+    }
+
     /// Get path for object: /nexis-store/ab/cd/abcd1234-name/
-    pub fn object_path(&self, hash: &str, name: &str) -> PathBuf {
-        let prefix1 = &hash[0..2];
-        let prefix2 = &hash[2..4];
-        self.root
-            .join("packages")
-            .join(prefix1)
-            .join(prefix2)
-            .join(format!("{}-{}", hash, name))
-    }
-    
-    /// Get path for file: /nexis-store/files/ab/cd/abcd1234.txt
-    pub fn file_path(&self, hash: &str) -> PathBuf {
-        let prefix1 = &hash[0..2];
-        let prefix2 = &hash[2..4];
-        self.root
-            .join("files")
-            .join(prefix1)
-            .join(prefix2)
-            .join(hash)
+    pub fn object_path(&self, hash: &str, name: &str) -> Result<PathBuf> {
+        Ok(self
+            .package_bucket(hash)?
+            .join(format!("{}-{}", hash, name)))
+    }
+
+    /// Get path for file: /nexis-store/files/ab/cd/abcd1234
+    pub fn file_path(&self, hash: &str) -> Result<PathBuf> {
+        let (prefix1, prefix2) = prefixes(hash)?;
+        Ok(self.files_dir().join(prefix1).join(prefix2).join(hash))
+    }
+}
+
+fn prefixes(hash: &str) -> Result<(&str, &str)> {
+    if !is_store_hash(hash) {
+        bail!("`{}` is not a store hash", hash);
+    }
+    Ok((
+        &hash[..HASH_PREFIX_LENGTH],
+        &hash[HASH_PREFIX_LENGTH..HASH_PREFIX_LENGTH * 2],
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_paths_need_store_hashes() {
+        let layout = StoreLayout::new(PathBuf::from("/store"));
+        let hash = "ab".repeat(32);
+        assert_eq!(
+            layout.file_path(&hash).unwrap(),
+            Path::new("/store/files/ab/ab").join(&hash)
+        );
+
+        for bad in [
+            "",
+            "a",
+            "abc",
+            &"zz".repeat(32),
+            &"ab".repeat(33),
+            "../../etc/passwd",
+        ] {
+            let err = layout.object_path(bad, "vim").unwrap_err();
+            assert!(err.to_string().contains("is not a store hash"), "{}", err);
+            assert!(layout.file_path(bad).is_err());
+        }
     }
 }
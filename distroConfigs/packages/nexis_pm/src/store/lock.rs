@@ -0,0 +1,96 @@
+//! Locking the store against garbage collection
+//!
+//! Objects enter the store before anything roots them: a build adds every
+//! package before registering the generation that roots them, and a
+//! profile is renamed into place before its generation is registered. A
+//! collection running in between would take them for garbage.
+//!
+//! Work that adds objects therefore holds a shared lock on
+//! `<store>/gc.lock` from the first object it adds until the last one is
+//! rooted, and [`GarbageCollector::collect`](super::gc::GarbageCollector::collect)
+//! holds it exclusively: a collection waits for running builds, and builds
+//! started during a collection wait for it. These are `flock(2)` locks, so
+//! they are released however the process exits.
+
+use anyhow::{Context, Result};
+use rustix::fs::{flock, FlockOperation};
+use rustix::io::Errno;
+use std::fs;
+use std::path::Path;
+use tracing::info;
+
+/// A lock on the store, released when dropped
+#[derive(Debug)]
+pub struct StoreLock {
+    _file: fs::File,
+}
+
+impl StoreLock {
+    /// Lock `path`, waiting for conflicting holders to let go
+    pub(super) fn acquire(path: &Path, exclusive: bool) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let (operation, waiting) = if exclusive {
+            (FlockOperation::LockExclusive, "builds using the store")
+        } else {
+            (FlockOperation::LockShared, "garbage collection")
+        };
+        let attempt = if exclusive {
+            FlockOperation::NonBlockingLockExclusive
+        } else {
+            FlockOperation::NonBlockingLockShared
+        };
+
+        match flock(&file, attempt) {
+            Ok(()) => {}
+            Err(Errno::WOULDBLOCK) => {
+                info!("Waiting for {} to finish", waiting);
+                flock(&file, operation)
+                    .map_err(std::io::Error::from)
+                    .with_context(|| format!("Failed to lock {}", path.display()))?;
+            }
+            Err(e) => {
+                return Err(std::io::Error::from(e))
+                    .with_context(|| format!("Failed to lock {}", path.display()))
+            }
+        }
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shared_locks_exclude_exclusive_ones() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gc.lock");
+        let try_exclusive = || {
+            let file = fs::File::open(&path).unwrap();
+            flock(&file, FlockOperation::NonBlockingLockExclusive)
+        };
+
+        let first = StoreLock::acquire(&path, false).unwrap();
+        let second = StoreLock::acquire(&path, false).unwrap();
+        assert_eq!(try_exclusive(), Err(Errno::WOULDBLOCK));
+
+        drop(first);
+        assert_eq!(try_exclusive(), Err(Errno::WOULDBLOCK));
+        drop(second);
+        assert_eq!(try_exclusive(), Ok(()));
+
+        let exclusive = StoreLock::acquire(&path, true).unwrap();
+        let file = fs::File::open(&path).unwrap();
+        assert_eq!(
+            flock(&file, FlockOperation::NonBlockingLockShared),
+            Err(Errno::WOULDBLOCK)
+        );
+        drop(exclusive);
+    }
+}
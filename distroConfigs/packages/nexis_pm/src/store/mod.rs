@@ -0,0 +1,25 @@
+//! Content-addressed package store
+//!
+//! Everything nexis installs lives under `/nexis-store`:
+//! - Directory layout and hash bucketing
+//! - Metadata database (redb)
+//! - Package and file object storage
+//! - XFS reflink copies
+//! - BLAKE3 hashing
+//! - Garbage collection, and the lock that keeps it off live objects
+//! - Metadata queries
+
+pub mod database;
+pub mod gc;
+pub mod hash;
+pub mod layout;
+pub mod lock;
+pub mod objects;
+pub mod query;
+pub mod reflink;
+
+// Re-export commonly used items
+pub use database::{StoreDatabase, StoreTransaction};
+pub use layout::StoreLayout;
+pub use lock::StoreLock;
+pub use objects::{ObjectKind, Store, StoreObject};
@@ -0,0 +1,468 @@
+//! Package and file object storage
+//!
+//! [`Store`] ties the [`StoreLayout`], the [`StoreDatabase`] and reflink
+//! copies together. Objects are never written in place: a new object is
+//! staged under `.tmp/`, hashed, and renamed into its final path with
+//! `RENAME_NOREPLACE`, so readers only ever see complete objects and an
+//! existing object is never overwritten. Adding content that is already in
+//! the store returns the existing object.
+
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+use rustix::fs::{renameat_with, RenameFlags, CWD};
+use rustix::io::Errno;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+use tracing::debug;
+use walkdir::WalkDir;
+
+use super::database::{FileMetadata, PackageMetadata, StoreDatabase};
+use super::hash::{hash_file, is_store_hash, object_hash, unpack};
+use super::layout::StoreLayout;
+use super::lock::StoreLock;
+use super::reflink::reflink_copy;
+
+/// Kind of store object
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectKind {
+    /// A directory tree under `packages/`
+    Package,
+    /// A single file under `files/`
+    File,
+}
+
+/// An object in the store
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoreObject {
+    /// BLAKE3 hash of the object's contents
+    pub hash: String,
+    /// Package name (`None` for file objects)
+    pub name: Option<String>,
+    /// Kind of object
+    pub kind: ObjectKind,
+    /// Location of the object in the store
+    pub path: PathBuf,
+}
+
+/// Content-addressed object store
+pub struct Store {
+    layout: StoreLayout,
+    db: StoreDatabase,
+}
+
+impl Store {
+    /// Open the store at `root`, creating its directories and database if needed
+    pub fn open(root: impl AsRef<Path>) -> Result<Self> {
+        let layout = StoreLayout::new(root.as_ref().to_path_buf());
+        for dir in [layout.packages_dir(), layout.files_dir(), layout.tmp_dir()] {
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create {}", dir.display()))?;
+        }
+        let db = StoreDatabase::open(&layout.database_path()).with_context(|| {
+            format!(
+                "Failed to open store database in {}",
+                layout.root().display()
+            )
+        })?;
+        Ok(Self { layout, db })
+    }
+
+    /// Store directory layout
+    pub fn layout(&self) -> &StoreLayout {
+        &self.layout
+    }
+
+    /// Store metadata database
+    pub fn database(&self) -> &StoreDatabase {
+        &self.db
+    }
+
+    /// Keep garbage collection away until the returned lock is dropped
+    ///
+    /// Objects are unreferenced from the moment they are added until a
+    /// generation roots them; hold this across both.
+    pub fn lock_shared(&self) -> Result<StoreLock> {
+        StoreLock::acquire(&self.layout.lock_path(), false)
+    }
+
+    /// Wait for every shared lock to be dropped and hold off new ones
+    pub fn lock_exclusive(&self) -> Result<StoreLock> {
+        StoreLock::acquire(&self.layout.lock_path(), true)
+    }
+
+    /// Add a directory tree as a package object named `name`
+    pub fn add_tree(&self, name: &str, src: &Path) -> Result<StoreObject> {
+        self.add_tree_referencing(name, src, &[])
+    }
+
+    /// Add a directory tree as a package object referencing `references`
+    ///
+    /// The references are recorded in the same transaction as the object.
+    pub fn add_tree_referencing(
+        &self,
+        name: &str,
+        src: &Path,
+        references: &[&str],
+    ) -> Result<StoreObject> {
+        validate_name(name)?;
+        if !fs::symlink_metadata(src)?.is_dir() {
+            bail!("{} is not a directory", src.display());
+        }
+
+        let staging = self.staging_dir(name)?;
+        let staged = staging.path().join(name);
+        let size = copy_tree(src, &staged)
+            .with_context(|| format!("Failed to stage {}", src.display()))?;
+        self.add_staged_tree(name, &staged, size, None, references)
+    }
+
+    /// Add a package object named `name` from its canonical archive
+    ///
+    /// The store is left untouched unless the unpacked tree, named `name`,
+    /// hashes to `expected`.
+    pub fn add_archive(
+        &self,
+        name: &str,
+        archive: &mut impl Read,
+        expected: &str,
+    ) -> Result<StoreObject> {
+        validate_name(name)?;
+        let staging = self.staging_dir(name)?;
+        let staged = staging.path().join(name);
+        unpack(archive, &staged).with_context(|| format!("Failed to unpack `{}`", name))?;
+        if !fs::symlink_metadata(&staged)?.is_dir() {
+            bail!("The archive of `{}` does not hold a directory", name);
+        }
+        let size = WalkDir::new(&staged)
+            .into_iter()
+            .map(|entry| {
+                let entry = entry?;
+                Ok(if entry.file_type().is_file() {
+                    entry.metadata()?.len()
+                } else {
+                    0
+                })
+            })
+            .sum::<Result<u64>>()?;
+        self.add_staged_tree(name, &staged, size, Some(expected), &[])
+    }
+
+    /// Add a single file as a file object
+    pub fn add_file(&self, src: &Path) -> Result<StoreObject> {
+        let staging = self.staging_dir("file")?;
+        let staged = staging.path().join("file");
+        reflink_copy(src, &staged).with_context(|| format!("Failed to stage {}", src.display()))?;
+        self.add_staged_file(&staged)
+    }
+
+    /// Add in-memory contents as a file object
+    pub fn add_bytes(&self, contents: &[u8]) -> Result<StoreObject> {
+        let staging = self.staging_dir("file")?;
+        let staged = staging.path().join("file");
+        fs::write(&staged, contents)?;
+        self.add_staged_file(&staged)
+    }
+
+    /// Look up an object by hash
+    pub fn get(&self, hash: &str) -> Result<Option<StoreObject>> {
+        // Not a hash at all (e.g. a package name given to `nexis query`)
+        if !is_store_hash(hash) {
+            return Ok(None);
+        }
+
+        let file_path = self.layout.file_path(hash)?;
+        if file_path.exists() {
+            return Ok(Some(StoreObject {
+                hash: hash.to_string(),
+                name: None,
+                kind: ObjectKind::File,
+                path: file_path,
+            }));
+        }
+
+        let name = match self.db.get_package(hash)? {
+            Some(metadata) => Some(metadata.name),
+            // Not recorded (e.g. a crash right after the rename): find it on disk
+            None => self.find_package_name(hash)?,
+        };
+        let Some(name) = name else {
+            return Ok(None);
+        };
+        let path = self.layout.object_path(hash, &name)?;
+        Ok(path.exists().then(|| StoreObject {
+            hash: hash.to_string(),
+            name: Some(name),
+            kind: ObjectKind::Package,
+            path,
+        }))
+    }
+
+    /// Whether an object with this hash is in the store
+    pub fn exists(&self, hash: &str) -> Result<bool> {
+        Ok(self.get(hash)?.is_some())
+    }
+
+    fn add_staged_tree(
+        &self,
+        name: &str,
+        staged: &Path,
+        size: u64,
+        expected: Option<&str>,
+        references: &[&str],
+    ) -> Result<StoreObject> {
+        let _lock = self.lock_shared()?;
+        // Hash the staged copy so later changes to the source cannot skew it
+        let hash = object_hash(name, staged)?;
+        if let Some(expected) = expected.filter(|&expected| expected != hash) {
+            bail!(
+                "Hash mismatch for `{}`: expected {}, got {}",
+                name,
+                expected,
+                hash
+            );
+        }
+        let path = self.layout.object_path(&hash, name)?;
+
+        if self.install(staged, &path)? {
+            debug!("Added {} to the store", path.display());
+        }
+        let recorded = self.db.get_package(&hash)?.is_some();
+        self.db.transaction(|txn| {
+            if !recorded {
+                txn.insert_package(
+                    &hash,
+                    &PackageMetadata {
+                        name: name.to_string(),
+                        size,
+                        added_at: Utc::now(),
+                    },
+                )?;
+            }
+            txn.add_references(&hash, references)
+        })?;
+
+        Ok(StoreObject {
+            hash,
+            name: Some(name.to_string()),
+            kind: ObjectKind::Package,
+            path,
+        })
+    }
+
+    fn add_staged_file(&self, staged: &Path) -> Result<StoreObject> {
+        let _lock = self.lock_shared()?;
+        // Store objects are read-only; only the executable bit is kept
+        let mode = fs::metadata(staged)?.permissions().mode();
+        let mode = if mode & 0o111 != 0 { 0o555 } else { 0o444 };
+        fs::set_permissions(staged, fs::Permissions::from_mode(mode))?;
+
+        let hash = hash_file(staged)?;
+        let path = self.layout.file_path(&hash)?;
+        if self.install(staged, &path)? {
+            debug!("Added {} to the store", path.display());
+        }
+        if self.db.get_file(&hash)?.is_none() {
+            self.db.insert_file(
+                &hash,
+                &FileMetadata {
+                    size: fs::metadata(&path)?.len(),
+                    added_at: Utc::now(),
+                },
+            )?;
+        }
+
+        Ok(StoreObject {
+            hash,
+            name: None,
+            kind: ObjectKind::File,
+            path,
+        })
+    }
+
+    /// Rename a staged object into place, returning false if it already existed
+    fn install(&self, staged: &Path, path: &Path) -> Result<bool> {
+        let bucket = path.parent().expect("object paths are inside a bucket");
+        fs::create_dir_all(bucket)?;
+
+        match renameat_with(CWD, staged, CWD, path, RenameFlags::NOREPLACE) {
+            Ok(()) => Ok(true),
+            Err(Errno::EXIST) => Ok(false),
+            Err(e) => Err(std::io::Error::from(e))
+                .with_context(|| format!("Failed to move object into {}", path.display())),
+        }
+    }
+
+    fn staging_dir(&self, name: &str) -> Result<TempDir> {
+        tempfile::Builder::new()
+            .prefix(&format!("{}-", name))
+            .tempdir_in(self.layout.tmp_dir())
+            .with_context(|| format!("Failed to create staging directory for {}", name))
+    }
+
+    fn find_package_name(&self, hash: &str) -> Result<Option<String>> {
+        let bucket = self.layout.package_bucket(hash)?;
+        let prefix = format!("{}-", hash);
+        let entries = match fs::read_dir(&bucket) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        for entry in entries {
+            let file_name = entry?.file_name();
+            if let Some(name) = file_name.to_str().and_then(|n| n.strip_prefix(&prefix)) {
+                return Ok(Some(name.to_string()));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Copy a tree with reflinks, returning the total size of its files
+pub(crate) fn copy_tree(src: &Path, dst: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in WalkDir::new(src).sort_by_file_name() {
+        let entry = entry?;
+        let target = dst.join(entry.path().strip_prefix(src)?);
+        let file_type = entry.file_type();
+
+        if file_type.is_dir() {
+            fs::create_dir(&target)?;
+        } else if file_type.is_symlink() {
+            symlink(fs::read_link(entry.path())?, &target)?;
+        } else if file_type.is_file() {
+            reflink_copy(entry.path(), &target)?;
+            size += entry.metadata()?.len();
+        } else {
+            bail!(
+                "{} is not a regular file, directory or symlink",
+                entry.path().display()
+            );
+        }
+    }
+    Ok(size)
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.contains('/') || name.starts_with('.') {
+        bail!("Invalid store object name `{}`", name);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(dir: &Path) -> PathBuf {
+        let src = dir.join("src");
+        fs::create_dir_all(src.join("bin")).unwrap();
+        fs::write(src.join("bin/hello"), "#!/bin/sh\necho hello\n").unwrap();
+        fs::set_permissions(src.join("bin/hello"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::write(src.join("README"), "hello").unwrap();
+        symlink("bin/hello", src.join("hello")).unwrap();
+        src
+    }
+
+    #[test]
+    fn test_add_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let src = tree(dir.path());
+
+        let object = store.add_tree("hello", &src).unwrap();
+        assert_eq!(
+            object.path,
+            store.layout().object_path(&object.hash, "hello").unwrap()
+        );
+        assert_eq!(
+            fs::read_to_string(object.path.join("README")).unwrap(),
+            "hello"
+        );
+        assert_eq!(
+            fs::read_link(object.path.join("hello")).unwrap(),
+            PathBuf::from("bin/hello")
+        );
+        let mode = fs::metadata(object.path.join("bin/hello"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_ne!(mode & 0o111, 0);
+
+        assert!(store.exists(&object.hash).unwrap());
+        assert_eq!(store.get(&object.hash).unwrap(), Some(object.clone()));
+        assert_eq!(
+            store
+                .database()
+                .get_package(&object.hash)
+                .unwrap()
+                .unwrap()
+                .size,
+            26
+        );
+
+        // Staging directories are cleaned up
+        assert_eq!(fs::read_dir(store.layout().tmp_dir()).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_existing_objects_are_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let src = tree(dir.path());
+
+        let first = store.add_tree("hello", &src).unwrap();
+        let marker = first.path.join("marker");
+        fs::write(&marker, "").unwrap();
+
+        let second = store.add_tree("hello", &src).unwrap();
+        assert_eq!(first, second);
+        assert!(marker.exists());
+    }
+
+    #[test]
+    fn test_same_tree_under_two_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let src = tree(dir.path());
+
+        let hello = store.add_tree("hello", &src).unwrap();
+        let greeter = store.add_tree("greeter", &src).unwrap();
+        assert_ne!(hello.hash, greeter.hash);
+        assert_eq!(store.get(&hello.hash).unwrap(), Some(hello.clone()));
+        assert_eq!(store.get(&greeter.hash).unwrap(), Some(greeter.clone()));
+        for object in [&hello, &greeter] {
+            let metadata = store.database().get_package(&object.hash).unwrap();
+            assert_eq!(metadata.unwrap().name, object.name.clone().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_add_file_and_bytes() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let src = dir.path().join("motd");
+        fs::write(&src, "welcome").unwrap();
+
+        let from_file = store.add_file(&src).unwrap();
+        let from_bytes = store.add_bytes(b"welcome").unwrap();
+        assert_eq!(from_file, from_bytes);
+        assert_eq!(
+            from_file.path,
+            store.layout().file_path(&from_file.hash).unwrap()
+        );
+        assert_eq!(from_file.kind, ObjectKind::File);
+        assert_eq!(store.get(&from_file.hash).unwrap(), Some(from_file));
+    }
+
+    #[test]
+    fn test_unknown_objects() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path()).unwrap();
+        assert!(!store.exists(&"ab".repeat(32)).unwrap());
+        assert!(!store.exists("../../etc").unwrap());
+        assert!(store.add_tree("../escape", dir.path()).is_err());
+    }
+}
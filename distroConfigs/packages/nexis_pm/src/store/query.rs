@@ -0,0 +1,312 @@
+//! Queries over store metadata
+//!
+//! Answers the questions that come up when an object refuses to be
+//! collected: what does it pull in, what pulls it in, and which generation
+//! keeps it alive. Everything is computed from the reference graph in the
+//! [`StoreDatabase`](super::database::StoreDatabase).
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+
+use super::objects::Store;
+
+/// Shortest hash prefix accepted in place of a full hash
+const MIN_PREFIX_LEN: usize = 4;
+
+/// A store object as shown by queries
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ObjectInfo {
+    /// Object hash
+    pub hash: String,
+    /// Package name (`None` for file objects)
+    pub name: Option<String>,
+    /// Size in bytes, as recorded when the object was added
+    pub size: u64,
+    /// Location in the store (`None` if the object is missing on disk)
+    pub path: Option<PathBuf>,
+}
+
+/// The runtime closure of an object
+#[derive(Debug, Clone, Serialize)]
+pub struct Closure {
+    /// The object and everything it references, directly or not
+    pub objects: Vec<ObjectInfo>,
+    /// Sum of the sizes of `objects`
+    pub total_size: u64,
+}
+
+/// A generation keeping an object alive, and through which references
+#[derive(Debug, Clone, Serialize)]
+pub struct RootChain {
+    /// Generation id
+    pub generation: u64,
+    /// Reference chain from the generation's root to the object
+    pub chain: Vec<ObjectInfo>,
+}
+
+/// Read-only queries against a [`Store`]
+pub struct StoreQuery<'a> {
+    store: &'a Store,
+}
+
+impl<'a> StoreQuery<'a> {
+    /// Create a query engine for `store`
+    pub fn new(store: &'a Store) -> Self {
+        Self { store }
+    }
+
+    /// Resolve a store path, full hash, unique hash prefix or package name
+    /// to a hash
+    ///
+    /// A name matching several objects resolves to the only live one, if
+    /// there is exactly one.
+    pub fn resolve(&self, spec: &str) -> Result<String> {
+        // A store path names its object as `<hash>-<name>`
+        let spec = match Path::new(spec).file_name().and_then(|f| f.to_str()) {
+            Some(file_name) if spec.contains('/') => {
+                file_name.split('-').next().unwrap_or(file_name)
+            }
+            _ => spec,
+        };
+        let db = self.store.database();
+        if self.store.exists(spec)? || db.refcount(spec)? > 0 {
+            return Ok(spec.to_string());
+        }
+
+        let packages = db.packages()?;
+        let mut candidates: Vec<&String> = packages
+            .iter()
+            .filter(|(hash, metadata)| {
+                metadata.name == spec || (spec.len() >= MIN_PREFIX_LEN && hash.starts_with(spec))
+            })
+            .map(|(hash, _)| hash)
+            .collect();
+
+        if candidates.len() > 1 {
+            let mut live = Vec::new();
+            for hash in &candidates {
+                if db.refcount(hash)? > 0 {
+                    live.push(*hash);
+                }
+            }
+            if live.len() == 1 {
+                candidates = live;
+            }
+        }
+
+        match candidates.as_slice() {
+            [hash] => Ok((*hash).clone()),
+            [] => bail!("No store object matches `{}`", spec),
+            many => bail!(
+                "`{}` is ambiguous; it matches {}",
+                spec,
+                many.iter()
+                    .map(|h| h.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    /// Describe a single object
+    pub fn info(&self, hash: &str) -> Result<ObjectInfo> {
+        let db = self.store.database();
+        let (name, size) = match db.get_package(hash)? {
+            Some(metadata) => (Some(metadata.name), metadata.size),
+            None => (None, db.get_file(hash)?.map_or(0, |m| m.size)),
+        };
+        let object = self.store.get(hash)?;
+        Ok(ObjectInfo {
+            hash: hash.to_string(),
+            name: name.or_else(|| object.as_ref().and_then(|o| o.name.clone())),
+            size,
+            path: object.map(|o| o.path),
+        })
+    }
+
+    /// Everything `hash` needs at runtime, itself included
+    pub fn closure(&self, hash: &str) -> Result<Closure> {
+        let db = self.store.database();
+        let mut seen = BTreeSet::new();
+        let mut pending = vec![hash.to_string()];
+        while let Some(hash) = pending.pop() {
+            if seen.insert(hash.clone()) {
+                pending.extend(db.references(&hash)?);
+            }
+        }
+
+        let mut objects = seen
+            .iter()
+            .map(|hash| self.info(hash))
+            .collect::<Result<Vec<_>>>()?;
+        objects.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.name.cmp(&b.name)));
+        let total_size = objects.iter().map(|o| o.size).sum();
+        Ok(Closure {
+            objects,
+            total_size,
+        })
+    }
+
+    /// Objects that reference `hash` directly
+    pub fn referrers(&self, hash: &str) -> Result<Vec<ObjectInfo>> {
+        self.store
+            .database()
+            .referrers(hash)?
+            .iter()
+            .map(|hash| self.info(hash))
+            .collect()
+    }
+
+    /// Shortest reference chain from `from` to `to`, if `from` depends on `to`
+    pub fn why_depends(&self, from: &str, to: &str) -> Result<Option<Vec<ObjectInfo>>> {
+        match self.path(from, to)? {
+            Some(chain) => Ok(Some(
+                chain
+                    .iter()
+                    .map(|hash| self.info(hash))
+                    .collect::<Result<_>>()?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Generations keeping `hash` alive, with the chain from each one's root
+    pub fn roots(&self, hash: &str) -> Result<Vec<RootChain>> {
+        let mut chains = Vec::new();
+        for (generation, roots) in self.store.database().roots()? {
+            let mut shortest: Option<Vec<String>> = None;
+            for root in &roots {
+                if let Some(path) = self.path(root, hash)? {
+                    if shortest.as_ref().is_none_or(|s| path.len() < s.len()) {
+                        shortest = Some(path);
+                    }
+                }
+            }
+            if let Some(path) = shortest {
+                chains.push(RootChain {
+                    generation,
+                    chain: path
+                        .iter()
+                        .map(|hash| self.info(hash))
+                        .collect::<Result<_>>()?,
+                });
+            }
+        }
+        Ok(chains)
+    }
+
+    /// Breadth-first search over references
+    fn path(&self, from: &str, to: &str) -> Result<Option<Vec<String>>> {
+        let db = self.store.database();
+        let mut parents: HashMap<String, Option<String>> = HashMap::new();
+        let mut queue = VecDeque::from([from.to_string()]);
+        parents.insert(from.to_string(), None);
+
+        while let Some(hash) = queue.pop_front() {
+            if hash == to {
+                let mut path = vec![hash];
+                while let Some(Some(parent)) = parents.get(path.last().expect("path is non-empty"))
+                {
+                    path.push(parent.clone());
+                }
+                path.reverse();
+                return Ok(Some(path));
+            }
+            for reference in db.references(&hash)? {
+                if !parents.contains_key(&reference) {
+                    parents.insert(reference.clone(), Some(hash.clone()));
+                    queue.push_back(reference);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::StoreObject;
+    use std::fs;
+    use std::path::Path;
+
+    fn package(store: &Store, dir: &Path, name: &str, size: usize) -> StoreObject {
+        let src = dir.join(name);
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("data"), "x".repeat(size)).unwrap();
+        store.add_tree(name, &src).unwrap()
+    }
+
+    /// app → gtk → glib, app → glib, tool → glib; generation 1 roots app
+    fn fixture() -> (tempfile::TempDir, Store, [StoreObject; 4]) {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        let app = package(&store, dir.path(), "app", 10);
+        let gtk = package(&store, dir.path(), "gtk", 200);
+        let glib = package(&store, dir.path(), "glib", 30);
+        let tool = package(&store, dir.path(), "tool", 1);
+        store
+            .database()
+            .transaction(|txn| {
+                txn.add_references(&app.hash, &[&gtk.hash, &glib.hash])?;
+                txn.add_references(&gtk.hash, &[&glib.hash])?;
+                txn.add_references(&tool.hash, &[&glib.hash])?;
+                txn.add_root(1, &app.hash)
+            })
+            .unwrap();
+        (dir, store, [app, gtk, glib, tool])
+    }
+
+    fn names(objects: &[ObjectInfo]) -> Vec<&str> {
+        objects.iter().map(|o| o.name.as_deref().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_closure_is_sorted_by_size() {
+        let (_dir, store, [app, ..]) = fixture();
+        let closure = StoreQuery::new(&store).closure(&app.hash).unwrap();
+        assert_eq!(names(&closure.objects), ["gtk", "glib", "app"]);
+        assert_eq!(closure.total_size, 240);
+    }
+
+    #[test]
+    fn test_referrers_and_why_depends() {
+        let (_dir, store, [app, gtk, glib, tool]) = fixture();
+        let query = StoreQuery::new(&store);
+
+        let referrers = query.referrers(&glib.hash).unwrap();
+        let mut referrers = names(&referrers);
+        referrers.sort();
+        assert_eq!(referrers, ["app", "gtk", "tool"]);
+
+        let chain = query.why_depends(&app.hash, &glib.hash).unwrap().unwrap();
+        assert_eq!(names(&chain), ["app", "glib"]);
+        assert!(query.why_depends(&tool.hash, &gtk.hash).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_roots() {
+        let (_dir, store, [_, gtk, _, tool]) = fixture();
+        let query = StoreQuery::new(&store);
+
+        let roots = query.roots(&gtk.hash).unwrap();
+        assert_eq!(roots.len(), 1);
+        assert_eq!(roots[0].generation, 1);
+        assert_eq!(names(&roots[0].chain), ["app", "gtk"]);
+        assert!(query.roots(&tool.hash).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_resolve() {
+        let (_dir, store, [app, ..]) = fixture();
+        let query = StoreQuery::new(&store);
+        assert_eq!(query.resolve("app").unwrap(), app.hash);
+        assert_eq!(query.resolve(&app.hash[..8]).unwrap(), app.hash);
+        assert_eq!(query.resolve(&app.hash).unwrap(), app.hash);
+        let path = app.path.display().to_string();
+        assert_eq!(query.resolve(&path).unwrap(), app.hash);
+        assert!(query.resolve("missing").is_err());
+    }
+}
@@ -1,10 +1,16 @@
-use reflink_copy::reflink;
 use anyhow::Result;
+use reflink_copy::reflink;
+use std::path::Path;
 
 pub fn reflink_copy(src: &Path, dst: &Path) -> Result<()> {
     match reflink(src, dst) {
         Ok(_) => Ok(()),
-        Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::Unsupported | std::io::ErrorKind::InvalidInput
+            ) =>
+        {
             // Fallback to regular copy if reflink not supported
             std::fs::copy(src, dst)?;
             Ok(())
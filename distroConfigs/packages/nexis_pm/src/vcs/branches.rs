@@ -0,0 +1,15 @@
+//! Branch selection
+
+use super::git::{RemoteRef, RemoteRefs};
+use crate::constants::DEFAULT_BRANCHES;
+
+/// The branch to track when a repository has no usable version tags
+///
+/// Prefers the branch the remote's `HEAD` points at, then the first of
+/// [`DEFAULT_BRANCHES`] that exists.
+pub fn default_branch(refs: &RemoteRefs) -> Option<&RemoteRef> {
+    refs.head
+        .as_deref()
+        .and_then(|head| refs.branch(head))
+        .or_else(|| DEFAULT_BRANCHES.iter().find_map(|name| refs.branch(name)))
+}
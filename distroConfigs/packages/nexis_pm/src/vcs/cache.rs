@@ -0,0 +1,194 @@
+//! Cached remote listings
+//!
+//! Listing a remote is the only network access version resolution needs, so
+//! the listings are kept in a JSON file under the store:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "remotes": {
+//!     "https://github.com/madler/zlib.git": {
+//!       "tags": [{ "name": "v1.3.1", "commit": "51b7f2ab..." }],
+//!       "branches": [{ "name": "develop", "commit": "ef24c4c7..." }],
+//!       "head": "develop",
+//!       "fetched_at": "2026-10-16T09:12:44Z"
+//!     }
+//!   }
+//! }
+//! ```
+//!
+//! A listing younger than [`VERSION_CACHE_DURATION_HOURS`] is used instead
+//! of contacting the remote; offline resolution uses listings of any age.
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use tracing::warn;
+
+use super::git::RemoteRefs;
+use crate::constants::VERSION_CACHE_DURATION_HOURS;
+
+/// Current cache format version
+const CACHE_VERSION: u32 = 1;
+
+/// A remote listing and when it was taken
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedRefs {
+    /// What the remote advertised
+    #[serde(flatten)]
+    pub refs: RemoteRefs,
+    /// When the remote was listed
+    pub fetched_at: DateTime<Utc>,
+}
+
+impl CachedRefs {
+    /// Whether the listing is younger than `max_age`
+    pub fn is_fresh(&self, max_age: Duration) -> bool {
+        Utc::now() - self.fetched_at < max_age
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheFile {
+    version: u32,
+    #[serde(default)]
+    remotes: BTreeMap<String, CachedRefs>,
+}
+
+/// Persistent cache of remote listings, keyed by source URL
+#[derive(Debug, Clone)]
+pub struct RefCache {
+    path: PathBuf,
+    remotes: BTreeMap<String, CachedRefs>,
+}
+
+impl RefCache {
+    /// Load the cache at `path`
+    ///
+    /// A missing, corrupt or outdated cache is treated as empty; it only
+    /// ever saves network round-trips.
+    pub fn load(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let remotes = match fs::read_to_string(&path) {
+            Ok(content) => match serde_json::from_str::<CacheFile>(&content) {
+                Ok(file) if file.version == CACHE_VERSION => file.remotes,
+                Ok(file) => {
+                    warn!(
+                        "Ignoring version cache {} in format {}",
+                        path.display(),
+                        file.version
+                    );
+                    BTreeMap::new()
+                }
+                Err(e) => {
+                    warn!("Ignoring corrupt version cache {}: {}", path.display(), e);
+                    BTreeMap::new()
+                }
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        Ok(Self { path, remotes })
+    }
+
+    /// Cache file location
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The cached listing of `url`, however old
+    pub fn get(&self, url: &str) -> Option<&CachedRefs> {
+        self.remotes.get(url)
+    }
+
+    /// The cached listing of `url`, if younger than the cache duration
+    pub fn fresh(&self, url: &str) -> Option<&RemoteRefs> {
+        let max_age = Duration::hours(VERSION_CACHE_DURATION_HOURS as i64);
+        self.get(url)
+            .filter(|cached| cached.is_fresh(max_age))
+            .map(|cached| &cached.refs)
+    }
+
+    /// Record a listing of `url` taken just now
+    pub fn insert(&mut self, url: &str, refs: RemoteRefs) {
+        self.remotes.insert(
+            url.to_string(),
+            CachedRefs {
+                refs,
+                fetched_at: Utc::now(),
+            },
+        );
+    }
+
+    /// Write the cache back atomically
+    pub fn save(&self) -> Result<()> {
+        let file = CacheFile {
+            version: CACHE_VERSION,
+            remotes: self.remotes.clone(),
+        };
+        let content = serde_json::to_string_pretty(&file)?;
+
+        let dir = self.path.parent().unwrap_or(Path::new("."));
+        fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        tmp.write_all(content.as_bytes())?;
+        tmp.persist(&self.path)
+            .with_context(|| format!("Failed to write {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vcs::RemoteRef;
+
+    fn refs() -> RemoteRefs {
+        RemoteRefs {
+            tags: vec![RemoteRef {
+                name: "v1.0.0".into(),
+                commit: "a".repeat(40),
+            }],
+            branches: vec![],
+            head: Some("main".into()),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("store/version-cache.json");
+
+        let mut cache = RefCache::load(&path).unwrap();
+        assert!(cache.get("https://example.org/a.git").is_none());
+        cache.insert("https://example.org/a.git", refs());
+        cache.save().unwrap();
+
+        let cache = RefCache::load(&path).unwrap();
+        assert_eq!(cache.fresh("https://example.org/a.git"), Some(&refs()));
+    }
+
+    #[test]
+    fn test_stale_entries_are_not_fresh() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = RefCache::load(dir.path().join("cache.json")).unwrap();
+        cache.insert("url", refs());
+        cache.remotes.get_mut("url").unwrap().fetched_at -=
+            Duration::hours(VERSION_CACHE_DURATION_HOURS as i64 + 1);
+
+        assert!(cache.fresh("url").is_none());
+        assert!(cache.get("url").is_some());
+    }
+
+    #[test]
+    fn test_corrupt_cache_is_empty() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+        fs::write(&path, "{ not json").unwrap();
+        assert!(RefCache::load(&path).unwrap().get("url").is_none());
+    }
+}
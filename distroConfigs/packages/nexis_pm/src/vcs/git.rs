@@ -0,0 +1,231 @@
+//! Git remote operations
+//!
+//! Version resolution only needs to know what a remote advertises, so
+//! [`list_remote`] talks the fetch protocol through gix without cloning
+//! anything. [`checkout`] fetches into a bare mirror and writes out the tree
+//! of a single commit, which is all a build needs. Local paths and `file://`
+//! URLs work the same as network remotes, which is what the tests rely on.
+
+use anyhow::{bail, Context, Result};
+use gix::bstr::ByteSlice;
+use gix::object::tree::EntryKind;
+use gix::remote::Direction;
+use rustix::fs::{utimensat, AtFlags, Timespec, Timestamps, CWD};
+use serde::{Deserialize, Serialize};
+use std::ffi::OsStr;
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{symlink, PermissionsExt};
+use std::path::{Component, Path};
+use std::sync::atomic::{AtomicBool, Ordering};
+use walkdir::WalkDir;
+
+use crate::constants::{DEFAULT_EXEC_MODE, DEFAULT_FILE_MODE};
+
+/// A branch or tag advertised by a remote
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteRef {
+    /// Short name (`v1.2.0`, `main`)
+    pub name: String,
+    /// Commit the ref points at, with annotated tags peeled
+    pub commit: String,
+}
+
+/// Branches and tags advertised by a remote
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteRefs {
+    /// Tags (`refs/tags/*`)
+    pub tags: Vec<RemoteRef>,
+    /// Branches (`refs/heads/*`)
+    pub branches: Vec<RemoteRef>,
+    /// Branch the remote's `HEAD` points at, if advertised
+    pub head: Option<String>,
+}
+
+impl RemoteRefs {
+    /// Look up a tag by short name
+    pub fn tag(&self, name: &str) -> Option<&RemoteRef> {
+        self.tags.iter().find(|r| r.name == name)
+    }
+
+    /// Look up a branch by short name
+    pub fn branch(&self, name: &str) -> Option<&RemoteRef> {
+        self.branches.iter().find(|r| r.name == name)
+    }
+
+    /// Expand an abbreviated commit to a full SHA, if some ref points at it
+    pub fn expand_commit(&self, prefix: &str) -> Option<&str> {
+        let prefix = prefix.to_ascii_lowercase();
+        self.tags
+            .iter()
+            .chain(&self.branches)
+            .map(|r| r.commit.as_str())
+            .find(|commit| commit.starts_with(&prefix))
+    }
+}
+
+/// List the branches and tags of the repository at `url`
+///
+/// This performs blocking network I/O.
+pub fn list_remote(url: &str) -> Result<RemoteRefs> {
+    // gix needs a repository to hang the remote off; nothing is written to it
+    let scratch = tempfile::tempdir()?;
+    let repo = gix::init_bare(scratch.path())?;
+    let remote = repo
+        .remote_at(url)
+        .with_context(|| format!("Invalid repository URL `{}`", url))?
+        .with_refspecs(
+            [
+                "HEAD",
+                "+refs/heads/*:refs/heads/*",
+                "+refs/tags/*:refs/tags/*",
+            ],
+            Direction::Fetch,
+        )?;
+    let ref_map = remote
+        .connect(Direction::Fetch)
+        .with_context(|| format!("Failed to connect to {}", url))?
+        .ref_map(gix::progress::Discard, Default::default())
+        .with_context(|| format!("Failed to list refs of {}", url))?;
+
+    let mut refs = RemoteRefs::default();
+    for advertised in &ref_map.remote_refs {
+        if let gix::protocol::handshake::Ref::Symbolic {
+            full_ref_name,
+            target,
+            ..
+        } = advertised
+        {
+            if *full_ref_name == "HEAD" {
+                refs.head = target
+                    .to_str()
+                    .ok()
+                    .and_then(|t| t.strip_prefix("refs/heads/"))
+                    .map(str::to_string);
+            }
+        }
+
+        let (name, target, peeled) = advertised.unpack();
+        let Some(commit) = peeled.or(target) else {
+            continue;
+        };
+        let Ok(name) = name.to_str() else {
+            continue;
+        };
+        let commit = commit.to_hex().to_string();
+        if let Some(tag) = name.strip_prefix("refs/tags/") {
+            refs.tags.push(RemoteRef {
+                name: tag.to_string(),
+                commit,
+            });
+        } else if let Some(branch) = name.strip_prefix("refs/heads/") {
+            refs.branches.push(RemoteRef {
+                name: branch.to_string(),
+                commit,
+            });
+        }
+    }
+    Ok(refs)
+}
+
+/// Write the tree of `commit` in the repository at `url` to `dest`
+///
+/// `mirror` is a bare repository kept between calls; the remote is only
+/// fetched from when the mirror does not have the commit yet. Submodules are
+/// left as empty directories, as git does. Like `git archive`, every path
+/// gets the commit time as its modification time, so builds can derive
+/// `SOURCE_DATE_EPOCH` from the tree. This performs blocking network I/O;
+/// setting `interrupt` stops it, failing the checkout.
+pub fn checkout(
+    url: &str,
+    commit: &str,
+    mirror: &Path,
+    dest: &Path,
+    interrupt: &AtomicBool,
+) -> Result<()> {
+    let repo = if mirror.exists() {
+        gix::open(mirror).with_context(|| format!("Failed to open {}", mirror.display()))?
+    } else {
+        if let Some(parent) = mirror.parent() {
+            fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        gix::init_bare(mirror).with_context(|| format!("Failed to create {}", mirror.display()))?
+    };
+    let id = gix::ObjectId::from_hex(commit.as_bytes())
+        .with_context(|| format!("Invalid commit `{}`", commit))?;
+
+    if repo.find_object(id).is_err() {
+        let remote = repo
+            .remote_at(url)
+            .with_context(|| format!("Invalid repository URL `{}`", url))?
+            .with_refspecs(
+                ["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"],
+                Direction::Fetch,
+            )?;
+        remote
+            .connect(Direction::Fetch)
+            .with_context(|| format!("Failed to connect to {}", url))?
+            .prepare_fetch(gix::progress::Discard, Default::default())
+            .with_context(|| format!("Failed to list refs of {}", url))?
+            .receive(gix::progress::Discard, interrupt)
+            .with_context(|| format!("Failed to fetch {}", url))?;
+    }
+
+    let commit_object = repo
+        .find_object(id)
+        .with_context(|| format!("{} has no commit {}", url, commit))?
+        .try_into_commit()
+        .with_context(|| format!("{} is not a commit", commit))?;
+    let time = commit_object.time()?.seconds;
+    let tree = commit_object.tree()?;
+
+    fs::create_dir_all(dest).with_context(|| format!("Failed to create {}", dest.display()))?;
+    for entry in tree.traverse().breadthfirst.files()? {
+        if interrupt.load(Ordering::Relaxed) {
+            bail!("Checkout of {} was interrupted", url);
+        }
+        let relative = Path::new(OsStr::from_bytes(&entry.filepath));
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            bail!("Unsafe path `{}` in commit {}", relative.display(), commit);
+        }
+        let path = dest.join(relative);
+
+        match entry.mode.kind() {
+            EntryKind::Tree | EntryKind::Commit => fs::create_dir(&path)?,
+            EntryKind::Blob | EntryKind::BlobExecutable => {
+                let blob = repo.find_object(entry.oid)?;
+                fs::write(&path, &blob.data)?;
+                let mode = if entry.mode.kind() == EntryKind::BlobExecutable {
+                    DEFAULT_EXEC_MODE
+                } else {
+                    DEFAULT_FILE_MODE
+                };
+                fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+            }
+            EntryKind::Link => {
+                let blob = repo.find_object(entry.oid)?;
+                symlink(OsStr::from_bytes(&blob.data), &path)?;
+            }
+        }
+    }
+
+    // Children first, since writing into a directory changes its time
+    let timestamp = Timespec {
+        tv_sec: time,
+        tv_nsec: 0,
+    };
+    let times = Timestamps {
+        last_access: timestamp,
+        last_modification: timestamp,
+    };
+    for entry in WalkDir::new(dest).contents_first(true) {
+        let entry = entry?;
+        utimensat(CWD, entry.path(), &times, AtFlags::SYMLINK_NOFOLLOW)
+            .with_context(|| format!("Failed to set the time of {}", entry.path().display()))?;
+    }
+    Ok(())
+}
@@ -0,0 +1,17 @@
+//! Version control integration
+//!
+//! Read-only access to git remotes for version resolution:
+//! - Listing remote branches and tags (gix)
+//! - Persistent cache of remote listings
+//! - Version tag parsing
+//! - Default branch selection
+
+pub mod branches;
+pub mod cache;
+pub mod git;
+pub mod tags;
+
+// Re-export commonly used items
+pub use cache::RefCache;
+pub use git::{list_remote, RemoteRef, RemoteRefs};
+pub use tags::VersionTag;
@@ -0,0 +1,121 @@
+//! Version tags
+//!
+//! Projects spell release tags differently (`v1.2.0`, `release-1.2`,
+//! `openssl-3.0.1`). Tags are matched against [`TAG_PATTERNS`] and the
+//! `{version}` part is parsed leniently: missing minor and patch components
+//! default to zero, so `v1.2` is version 1.2.0.
+
+use semver::Version;
+
+use super::git::RemoteRef;
+use crate::constants::TAG_PATTERNS;
+
+/// A tag that names a version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionTag {
+    /// Parsed version
+    pub version: Version,
+    /// Tag name as advertised
+    pub tag: String,
+    /// Commit the tag points at
+    pub commit: String,
+}
+
+/// Parse the version out of a tag of package `name`
+pub fn parse_tag(tag: &str, name: &str) -> Option<Version> {
+    match_pattern(tag, name).map(|(_, version)| version)
+}
+
+/// Parse a version, accepting `1` and `1.2` as `1.0.0` and `1.2.0`
+pub fn parse_version(version: &str) -> Option<Version> {
+    if let Ok(version) = Version::parse(version) {
+        return Some(version);
+    }
+    let split = version.find(['-', '+']).unwrap_or(version.len());
+    let (core, rest) = version.split_at(split);
+    let components = core.split('.').count();
+    if !(1..3).contains(&components) {
+        return None;
+    }
+    let padded = format!("{}{}{}", core, ".0".repeat(3 - components), rest);
+    Version::parse(&padded).ok()
+}
+
+/// Version tags among `tags`, sorted by ascending version
+///
+/// When several tags name the same version (`v1.0.0` and `1.0.0`), the one
+/// matching the earliest pattern wins.
+pub fn version_tags(tags: &[RemoteRef], name: &str) -> Vec<VersionTag> {
+    let mut versions: Vec<(usize, VersionTag)> = tags
+        .iter()
+        .filter_map(|tag| {
+            let (rank, version) = match_pattern(&tag.name, name)?;
+            Some((
+                rank,
+                VersionTag {
+                    version,
+                    tag: tag.name.clone(),
+                    commit: tag.commit.clone(),
+                },
+            ))
+        })
+        .collect();
+    versions.sort_by(|(rank_a, a), (rank_b, b)| {
+        a.version
+            .cmp(&b.version)
+            .then(rank_a.cmp(rank_b))
+            .then_with(|| a.tag.cmp(&b.tag))
+    });
+    versions.dedup_by(|(_, later), (_, kept)| later.version == kept.version);
+    versions.into_iter().map(|(_, tag)| tag).collect()
+}
+
+/// The first pattern `tag` matches (by index), and the version it names
+fn match_pattern(tag: &str, name: &str) -> Option<(usize, Version)> {
+    TAG_PATTERNS.iter().enumerate().find_map(|(rank, pattern)| {
+        let pattern = pattern.replace("{name}", name);
+        let (prefix, suffix) = pattern.split_once("{version}")?;
+        let version = tag.strip_prefix(prefix)?.strip_suffix(suffix)?;
+        Some((rank, parse_version(version)?))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tag(name: &str) -> RemoteRef {
+        RemoteRef {
+            name: name.to_string(),
+            commit: format!("{:0>40}", name.len()),
+        }
+    }
+
+    #[test]
+    fn test_parse_tag_patterns() {
+        let v = |s| Some(Version::parse(s).unwrap());
+        assert_eq!(parse_tag("v1.2.3", "zlib"), v("1.2.3"));
+        assert_eq!(parse_tag("1.2.3", "zlib"), v("1.2.3"));
+        assert_eq!(parse_tag("release-2.0", "zlib"), v("2.0.0"));
+        assert_eq!(parse_tag("zlib-1.3", "zlib"), v("1.3.0"));
+        assert_eq!(parse_tag("version-4", "zlib"), v("4.0.0"));
+        assert_eq!(parse_tag("v2.0.0-rc.1", "zlib"), v("2.0.0-rc.1"));
+        assert_eq!(parse_tag("openssl-1.3", "zlib"), None);
+        assert_eq!(parse_tag("nightly", "zlib"), None);
+        assert_eq!(parse_tag("v1.2.3.4", "zlib"), None);
+    }
+
+    #[test]
+    fn test_version_tags_sorted_and_deduplicated() {
+        let tags = [
+            tag("1.0.0"),
+            tag("v2.0"),
+            tag("v1.0.0"),
+            tag("docs"),
+            tag("v1.5.0"),
+        ];
+        let versions = version_tags(&tags, "pkg");
+        let names: Vec<&str> = versions.iter().map(|t| t.tag.as_str()).collect();
+        assert_eq!(names, ["v1.0.0", "v1.5.0", "v2.0"]);
+    }
+}
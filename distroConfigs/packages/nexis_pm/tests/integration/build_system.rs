@@ -0,0 +1,85 @@
+//! Locking and building packages end to end
+
+use nexis_pm::build::Executor;
+use nexis_pm::config::Package;
+use nexis_pm::packages::version::{has_git_source, lock_by_hash};
+use nexis_pm::packages::{BuildJob, Fetcher, ParallelBuilder, Pipeline};
+use nexis_pm::store::Store;
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+/// A release tarball of `hello` wrapping its tree in `hello-<version>/`
+fn tarball(dir: &Path, version: &str) {
+    let mut tar = tar::Builder::new(flate2::write::GzEncoder::new(
+        Vec::new(),
+        flate2::Compression::default(),
+    ));
+    let mut header = tar::Header::new_gnu();
+    header.set_size(6);
+    header.set_mode(0o644);
+    header.set_cksum();
+    tar.append_data(
+        &mut header,
+        format!("hello-{}/hello.txt", version),
+        &b"hello\n"[..],
+    )
+    .unwrap();
+    let archive = tar.into_inner().unwrap().finish().unwrap();
+    fs::write(dir.join(format!("hello-{}.tar.gz", version)), archive).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_lock_and_build_tarball_source() {
+    let dir = tempfile::tempdir().unwrap();
+    tarball(dir.path(), "1.0.0");
+    let package: Package = toml::from_str(&format!(
+        r#"name = "hello"
+version = "1.0.0"
+source = "file://{}/hello-{{version}}.tar.gz"
+[recipe]
+install = ["read greeting < hello.txt", "echo \"$greeting, world\" > $out/hello.txt"]
+"#,
+        dir.path().display()
+    ))
+    .unwrap();
+    assert!(!has_git_source(&package));
+
+    let fetcher = Fetcher::new(dir.path().join("downloads")).unwrap();
+    let locked = lock_by_hash(&package, &fetcher).await.unwrap();
+    assert_eq!(
+        locked.resolved,
+        format!("file://{}/hello-1.0.0.tar.gz", dir.path().display())
+    );
+    assert_eq!(locked.rev, None);
+    assert!(locked.source_hash.is_some());
+
+    // Hashes are required, as under `build --locked`
+    let pipeline = Pipeline::new(fetcher).with_require_hashes(true);
+    let acquired = pipeline.acquire(&package, &locked).await.unwrap();
+    let store = Arc::new(Store::open(dir.path().join("store")).unwrap());
+    let builder = ParallelBuilder::new(store, dir.path(), 1)
+        .unwrap()
+        .with_executor(Executor::new(dir.path().join("logs")));
+    let job = BuildJob {
+        package: package.clone(),
+        acquired,
+        dependencies: Vec::new(),
+        references: Vec::new(),
+    };
+    let outcomes = tokio::task::block_in_place(|| builder.build_packages(vec![job]));
+    let built = outcomes[0].1.built().unwrap();
+    assert_eq!(
+        fs::read_to_string(built.object.path.join("hello.txt")).unwrap(),
+        "hello, world\n"
+    );
+
+    // A tarball that changes behind its URL no longer matches the lock
+    fs::write(dir.path().join("hello-1.0.0.tar.gz"), "tampered").unwrap();
+    let fetcher = Fetcher::new(dir.path().join("other-downloads")).unwrap();
+    let err = Pipeline::new(fetcher)
+        .acquire(&package, &locked)
+        .await
+        .unwrap_err();
+    assert!(format!("{:#}", err).contains("Hash mismatch"));
+}
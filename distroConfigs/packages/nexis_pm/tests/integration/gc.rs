@@ -0,0 +1,240 @@
+//! Garbage collection against a temporary store
+
+use chrono::{Duration, Utc};
+use nexis_pm::store::gc::{GarbageCollector, GcOptions};
+use nexis_pm::store::{Store, StoreObject};
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+struct Fixture {
+    dir: TempDir,
+    store: Store,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Store::open(dir.path().join("store")).unwrap();
+        Self { dir, store }
+    }
+
+    fn package(&self, name: &str, contents: &str) -> StoreObject {
+        let src = self.dir.path().join("src").join(name);
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("data"), contents).unwrap();
+        self.store.add_tree(name, &src).unwrap()
+    }
+
+    /// Register a generation created `days_ago` that roots `objects`
+    fn generation(&self, id: u64, days_ago: i64, objects: &[&StoreObject]) {
+        fs::create_dir_all(self.store.layout().generations_dir().join(id.to_string())).unwrap();
+        self.store
+            .database()
+            .transaction(|txn| {
+                txn.register_generation(id, Utc::now() - Duration::days(days_ago))?;
+                for object in objects {
+                    txn.add_root(id, &object.hash)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+    }
+
+    fn references(&self, object: &StoreObject, references: &[&StoreObject]) {
+        let hashes: Vec<&str> = references.iter().map(|r| r.hash.as_str()).collect();
+        self.store
+            .database()
+            .transaction(|txn| txn.add_references(&object.hash, &hashes))
+            .unwrap();
+    }
+
+    fn collect(&self, options: GcOptions) -> nexis_pm::store::gc::GcReport {
+        GarbageCollector::new(&self.store)
+            .collect(&options)
+            .unwrap()
+    }
+
+    fn exists(&self, object: &StoreObject) -> bool {
+        self.store.exists(&object.hash).unwrap()
+    }
+}
+
+fn trash_is_empty(store: &Store) -> bool {
+    let trash = store.layout().trash_dir();
+    !trash.exists() || fs::read_dir(trash).unwrap().next().is_none()
+}
+
+#[test]
+fn test_keep_drops_old_generations_and_their_closure() {
+    let fx = Fixture::new();
+    let libc = fx.package("libc", "libc");
+    let old_app = fx.package("app", "app 1");
+    let new_app = fx.package("app", "app 2");
+    let tool = fx.package("tool", "tool");
+    fx.references(&old_app, &[&libc]);
+    fx.references(&new_app, &[&libc]);
+
+    fx.generation(1, 3, &[&old_app, &tool]);
+    fx.generation(2, 2, &[&old_app]);
+    fx.generation(3, 1, &[&new_app]);
+
+    let report = fx.collect(GcOptions {
+        keep: Some(2),
+        ..Default::default()
+    });
+    assert_eq!(report.generations_removed, [1]);
+    assert_eq!(report.objects_removed.len(), 1);
+    assert_eq!(report.bytes_freed, "tool".len() as u64);
+    assert!(!fx.exists(&tool));
+    assert!(fx.exists(&old_app) && fx.exists(&new_app) && fx.exists(&libc));
+    assert!(!fx.store.layout().generations_dir().join("1").exists());
+
+    let report = fx.collect(GcOptions {
+        keep: Some(1),
+        ..Default::default()
+    });
+    assert_eq!(report.generations_removed, [2]);
+    assert!(!fx.exists(&old_app));
+    assert!(fx.exists(&new_app) && fx.exists(&libc));
+    assert_eq!(fx.store.database().refcount(&libc.hash).unwrap(), 1);
+    assert!(fx.store.database().verify(false).unwrap().is_empty());
+    assert!(trash_is_empty(&fx.store));
+}
+
+#[test]
+fn test_older_than_and_current_generation() {
+    let fx = Fixture::new();
+    let a = fx.package("a", "a");
+    let b = fx.package("b", "b");
+    let c = fx.package("c", "c");
+    fx.generation(1, 60, &[&a]);
+    fx.generation(2, 45, &[&b]);
+    fx.generation(3, 1, &[&c]);
+
+    // Generation 1 is current, so it survives despite its age
+    let generations = fx.store.layout().generations_dir();
+    std::os::unix::fs::symlink(generations.join("1"), generations.join("current")).unwrap();
+
+    let report = fx.collect(GcOptions {
+        older_than: Some(Duration::days(30)),
+        ..Default::default()
+    });
+    assert_eq!(report.generations_removed, [2]);
+    assert!(fx.exists(&a) && !fx.exists(&b) && fx.exists(&c));
+}
+
+#[test]
+fn test_dry_run_changes_nothing() {
+    let fx = Fixture::new();
+    let kept = fx.package("kept", "kept");
+    let garbage = fx.package("garbage", "garbage");
+    fx.generation(1, 1, &[&kept]);
+
+    let report = fx.collect(GcOptions {
+        dry_run: true,
+        ..Default::default()
+    });
+    assert!(report.dry_run);
+    assert_eq!(report.objects_removed, std::slice::from_ref(&garbage.path));
+    assert_eq!(report.bytes_freed, "garbage".len() as u64);
+    assert!(fx.exists(&garbage));
+
+    let report = fx.collect(GcOptions::default());
+    assert_eq!(report.objects_removed, std::slice::from_ref(&garbage.path));
+    assert!(!fx.exists(&garbage) && fx.exists(&kept));
+}
+
+#[test]
+fn test_recovers_from_interrupted_run() {
+    let fx = Fixture::new();
+    let kept = fx.package("kept", "kept");
+    fx.generation(1, 1, &[&kept]);
+
+    // A run that crashed after moving an object to the trash...
+    let trash = fx.store.layout().trash_dir();
+    fs::create_dir_all(trash.join("deadbeef-half-deleted")).unwrap();
+    fs::write(trash.join("deadbeef-half-deleted/data"), "12345").unwrap();
+    // ...and one that crashed after its database commit, before trashing
+    let orphan = fx.package("orphan", "orphan");
+    fx.store
+        .database()
+        .transaction(|txn| txn.remove_object(&orphan.hash))
+        .unwrap();
+    fs::create_dir_all(fx.store.layout().generations_dir().join("7")).unwrap();
+
+    let report = fx.collect(GcOptions::default());
+    assert_eq!(report.bytes_freed, 5 + "orphan".len() as u64);
+    assert!(!fx.exists(&orphan) && fx.exists(&kept));
+    assert!(!fx.store.layout().generations_dir().join("7").exists());
+    assert!(trash_is_empty(&fx.store));
+}
+
+#[test]
+fn test_waits_for_objects_to_be_rooted() {
+    let fx = Fixture::new();
+    let lock = fx.store.lock_shared().unwrap();
+    let building = fx.package("building", "building");
+
+    std::thread::scope(|scope| {
+        let gc = scope.spawn(|| fx.collect(GcOptions::default()));
+        std::thread::sleep(std::time::Duration::from_millis(200));
+        assert!(!gc.is_finished());
+
+        fx.generation(1, 0, &[&building]);
+        drop(lock);
+        assert!(gc.join().unwrap().objects_removed.is_empty());
+    });
+    assert!(fx.exists(&building));
+}
+
+#[test]
+fn test_collects_read_only_objects() {
+    let fx = Fixture::new();
+    let file = fx.store.add_bytes(b"motd").unwrap();
+    let tree = fx.package("locked", "locked");
+    set_read_only(&tree.path);
+
+    // Root may move read-only directories; an unprivileged owner may not
+    let report = unprivileged(&fx, || fx.collect(GcOptions::default()));
+    assert_eq!(report.objects_removed.len(), 2);
+    assert!(!fx.exists(&file) && !fx.exists(&tree));
+}
+
+fn set_read_only(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o555)).unwrap();
+}
+
+/// Run `f` as `nobody`, owning the fixture, if the tests run as root
+///
+/// Only the thread running `f` changes its ids. Threads inherit the ids of
+/// the thread spawning them, so the rayon pool other tests share is started
+/// first.
+fn unprivileged<T: Send>(fx: &Fixture, f: impl FnOnce() -> T + Send) -> T {
+    use rustix::process::{geteuid, Gid, Uid};
+    use rustix::thread::{set_thread_groups, set_thread_res_gid, set_thread_res_uid};
+
+    if !geteuid().is_root() {
+        return f();
+    }
+    rayon::broadcast(|_| ());
+    const NOBODY: u32 = 65534;
+    for entry in walkdir::WalkDir::new(fx.dir.path()) {
+        let entry = entry.unwrap();
+        std::os::unix::fs::lchown(entry.path(), Some(NOBODY), Some(NOBODY)).unwrap();
+    }
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                // SAFETY: 65534 is neither root nor the invalid id -1
+                let (uid, gid) = unsafe { (Uid::from_raw(NOBODY), Gid::from_raw(NOBODY)) };
+                set_thread_groups(&[]).unwrap();
+                set_thread_res_gid(gid, gid, gid).unwrap();
+                set_thread_res_uid(uid, uid, uid).unwrap();
+                f()
+            })
+            .join()
+            .unwrap()
+    })
+}
@@ -0,0 +1,4 @@
+//! Integration tests for NexisPM
+
+mod build_system;
+mod gc;